@@ -3,20 +3,33 @@ use std::net::{SocketAddr, TcpStream};
 
 use structopt::StructOpt;
 
-use tcp_demo_lines::{LinesCodec, DEFAULT_SERVER_ADDR};
+use tcp_demo_lines::{error_exit_code, json_error, parse_addr, LinesCodec, DEFAULT_SERVER_ADDR};
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "client")]
 struct Args {
     message: String,
     /// Server destination address
-    #[structopt(long, default_value = DEFAULT_SERVER_ADDR, global = true)]
+    #[structopt(long, default_value = DEFAULT_SERVER_ADDR, global = true, parse(try_from_str = parse_addr))]
     addr: SocketAddr,
+    /// Print errors to stderr as JSON (`{"error": "...", "kind": "..."}`) and exit with a code
+    /// for the kind of error (e.g. 2 = connection refused, 3 = timeout, see `error_exit_code`)
+    #[structopt(long)]
+    json_errors: bool,
 }
 
 fn main() -> io::Result<()> {
     let args = Args::from_args();
+    match run(&args) {
+        Err(e) if args.json_errors => {
+            eprintln!("{}", json_error(&e));
+            std::process::exit(error_exit_code(&e));
+        }
+        result => result,
+    }
+}
 
+fn run(args: &Args) -> io::Result<()> {
     let stream = TcpStream::connect(args.addr)?;
 
     // Codec is our interface for reading/writing messages.
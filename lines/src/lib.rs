@@ -3,27 +3,87 @@
 use std::io::{self, BufRead, Write};
 use std::net::TcpStream;
 
+// Shared with the raw server, which accepts & handles connections the same way
+pub use tcp_demo_raw::{is_client_disconnect, panic_message, serve_connections};
+// Shared with the raw & protocol clients' `--json-errors`
+pub use tcp_demo_raw::{error_exit_code, json_error};
+// Shared with the raw & protocol binaries' `--addr`
+pub use tcp_demo_raw::parse_addr;
+// The degraded mode of `LinesCodec` (see `is_degraded`) is the same as the protocol's
+use tcp_demo_raw::StreamHandle;
+
 pub const DEFAULT_SERVER_ADDR: &str = "127.0.0.1:4000";
 
+/// Suffix that `LinesCodec` ends each sent line with
+///
+/// Either is accepted when reading, since both end with `\n`
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum LineEnding {
+    /// `\n` (Unix style)
+    #[default]
+    Lf,
+    /// `\r\n` (Windows style, and what many text protocols like HTTP & SMTP expect)
+    Crlf,
+}
+
+impl LineEnding {
+    fn as_bytes(self) -> &'static [u8] {
+        match self {
+            LineEnding::Lf => b"\n",
+            LineEnding::Crlf => b"\r\n",
+        }
+    }
+}
+
 ///A smarter implementation of `extract_line` that supports writing messages also
 pub struct LinesCodec {
-    reader: io::BufReader<TcpStream>,
-    writer: io::LineWriter<TcpStream>,
+    reader: io::BufReader<StreamHandle<TcpStream>>,
+    writer: io::LineWriter<StreamHandle<TcpStream>>,
+    line_ending: LineEnding,
 }
 
 impl LinesCodec {
     /// Encapsulate a TcpStream with reader/writer functionality
+    ///
+    /// If the stream can't be cloned (e.g. the process is out of file descriptors), the codec
+    /// falls back to a degraded mode rather than failing (see `is_degraded`)
     pub fn new(stream: TcpStream) -> io::Result<Self> {
-        let writer = io::LineWriter::new(stream.try_clone()?);
-        let reader = io::BufReader::new(stream);
-        Ok(Self { reader, writer })
+        Self::with_line_ending(stream, LineEnding::default())
+    }
+
+    /// Same as `new`, but ending sent lines with `line_ending`
+    pub fn with_line_ending(stream: TcpStream, line_ending: LineEnding) -> io::Result<Self> {
+        Ok(Self::with_clone(stream.try_clone(), stream, line_ending))
     }
 
-    /// Write this line (with a '\n' suffix) to the TcpStream
+    /// Use `clone` as the writer's handle to `stream`, or share `stream` if it couldn't be cloned
+    fn with_clone(
+        clone: io::Result<TcpStream>,
+        stream: TcpStream,
+        line_ending: LineEnding,
+    ) -> Self {
+        let (reader, writer) = StreamHandle::pair(stream, clone);
+        Self {
+            reader: io::BufReader::new(reader),
+            writer: io::LineWriter::new(writer),
+            line_ending,
+        }
+    }
+
+    /// Whether the stream couldn't be cloned, so reads & writes share the one stream
+    ///
+    /// A degraded codec still works for sequential request/response (which is all the
+    /// `&mut self` methods allow anyway), but it's no longer full-duplex: the stream is locked
+    /// for the duration of each read or write
+    pub fn is_degraded(&self) -> bool {
+        self.writer.get_ref().is_shared()
+    }
+
+    /// Write this line (with a '\n' or '\r\n' suffix) to the TcpStream
     pub fn send_message(&mut self, message: &str) -> io::Result<()> {
-        self.writer.write_all(&message.as_bytes())?;
-        // This will also signal a `writer.flush()` for us!
-        self.writer.write(&['\n' as u8])?;
+        self.writer.write_all(message.as_bytes())?;
+        // Both endings contain a '\n', so this will also signal a `writer.flush()` for us!
+        self.writer.write_all(self.line_ending.as_bytes())?;
         Ok(())
     }
 
@@ -32,7 +92,89 @@ impl LinesCodec {
         let mut line = String::new();
         // Use `BufRead::read_line()` to read a line from the TcpStream
         self.reader.read_line(&mut line)?;
-        line.pop(); // Drop the trailing "\n"
+        trim_line_ending(&mut line);
         Ok(line)
     }
 }
+
+/// Drop the trailing "\n" or "\r\n" from a line
+///
+/// A lone "\r" isn't a line ending, so it's only dropped when followed by "\n"
+fn trim_line_ending(line: &mut String) {
+    if line.ends_with('\n') {
+        line.pop();
+        if line.ends_with('\r') {
+            line.pop();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::TcpListener;
+
+    /// Both ends of a loopback TCP connection
+    fn stream_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (client, server)
+    }
+
+    #[test]
+    fn test_crlf_roundtrip() {
+        let (client, server) = stream_pair();
+        let mut client = LinesCodec::with_line_ending(client, LineEnding::Crlf).unwrap();
+        let mut server = LinesCodec::new(server).unwrap();
+
+        client.send_message("Hello").unwrap();
+        let mut raw = String::new();
+        server.reader.read_line(&mut raw).unwrap();
+        assert_eq!(raw, "Hello\r\n");
+
+        client.send_message("World").unwrap();
+        assert_eq!(server.read_message().unwrap(), "World");
+        server.send_message("Hi").unwrap();
+        assert_eq!(client.read_message().unwrap(), "Hi");
+    }
+
+    #[test]
+    fn test_degraded_without_clone() {
+        let (client, server) = stream_pair();
+        let mut client = LinesCodec::new(client).unwrap();
+        let clone_err = io::Error::other("Too many open files");
+        let mut server = LinesCodec::with_clone(Err(clone_err), server, LineEnding::default());
+        assert!(server.is_degraded());
+        assert!(!client.is_degraded());
+
+        // Sequential request/response still works over the shared stream
+        for message in ["Hello", "big", "world"] {
+            client.send_message(message).unwrap();
+            let line = server.read_message().unwrap();
+            server.send_message(&line.to_uppercase()).unwrap();
+            assert_eq!(client.read_message().unwrap(), message.to_uppercase());
+        }
+    }
+
+    #[test]
+    fn test_read_message_strips_crlf() {
+        // e.g. from `nc -C` or telnet
+        let (mut client, server) = stream_pair();
+        let mut server = LinesCodec::new(server).unwrap();
+
+        client.write_all(b"hello\r\n").unwrap();
+        assert_eq!(server.read_message().unwrap(), "hello");
+    }
+
+    #[test]
+    fn test_mixed_line_endings() {
+        let (mut client, server) = stream_pair();
+        let mut server = LinesCodec::new(server).unwrap();
+
+        client.write_all(b"one\ntwo\r\nthree\rfour\n").unwrap();
+        assert_eq!(server.read_message().unwrap(), "one");
+        assert_eq!(server.read_message().unwrap(), "two");
+        assert_eq!(server.read_message().unwrap(), "three\rfour");
+    }
+}
@@ -0,0 +1,17 @@
+//! Feed arbitrary bytes to the message parsers, which should only ever return `Ok` or `Err`
+//!
+//! Run with `cargo +nightly fuzz run deserialize_request` (from the `protocol` directory)
+
+#![no_main]
+
+use std::io::Cursor;
+
+use libfuzzer_sys::fuzz_target;
+use tcp_demo_protocol::{Deserialize, Request, RequestRef, Response};
+
+fuzz_target!(|data: &[u8]| {
+    // Errors are expected, but a panic (or an abort, e.g. from a huge allocation) is a bug
+    let _ = Request::deserialize(&mut Cursor::new(data));
+    let _ = RequestRef::deserialize_ref(data);
+    let _ = Response::deserialize(&mut Cursor::new(data));
+});
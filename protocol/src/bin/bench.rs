@@ -0,0 +1,267 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use structopt::StructOpt;
+
+use tcp_demo_protocol::{
+    FlushStrategy, Protocol, Request, Response, ServerAddr, Stream, DEFAULT_SERVER_ADDR,
+};
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "bench")]
+struct Args {
+    /// Message to send in each Echo request
+    #[structopt(default_value = "Hello")]
+    message: String,
+    /// Server destination address (or `unix:/path/to.sock` for a Unix domain socket)
+    #[structopt(long, default_value = DEFAULT_SERVER_ADDR)]
+    addr: ServerAddr,
+    /// Number of concurrent (keep-alive) connections
+    #[structopt(long, default_value = "4")]
+    connections: usize,
+    /// Total number of requests, split between the connections
+    #[structopt(long, default_value = "10000")]
+    requests: usize,
+    /// Send this many requests (buffered into one `write`) before reading their responses,
+    /// rather than waiting for each response before the next request. Each latency is then
+    /// from the start of its batch
+    #[structopt(long, default_value = "1")]
+    batch: usize,
+    /// Read every response into the same `Response` (with `Protocol::read_message_into`),
+    /// reusing its allocation, to compare the allocations/request with reading a new one each time
+    #[structopt(long)]
+    reuse: bool,
+}
+
+/// Allocator that counts the allocations (and reallocations) made by the whole process
+struct CountAllocs;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountAllocs {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountAllocs = CountAllocs;
+
+/// Stream wrapper that counts the `write` calls (i.e. syscalls) made on the socket
+struct CountWrites<S> {
+    inner: S,
+    writes: Arc<AtomicUsize>,
+}
+
+impl<S: Read> Read for CountWrites<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl<S: Write> Write for CountWrites<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writes.fetch_add(1, Ordering::Relaxed);
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<S: Stream> Stream for CountWrites<S> {
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(Self {
+            inner: self.inner.try_clone()?,
+            writes: self.writes.clone(),
+        })
+    }
+}
+
+/// Latencies of the successful requests on one connection, and the number that failed
+struct ConnectionResult {
+    latencies: Vec<Duration>,
+    errors: usize,
+}
+
+/// Send `requests` Echo requests in batches of `batch` on a single connection, reading the
+/// responses into one reused `Response` with `reuse`
+fn run_connection<S: Stream>(
+    mut protocol: Protocol<S>,
+    message: &str,
+    requests: usize,
+    batch: usize,
+    reuse: bool,
+) -> ConnectionResult {
+    let mut result = ConnectionResult {
+        latencies: Vec::with_capacity(requests),
+        errors: 0,
+    };
+    // Nothing is sent until the first response of a batch is read (which flushes the batch)
+    protocol.set_flush_strategy(FlushStrategy::Manual);
+    let request = Request::Echo(message.to_string());
+    let mut resp = Response::Ok(String::new());
+    let mut remaining = requests;
+    'batches: while remaining > 0 {
+        let size = batch.min(remaining);
+        remaining -= size;
+        let start = Instant::now();
+        let sent = (0..size).try_for_each(|_| protocol.send_message(&request));
+        for _ in 0..size {
+            let read = match &sent {
+                Ok(()) if reuse => protocol.read_message_into::<Response>(&mut resp),
+                Ok(()) => protocol
+                    .read_message_required::<Response>()
+                    .map(|new| resp = new),
+                Err(e) => Err(io::Error::new(e.kind(), e.to_string())),
+            };
+            match read.map(|()| &resp) {
+                Ok(Response::Ok(_)) => result.latencies.push(start.elapsed()),
+                // A Pong, Tokens or Segments would be a reply to some other request
+                Ok(Response::Err(_))
+                | Ok(Response::Pong)
+                | Ok(Response::Tokens(_))
+                | Ok(Response::Segments(_)) => result.errors += 1,
+                // The connection is unusable, so count the rest of its requests as failed
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    result.errors += requests - result.latencies.len() - result.errors;
+                    break 'batches;
+                }
+            }
+        }
+    }
+    result
+}
+
+fn connect_and_run(
+    addr: &ServerAddr,
+    message: &str,
+    requests: usize,
+    batch: usize,
+    reuse: bool,
+    writes: Arc<AtomicUsize>,
+) -> ConnectionResult {
+    let protocol = match addr {
+        ServerAddr::Tcp(addr) => TcpStream::connect(addr).and_then(|inner| {
+            Protocol::with_stream(CountWrites { inner, writes })
+                .map(|p| run_connection(p, message, requests, batch, reuse))
+        }),
+        #[cfg(unix)]
+        ServerAddr::Unix(path) => UnixStream::connect(path).and_then(|inner| {
+            Protocol::with_stream(CountWrites { inner, writes })
+                .map(|p| run_connection(p, message, requests, batch, reuse))
+        }),
+    };
+    protocol.unwrap_or_else(|e| {
+        eprintln!("Error: {}", e);
+        ConnectionResult {
+            latencies: vec![],
+            errors: requests,
+        }
+    })
+}
+
+/// The latency that `pct` percent of requests were at or under (nearest-rank method)
+///
+/// `latencies` must be sorted and non-empty
+fn percentile(latencies: &[Duration], pct: f64) -> Duration {
+    let rank = (pct / 100.0 * latencies.len() as f64).ceil() as usize;
+    latencies[rank.clamp(1, latencies.len()) - 1]
+}
+
+fn main() -> io::Result<()> {
+    let args = Args::from_args();
+    if args.connections == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--connections must be at least 1",
+        ));
+    }
+    if args.batch == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--batch must be at least 1",
+        ));
+    }
+
+    let writes = Arc::new(AtomicUsize::new(0));
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    let threads: Vec<_> = (0..args.connections)
+        .map(|idx| {
+            // Spread the requests as evenly as possible across the connections
+            let requests = args.requests / args.connections
+                + usize::from(idx < args.requests % args.connections);
+            let addr = args.addr.clone();
+            let message = args.message.clone();
+            let (batch, reuse, writes) = (args.batch, args.reuse, writes.clone());
+            std::thread::spawn(move || {
+                connect_and_run(&addr, &message, requests, batch, reuse, writes)
+            })
+        })
+        .collect();
+
+    let mut latencies: Vec<Duration> = Vec::with_capacity(args.requests);
+    let mut errors = 0;
+    for thread in threads {
+        let result = thread.join().expect("Connection thread panicked");
+        latencies.extend(result.latencies);
+        errors += result.errors;
+    }
+    let elapsed = start.elapsed();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+
+    println!("requests        {:>10}", latencies.len());
+    println!("errors          {:>10}", errors);
+    println!("elapsed         {:>10.3}s", elapsed.as_secs_f64());
+    println!(
+        "requests/sec    {:>10.0}",
+        latencies.len() as f64 / elapsed.as_secs_f64()
+    );
+    // With `--batch`, many requests share each `write` syscall
+    let writes = writes.load(Ordering::Relaxed);
+    println!("writes          {:>10}", writes);
+    println!(
+        "writes/request  {:>10.2}",
+        writes as f64 / args.requests.max(1) as f64
+    );
+    // Includes the client's setup (threads, latency vectors, ...), which `--reuse` doesn't change
+    println!(
+        "allocs/request  {:>10.2}",
+        allocations as f64 / args.requests.max(1) as f64
+    );
+    if latencies.is_empty() {
+        return Ok(());
+    }
+    latencies.sort_unstable();
+    for (name, pct) in [("p50", 50.0), ("p90", 90.0), ("p99", 99.0)] {
+        println!(
+            "latency {}     {:>10}µs",
+            name,
+            percentile(&latencies, pct).as_micros()
+        );
+    }
+    println!(
+        "latency max     {:>10}µs",
+        latencies[latencies.len() - 1].as_micros()
+    );
+    Ok(())
+}
@@ -1,39 +1,493 @@
-use std::io;
-use std::net::SocketAddr;
+use std::io::{self, BufRead};
+use std::net::TcpStream;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
 
 use structopt::StructOpt;
 
-use tcp_demo_protocol::{Protocol, Request, Response, DEFAULT_SERVER_ADDR};
+#[cfg(feature = "config")]
+use tcp_demo_protocol::config::{self, Config};
+
+use tcp_demo_protocol::{
+    discovery, error_exit_code, json_error, new_request_id, retry_with_backoff,
+    sockopt::KeepaliveCfg, timestamp_nanos, trace::TraceStream, ExponentialBackoff,
+    JitteredBackoff, Protocol, ProtocolBuilder, Request, Response, ServerAddr, Stream, Traced,
+    DEFAULT_SERVER_ADDR,
+};
+
+/// Environment variable with the `--jumble` amount to use when the flag isn't given
+const JUMBLE_ENV_VAR: &str = "TCP_DEMO_JUMBLE";
+
+/// How many responses to read for the request (see `--expect-responses`)
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ExpectResponses {
+    /// Exactly this many
+    Count(u32),
+    /// Every one until the server closes the connection
+    UntilEof,
+}
+
+impl FromStr for ExpectResponses {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "until-eof" => Ok(Self::UntilEof),
+            _ => match s.parse() {
+                Ok(count) if count >= 1 => Ok(Self::Count(count)),
+                _ => Err(format!(
+                    "Invalid '{}', expected a count of at least 1 or 'until-eof'",
+                    s
+                )),
+            },
+        }
+    }
+}
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "client")]
 struct Args {
-    message: String,
-    // Jumble the message by how much (default = will not jumble)
-    #[structopt(short, long, default_value = "0")]
-    jumble: u16,
-    /// Server destination address
+    #[structopt(required_unless = "stdin-lines")]
+    message: Option<String>,
+    // Jumble the message by how much (default = `$TCP_DEMO_JUMBLE`, or 0 = will not jumble)
+    #[structopt(short, long)]
+    jumble: Option<u16>,
+    /// Ask the server for character, word & line counts of the message instead
+    #[structopt(long, conflicts_with = "jumble")]
+    stats: bool,
+    /// Ask the server for the CRC32 of the message (as hex) instead
+    #[structopt(long, conflicts_with_all = &["jumble", "stats"])]
+    checksum: bool,
+    /// Ask the server how many times each character occurs in the message instead, printing
+    /// one `character: count` line each
+    #[structopt(long, conflicts_with_all = &["jumble", "stats", "checksum"])]
+    histogram: bool,
+    /// Ask the server to wait this many milliseconds before echoing the message
+    #[structopt(long, conflicts_with_all = &["jumble", "stats"])]
+    delay_ms: Option<u32>,
+    /// Ask the server for the hex of the message bytes it received (for debugging the wire format)
+    #[structopt(long, conflicts_with_all = &["jumble", "stats", "checksum", "delay-ms"])]
+    reflect: bool,
+    /// Ask the server to join the message with these strings, e.g. `Hello --concat big --concat world`
+    #[structopt(long, conflicts_with_all = &["jumble", "stats", "checksum", "delay-ms", "reflect"])]
+    concat: Vec<String>,
+    /// Ask the server to split the message at each occurrence of this delimiter, printing
+    /// one piece per line
+    #[structopt(long, conflicts_with_all = &["jumble", "stats", "checksum", "delay-ms", "reflect", "concat"])]
+    split: Option<String>,
+    /// Ask the server to XOR the message with this (repeating) key, printing the result as
+    /// base64. NOT encryption, only a demo of transforming bytes (see `Request::Xor`)
+    #[structopt(long, conflicts_with_all = &["jumble", "stats", "checksum", "delay-ms", "reflect", "concat", "split", "repeat", "log"])]
+    xor: Option<String>,
+    /// Ask the server to undo `--xor` with this key, for a base64 message it printed
+    #[structopt(long, conflicts_with_all = &["jumble", "stats", "checksum", "delay-ms", "reflect", "concat", "split", "repeat", "log", "xor"])]
+    unxor: Option<String>,
+    /// Ask the server to send the message back this many times (as separate responses),
+    /// printing each one as it arrives
+    #[structopt(long, conflicts_with_all = &["jumble", "stats", "checksum", "delay-ms", "reflect", "concat", "split", "stdin-lines"])]
+    repeat: Option<u32>,
+    /// Milliseconds between the `--repeat` responses
+    #[structopt(long, default_value = "1000")]
+    interval_ms: u32,
+    /// Read and print this many responses to the request, or `until-eof` to close the sending
+    /// side once it's sent (so the server closes the connection when it's done) and print
+    /// every response until then. Defaults to 1, or the `--repeat` count
+    #[structopt(long, conflicts_with_all = &["log", "stdin-lines"])]
+    expect_responses: Option<ExpectResponses>,
+    /// Send the message to be logged by the server, without waiting for a response
+    #[structopt(long, conflicts_with_all = &["jumble", "stats", "checksum", "delay-ms", "reflect", "concat", "split", "repeat", "trace"])]
+    log: bool,
+    /// Ask the server to time handling the request, which it prefixes to the response
+    /// (e.g. `[12µs] 'Hello' from the other side!`)
+    #[structopt(long, conflicts_with_all = &["log", "repeat"])]
+    timed: bool,
+    /// Reject responses with a message (or tokens/segments, in total) larger than this many bytes
+    #[structopt(long)]
+    max_response_size: Option<usize>,
+    /// Server destination address (or `unix:/path/to.sock` for a Unix domain socket)
     #[structopt(long, default_value = DEFAULT_SERVER_ADDR, global = true)]
-    addr: SocketAddr,
+    addr: ServerAddr,
+    /// Enable TCP keepalive probes after the connection is idle for this many seconds
+    #[structopt(long)]
+    keepalive_secs: Option<u64>,
+    /// Retry connecting this many times if it fails (e.g. the server is still starting), waiting
+    /// longer before each retry (see `connect_backoff`)
+    #[structopt(long, default_value = "0")]
+    connect_retries: usize,
+    /// Print the request/response round-trip time (in microseconds) to stderr
+    #[structopt(long)]
+    timing: bool,
+    /// Authenticate messages with this pre-shared key (the server must use the same key)
+    #[cfg(feature = "hmac")]
+    #[structopt(long)]
+    psk: Option<String>,
+    /// Find the server with a UDP broadcast instead of using `--addr`
+    #[structopt(long)]
+    discover: bool,
+    /// Wrap messages in a length-prefixed frame (the server must use `--framed` too)
+    #[structopt(long)]
+    framed: bool,
+    /// Send a timestamp with every message, and print the round trip from the one the server
+    /// echoes back to stderr (the server must use `--timestamps` too)
+    #[structopt(long)]
+    timestamps: bool,
+    /// Send a random request ID (printed to stderr) so the server's logs can be matched up
+    /// (the server must use `--trace-ids`)
+    #[structopt(long)]
+    trace: bool,
+    /// Print a hex dump of every byte sent & received to stderr (for debugging the wire format)
+    #[structopt(long, conflicts_with = "keepalive-secs")]
+    trace_bytes: bool,
+    /// Print errors to stderr as JSON (`{"error": "...", "kind": "..."}`) and exit with a code
+    /// for the kind of error (e.g. 2 = connection refused, 3 = timeout, see `error_exit_code`)
+    #[structopt(long)]
+    json_errors: bool,
+    /// Send each line of stdin as the message of a separate request (instead of the `message`
+    /// argument), over one connection, printing each response. Blank lines are skipped
+    #[structopt(long)]
+    stdin_lines: bool,
+    /// Turn off `--framed`, if the `--config` file turns it on
+    #[cfg(feature = "config")]
+    #[structopt(long, conflicts_with = "framed")]
+    no_framed: bool,
+    /// Turn off `--timestamps`, if the `--config` file turns it on
+    #[cfg(feature = "config")]
+    #[structopt(long, conflicts_with = "timestamps")]
+    no_timestamps: bool,
+    /// Turn off `--timing`, if the `--config` file turns it on
+    #[cfg(feature = "config")]
+    #[structopt(long, conflicts_with = "timing")]
+    no_timing: bool,
+    /// Turn off `--json-errors`, if the `--config` file turns it on
+    #[cfg(feature = "config")]
+    #[structopt(long, conflicts_with = "json-errors")]
+    no_json_errors: bool,
+    /// Read options from this (TOML, or JSON if it ends in `.json`) file too, the command line's
+    /// take precedence (see `Args::with_config`)
+    #[cfg(feature = "config")]
+    #[structopt(long)]
+    config: Option<std::path::PathBuf>,
 }
 
-fn main() -> io::Result<()> {
-    let args = Args::from_args();
+#[cfg(feature = "config")]
+impl Args {
+    /// Parse the command line, then fill in the options it doesn't give from the `--config`
+    /// file, if there is one (see `tcp_demo_protocol::config`)
+    fn with_config() -> io::Result<Self> {
+        let matches = Self::clap().get_matches();
+        let mut args = Self::from_clap(&matches);
+        let config = match &args.config {
+            Some(path) => Config::load(path)?,
+            None => return Ok(args),
+        };
+        // `--addr` has a default, so only the matches say whether it was given
+        if let (Some(addr), 0) = (config.addr, matches.occurrences_of("addr")) {
+            args.addr = addr;
+        }
+        args.keepalive_secs = args.keepalive_secs.or(config.keepalive_secs);
+        if let (Some(retries), 0) = (
+            config.connect_retries,
+            matches.occurrences_of("connect-retries"),
+        ) {
+            args.connect_retries = retries;
+        }
+        #[cfg(feature = "hmac")]
+        {
+            args.psk = args.psk.or(config.psk);
+        }
+        args.framed = config::flag(args.framed, args.no_framed, config.framed);
+        args.timestamps = config::flag(args.timestamps, args.no_timestamps, config.timestamps);
+        args.timing = config::flag(args.timing, args.no_timing, config.timing);
+        args.json_errors = config::flag(args.json_errors, args.no_json_errors, config.json_errors);
+        args.max_response_size = args.max_response_size.or(config.max_response_size);
+        Ok(args)
+    }
+}
 
-    let req = if args.jumble > 0 {
-        Request::Jumble {
-            message: args.message,
-            amount: args.jumble,
+fn main() -> io::Result<()> {
+    #[cfg(feature = "config")]
+    let mut args = Args::with_config()?;
+    #[cfg(not(feature = "config"))]
+    let mut args = Args::from_args();
+    let env_jumble = std::env::var(JUMBLE_ENV_VAR).ok();
+    let result = jumble_amount(args.jumble, env_jumble.as_deref()).and_then(|amount| {
+        args.jumble = Some(amount);
+        run(&args)
+    });
+    match result {
+        Err(e) if args.json_errors => {
+            eprintln!("{}", json_error(&e));
+            std::process::exit(error_exit_code(&e));
         }
+        result => result,
+    }
+}
+
+fn run(args: &Args) -> io::Result<()> {
+    let addr = if args.discover {
+        ServerAddr::Tcp(discovery::discover(Duration::from_secs(2))?)
     } else {
-        Request::Echo(args.message)
+        args.addr.clone()
     };
 
-    Protocol::connect(args.addr)
-        .and_then(|mut client| {
-            client.send_message(&req)?;
-            Ok(client)
-        })
-        .and_then(|mut client| client.read_message::<Response>())
-        .map(|resp| println!("{}", resp.message()))
+    let mut backoff = connect_backoff(args.connect_retries);
+    match addr {
+        ServerAddr::Tcp(addr) if args.trace_bytes => {
+            let stream = retry_with_backoff(&mut backoff, || TcpStream::connect(addr))?;
+            Protocol::with_stream(TraceStream::stderr(stream))
+                .and_then(|client| session(client, args))
+        }
+        ServerAddr::Tcp(addr) => {
+            let mut builder = ProtocolBuilder::new();
+            if let Some(secs) = args.keepalive_secs {
+                builder = builder.keepalive(KeepaliveCfg::new(Duration::from_secs(secs)));
+            }
+            retry_with_backoff(&mut backoff, || builder.clone().connect(addr))
+                .and_then(|client| session(client, args))
+        }
+        #[cfg(unix)]
+        ServerAddr::Unix(path) if args.trace_bytes => {
+            let stream = retry_with_backoff(&mut backoff, || UnixStream::connect(&path))?;
+            Protocol::with_stream(TraceStream::stderr(stream))
+                .and_then(|client| session(client, args))
+        }
+        #[cfg(unix)]
+        ServerAddr::Unix(path) => {
+            retry_with_backoff(&mut backoff, || Protocol::connect_unix(&path))
+                .and_then(|client| session(client, args))
+        }
+    }
+}
+
+/// How long to wait before each of `--connect-retries`: from 50ms, doubling up to 2s, and
+/// jittered so clients started together don't all retry together
+fn connect_backoff(retries: usize) -> JitteredBackoff<ExponentialBackoff> {
+    JitteredBackoff::new(ExponentialBackoff::new(
+        Duration::from_millis(50),
+        Duration::from_secs(2),
+        retries,
+    ))
+}
+
+/// The `--jumble` amount: the flag's if it was given, otherwise `$TCP_DEMO_JUMBLE`'s (`env`)
+/// if that's set, otherwise 0 (don't jumble)
+fn jumble_amount(flag: Option<u16>, env: Option<&str>) -> io::Result<u16> {
+    match (flag, env) {
+        (Some(amount), _) => Ok(amount),
+        (None, Some(value)) if !value.trim().is_empty() => value.trim().parse().map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Invalid {} '{}', expected an amount from 0 to {}",
+                    JUMBLE_ENV_VAR,
+                    value,
+                    u16::MAX
+                ),
+            )
+        }),
+        (None, _) => Ok(0),
+    }
+}
+
+/// Build the kind of request chosen by the command line flags (Echo by default)
+fn build_request(message: String, args: &Args) -> Request {
+    let request = build_untimed_request(message, args);
+    if args.timed {
+        Request::Timed(Box::new(request))
+    } else {
+        request
+    }
+}
+
+/// Build the request to send, or to wrap in a `Request::Timed` with `--timed`
+fn build_untimed_request(message: String, args: &Args) -> Request {
+    if args.log {
+        Request::Log(message)
+    } else if !args.concat.is_empty() {
+        let mut parts = vec![message];
+        parts.extend(args.concat.iter().cloned());
+        Request::Concat(parts)
+    } else if let Some(count) = args.repeat {
+        Request::Repeat {
+            message,
+            count,
+            interval_ms: args.interval_ms,
+        }
+    } else if let Some(delimiter) = &args.split {
+        Request::Split {
+            message,
+            delimiter: delimiter.clone(),
+        }
+    } else if let Some(key) = &args.xor {
+        Request::Xor {
+            message,
+            key: key.clone(),
+        }
+    } else if let Some(key) = &args.unxor {
+        Request::Unxor {
+            message,
+            key: key.clone(),
+        }
+    } else if args.reflect {
+        Request::Reflect(message.into_bytes())
+    } else if let Some(ms) = args.delay_ms {
+        Request::Delay { message, ms }
+    } else if args.checksum {
+        Request::Checksum(message)
+    } else if args.histogram {
+        Request::Histogram(message)
+    } else if args.stats {
+        Request::Stats(message)
+    } else if let Some(amount @ 1..) = args.jumble {
+        Request::Jumble { message, amount }
+    } else {
+        Request::Echo(message)
+    }
+}
+
+/// Send the request(s) over an established connection, printing the response(s)
+fn session<S: Stream>(mut client: Protocol<S>, args: &Args) -> io::Result<()> {
+    #[cfg(feature = "hmac")]
+    if let Some(psk) = &args.psk {
+        client.set_psk(psk.as_bytes());
+    }
+    client.set_framed(args.framed);
+    client.set_timestamps(args.timestamps);
+
+    if !args.stdin_lines {
+        let message = args.message.clone().expect("message is required");
+        let req = build_request(message, args);
+        let is_jumble = matches!(req, Request::Jumble { .. });
+        let resp = exchange(&mut client, req, args)?;
+        print_response(if is_jumble {
+            highlight_jumble(resp)
+        } else {
+            resp
+        })?;
+        // The rest of a Repeat's responses are streamed after the first (an error ends it early)
+        match args.expect_responses {
+            Some(ExpectResponses::UntilEof) => {
+                // No more requests, so the server closes the connection once it's answered
+                client.shutdown_write()?;
+                while let Some(resp) = client.read_message::<Response>()? {
+                    print_response(Some(resp))?;
+                }
+            }
+            Some(ExpectResponses::Count(count)) => {
+                for _ in 1..count {
+                    print_response(Some(client.read_message_required::<Response>()?))?;
+                }
+            }
+            None => {
+                for _ in 1..args.repeat.unwrap_or(1) {
+                    print_response(Some(client.read_message_required::<Response>()?))?;
+                }
+            }
+        }
+        return Ok(());
+    }
+    for line in io::stdin().lock().lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let req = build_request(line, args);
+        let is_jumble = matches!(req, Request::Jumble { .. });
+        let mut resp = exchange(&mut client, req, args)?;
+        if is_jumble {
+            resp = highlight_jumble(resp);
+        }
+        // The server rejected this message, the rest may still be fine
+        if let Err(e) = print_response(resp) {
+            eprintln!("Error: {}", e);
+        }
+    }
+    Ok(())
+}
+
+/// Print a successful response's message, or return the server's error
+fn print_response(resp: Option<Response>) -> io::Result<()> {
+    match resp {
+        Some(Response::Ok(message)) => {
+            println!("{}", message);
+            Ok(())
+        }
+        Some(Response::Tokens(tokens)) => {
+            for token in tokens {
+                println!("{}", token);
+            }
+            Ok(())
+        }
+        Some(Response::Segments(segments)) => {
+            for (name, value) in segments {
+                println!("{}: {}", name, value);
+            }
+            Ok(())
+        }
+        Some(Response::Err(message)) => Err(io::Error::other(message)),
+        // Only sent in reply to a Ping, which this client doesn't send
+        Some(Response::Pong) => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Unexpected Pong response",
+        )),
+        // Nothing to wait for with `--log`
+        None => Ok(()),
+    }
+}
+
+/// Turn a Jumble's Response from a server with `--show-diff` (the jumbled message & the
+/// positions that changed, as Tokens) into the message with a `^` under each changed character
+fn highlight_jumble(resp: Option<Response>) -> Option<Response> {
+    match resp {
+        Some(Response::Tokens(tokens)) if tokens.len() == 2 => {
+            let changed: Vec<usize> = tokens[1]
+                .split(',')
+                .filter_map(|position| position.parse().ok())
+                .collect();
+            let markers: String = (0..tokens[0].chars().count())
+                .map(|i| if changed.contains(&i) { '^' } else { ' ' })
+                .collect();
+            Some(Response::Ok(format!(
+                "{}\n{}",
+                tokens[0],
+                markers.trim_end()
+            )))
+        }
+        resp => resp,
+    }
+}
+
+/// Send the request and read the response (if it has one), for any kind of stream
+fn exchange<S: Stream>(
+    client: &mut Protocol<S>,
+    req: Request,
+    args: &Args,
+) -> io::Result<Option<Response>> {
+    if !req.expects_response() {
+        return client.send_only(&req).map(|_| None);
+    }
+    // The connection is already established, so this only times the network round trip
+    // (plus the server's handling), not the TCP handshake
+    let start = Instant::now();
+    if args.trace {
+        let id = new_request_id();
+        eprintln!("Sending request {:016x}", id);
+        client.send_message(&Traced { id, message: req })?;
+    } else {
+        client.send_message(&req)?;
+    }
+    let resp = match args.max_response_size {
+        Some(max_size) => client.read_response_with_limit(max_size),
+        None => client.read_message_required::<Response>(),
+    }?;
+    if args.timing {
+        eprintln!("Round trip: {}µs", start.elapsed().as_micros());
+    }
+    if let Some(timestamp) = client.last_timestamp() {
+        let elapsed_ns = timestamp_nanos().saturating_sub(timestamp);
+        eprintln!("Round trip (echoed timestamp): {}µs", elapsed_ns / 1_000);
+    }
+    Ok(Some(resp))
 }
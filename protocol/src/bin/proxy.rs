@@ -0,0 +1,173 @@
+//! Relay framed messages between clients and an upstream server, optionally logging or
+//! modifying them on the way through
+//!
+//! Each connection gets two threads, one copying Request frames from the client to the server
+//! and one copying Response frames back. Frames are passed along without being parsed (see
+//! `Protocol::send_raw`), so the proxy works with any request type, unless it has to look
+//! inside them for `--log` or `--uppercase`. Both the clients and the server must use
+//! `--framed` (and not `--psk`, `--timestamps` or `--trace-ids`, which the proxy doesn't speak)
+
+use std::io;
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+
+use structopt::StructOpt;
+
+use tcp_demo_protocol::{
+    parse_addr, Deserialize, Protocol, RequestRef, Response, DEFAULT_SERVER_ADDR,
+};
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "proxy")]
+struct Args {
+    /// Listening address for clients
+    #[structopt(long, default_value = "127.0.0.1:4001", parse(try_from_str = parse_addr))]
+    addr: SocketAddr,
+    /// Server to relay the clients' messages to
+    #[structopt(long, default_value = DEFAULT_SERVER_ADDR, parse(try_from_str = parse_addr))]
+    upstream: SocketAddr,
+    /// Print every message relayed
+    #[structopt(long)]
+    log: bool,
+    /// Uppercase the message of every `Response::Ok` on its way back to the client
+    #[structopt(long)]
+    uppercase: bool,
+}
+
+/// Which way a frame is going, for logging it and picking how to parse it
+#[derive(Debug, Clone, Copy)]
+enum Direction {
+    /// Client to server (Requests)
+    Upstream,
+    /// Server to client (Responses)
+    Downstream,
+}
+
+/// How messages are changed (and reported) in transit
+#[derive(Debug, Clone, Copy)]
+struct Middlebox {
+    log: bool,
+    uppercase: bool,
+}
+
+impl Middlebox {
+    /// Relay one frame, the way `direction` says (see `Direction`)
+    fn relay(
+        &self,
+        frame: &[u8],
+        direction: Direction,
+        peer_addr: &SocketAddr,
+        dest: &mut Protocol,
+    ) -> io::Result<()> {
+        match direction {
+            Direction::Upstream => {
+                if self.log {
+                    match RequestRef::deserialize_ref(frame) {
+                        Ok(request) => eprintln!("{} -> {:?}", peer_addr, request),
+                        Err(e) => eprintln!("{} -> Malformed request: {}", peer_addr, e),
+                    }
+                }
+                dest.send_raw(frame)
+            }
+            Direction::Downstream if self.log || self.uppercase => {
+                let response = match Response::deserialize(&mut &frame[..]) {
+                    Ok(response) => response,
+                    // Not ours to fix, pass it on for the client to report
+                    Err(e) => {
+                        eprintln!("{} <- Malformed response: {}", peer_addr, e);
+                        return dest.send_raw(frame);
+                    }
+                };
+                let response = match response {
+                    Response::Ok(message) if self.uppercase => Response::Ok(message.to_uppercase()),
+                    response => response,
+                };
+                if self.log {
+                    eprintln!("{} <- {:?}", peer_addr, response);
+                }
+                dest.send_message(&response)?;
+                dest.flush()
+            }
+            Direction::Downstream => dest.send_raw(frame),
+        }
+    }
+}
+
+/// Copy frames from `src` to `dest` until `src` closes the connection, then close `dest`'s
+/// write half to pass that on
+fn pipe(
+    src: TcpStream,
+    dest: TcpStream,
+    direction: Direction,
+    peer_addr: SocketAddr,
+    middlebox: Middlebox,
+) -> io::Result<()> {
+    let mut reader = Protocol::with_stream(src)?;
+    reader.set_framed(true);
+    let mut writer = Protocol::with_stream(dest.try_clone()?)?;
+    writer.set_framed(true);
+    let mut frame = vec![];
+    let result = loop {
+        match reader.read_frame_into(&mut frame) {
+            Ok(()) => {}
+            // The end closed its side (between frames, or part way through one, which
+            // there's nothing more to do about)
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break Ok(()),
+            Err(e) => break Err(e),
+        }
+        if let Err(e) = middlebox.relay(&frame, direction, &peer_addr, &mut writer) {
+            break Err(e);
+        }
+    };
+    let _ = dest.shutdown(Shutdown::Write);
+    result
+}
+
+/// Connect to the upstream server for a client, and relay between them until both are done
+fn handle_connection(
+    client: TcpStream,
+    upstream_addr: SocketAddr,
+    middlebox: Middlebox,
+) -> io::Result<()> {
+    let peer_addr = client.peer_addr()?;
+    let upstream = TcpStream::connect(upstream_addr)?;
+    eprintln!("Relaying {} to {}", peer_addr, upstream_addr);
+    let responses = {
+        let (upstream, client) = (upstream.try_clone()?, client.try_clone()?);
+        std::thread::spawn(move || {
+            pipe(
+                upstream,
+                client,
+                Direction::Downstream,
+                peer_addr,
+                middlebox,
+            )
+        })
+    };
+    let requests = pipe(client, upstream, Direction::Upstream, peer_addr, middlebox);
+    let responses = responses.join().expect("Relay thread panicked");
+    eprintln!("Closed {}", peer_addr);
+    requests.and(responses)
+}
+
+fn main() -> io::Result<()> {
+    let args = Args::from_args();
+    let middlebox = Middlebox {
+        log: args.log,
+        uppercase: args.uppercase,
+    };
+    let listener = TcpListener::bind(args.addr)?;
+    eprintln!(
+        "Proxying '{}' to '{}'",
+        listener.local_addr()?,
+        args.upstream
+    );
+    for client in listener.incoming().flatten() {
+        let upstream = args.upstream;
+        std::thread::spawn(move || {
+            if let Err(e) = handle_connection(client, upstream, middlebox) {
+                eprintln!("Error: {}", e);
+            }
+        });
+    }
+    Ok(())
+}
@@ -0,0 +1,39 @@
+//! Replay a captured byte stream (e.g. the client's side of a connection, saved as raw bytes
+//! from a packet capture) through `Request::deserialize`, to see where it stops making sense
+
+use std::io::{self, Cursor};
+use std::path::PathBuf;
+
+use structopt::StructOpt;
+
+use tcp_demo_protocol::{Deserialize, Request};
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "replay")]
+struct Args {
+    /// File of the raw (unframed) requests a client sent, back to back
+    file: PathBuf,
+}
+
+fn main() -> io::Result<()> {
+    let args = Args::from_args();
+    let bytes = std::fs::read(&args.file)?;
+    let mut cursor = Cursor::new(&bytes[..]);
+    while (cursor.position() as usize) < bytes.len() {
+        let offset = cursor.position();
+        match Request::deserialize(&mut cursor) {
+            Ok(request) => println!("{}: {:?}", offset, request),
+            Err(e) => {
+                // Without framing, there's no telling where the next request would start
+                println!("{}: Error: {}", offset, e);
+                println!(
+                    "Stopped with {} of {} bytes unparsed",
+                    bytes.len() - offset as usize,
+                    bytes.len()
+                );
+                std::process::exit(1);
+            }
+        }
+    }
+    Ok(())
+}
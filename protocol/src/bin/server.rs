@@ -1,58 +1,884 @@
 use std::io;
-use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream};
+use std::num::NonZeroUsize;
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use structopt::StructOpt;
 
-use tcp_demo_protocol::{Protocol, Request, Response, DEFAULT_SERVER_ADDR};
+#[cfg(feature = "config")]
+use tcp_demo_protocol::config::{self, Config};
+
+use tcp_demo_protocol::{
+    append_metadata,
+    cache::ResponseCache,
+    chain::{append_chain_hash, HashChain},
+    check_jumble, discovery, is_client_disconnect, is_recoverable, jumble_diff, jumble_message,
+    metrics::Metrics,
+    panic_message,
+    priority::request_priority,
+    server::handle_stateless,
+    sockopt,
+    workers::WorkerPool,
+    Allowlist, FlushStrategy, Protocol, Request, Response, ServerAddr, Stream, Traced,
+    DEFAULT_SERVER_ADDR, GOODBYE_ACK, MAX_SIZE_UNLIMITED,
+};
+
+/// Longest a `Request::Delay` (or `Request::Repeat`, in total) can make a connection thread
+/// sleep for
+const MAX_DELAY: Duration = Duration::from_secs(10);
+
+/// Most requests being handled on their own threads at once, across every connection (with
+/// `--handler-timeout-ms`, see `respond_with_timeout`)
+const MAX_RUNNING_HANDLERS: usize = 64;
+
+/// Most Responses a `Request::Repeat` can ask for, since a short (or 0) interval doesn't
+/// bound how long it keeps a connection thread busy
+const MAX_REPEAT_COUNT: u32 = 1_000;
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "server")]
 struct Args {
-    /// Service listening address
+    /// Service listening address (or `unix:/path/to.sock` for a Unix domain socket)
     #[structopt(long, default_value = DEFAULT_SERVER_ADDR, global = true)]
-    addr: SocketAddr,
+    addr: ServerAddr,
+    /// Respond to UDP discovery broadcasts from clients (see `client --discover`)
+    #[structopt(long)]
+    discovery: bool,
+    /// Enable TCP keepalive probes after a connection is idle for this many seconds
+    #[structopt(long)]
+    keepalive_secs: Option<u64>,
+    /// Authenticate messages with this pre-shared key (clients must use the same key)
+    #[cfg(feature = "hmac")]
+    #[structopt(long)]
+    psk: Option<String>,
+    /// Only handle these request types, e.g. `--allow echo` (default allows all types)
+    #[structopt(long)]
+    allow: Vec<String>,
+    /// Append the client's observed address & the processing time to Echo responses
+    #[structopt(long)]
+    with_metadata: bool,
+    /// Send Jumble responses as two tokens: the jumbled message, and the (comma separated)
+    /// positions of the characters that changed
+    #[structopt(long)]
+    show_diff: bool,
+    /// Wrap messages in a length-prefixed frame, so a malformed request doesn't end the connection
+    /// (clients must use `--framed` too)
+    #[structopt(long)]
+    framed: bool,
+    /// Expect clients to send a request ID with each request (see `client --trace`), for logging
+    #[structopt(long)]
+    trace_ids: bool,
+    /// Expect a timestamp with every message, and echo the request's in its response
+    /// (clients must use `--timestamps` too)
+    #[structopt(long)]
+    timestamps: bool,
+    /// Shutdown gracefully after running for this many seconds (e.g. for demos & CI)
+    #[structopt(long)]
+    run_for_secs: Option<u64>,
+    /// Cache the Responses to this many distinct (deterministic) requests, e.g. large Jumbles
+    /// (at least 1, leave it out for no cache)
+    #[structopt(long)]
+    cache_size: Option<NonZeroUsize>,
+    /// Answer requests that take longer than this many milliseconds to handle with an error,
+    /// and close the connection (the handling carries on in the background, see
+    /// `respond_with_timeout`)
+    #[structopt(long)]
+    handler_timeout_ms: Option<u64>,
+    /// Reject requests larger than this many bytes on the wire, closing the connection
+    /// (clients can ask for it with a `Request::MaxSize`)
+    #[structopt(long)]
+    max_message_size: Option<usize>,
+    /// Close each connection after handling this many requests (not counting keepalive pings),
+    /// answering the next one with an error saying so
+    #[structopt(long)]
+    max_requests_per_conn: Option<u64>,
+    /// Chain a hash over each connection's requests, and add it to every Response (see
+    /// `tcp_demo_protocol::chain`)
+    #[structopt(long)]
+    hash_chain: bool,
+    /// Handle connections one at a time on the main thread, instead of a thread per connection
+    /// (for stepping through in a debugger, or logs that aren't interleaved)
+    #[structopt(long)]
+    single_threaded: bool,
+    /// Handle requests on a fixed pool of this many worker threads, which take the most urgent
+    /// request waiting first (see `respond_on_pool`)
+    #[structopt(long, conflicts_with = "handler-timeout-ms")]
+    workers: Option<NonZeroUsize>,
+    /// Turn off `--framed`, if the `--config` file turns it on
+    #[cfg(feature = "config")]
+    #[structopt(long, conflicts_with = "framed")]
+    no_framed: bool,
+    /// Turn off `--timestamps`, if the `--config` file turns it on
+    #[cfg(feature = "config")]
+    #[structopt(long, conflicts_with = "timestamps")]
+    no_timestamps: bool,
+    /// Turn off `--trace-ids`, if the `--config` file turns it on
+    #[cfg(feature = "config")]
+    #[structopt(long, conflicts_with = "trace-ids")]
+    no_trace_ids: bool,
+    /// Turn off `--with-metadata`, if the `--config` file turns it on
+    #[cfg(feature = "config")]
+    #[structopt(long, conflicts_with = "with-metadata")]
+    no_with_metadata: bool,
+    /// Turn off `--hash-chain`, if the `--config` file turns it on
+    #[cfg(feature = "config")]
+    #[structopt(long, conflicts_with = "hash-chain")]
+    no_hash_chain: bool,
+    /// Read options from this (TOML, or JSON if it ends in `.json`) file too, the command line's
+    /// take precedence (see `Args::with_config`)
+    #[cfg(feature = "config")]
+    #[structopt(long)]
+    config: Option<std::path::PathBuf>,
 }
 
-/// Given a TcpStream:
+#[cfg(feature = "config")]
+impl Args {
+    /// Parse the command line, then fill in the options it doesn't give from the `--config`
+    /// file, if there is one (see `tcp_demo_protocol::config`)
+    fn with_config() -> io::Result<Self> {
+        let matches = Self::clap().get_matches();
+        let mut args = Self::from_clap(&matches);
+        let config = match &args.config {
+            Some(path) => Config::load(path)?,
+            None => return Ok(args),
+        };
+        // `--addr` has a default, so only the matches say whether it was given
+        if let (Some(addr), 0) = (config.addr, matches.occurrences_of("addr")) {
+            args.addr = addr;
+        }
+        args.keepalive_secs = args.keepalive_secs.or(config.keepalive_secs);
+        #[cfg(feature = "hmac")]
+        {
+            args.psk = args.psk.or(config.psk);
+        }
+        if args.allow.is_empty() {
+            args.allow = config.allow.unwrap_or_default();
+        }
+        args.framed = config::flag(args.framed, args.no_framed, config.framed);
+        args.timestamps = config::flag(args.timestamps, args.no_timestamps, config.timestamps);
+        args.trace_ids = config::flag(args.trace_ids, args.no_trace_ids, config.trace_ids);
+        args.with_metadata = config::flag(
+            args.with_metadata,
+            args.no_with_metadata,
+            config.with_metadata,
+        );
+        args.hash_chain = config::flag(args.hash_chain, args.no_hash_chain, config.hash_chain);
+        args.cache_size = args.cache_size.or(config.cache_size);
+        args.handler_timeout_ms = args.handler_timeout_ms.or(config.handler_timeout_ms);
+        args.max_message_size = args.max_message_size.or(config.max_message_size);
+        args.max_requests_per_conn = args.max_requests_per_conn.or(config.max_requests_per_conn);
+        args.run_for_secs = args.run_for_secs.or(config.run_for_secs);
+        Ok(args)
+    }
+}
+
+/// State shared by the accept loop and every connection thread
+struct Context {
+    allowlist: Allowlist,
+    metrics: Metrics,
+    /// Set when the server should stop accepting connections
+    shutdown: AtomicBool,
+    #[cfg(feature = "hmac")]
+    psk: Option<String>,
+    /// Append `[peer=... time_us=...]` to Echo responses (see `append_metadata`)
+    with_metadata: bool,
+    /// Answer Jumbles with the changed positions too (see `jumble_diff`)
+    show_diff: bool,
+    framed: bool,
+    /// Requests are wrapped in a `Traced` with the client's request ID
+    trace_ids: bool,
+    timestamps: bool,
+    cache: Option<ResponseCache>,
+    handler_timeout: Option<Duration>,
+    /// Handler threads still running (see `respond_with_timeout`)
+    running_handlers: AtomicUsize,
+    max_message_size: Option<usize>,
+    /// Requests handled on a connection before it's closed (see `handle_connection`)
+    max_requests: Option<u64>,
+    /// Add each connection's request hash chain to its Responses
+    hash_chain: bool,
+    /// Handle each connection on the accept loop's thread (see `accept_loop`)
+    single_threaded: bool,
+    /// Handle requests on these workers (see `respond_on_pool`)
+    workers: Option<WorkerPool>,
+    /// ID for the next connection, keying its requests' jobs on the `workers`
+    next_connection_id: AtomicU64,
+}
+
+/// Given a stream (TcpStream or UnixStream), until the client closes the connection:
 /// - Deserialize the request
+/// - Answer a keepalive `Ping` with `Pong` (before any of the steps below)
+/// - Chain the request's hash with `--hash-chain`
+/// - Close the connection if it's had `--max-requests-per-conn` requests already
+/// - Check the request type is allowed
 /// - Handle the request
 /// - Serialize and write the Response to the stream
-fn handle_connection(stream: TcpStream) -> io::Result<()> {
-    let peer_addr = stream.peer_addr().expect("Stream has peer_addr");
+fn handle_connection<S: Stream>(
+    stream: S,
+    peer_addr: String,
+    ctx: &Arc<Context>,
+) -> io::Result<()> {
     let mut protocol = Protocol::with_stream(stream)?;
+    // A degraded connection can't have a `shutdown_handle` either (it needs its own clone of
+    // the stream), so nothing can interrupt its blocked reads. A graceful shutdown only stops
+    // the accept loop, so the connection still ends when its client disconnects or the
+    // process exits
+    if protocol.is_degraded() {
+        eprintln!(
+            "Warning: couldn't clone the stream, sharing it for reads & writes [{}]",
+            peer_addr
+        );
+    }
+    protocol.set_framed(ctx.framed);
+    protocol.set_timestamps(ctx.timestamps);
+    protocol.set_max_message_size(ctx.max_message_size);
+    // Responses are sent when the next read would wait for the client, so the responses to
+    // pipelined requests are coalesced into one `write` (rather than several small segments,
+    // which Nagle's algorithm would hold back waiting for the client to ACK the first)
+    protocol.set_flush_strategy(FlushStrategy::Manual);
+    #[cfg(feature = "hmac")]
+    if let Some(psk) = &ctx.psk {
+        protocol.set_psk(psk.as_bytes());
+    }
+
+    let mut recorded = ByteCounts::default();
+    let result = serve_requests(&mut protocol, &peer_addr, ctx, &mut recorded);
+    record_bytes(&protocol, &mut recorded, &ctx.metrics);
+    result
+}
+
+/// The keep-alive loop of `handle_connection`, reading requests until the client closes the
+/// connection (or the connection has to be closed)
+fn serve_requests<S: Stream>(
+    protocol: &mut Protocol<S>,
+    peer_addr: &str,
+    ctx: &Arc<Context>,
+    recorded: &mut ByteCounts,
+) -> io::Result<()> {
+    // A single Request is reused for every message on this connection
+    // so that the message allocation can be recycled
+    let mut traced = Traced {
+        id: 0,
+        message: Request::Echo(String::new()),
+    };
+    let mut handled: u64 = 0;
+    let mut chain = HashChain::new();
+    let connection_id = ctx.next_connection_id.fetch_add(1, Ordering::SeqCst);
+    loop {
+        // Before waiting for the next request, so the metrics are up to date while it's idle
+        record_bytes(protocol, recorded, &ctx.metrics);
+        let read = if ctx.trace_ids {
+            protocol.read_message_into::<Traced<Request>>(&mut traced)
+        } else {
+            protocol.read_message_into::<Request>(&mut traced.message)
+        };
+        match read {
+            Ok(()) => {}
+            // The client has closed the connection
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            // The malformed request was skipped (see `MalformedFrame`), so report it and carry on
+            Err(e) if is_recoverable(&e) => {
+                eprintln!("Error: {} [{}]", e, peer_addr);
+                ctx.metrics.record_error();
+                reply(protocol, &Response::Err(e.to_string()))?;
+                continue;
+            }
+            Err(e) => return Err(e),
+        }
+        let start = Instant::now();
+        let request = &traced.message;
+        if ctx.trace_ids {
+            eprintln!(
+                "Incoming {:?} [{}] (request {:016x})",
+                request, peer_addr, traced.id
+            );
+        } else {
+            eprintln!("Incoming {:?} [{}]", request, peer_addr);
+        }
+        ctx.metrics.record_request(request);
+
+        // Keepalives are answered here, before dispatch, so the allowlist, the cache and
+        // `handle_request` only ever see application requests
+        if let Request::Ping = request {
+            reply(protocol, &Response::Pong)?;
+            continue;
+        }
+        // The client is closing the connection (see `Protocol::close`), so acknowledge and
+        // close ours after it
+        if let Request::Goodbye = request {
+            let resp = Response::Ok(String::from(GOODBYE_ACK));
+            reply(protocol, &resp)?;
+            protocol.flush()?;
+            return Ok(());
+        }
+
+        // Every other request is chained (even those without a response), in the order read
+        let chain_hash = if ctx.hash_chain {
+            Some(chain.push_message(request)?)
+        } else {
+            None
+        };
+
+        // Keep-alive clients that expect to send any number of requests have to reconnect when
+        // this closes the connection, so the limit should be well above a normal session's
+        if ctx.max_requests.is_some_and(|max| handled >= max) {
+            eprintln!(
+                "Closing after {} requests, the limit per connection [{}]",
+                handled, peer_addr
+            );
+            // Unless the request has no response, which the client wouldn't be reading
+            if request.expects_response() {
+                let resp = with_chain_hash(
+                    Response::Err(format!(
+                        "Request limit of {} per connection reached, closing the connection",
+                        handled
+                    )),
+                    chain_hash,
+                );
+                reply(protocol, &resp)?;
+            }
+            protocol.flush()?;
+            return Ok(());
+        }
+        handled += 1;
+
+        // Never reply to these, not even with an error, or the client would read
+        // the reply as the response to its next request
+        if !request.expects_response() {
+            if !ctx.allowlist.allows(request) {
+                eprintln!(
+                    "Dropped request type '{}', it's not allowed [{}]",
+                    request.type_name(),
+                    peer_addr
+                );
+            } else if let Request::Log(message) = request {
+                eprintln!("Log [{}]: {}", peer_addr, message);
+            }
+            continue;
+        }
+
+        // Streamed back as several Responses, rather than the one built below
+        if let Request::Repeat {
+            message,
+            count,
+            interval_ms,
+        } = request
+        {
+            if ctx.allowlist.allows(request) {
+                repeat(protocol, message, *count, *interval_ms, chain_hash)?;
+                continue;
+            }
+        }
+
+        let resp = if !ctx.allowlist.allows(request) {
+            Response::Err(format!(
+                "Request type '{}' is not allowed",
+                request.type_name()
+            ))
+        } else {
+            match ctx.handler_timeout {
+                Some(timeout) => {
+                    match respond_with_timeout(request, peer_addr, start, ctx, timeout) {
+                        Some(resp) => resp,
+                        // The handler is still running, so close the connection rather than let
+                        // the client start another one alongside it
+                        None => {
+                            let resp = with_chain_hash(
+                                Response::Err(String::from(
+                                    "Handler timed out, closing the connection",
+                                )),
+                                chain_hash,
+                            );
+                            reply(protocol, &resp)?;
+                            protocol.flush()?;
+                            return Ok(());
+                        }
+                    }
+                }
+                None => match &ctx.workers {
+                    Some(workers) => {
+                        respond_on_pool(request, peer_addr, start, ctx, workers, connection_id)
+                    }
+                    None => respond(request, peer_addr, start, ctx),
+                },
+            }
+        };
+        let resp = with_chain_hash(resp, chain_hash);
+
+        reply(protocol, &resp)?;
+    }
+}
+
+/// Bytes of a connection already added to the server's `Metrics` (see `record_bytes`)
+#[derive(Debug, Default)]
+struct ByteCounts {
+    sent: u64,
+    received: u64,
+}
+
+/// Add the bytes a connection sent & received since the last call to the metrics
+///
+/// These are the `Protocol`'s counts of what went on the wire, so they include the frame
+/// lengths, timestamps, tags, etc. of whatever the connection is configured for
+fn record_bytes<S: Stream>(protocol: &Protocol<S>, recorded: &mut ByteCounts, metrics: &Metrics) {
+    metrics.record_bytes_in(protocol.bytes_received() - recorded.received);
+    metrics.record_bytes_out(protocol.bytes_sent() - recorded.sent);
+    recorded.received = protocol.bytes_received();
+    recorded.sent = protocol.bytes_sent();
+}
+
+/// Most tokens `handle_request` can answer with, leaving room in the u16 count for the chain
+/// hash's token with `--hash-chain` (see `with_chain_hash`)
+fn max_tokens(ctx: &Context) -> usize {
+    u16::MAX as usize - ctx.hash_chain as usize
+}
+
+/// Add the connection's hash chain to a Response, if there is one (with `--hash-chain`)
+fn with_chain_hash(mut resp: Response, chain_hash: Option<u32>) -> Response {
+    if let Some(hash) = chain_hash {
+        append_chain_hash(&mut resp, hash);
+    }
+    resp
+}
+
+/// Build the Response to an (allowed) request, from the cache if it's there
+fn respond(request: &Request, peer_addr: &str, start: Instant, ctx: &Context) -> Response {
+    let handle = || handle_request(request, peer_addr, start, ctx);
+    match &ctx.cache {
+        // The metadata differs for every request, so those Echo responses can't be reused
+        Some(cache)
+            if request.is_cacheable()
+                && !(ctx.with_metadata && matches!(request, Request::Echo(_))) =>
+        {
+            cache.get_or_insert_with(request, handle)
+        }
+        _ => handle(),
+    }
+}
+
+/// Like `respond`, but giving up on the Response after `timeout` (with `--handler-timeout-ms`),
+/// returning `None` if it timed out
+///
+/// The request is handled on its own thread, and std threads can't be killed, so a timed out
+/// handler isn't stopped: it carries on in the background (and its Response is still cached),
+/// the timeout only bounds how long the client waits. That's also why it isn't a scoped
+/// thread, which would have to be joined before returning.
+///
+/// So that clients can't pile up those threads, the connection is closed after a timeout (at
+/// most one per connection), and once `MAX_RUNNING_HANDLERS` are running across every
+/// connection, requests are refused until some finish
+fn respond_with_timeout(
+    request: &Request,
+    peer_addr: &str,
+    start: Instant,
+    ctx: &Arc<Context>,
+    timeout: Duration,
+) -> Option<Response> {
+    if ctx.running_handlers.fetch_add(1, Ordering::SeqCst) >= MAX_RUNNING_HANDLERS {
+        ctx.running_handlers.fetch_sub(1, Ordering::SeqCst);
+        eprintln!("Refused request, too many handlers running [{}]", peer_addr);
+        return Some(Response::Err(String::from(
+            "Server is busy, too many requests are being handled",
+        )));
+    }
+    let (tx, rx) = mpsc::channel();
+    let handler = {
+        let (request, peer_addr, ctx) = (request.clone(), peer_addr.to_string(), ctx.clone());
+        std::thread::spawn(move || {
+            // Counted as running until it's done, even if it panics
+            let _running = RunningHandler(&ctx.running_handlers);
+            // Nobody's waiting for the Response any more if the handler timed out
+            let _ = tx.send(respond(&request, &peer_addr, start, &ctx));
+        })
+    };
+    match rx.recv_timeout(timeout) {
+        Ok(resp) => Some(resp),
+        Err(RecvTimeoutError::Timeout) => {
+            eprintln!(
+                "Handler timed out after {}ms [{}]",
+                timeout.as_millis(),
+                peer_addr
+            );
+            None
+        }
+        // The handler panicked, so carry on unwinding on the connection's thread like it would
+        // without the timeout (which closes the connection)
+        Err(RecvTimeoutError::Disconnected) => match handler.join() {
+            Err(panic) => panic::resume_unwind(panic),
+            Ok(()) => unreachable!("The handler always sends a Response"),
+        },
+    }
+}
 
-    let request = protocol.read_message::<Request>()?;
-    eprintln!("Incoming {:?} [{}]", request, peer_addr);
-    let resp = match request {
-        Request::Echo(message) => Response(format!("'{}' from the other side!", message)),
-        Request::Jumble { message, amount } => Response(jumble_message(&message, amount)),
+/// Like `respond`, but on one of the `--workers` threads, waiting for it to finish
+///
+/// The connection's thread still reads the requests and writes the Responses, the pool bounds
+/// how many are handled at once across every connection. When every worker is busy, the next
+/// free one takes the most urgent request waiting (see `request_priority`), so e.g. an Echo
+/// isn't stuck behind another client's large Jumbles.
+///
+/// The connection's thread waits for each Response before reading the next request, but the
+/// jobs are keyed by `connection_id` too, so the pool itself never runs a connection's requests
+/// concurrently or out of order (see `tcp_demo_protocol::workers`).
+fn respond_on_pool(
+    request: &Request,
+    peer_addr: &str,
+    start: Instant,
+    ctx: &Arc<Context>,
+    workers: &WorkerPool,
+    connection_id: u64,
+) -> Response {
+    let (tx, rx) = mpsc::channel();
+    {
+        let (request, peer_addr, ctx) = (request.clone(), peer_addr.to_string(), ctx.clone());
+        workers.execute(connection_id, request_priority(&request), move || {
+            let resp = panic::catch_unwind(AssertUnwindSafe(|| {
+                respond(&request, &peer_addr, start, &ctx)
+            }));
+            let _ = tx.send(resp);
+        });
+    }
+    match rx.recv().expect("The workers run every job") {
+        Ok(resp) => resp,
+        // Carry on unwinding on the connection's thread like it would without the pool (which
+        // closes the connection)
+        Err(panic) => panic::resume_unwind(panic),
+    }
+}
+
+/// Counts a handler thread as running until it's dropped (see `respond_with_timeout`)
+struct RunningHandler<'a>(&'a AtomicUsize);
+
+impl Drop for RunningHandler<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Send a Response, echoing the timestamp of the request it's for (with `--timestamps`)
+fn reply<S: Stream>(protocol: &mut Protocol<S>, resp: &Response) -> io::Result<()> {
+    match protocol.last_timestamp() {
+        Some(timestamp) => protocol.send_message_at(resp, timestamp),
+        None => protocol.send_message(resp),
+    }
+}
+
+/// Build the Response to an (allowed) request
+fn handle_request(request: &Request, peer_addr: &str, start: Instant, ctx: &Context) -> Response {
+    match request {
+        Request::Echo(message) if ctx.with_metadata => {
+            let mut message = format!("'{}' from the other side!", message);
+            append_metadata(&mut message, peer_addr, start.elapsed());
+            Response::Ok(message)
+        }
+        Request::Jumble { message, amount } if ctx.show_diff => match check_jumble(message) {
+            Err(e) => Response::Err(e),
+            Ok(()) => {
+                let jumbled = jumble_message(message, *amount);
+                let positions = jumble_diff(message, &jumbled)
+                    .iter()
+                    .map(usize::to_string)
+                    .collect::<Vec<_>>()
+                    .join(",");
+                Response::Tokens(vec![jumbled, positions])
+            }
+        },
+        Request::Delay { message, ms } => {
+            // Cap the delay so a client can't tie up a thread indefinitely
+            std::thread::sleep(Duration::from_millis(*ms as u64).min(MAX_DELAY));
+            Response::Ok(format!("'{}' from the other side!", message))
+        }
+        Request::Split { delimiter, .. } if delimiter.is_empty() => {
+            Response::Err(String::from("Split delimiter can't be empty"))
+        }
+        Request::Split { message, delimiter } => {
+            let tokens: Vec<String> = message
+                .split(delimiter.as_str())
+                .map(String::from)
+                .collect();
+            // The count goes on the wire as a u16 (e.g. a message of only delimiters can exceed it)
+            if tokens.len() > max_tokens(ctx) {
+                Response::Err(format!("Too many tokens: {}", tokens.len()))
+            } else {
+                Response::Tokens(tokens)
+            }
+        }
+        Request::MaxSize => Response::Ok(match ctx.max_message_size {
+            Some(max_size) => max_size.to_string(),
+            None => String::from(MAX_SIZE_UNLIMITED),
+        }),
+        // These aren't answered with a single Response from here, so there's nothing to time
+        Request::Timed(inner)
+            if matches!(
+                **inner,
+                Request::Ping | Request::Goodbye | Request::Log(_) | Request::Repeat { .. }
+            ) =>
+        {
+            Response::Err(format!(
+                "Request type '{}' can't be timed",
+                inner.type_name()
+            ))
+        }
+        Request::Timed(inner) if !ctx.allowlist.allows(inner) => Response::Err(format!(
+            "Request type '{}' is not allowed",
+            inner.type_name()
+        )),
+        Request::Timed(inner) => {
+            let timer = Instant::now();
+            let resp = handle_request(inner, peer_addr, start, ctx);
+            let micros = timer.elapsed().as_micros();
+            match resp {
+                Response::Ok(message) => Response::Ok(format!("[{}µs] {}", micros, message)),
+                // No room for one more token in the u16 count
+                Response::Tokens(tokens) if tokens.len() >= max_tokens(ctx) => {
+                    Response::Err(format!("Too many tokens: {}", tokens.len() + 1))
+                }
+                Response::Tokens(mut tokens) => {
+                    tokens.insert(0, format!("{}µs", micros));
+                    Response::Tokens(tokens)
+                }
+                err => err,
+            }
+        }
+        Request::Log(_) => unreachable!("Requests without a response are handled separately"),
+        Request::Goodbye => unreachable!("Goodbyes are answered before dispatch"),
+        Request::Repeat { .. } => unreachable!("Repeats are streamed separately"),
+        // The rest don't depend on the server's options
+        request => handle_stateless(request).expect("Every other request type is stateless"),
+    }
+}
+
+/// Send the message `count` times, `interval_ms` apart (see `Request::Repeat`)
+fn repeat<S: Stream>(
+    protocol: &mut Protocol<S>,
+    message: &str,
+    count: u32,
+    interval_ms: u32,
+    chain_hash: Option<u32>,
+) -> io::Result<()> {
+    let interval = Duration::from_millis(interval_ms as u64);
+    // Like Delay, cap how long a client can tie up a thread for
+    let invalid = if count == 0 {
+        Some(String::from("Repeat count must be at least 1"))
+    } else if count > MAX_REPEAT_COUNT {
+        Some(format!("Repeat count must be at most {}", MAX_REPEAT_COUNT))
+    } else if interval * (count - 1) > MAX_DELAY {
+        Some(format!(
+            "Repeat would take longer than {}s",
+            MAX_DELAY.as_secs()
+        ))
+    } else {
+        None
     };
+    if let Some(err) = invalid {
+        let resp = with_chain_hash(Response::Err(err), chain_hash);
+        reply(protocol, &resp)?;
+        return Ok(());
+    }
 
-    protocol.send_message(&resp)
+    for i in 1..=count {
+        if i > 1 {
+            std::thread::sleep(interval);
+        }
+        let resp = with_chain_hash(
+            Response::Ok(format!("{} ({}/{})", message, i, count)),
+            chain_hash,
+        );
+        reply(protocol, &resp)?;
+        // There's no read in between to send it
+        protocol.flush()?;
+    }
+    Ok(())
+}
+
+/// Handle each connection in its own thread until shutdown is signaled
+///
+/// With `--single-threaded`, each connection is handled right here instead, so the next
+/// client isn't accepted until the current one disconnects (and a shutdown isn't noticed until
+/// then either)
+fn accept_loop<S: Stream + Send + 'static>(
+    incoming: impl Iterator<Item = io::Result<(S, String)>>,
+    ctx: &Arc<Context>,
+) {
+    for (stream, peer_addr) in incoming.flatten() {
+        if ctx.shutdown.load(Ordering::SeqCst) {
+            break;
+        }
+        ctx.metrics.record_connection();
+        if ctx.single_threaded {
+            run_connection(stream, peer_addr, ctx);
+        } else {
+            let ctx = ctx.clone();
+            std::thread::spawn(move || run_connection(stream, peer_addr, &ctx));
+        }
+    }
+}
+
+/// Handle a connection until it's closed, reporting (and counting) how it ended
+fn run_connection<S: Stream>(stream: S, peer_addr: String, ctx: &Arc<Context>) {
+    // Catch a panicking handler so it's reported (and counted) with its connection, the
+    // stream is dropped (closing the connection) as the panic unwinds
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        handle_connection(stream, peer_addr.clone(), ctx)
+    }));
+    match result {
+        Ok(Ok(())) => {}
+        // Not a server error, so don't count it as one
+        Ok(Err(e)) if is_client_disconnect(&e) => {
+            eprintln!("Client disconnected before response [{}]", peer_addr);
+        }
+        Ok(Err(e)) => {
+            ctx.metrics.record_error();
+            eprintln!("Error: {}", e);
+        }
+        Err(panic) => {
+            ctx.metrics.record_error();
+            eprintln!(
+                "Error: handler panicked: {} [{}]",
+                panic_message(&*panic),
+                peer_addr
+            );
+        }
+    }
 }
 
-/// Shake the characters around a little bit
-fn jumble_message(message: &str, amount: u16) -> String {
-    let mut chars: Vec<char> = message.chars().collect();
-    // Do some jumbling
-    for i in 1..=amount as usize {
-        let shuffle = i % chars.len();
-        chars.swap(0, shuffle);
+/// Signal shutdown, then unblock the accept loop (which is waiting in `accept()`)
+/// by connecting to ourselves
+///
+/// `accept()` blocks until a client connects, and std has no way to cancel it from
+/// another thread. So we become that client: the accept loop wakes up for our connection,
+/// sees the shutdown flag, and stops before handling it.
+fn shutdown(ctx: &Context, addr: &ServerAddr) {
+    ctx.shutdown.store(true, Ordering::SeqCst);
+    match addr {
+        ServerAddr::Tcp(addr) => {
+            let mut addr: SocketAddr = *addr;
+            // Listening on every address (e.g. `0.0.0.0` or `[::]`) isn't an address to
+            // connect to, so use the loopback address of the same family (an IPv6-only
+            // listener can't be reached over IPv4)
+            if addr.ip().is_unspecified() {
+                if addr.is_ipv6() {
+                    addr.set_ip(Ipv6Addr::LOCALHOST.into());
+                } else {
+                    addr.set_ip(Ipv4Addr::LOCALHOST.into());
+                }
+            }
+            let _ = TcpStream::connect(addr);
+        }
+        #[cfg(unix)]
+        ServerAddr::Unix(path) => {
+            let _ = UnixStream::connect(path);
+        }
     }
-    chars.into_iter().collect()
 }
 
 fn main() -> io::Result<()> {
+    #[cfg(feature = "config")]
+    let args = Args::with_config()?;
+    #[cfg(not(feature = "config"))]
     let args = Args::from_args();
-    eprintln!("Starting server on '{}'", args.addr);
+    let allowlist = Allowlist::from_names(&args.allow)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let ctx = Arc::new(Context {
+        allowlist,
+        metrics: Metrics::new(),
+        shutdown: AtomicBool::new(false),
+        #[cfg(feature = "hmac")]
+        psk: args.psk.clone(),
+        with_metadata: args.with_metadata,
+        show_diff: args.show_diff,
+        framed: args.framed,
+        trace_ids: args.trace_ids,
+        timestamps: args.timestamps,
+        cache: args.cache_size.map(|size| ResponseCache::new(size.get())),
+        handler_timeout: args.handler_timeout_ms.map(Duration::from_millis),
+        running_handlers: AtomicUsize::new(0),
+        max_message_size: args.max_message_size,
+        max_requests: args.max_requests_per_conn,
+        hash_chain: args.hash_chain,
+        single_threaded: args.single_threaded,
+        workers: args.workers.map(|workers| WorkerPool::new(workers.get())),
+        next_connection_id: AtomicU64::new(0),
+    });
+    match &args.addr {
+        ServerAddr::Tcp(addr) => {
+            let listener = TcpListener::bind(addr)?;
+            // The bound address, which has the actual port if `--addr` asked for any port (`:0`)
+            let local_addr = listener.local_addr()?;
+            eprintln!("Starting server on '{}'", local_addr);
+            if args.discovery {
+                discovery::spawn_discovery_responder(local_addr)?;
+                eprintln!(
+                    "Listening for discovery on udp/{}",
+                    discovery::DISCOVERY_PORT
+                );
+            }
+            let bound_addr = ServerAddr::Tcp(local_addr);
+            set_ctrlc_handler(&ctx, bound_addr.clone())?;
+            if let Some(secs) = args.run_for_secs {
+                spawn_shutdown_timer(&ctx, bound_addr, Duration::from_secs(secs));
+            }
 
-    let listener = TcpListener::bind(args.addr)?;
-    for stream in listener.incoming() {
-        if let Ok(stream) = stream {
-            std::thread::spawn(move || {
-                handle_connection(stream).map_err(|e| eprintln!("Error: {}", e))
+            let keepalive = args
+                .keepalive_secs
+                .map(|secs| sockopt::KeepaliveCfg::new(Duration::from_secs(secs)));
+            let incoming = listener.incoming().map(|stream| {
+                let stream = stream?;
+                if let Some(cfg) = keepalive {
+                    if let Err(e) = sockopt::set_tcp_keepalive(&stream, cfg) {
+                        eprintln!("Error: {}", e);
+                    }
+                }
+                // The peer is the client's ephemeral source port, not our listening port
+                let peer_addr = stream.peer_addr()?;
+                eprintln!("Accepted {} on {}", peer_addr, stream.local_addr()?);
+                Ok((stream, peer_addr.to_string()))
             });
+            accept_loop(incoming, &ctx);
+        }
+        #[cfg(unix)]
+        ServerAddr::Unix(path) => {
+            let listener = UnixListener::bind(path)?;
+            eprintln!("Starting server on '{}'", args.addr);
+            set_ctrlc_handler(&ctx, args.addr.clone())?;
+            if let Some(secs) = args.run_for_secs {
+                spawn_shutdown_timer(&ctx, args.addr.clone(), Duration::from_secs(secs));
+            }
+
+            // Unix domain socket clients are usually unnamed, so there's no useful peer address
+            let incoming = listener
+                .incoming()
+                .map(|stream| Ok((stream?, String::from("unix"))));
+            accept_loop(incoming, &ctx);
+            std::fs::remove_file(path)?;
         }
     }
+
+    eprintln!("Shutting down");
+    println!("{}", ctx.metrics);
+    if let Some(cache) = &ctx.cache {
+        println!("cache hits/misses  {}/{}", cache.hits(), cache.misses());
+    }
     Ok(())
 }
+
+/// Gracefully shutdown on Ctrl-C
+fn set_ctrlc_handler(ctx: &Arc<Context>, addr: ServerAddr) -> io::Result<()> {
+    let ctx = ctx.clone();
+    ctrlc::set_handler(move || shutdown(&ctx, &addr)).map_err(io::Error::other)
+}
+
+/// Gracefully shutdown once `run_for` has elapsed
+fn spawn_shutdown_timer(ctx: &Arc<Context>, addr: ServerAddr, run_for: Duration) {
+    let ctx = ctx.clone();
+    std::thread::spawn(move || {
+        std::thread::sleep(run_for);
+        shutdown(&ctx, &addr);
+    });
+}
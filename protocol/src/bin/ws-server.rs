@@ -0,0 +1,117 @@
+//! Serve Requests to browsers over WebSocket, one Request or Response per binary message
+//! (see `tcp_demo_protocol::websocket`)
+
+use std::io::{self, BufReader};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+
+use structopt::StructOpt;
+
+use tcp_demo_protocol::websocket::{self, Opcode};
+use tcp_demo_protocol::{
+    is_client_disconnect, parse_addr, server::handle_stateless, Deserialize, Request, Response,
+};
+
+/// Default listening address, next to the TCP server's
+const DEFAULT_WS_ADDR: &str = "127.0.0.1:4080";
+
+/// WebSocket close status for a peer that broke the protocol (RFC 6455 7.4.1)
+const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+
+/// WebSocket close status for a kind of data the endpoint can't accept (RFC 6455 7.4.1)
+const CLOSE_UNSUPPORTED_DATA: u16 = 1003;
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "ws-server")]
+struct Args {
+    /// Service listening address
+    #[structopt(long, default_value = DEFAULT_WS_ADDR, parse(try_from_str = parse_addr))]
+    addr: SocketAddr,
+}
+
+fn main() -> io::Result<()> {
+    let args = Args::from_args();
+    let listener = TcpListener::bind(args.addr)?;
+    eprintln!(
+        "Starting WebSocket server on 'ws://{}'",
+        listener.local_addr()?
+    );
+    for stream in listener.incoming().flatten() {
+        std::thread::spawn(move || {
+            let peer_addr = stream
+                .peer_addr()
+                .map_or_else(|_| String::from("unknown"), |addr| addr.to_string());
+            match handle_connection(stream) {
+                Ok(()) => {}
+                Err(e) if is_client_disconnect(&e) => {}
+                Err(e) => eprintln!("Error: {} [{}]", e, peer_addr),
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Upgrade the connection to WebSocket, then answer the Request in each binary message until
+/// the client closes the connection
+fn handle_connection(mut stream: TcpStream) -> io::Result<()> {
+    websocket::accept(&mut stream)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    loop {
+        let frame = match websocket::read_frame(&mut reader) {
+            Ok(frame) => frame,
+            // Closed without a Close frame
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        // Clients must mask every frame (RFC 6455 5.1), so the server has to close the connection
+        if !frame.masked {
+            let status = CLOSE_PROTOCOL_ERROR.to_be_bytes();
+            websocket::write_frame(&mut stream, Opcode::Close, &status, None)?;
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Unmasked frame, clients must mask every frame",
+            ));
+        }
+        match frame.opcode {
+            Opcode::Binary => {
+                // Every message is a whole frame, so a malformed one can be answered with an
+                // error without losing track of where the next one starts (like `--framed`)
+                let resp = match Request::deserialize_exact(&mut &frame.payload[..]) {
+                    Ok(Request::Log(message)) => {
+                        eprintln!("Log: {}", message);
+                        continue;
+                    }
+                    Ok(request) => handle_request(&request),
+                    Err(e) => Response::Err(format!("Invalid request: {}", e)),
+                };
+                websocket::write_message(&mut stream, &resp)?;
+            }
+            Opcode::Ping => {
+                websocket::write_frame(&mut stream, Opcode::Pong, &frame.payload, None)?
+            }
+            Opcode::Pong => {}
+            Opcode::Close => {
+                // Echo the status code back to complete the closing handshake
+                let status = &frame.payload[..frame.payload.len().min(2)];
+                return websocket::write_frame(&mut stream, Opcode::Close, status, None);
+            }
+            Opcode::Text => {
+                let status = CLOSE_UNSUPPORTED_DATA.to_be_bytes();
+                websocket::write_frame(&mut stream, Opcode::Close, &status, None)?;
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Text message, Requests must be sent as binary messages",
+                ));
+            }
+        }
+    }
+}
+
+/// Build the Response to a request, for the request types that don't need any server state
+fn handle_request(request: &Request) -> Response {
+    handle_stateless(request).unwrap_or_else(|| {
+        Response::Err(format!(
+            "Request type '{}' isn't supported over WebSocket",
+            request.type_name()
+        ))
+    })
+}
@@ -0,0 +1,154 @@
+//! Server-side cache of Responses to repeated (deterministic) requests
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use crate::{Request, Response, Serialize};
+
+/// A bounded, least-recently-used cache of Responses, shared between connection threads
+///
+/// Requests are keyed by their serialized bytes, so only byte-for-byte identical requests
+/// share a Response (e.g. the same message jumbled by the same amount). Only cache requests
+/// whose Response depends on nothing but the request (see `Request::is_cacheable`).
+pub struct ResponseCache {
+    capacity: usize,
+    state: Mutex<CacheState>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Default)]
+struct CacheState {
+    /// Cached Response and when it was last used, by serialized request
+    entries: HashMap<Vec<u8>, (Response, u64)>,
+    /// Serialized requests ordered by when they were last used (oldest first)
+    recency: BTreeMap<u64, Vec<u8>>,
+    /// Incremented on every use, so each use gets a unique (increasing) position
+    clock: u64,
+}
+
+impl ResponseCache {
+    /// Create an empty cache holding at most `capacity` Responses
+    pub fn new(capacity: usize) -> Self {
+        assert!(capacity > 0, "ResponseCache capacity must be at least 1");
+        Self {
+            capacity,
+            state: Mutex::new(CacheState::default()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Get the cached Response for `request`, or cache the result of `handle` if there isn't one
+    ///
+    /// The lock isn't held while `handle` runs, so two threads missing on the same request at once
+    /// will both handle it (the second result replaces the first)
+    pub fn get_or_insert_with(
+        &self,
+        request: &Request,
+        handle: impl FnOnce() -> Response,
+    ) -> Response {
+        let mut key: Vec<u8> = vec![];
+        request
+            .serialize(&mut key)
+            .expect("Writing to a Vec can't fail");
+        if let Some(resp) = self.state.lock().unwrap().get(&key) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return resp;
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let resp = handle();
+        self.state
+            .lock()
+            .unwrap()
+            .insert(key, resp.clone(), self.capacity);
+        resp
+    }
+
+    /// Number of requests answered from the cache
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// Number of requests that had to be handled (and were then cached)
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    /// Number of cached Responses
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl CacheState {
+    fn get(&mut self, key: &[u8]) -> Option<Response> {
+        self.clock += 1;
+        let (resp, last_used) = self.entries.get_mut(key)?;
+        // Move to the most recently used end
+        let key = self
+            .recency
+            .remove(last_used)
+            .expect("entries are in recency");
+        *last_used = self.clock;
+        self.recency.insert(self.clock, key);
+        Some(resp.clone())
+    }
+
+    fn insert(&mut self, key: Vec<u8>, resp: Response, capacity: usize) {
+        self.clock += 1;
+        if let Some((_, last_used)) = self.entries.remove(&key) {
+            self.recency.remove(&last_used);
+        }
+        while self.entries.len() >= capacity {
+            let (_, oldest) = self.recency.pop_first().expect("entries are in recency");
+            self.entries.remove(&oldest);
+        }
+        self.recency.insert(self.clock, key.clone());
+        self.entries.insert(key, (resp, self.clock));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::jumble_message;
+    use std::cell::Cell;
+
+    #[test]
+    fn test_cache_hit_and_eviction() {
+        let cache = ResponseCache::new(2);
+        let handled = Cell::new(0);
+        let jumble = |cache: &ResponseCache, message: &str| {
+            let req = Request::Jumble {
+                message: message.to_string(),
+                amount: 100,
+            };
+            cache.get_or_insert_with(&req, || {
+                handled.set(handled.get() + 1);
+                Response::Ok(jumble_message(message, 100))
+            })
+        };
+
+        let first = jumble(&cache, "Hello, world!");
+        // The identical request is answered from the cache
+        let second = jumble(&cache, "Hello, world!");
+        assert_eq!(first, second);
+        assert_eq!((cache.hits(), cache.misses()), (1, 1));
+
+        // "Hello, world!" was used more recently than "a", so "a" is evicted for "b"
+        jumble(&cache, "a");
+        jumble(&cache, "Hello, world!");
+        jumble(&cache, "b");
+        assert_eq!(cache.len(), 2);
+        jumble(&cache, "Hello, world!");
+        jumble(&cache, "a");
+        assert_eq!((cache.hits(), cache.misses()), (3, 4));
+        assert_eq!(handled.get(), 4);
+    }
+}
@@ -0,0 +1,139 @@
+//! Running hash chain over the requests on a connection, for tamper evidence
+//!
+//! Each request's hash covers the hash before it, `hash = crc32(previous_hash || request)`, so
+//! the latest hash depends on every request so far *and their order*. With `--hash-chain` the
+//! server keeps one chain per connection and adds the latest hash to every Response (see
+//! `append_chain_hash`). A client that chains the requests it sent the same way can check
+//! that the server received exactly those, in that order.
+//!
+//! CRC-32 makes this a demo of chaining, not security: anyone can compute a CRC, so a
+//! tamperer in the middle could rewrite the hashes too. A real chain would use a keyed hash
+//! (like the `auth` module's HMAC) for that reason.
+
+use std::io;
+
+use crate::{crc32, Response, Serialize};
+
+/// Name of the hash in a Response (`[chain=1234abcd]`, a `chain=1234abcd` token, or a
+/// `chain` segment)
+const CHAIN_TAG: &str = "chain";
+
+/// The running hash of the messages seen so far (see the module docs)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HashChain {
+    hash: u32,
+}
+
+impl HashChain {
+    /// A chain of no messages, with a hash of 0
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The hash of every message so far
+    pub fn hash(&self) -> u32 {
+        self.hash
+    }
+
+    /// Chain the next message's bytes, returning the new hash
+    pub fn push(&mut self, bytes: &[u8]) -> u32 {
+        let mut chained = Vec::with_capacity(4 + bytes.len());
+        chained.extend_from_slice(&self.hash.to_be_bytes());
+        chained.extend_from_slice(bytes);
+        self.hash = crc32(&chained);
+        self.hash
+    }
+
+    /// Chain the next message, as it's serialized on the wire (without any frame, timestamp,
+    /// etc.), returning the new hash
+    pub fn push_message(&mut self, message: &impl Serialize) -> io::Result<u32> {
+        let mut bytes = vec![];
+        message.serialize(&mut bytes)?;
+        Ok(self.push(&bytes))
+    }
+}
+
+/// Add a chain hash to a Response, in the way that suits its kind (see `response_chain_hash`)
+///
+/// A `Pong` has nowhere to put it, so it's left as is
+pub fn append_chain_hash(response: &mut Response, hash: u32) {
+    let hash = format!("{:08x}", hash);
+    match response {
+        Response::Ok(message) | Response::Err(message) => {
+            message.push_str(&format!(" [{}={}]", CHAIN_TAG, hash))
+        }
+        Response::Tokens(tokens) => tokens.push(format!("{}={}", CHAIN_TAG, hash)),
+        Response::Segments(segments) => segments.push((CHAIN_TAG.to_string(), hash)),
+        Response::Pong => {}
+    }
+}
+
+/// The chain hash added to a Response by `append_chain_hash`, if it has one
+pub fn response_chain_hash(response: &Response) -> Option<u32> {
+    let hash = match response {
+        Response::Ok(message) | Response::Err(message) => message
+            .strip_suffix(']')?
+            .rsplit_once(&format!(" [{}=", CHAIN_TAG))?
+            .1
+            .to_string(),
+        Response::Tokens(tokens) => tokens
+            .last()?
+            .strip_prefix(&format!("{}=", CHAIN_TAG))?
+            .to_string(),
+        Response::Segments(_) => response.segment(CHAIN_TAG)?.to_string(),
+        Response::Pong => return None,
+    };
+    // Always 8 digits, so a message that happens to end like one isn't mistaken for it
+    if hash.len() != 8 {
+        return None;
+    }
+    u32::from_str_radix(&hash, 16).ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Request;
+
+    /// The chain hash of Echo requests with these messages
+    fn chain(messages: &[&str]) -> u32 {
+        let mut chain = HashChain::new();
+        for message in messages {
+            chain
+                .push_message(&Request::Echo(message.to_string()))
+                .unwrap();
+        }
+        chain.hash()
+    }
+
+    #[test]
+    fn test_hash_chain_order() {
+        assert_eq!(chain(&[]), 0);
+        assert_eq!(chain(&["Hello", "World"]), chain(&["Hello", "World"]));
+        // Same messages, different order
+        assert_ne!(chain(&["Hello", "World"]), chain(&["World", "Hello"]));
+        // A message left out, or repeated
+        assert_ne!(chain(&["Hello", "World"]), chain(&["World"]));
+        assert_ne!(chain(&["Hello"]), chain(&["Hello", "Hello"]));
+    }
+
+    #[test]
+    fn test_response_chain_hash() {
+        for mut response in [
+            Response::Ok(String::from("Hello")),
+            Response::Err(String::from("Oops [chain=1]")),
+            Response::Tokens(vec![String::from("Hello")]),
+            Response::Segments(vec![(String::from("body"), String::from("Hello"))]),
+        ] {
+            assert_eq!(response_chain_hash(&response), None);
+            append_chain_hash(&mut response, 0x1234abcd);
+            assert_eq!(response_chain_hash(&response), Some(0x1234abcd));
+        }
+        let mut response = Response::Ok(String::from("Hello"));
+        append_chain_hash(&mut response, 0xab);
+        assert_eq!(
+            response,
+            Response::Ok(String::from("Hello [chain=000000ab]"))
+        );
+    }
+}
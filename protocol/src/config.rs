@@ -0,0 +1,228 @@
+//! Options from a config file (`--config path`), for setups with too many to type
+//!
+//! A `Config` has a field for each option that makes sense to keep in a file, named after the
+//! command line option it sets (`cache_size` for `--cache-size`). Every field is optional, and
+//! the server & client each take the ones they have options for, ignoring the rest. Options
+//! given on the command line override the file's, including `--no-<flag>` to turn off a flag
+//! the file turns on (see `flag`).
+//!
+//! The file is [TOML](https://toml.io), or JSON if its name ends in `.json`, with the options at
+//! the top level. Unknown keys, or values of the wrong type, are errors:
+//! ```toml
+//! # server.toml
+//! addr = "0.0.0.0:4000"
+//! allow = ["echo", "jumble"]
+//! cache_size = 100 # distinct requests
+//! framed = true
+//! ```
+//! ```json
+//! {"addr": "0.0.0.0:4000", "allow": ["echo", "jumble"], "cache_size": 100, "framed": true}
+//! ```
+//!
+//! Parsing is done by serde (`toml` & `serde_json`), with `Config` deriving `Deserialize`
+
+use std::io;
+use std::num::NonZeroUsize;
+use std::path::Path;
+
+use serde::{Deserialize, Deserializer};
+
+use crate::ServerAddr;
+
+/// The options set by a config file (`None` for those it doesn't set)
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// `--addr`, for both the server & client
+    #[serde(default, deserialize_with = "deserialize_addr")]
+    pub addr: Option<ServerAddr>,
+    /// `--keepalive-secs`, for both
+    pub keepalive_secs: Option<u64>,
+    /// `--psk`, for both (with the `hmac` feature)
+    pub psk: Option<String>,
+    /// `--framed`, for both
+    pub framed: Option<bool>,
+    /// `--timestamps`, for both
+    pub timestamps: Option<bool>,
+    /// `--allow`, for the server
+    pub allow: Option<Vec<String>>,
+    /// `--trace-ids`, for the server
+    pub trace_ids: Option<bool>,
+    /// `--with-metadata`, for the server
+    pub with_metadata: Option<bool>,
+    /// `--cache-size`, for the server
+    pub cache_size: Option<NonZeroUsize>,
+    /// `--handler-timeout-ms`, for the server
+    pub handler_timeout_ms: Option<u64>,
+    /// `--max-message-size`, for the server
+    pub max_message_size: Option<usize>,
+    /// `--max-requests-per-conn`, for the server
+    pub max_requests_per_conn: Option<u64>,
+    /// `--hash-chain`, for the server
+    pub hash_chain: Option<bool>,
+    /// `--run-for-secs`, for the server
+    pub run_for_secs: Option<u64>,
+    /// `--connect-retries`, for the client
+    pub connect_retries: Option<usize>,
+    /// `--max-response-size`, for the client
+    pub max_response_size: Option<usize>,
+    /// `--timing`, for the client
+    pub timing: Option<bool>,
+    /// `--json-errors`, for the client
+    pub json_errors: Option<bool>,
+}
+
+/// Parse `addr` like `--addr` does (see `ServerAddr`'s `FromStr`)
+fn deserialize_addr<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<ServerAddr>, D::Error> {
+    match Option::<String>::deserialize(deserializer)? {
+        Some(addr) => addr.parse().map(Some).map_err(serde::de::Error::custom),
+        None => Ok(None),
+    }
+}
+
+impl Config {
+    /// Read and parse a config file, as JSON if its name ends in `.json`, otherwise as TOML
+    /// (see the module docs)
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path)?;
+        let config = if path.extension().is_some_and(|ext| ext == "json") {
+            Self::from_json(&contents)
+        } else {
+            Self::from_toml(&contents)
+        };
+        config.map_err(|e| io::Error::new(e.kind(), format!("{} (in {})", e, path.display())))
+    }
+
+    /// Parse a TOML config, with an `io::ErrorKind::InvalidData` error saying what's invalid
+    pub fn from_toml(contents: &str) -> io::Result<Self> {
+        toml::from_str(contents).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid config: {}", e.to_string().trim_end()),
+            )
+        })
+    }
+
+    /// Parse a JSON config (an object of options), with an `io::ErrorKind::InvalidData` error
+    /// saying what's invalid
+    pub fn from_json(contents: &str) -> io::Result<Self> {
+        serde_json::from_str(contents).map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("Invalid config: {}", e))
+        })
+    }
+}
+
+/// Whether a flag is on, given `--<flag>` (`on`), `--no-<flag>` (`off`) and the file's value
+///
+/// The command line wins either way, and the flag is off if neither sets it
+pub fn flag(on: bool, off: bool, file: Option<bool>) -> bool {
+    match (on, off) {
+        (true, _) => true,
+        (_, true) => false,
+        _ => file.unwrap_or(false),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SAMPLE_TOML: &str = r#"
+        # A sample config
+        addr = "0.0.0.0:4000"  # every interface
+        framed = true
+        cache_size = 1_000
+        allow = [
+            "echo",
+            "jumble",
+        ]
+        psk = 'a "quoted" key'
+    "#;
+
+    const SAMPLE_JSON: &str = r#"{
+        "addr": "0.0.0.0:4000",
+        "framed": true,
+        "cache_size": 1000,
+        "allow": ["echo", "jumble"],
+        "psk": "a \"quoted\" key",
+        "timing": null
+    }"#;
+
+    fn sample() -> Config {
+        Config {
+            addr: Some("0.0.0.0:4000".parse().unwrap()),
+            framed: Some(true),
+            cache_size: NonZeroUsize::new(1_000),
+            allow: Some(vec![String::from("echo"), String::from("jumble")]),
+            psk: Some(String::from("a \"quoted\" key")),
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn test_parse_config() {
+        assert_eq!(Config::from_toml(SAMPLE_TOML).unwrap(), sample());
+        assert_eq!(Config::from_json(SAMPLE_JSON).unwrap(), sample());
+        assert_eq!(Config::from_toml("").unwrap(), Config::default());
+        assert_eq!(Config::from_json(" {} ").unwrap(), Config::default());
+        assert_eq!(
+            Config::from_json(r#"{"psk": "café\n"}"#).unwrap().psk,
+            Some(String::from("café\n"))
+        );
+    }
+
+    #[test]
+    fn test_parse_config_invalid() {
+        for invalid in [
+            "[server]\nframed = true",
+            "addr = \"0.0.0.0:4000",
+            "addr = \"nowhere\"",
+            "framed = yes",
+            "framed = \"true\"",
+            "cache_size = 0",
+            "cache_size = -1",
+            "colour = true",
+            "allow = [1]",
+            "framed = true\nframed = false",
+        ] {
+            let err = Config::from_toml(invalid).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{}", invalid);
+        }
+        for invalid in [
+            "",
+            "[]",
+            r#"{"framed": true"#,
+            r#"{"framed": true} {}"#,
+            r#"{"framed": 1}"#,
+            r#"{"colour": true}"#,
+            r#"{"addr": {"ip": "0.0.0.0"}}"#,
+            r#"{"framed": true, "framed": true}"#,
+        ] {
+            let err = Config::from_json(invalid).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_flag() {
+        assert!(flag(true, false, Some(false)));
+        assert!(!flag(false, true, Some(true)));
+        assert!(flag(false, false, Some(true)));
+        assert!(!flag(false, false, None));
+    }
+
+    #[test]
+    fn test_load_config() {
+        let dir = std::env::temp_dir();
+        for (name, contents) in [("toml", SAMPLE_TOML), ("json", SAMPLE_JSON)] {
+            let path = dir.join(format!("tcp_demo_config_{}.{}", std::process::id(), name));
+            std::fs::write(&path, contents).unwrap();
+            let config = Config::load(&path);
+            std::fs::remove_file(&path).unwrap();
+            assert_eq!(config.unwrap(), sample());
+        }
+    }
+}
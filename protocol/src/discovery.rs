@@ -0,0 +1,127 @@
+//! UDP broadcast discovery, so a client can find a server on the LAN without knowing its IP
+//!
+//! The client broadcasts a small datagram ([`DISCOVERY_MAGIC`]) to [`DISCOVERY_PORT`] and
+//! any listening server replies with the `SocketAddr` its TCP listener is bound to.
+//!
+//! ## Caveats
+//! - Broadcasts don't cross routers, so this only finds servers on the same subnet
+//! - Host firewalls commonly drop inbound UDP; the server needs [`DISCOVERY_PORT`]/udp allowed
+//!   and the client needs to accept the unicast reply (most stateful firewalls allow this)
+//! - Some networks (e.g. guest Wi-Fi with client isolation) block broadcast traffic entirely
+
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// UDP port that servers listen on for discovery datagrams
+pub const DISCOVERY_PORT: u16 = 4001;
+/// Payload of a discovery request datagram
+pub const DISCOVERY_MAGIC: &[u8] = b"TCP_DEMO_DISCOVER";
+
+/// Broadcast a discovery request and wait (up to `timeout`) for a server to reply with its address
+pub fn discover(timeout: Duration) -> io::Result<SocketAddr> {
+    discover_at(
+        SocketAddr::from((Ipv4Addr::BROADCAST, DISCOVERY_PORT)),
+        timeout,
+    )
+}
+
+/// Send a discovery request to `target` (usually a broadcast address) and wait for a reply
+fn discover_at(target: SocketAddr, timeout: Duration) -> io::Result<SocketAddr> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.set_broadcast(true)?;
+    socket.send_to(DISCOVERY_MAGIC, target)?;
+
+    let deadline = Instant::now() + timeout;
+    let mut buf = [0u8; 64];
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining == Duration::from_secs(0) {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "No server responded to discovery",
+            ));
+        }
+        socket.set_read_timeout(Some(remaining))?;
+        let (len, responder) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                continue
+            }
+            Err(e) => return Err(e),
+        };
+        // Ignore anything that isn't a valid address (some other chatty service on our port)
+        if let Some(addr) = parse_reply(&buf[..len], responder) {
+            return Ok(addr);
+        }
+    }
+}
+
+/// Parse a server's reply, filling in the responder's IP if the server is bound to `0.0.0.0`
+fn parse_reply(reply: &[u8], responder: SocketAddr) -> Option<SocketAddr> {
+    let mut addr: SocketAddr = std::str::from_utf8(reply).ok()?.parse().ok()?;
+    if addr.ip().is_unspecified() {
+        addr.set_ip(responder.ip());
+    }
+    Some(addr)
+}
+
+/// Listen for discovery requests on [`DISCOVERY_PORT`] in a background thread,
+/// replying to each with `tcp_addr`
+pub fn spawn_discovery_responder(tcp_addr: SocketAddr) -> io::Result<thread::JoinHandle<()>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, DISCOVERY_PORT))?;
+    Ok(spawn_responder(socket, tcp_addr))
+}
+
+fn spawn_responder(socket: UdpSocket, tcp_addr: SocketAddr) -> thread::JoinHandle<()> {
+    let reply = tcp_addr.to_string();
+    thread::spawn(move || {
+        let mut buf = [0u8; 64];
+        loop {
+            let (len, peer) = match socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(e) => {
+                    eprintln!("Discovery error: {}", e);
+                    continue;
+                }
+            };
+            if &buf[..len] == DISCOVERY_MAGIC {
+                eprintln!("Discovery request from {}", peer);
+                if let Err(e) = socket.send_to(reply.as_bytes(), peer) {
+                    eprintln!("Discovery error: {}", e);
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_discover_responder() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let responder_addr = socket.local_addr().unwrap();
+        let tcp_addr: SocketAddr = "0.0.0.0:4000".parse().unwrap();
+        spawn_responder(socket, tcp_addr);
+
+        let found = discover_at(responder_addr, Duration::from_secs(2)).unwrap();
+        // The unspecified IP is replaced with the responder's IP
+        assert_eq!(found, "127.0.0.1:4000".parse().unwrap());
+    }
+
+    #[test]
+    fn test_discover_timeout() {
+        // Nobody is listening on this socket, so it will never respond
+        let quiet = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let err = discover_at(quiet.local_addr().unwrap(), Duration::from_millis(50)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+}
@@ -0,0 +1,115 @@
+//! Guess how a connection's messages are framed from its first byte, so one port can serve
+//! both the `lines` clients (newline-terminated text) and this crate's (length-prefixed)
+//!
+//! This is a heuristic, not part of either protocol. A length-prefixed message starts with a
+//! request type byte (one of `REQUEST_TYPES`, all below `b' '`), a sequence number, a
+//! timestamp, or a frame length, all of which are nearly always ASCII control characters, while
+//! a line of text nearly always starts with a printable one. Only printable ASCII counts as
+//! text: the type bytes include `\t` (Ping), `\n` (Checksum) and `\r` (Repeat).
+//! But there are cases where it guesses wrong:
+//! - With `Endian::Little` and `framed`, the first byte is the low byte of the frame length, so
+//!   a 72 byte message starts with `b'H'`, and is detected as a line
+//! - A line starting with a non-ASCII character (e.g. "é", `0xc3 0xa9` in UTF-8), or with
+//!   whitespace other than a space (including a blank line), is detected as length-prefixed
+//!
+//! So only rely on it where the clients are known to avoid those
+
+use std::io::{self, BufRead};
+
+/// How the messages on a connection are separated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Framing {
+    /// Newline-terminated text, like the `lines` crate's `LinesCodec`
+    Lines,
+    /// Length-prefixed binary messages, like this crate's `Protocol`
+    LengthPrefixed,
+}
+
+/// Peek at the first byte waiting in `reader` to guess its `Framing` (see the module docs for
+/// how it can guess wrong), without consuming anything
+///
+/// Blocks until there's at least one byte to read, and returns an `io::ErrorKind::UnexpectedEof`
+/// error if the connection is closed before then
+pub fn detect_framing(reader: &mut impl BufRead) -> io::Result<Framing> {
+    let first = match reader.fill_buf()?.first() {
+        Some(&byte) => byte,
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Connection closed before its framing could be detected",
+            ))
+        }
+    };
+    match first {
+        b' '..=b'~' => Ok(Framing::Lines),
+        _ => Ok(Framing::LengthPrefixed),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{BufReader, Read};
+
+    use super::*;
+    use crate::memory::MemoryStream;
+    use crate::{Protocol, Request, REQUEST_TYPES};
+
+    #[test]
+    fn test_detect_lines() {
+        let mut reader = BufReader::new(&b"Hello, world!\n"[..]);
+        assert_eq!(detect_framing(&mut reader).unwrap(), Framing::Lines);
+        // Nothing was consumed
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "Hello, world!\n");
+
+        // Leading spaces too
+        let mut reader = BufReader::new(&b"  indented\n"[..]);
+        assert_eq!(detect_framing(&mut reader).unwrap(), Framing::Lines);
+    }
+
+    #[test]
+    fn test_detect_every_request_type() {
+        // Unframed, the first byte is the type byte, some of which are `\t`, `\n` & `\r`
+        for (name, code) in REQUEST_TYPES {
+            let bytes = [*code, 0, 5];
+            let mut reader = BufReader::new(&bytes[..]);
+            assert_eq!(
+                detect_framing(&mut reader).unwrap(),
+                Framing::LengthPrefixed,
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn test_detect_length_prefixed() {
+        for framed in [false, true] {
+            let (client_stream, mut server_stream) = MemoryStream::pair();
+            let mut client = Protocol::with_stream(client_stream).unwrap();
+            client.set_framed(framed);
+            client
+                .send_message(&Request::Echo(String::from("Hello")))
+                .unwrap();
+            let mut sent = vec![];
+            server_stream.read_to_end(&mut sent).unwrap();
+
+            let mut reader = BufReader::new(&sent[..]);
+            assert_eq!(
+                detect_framing(&mut reader).unwrap(),
+                Framing::LengthPrefixed
+            );
+            let mut unread = vec![];
+            reader.read_to_end(&mut unread).unwrap();
+            assert_eq!(unread, sent);
+        }
+    }
+
+    #[test]
+    fn test_detect_closed_connection() {
+        let mut reader = BufReader::new(&b""[..]);
+        let err = detect_framing(&mut reader).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -8,19 +8,157 @@
 //! [tokio_util::codec](https://docs.rs/tokio-util/0.3.1/tokio_util/codec/index.html)
 //! [bincode](https://github.com/servo/bincode)
 
-use std::convert::From;
-use std::io::{self, Read, Write};
-use std::net::{SocketAddr, TcpStream};
+use std::collections::{HashMap, HashSet};
+use std::convert::{From, TryFrom};
+use std::io::{self, BufRead, Read, Write};
+use std::marker::PhantomData;
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use byteorder::{NetworkEndian, ReadBytesExt, WriteBytesExt};
+use byteorder::{ReadBytesExt, WriteBytesExt};
+// Re-exported for choosing the byte order of `Serialize::serialize_with_order` & co.
+pub use byteorder::{ByteOrder, LittleEndian, NetworkEndian};
+// Shared with the raw & lines servers, which report failed connections the same way
+pub use tcp_demo_raw::{is_client_disconnect, panic_message};
+// Reads in this module go through `read_exact` (and `write_all` for writes), which already retry
+// interrupted syscalls, so this is for the remaining calls like `flush`
+pub use tcp_demo_raw::retry_on_interrupt;
+// Shared with the raw & lines clients' `--json-errors`
+pub use tcp_demo_raw::{error_exit_code, json_error};
+// Shared with the raw & lines binaries' `--addr` (and used for TCP `ServerAddr`s)
+pub use tcp_demo_raw::parse_addr;
+// The degraded mode of `Protocol` (see `is_degraded`) is the same as the `LinesCodec`'s
+use tcp_demo_raw::StreamHandle;
+
+#[cfg(feature = "hmac")]
+pub mod auth;
+pub mod cache;
+pub mod chain;
+#[cfg(feature = "config")]
+pub mod config;
+pub mod discovery;
+pub mod framing;
+#[cfg(test)]
+mod memory;
+pub mod metrics;
+pub mod pool;
+pub mod priority;
+pub mod server;
+pub mod sockopt;
+pub mod trace;
+#[cfg(feature = "websocket")]
+pub mod websocket;
+pub mod workers;
 
 pub const DEFAULT_SERVER_ADDR: &str = "127.0.0.1:4000";
 
+/// Address of a server, either a TCP `SocketAddr` or (on Unix platforms) a Unix domain socket path
+///
+/// Parsed from strings like `127.0.0.1:4000` or `unix:/tmp/demo.sock`
+#[derive(Debug, Clone, PartialEq)]
+pub enum ServerAddr {
+    /// TCP socket address
+    Tcp(SocketAddr),
+    /// Unix domain socket path, for local IPC without the TCP overhead
+    #[cfg(unix)]
+    Unix(std::path::PathBuf),
+}
+
+impl std::str::FromStr for ServerAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("unix:") {
+            #[cfg(unix)]
+            Some(path) => Ok(ServerAddr::Unix(path.into())),
+            #[cfg(not(unix))]
+            Some(_) => Err(String::from(
+                "Unix domain sockets are not supported on this platform",
+            )),
+            None => parse_addr(s).map(ServerAddr::Tcp),
+        }
+    }
+}
+
+impl std::fmt::Display for ServerAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ServerAddr::Tcp(addr) => write!(f, "{}", addr),
+            #[cfg(unix)]
+            ServerAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
 /// Trait for something that can be converted to bytes (&[u8])
 pub trait Serialize {
     /// Serialize to a `Write`able buffer
     fn serialize(&self, buf: &mut impl Write) -> io::Result<usize>;
+
+    /// Serialize with integers in `E`'s byte order rather than `NetworkEndian`, and length
+    /// prefixes in its `LengthEncoding` (see `WireOrder`)
+    ///
+    /// Only needed for peers that use another byte order or length encoding (see `Endian`).
+    /// Implementors with integer fields should override this: the default only supports
+    /// `NetworkEndian` (i.e. `serialize`), failing with `io::ErrorKind::Unsupported` otherwise
+    fn serialize_with_order<E: WireOrder>(&self, buf: &mut impl Write) -> io::Result<usize> {
+        check_default_wire_order::<E>()?;
+        self.serialize(buf)
+    }
+
+    /// Serialize into a fixed-size buffer (e.g. a reusable stack array), returning
+    /// the number of bytes written
+    ///
+    /// Fails with `io::ErrorKind::WriteZero` if `buf` is too small to hold the message
+    fn serialize_into_slice(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let capacity = buf.len();
+        // `&mut [u8]` implements `Write`, advancing the slice past the bytes written
+        let mut remaining = buf;
+        self.serialize(&mut remaining)?;
+        Ok(capacity - remaining.len())
+    }
+}
+
+/// What to do when a received string isn't valid UTF-8
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum OnInvalidUtf8 {
+    /// Reject the whole message
+    #[default]
+    Error,
+    /// Replace each invalid sequence with `U+FFFD` (`�`), like `String::from_utf8_lossy`
+    Lossy,
+    /// Replace each invalid sequence with the given character (e.g. `'?'` for ASCII-only output)
+    Replace(char),
 }
+
+impl OnInvalidUtf8 {
+    /// Decode bytes to a String according to this policy
+    pub fn decode(self, bytes: Vec<u8>) -> io::Result<String> {
+        match self {
+            OnInvalidUtf8::Error => String::from_utf8(bytes)
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid utf8")),
+            OnInvalidUtf8::Lossy => Ok(match String::from_utf8(bytes) {
+                Ok(value) => value,
+                Err(e) => String::from_utf8_lossy(e.as_bytes()).into_owned(),
+            }),
+            OnInvalidUtf8::Replace(replacement) => Ok(match String::from_utf8(bytes) {
+                Ok(value) => value,
+                Err(e) => {
+                    let mut value = String::with_capacity(e.as_bytes().len());
+                    for chunk in e.as_bytes().utf8_chunks() {
+                        value.push_str(chunk.valid());
+                        if !chunk.invalid().is_empty() {
+                            value.push(replacement);
+                        }
+                    }
+                    value
+                }
+            }),
+        }
+    }
+}
+
 /// Trait for something that can be converted from bytes (&[u8])
 pub trait Deserialize {
     /// The type that this deserializes to
@@ -28,15 +166,192 @@ pub trait Deserialize {
 
     /// Deserialize from a `Read`able buffer
     fn deserialize(buf: &mut impl Read) -> io::Result<Self::Output>;
+
+    /// Deserialize from a `Read`able buffer into an existing value
+    ///
+    /// Implementors can override this to reuse the allocations already held by `dest`
+    /// (e.g. a `String`'s capacity), which avoids allocator churn when reading many messages
+    fn deserialize_into(buf: &mut impl Read, dest: &mut Self::Output) -> io::Result<()> {
+        *dest = Self::deserialize(buf)?;
+        Ok(())
+    }
+
+    /// Deserialize from a `Read`able buffer, handling invalid UTF-8 in strings with `on_invalid`
+    ///
+    /// Implementors with string fields should override this (the default ignores `on_invalid`)
+    fn deserialize_with(
+        buf: &mut impl Read,
+        _on_invalid: OnInvalidUtf8,
+    ) -> io::Result<Self::Output> {
+        Self::deserialize(buf)
+    }
+
+    /// Same as `deserialize_into`, but handling invalid UTF-8 in strings with `on_invalid`
+    fn deserialize_into_with(
+        buf: &mut impl Read,
+        dest: &mut Self::Output,
+        _on_invalid: OnInvalidUtf8,
+    ) -> io::Result<()> {
+        Self::deserialize_into(buf, dest)
+    }
+
+    /// Same as `deserialize_with`, but with integers in `E`'s byte order and length prefixes in
+    /// its `LengthEncoding` (see `WireOrder`)
+    ///
+    /// Implementors with integer fields should override this (the default only supports
+    /// `NetworkEndian`, like `Serialize::serialize_with_order`'s)
+    fn deserialize_with_order<E: WireOrder>(
+        buf: &mut impl Read,
+        on_invalid: OnInvalidUtf8,
+    ) -> io::Result<Self::Output> {
+        check_default_wire_order::<E>()?;
+        Self::deserialize_with(buf, on_invalid)
+    }
+
+    /// Same as `deserialize_into_with`, but with `E`'s byte order and length encoding
+    fn deserialize_into_with_order<E: WireOrder>(
+        buf: &mut impl Read,
+        dest: &mut Self::Output,
+        on_invalid: OnInvalidUtf8,
+    ) -> io::Result<()> {
+        check_default_wire_order::<E>()?;
+        Self::deserialize_into_with(buf, dest, on_invalid)
+    }
+
+    /// Deserialize exactly one message from a `Read`able buffer, erroring if any bytes follow it
+    ///
+    /// For a one-shot exchange, trailing bytes mean the peer is out of sync (or padding messages
+    /// for some malicious reason). NOTE: On a socket this waits for the peer to close its write
+    /// half, so only use it when the peer sends a single message
+    fn deserialize_exact(buf: &mut impl Read) -> io::Result<Self::Output> {
+        let message = Self::deserialize(buf)?;
+        let mut trailing = [0u8; 1];
+        if buf.read(&mut trailing)? != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "trailing bytes after message",
+            ));
+        }
+        Ok(message)
+    }
 }
 
 /// Request object (client -> server)
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Request {
     /// Echo a message back
     Echo(String),
     /// Jumble up a message with given amount of entropy before echoing
+    ///
+    /// Any `amount` is fine, even `u16::MAX`: it only seeds the shuffle (see `jumble_message`).
+    /// The servers refuse messages of more than `MAX_JUMBLE_CHARS` characters though
     Jumble { message: String, amount: u16 },
+    /// Count the characters, words and lines in a message (see `text_stats`)
+    Stats(String),
+    /// Wait `ms` milliseconds before echoing a message, to simulate a slow server
+    Delay { message: String, ms: u32 },
+    /// Reply with the hex of exactly the payload bytes the server received (see `bytes_to_hex`)
+    ///
+    /// Unlike Echo, the payload doesn't need to be UTF-8, which is handy for debugging framing
+    Reflect(Vec<u8>),
+    /// Log a message on the server, which *doesn't* reply (see `Protocol::send_only`)
+    Log(String),
+    /// Join the strings together (with a space between each)
+    ///
+    /// Unlike the other requests, this has a variable number of strings, so the strings are
+    /// preceded by a (u16) count of them
+    Concat(Vec<String>),
+    /// Do nothing, the server replies with an empty Response
+    ///
+    /// This is the smallest possible message (just the type byte), useful for exercising the framing
+    Noop,
+    /// Keepalive check, the server replies with `Response::Pong`
+    ///
+    /// Like `Noop` this is just the type byte, but it's answered by the server's request loop
+    /// itself rather than by the request handling
+    Ping,
+    /// Reply with the CRC32 of the message bytes, as hex (see `crc32`)
+    Checksum(String),
+    /// Key/value pairs (e.g. config to push), the server replies with them as `key=value` lines
+    ///
+    /// Like Concat, the pairs are preceded by a (u16) count of them, then each pair is
+    /// the key's (length/bytes) tuple followed by the value's
+    KeyValues(Vec<(String, String)>),
+    /// Split the message at each occurrence of the delimiter, the server replies with
+    /// the pieces as a `Response::Tokens`
+    ///
+    /// The message's (length/bytes) tuple is followed by the delimiter's. An empty delimiter
+    /// is an error, and a message without the delimiter is a single token
+    Split { message: String, delimiter: String },
+    /// Reply with the message `count` times, as separate Responses `interval_ms` apart
+    ///
+    /// Unlike the other requests, the server streams back several Responses (the client reads
+    /// until it has `count` of them), though a `Response::Err` (e.g. for a `count` of 0) ends
+    /// the stream early. Like Delay's `ms`, each fixed size field still gets a length
+    Repeat {
+        message: String,
+        count: u32,
+        interval_ms: u32,
+    },
+    /// Handle the wrapped request as usual, but with how long that took in the Response
+    ///
+    /// The inner request follows the Timed type byte as a whole (with its own type byte),
+    /// so any request can be wrapped, even another Timed (up to `MAX_NESTING_DEPTH`)
+    Timed(Box<Request>),
+    /// Start closing the connection, the server replies with `GOODBYE_ACK` and then closes its
+    /// end (see `Protocol::close`)
+    ///
+    /// Like `Ping`, this is just the type byte, and it's answered by the server's request loop
+    Goodbye,
+    /// XOR the message's bytes with the (repeating) key, the server replies with the result
+    /// base64 encoded (see `xor_bytes`), since it's rarely valid UTF-8
+    ///
+    /// **XOR with a repeating key is NOT encryption**: it leaks the message length, and the
+    /// key falls out of any known plaintext. It's only here to illustrate byte manipulation.
+    /// Like Split, the message's (length/bytes) tuple is followed by the key's
+    Xor { message: String, key: String },
+    /// Undo an `Xor`: base64 decode the message and XOR it with the key, the server replies
+    /// with the original message (or an error, if that isn't valid UTF-8, e.g. the wrong key)
+    Unxor { message: String, key: String },
+    /// Ask for the largest message the server accepts (its `max_message_size`, in bytes on the
+    /// wire), so a client can split up or reject a payload before sending it. The server
+    /// replies with the limit as a decimal string, or `MAX_SIZE_UNLIMITED`
+    /// (see `Protocol::server_max_message_size`)
+    ///
+    /// Like `Noop`, this is just the type byte
+    MaxSize,
+    /// Count how many times each character occurs in the message, the server replies with a
+    /// `Response::Segments` of (character, count) pairs, sorted by character (see `char_histogram`)
+    Histogram(String),
+}
+
+/// The message of the `Response::Ok` acknowledging a `Request::Goodbye`
+pub const GOODBYE_ACK: &str = "bye";
+
+/// The message of the `Response::Ok` to a `Request::MaxSize` when the server has no limit
+pub const MAX_SIZE_UNLIMITED: &str = "unlimited";
+
+/// Most characters the servers will jumble in one `Request::Jumble`
+///
+/// The shuffle makes a swap per character (see `jumble_message`), so this bounds the work a
+/// single request can ask for
+pub const MAX_JUMBLE_CHARS: usize = 10_000;
+
+/// How many Requests deep a Request can be nested in others (see `Request::Timed`)
+///
+/// Deserializing recurses once per level, so without a limit a peer could send a long run of
+/// Timed type bytes to overflow the stack
+pub const MAX_NESTING_DEPTH: usize = 8;
+
+/// Reject a Request nested in `depth` others from wrapping yet another one
+fn check_nesting_depth(depth: usize) -> io::Result<()> {
+    if depth >= MAX_NESTING_DEPTH {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Requests are nested more than {} deep", MAX_NESTING_DEPTH),
+        ));
+    }
+    Ok(())
 }
 
 /// Encode the Request type as a single byte (as long as we don't exceed 255 types)
@@ -47,6 +362,23 @@ impl From<&Request> for u8 {
         match req {
             Request::Echo(_) => 1,
             Request::Jumble { .. } => 2,
+            Request::Stats(_) => 3,
+            Request::Delay { .. } => 4,
+            Request::Reflect(_) => 5,
+            Request::Log(_) => 6,
+            Request::Concat(_) => 7,
+            Request::Noop => 8,
+            Request::Ping => 9,
+            Request::Checksum(_) => 10,
+            Request::KeyValues(_) => 11,
+            Request::Split { .. } => 12,
+            Request::Repeat { .. } => 13,
+            Request::Timed(_) => 14,
+            Request::Goodbye => 15,
+            Request::Xor { .. } => 16,
+            Request::Unxor { .. } => 17,
+            Request::MaxSize => 18,
+            Request::Histogram(_) => 19,
         }
     }
 }
@@ -58,12 +390,421 @@ impl From<&Request> for u8 {
 /// ```
 ///
 /// Starts with a type, and then is an arbitrary length of (length/bytes) tuples
+/// (possibly zero, like `Noop`). Concat's tuples are preceded by a count of them:
+/// ```ignore
+/// |    u8    |    u16    |     u16     |     [u8]      | ... (count times)
+/// |   type   |   count   |    length   |  value bytes  | ...
+/// ```
+///
+/// And KeyValues' by a count of key/value pairs (so there are twice as many tuples):
+/// ```ignore
+/// |    u8    |    u16    |   u16   |   [u8]    |   u16   |    [u8]     | ... (count times)
+/// |   type   |   count   |  length | key bytes |  length | value bytes | ...
+/// ```
+///
+/// Timed has no tuples of its own, just the whole inner Request:
+/// ```ignore
+/// |    u8    |    u8    |     [u8]      |
+/// |   type   |   type   |  ...          |
+/// ```
 impl Request {
     /// View the message portion of this request
     pub fn message(&self) -> &str {
         match self {
-            Request::Echo(message) => &message,
-            Request::Jumble { message, .. } => &message,
+            Request::Echo(message) => message,
+            Request::Jumble { message, .. } => message,
+            Request::Stats(message) => message,
+            Request::Checksum(message) => message,
+            Request::Histogram(message) => message,
+            Request::Delay { message, .. } => message,
+            Request::Log(message) => message,
+            Request::Split { message, .. } => message,
+            Request::Repeat { message, .. } => message,
+            Request::Xor { message, .. } => message,
+            Request::Unxor { message, .. } => message,
+            Request::Timed(inner) => inner.message(),
+            // Not necessarily UTF-8
+            Request::Reflect(_)
+            | Request::Noop
+            | Request::Ping
+            | Request::Goodbye
+            | Request::MaxSize => "",
+            // More than one message
+            Request::Concat(_) | Request::KeyValues(_) => "",
+        }
+    }
+
+    /// Take the message `String` out of this request (to reuse its allocation)
+    fn take_message(&mut self) -> String {
+        match self {
+            Request::Echo(message) => std::mem::take(message),
+            Request::Jumble { message, .. } => std::mem::take(message),
+            Request::Stats(message) => std::mem::take(message),
+            Request::Checksum(message) => std::mem::take(message),
+            Request::Histogram(message) => std::mem::take(message),
+            Request::Delay { message, .. } => std::mem::take(message),
+            Request::Log(message) => std::mem::take(message),
+            Request::Split { message, .. } => std::mem::take(message),
+            Request::Repeat { message, .. } => std::mem::take(message),
+            Request::Xor { message, .. } => std::mem::take(message),
+            Request::Unxor { message, .. } => std::mem::take(message),
+            Request::Timed(inner) => inner.take_message(),
+            Request::Concat(parts) => parts.first_mut().map(std::mem::take).unwrap_or_default(),
+            Request::KeyValues(pairs) => pairs
+                .first_mut()
+                .map(|(key, _)| std::mem::take(key))
+                .unwrap_or_default(),
+            // An empty `String` with the payload's capacity
+            Request::Reflect(payload) => {
+                let mut bytes = std::mem::take(payload);
+                bytes.clear();
+                String::from_utf8(bytes).expect("Empty bytes are valid UTF-8")
+            }
+            Request::Noop | Request::Ping | Request::Goodbye | Request::MaxSize => String::new(),
+        }
+    }
+
+    /// Whether the Response depends only on the request's bytes, so it can be cached
+    /// (see `cache::ResponseCache`)
+    ///
+    /// Delay & Repeat aren't, since their point is to take time, nor is Timed (whose Response
+    /// has the time taken), and nor are requests without a Response
+    pub fn is_cacheable(&self) -> bool {
+        matches!(
+            self,
+            Request::Echo(_)
+                | Request::Jumble { .. }
+                | Request::Stats(_)
+                | Request::Checksum(_)
+                | Request::Histogram(_)
+                | Request::Reflect(_)
+                | Request::Concat(_)
+                | Request::KeyValues(_)
+                | Request::Split { .. }
+                | Request::Xor { .. }
+                | Request::Unxor { .. }
+        )
+    }
+
+    /// Whether the server replies to this request with a `Response`
+    ///
+    /// Only `Log` is fire-and-forget, every other request gets exactly one `Response` (except
+    /// `Repeat`, which gets up to `count` of them)
+    pub fn expects_response(&self) -> bool {
+        !matches!(self, Request::Log(_))
+    }
+
+    /// Number of bytes `serialize` writes for this request, without writing them (e.g. to
+    /// size a buffer). Framing, timestamps & PSK tags (see `ProtocolConfig`) aren't included
+    pub fn serialized_size(&self) -> usize {
+        self.serialized_size_with(LengthEncoding::Fixed)
+    }
+
+    /// Same as `serialized_size`, but with length prefixes in `lengths`
+    pub fn serialized_size_with(&self, lengths: LengthEncoding) -> usize {
+        // Each variable length field is preceded by its length
+        let field = |bytes: usize| lengths.prefix_len(bytes) + bytes;
+        // And each u32 by its length too (always 4)
+        let u32_field = field(4);
+        let fields = match self {
+            Request::Echo(message)
+            | Request::Stats(message)
+            | Request::Checksum(message)
+            | Request::Histogram(message)
+            | Request::Log(message) => field(message.len()),
+            // `amount` is a u16, preceded by its length (always 2)
+            Request::Jumble { message, .. } => field(message.len()) + field(2),
+            Request::Delay { message, .. } => field(message.len()) + u32_field,
+            Request::Reflect(payload) => field(payload.len()),
+            // The count, then the tuples
+            Request::Concat(parts) => 2 + parts.iter().map(|part| field(part.len())).sum::<usize>(),
+            Request::KeyValues(pairs) => {
+                2 + pairs
+                    .iter()
+                    .map(|(key, value)| field(key.len()) + field(value.len()))
+                    .sum::<usize>()
+            }
+            Request::Split { message, delimiter } => field(message.len()) + field(delimiter.len()),
+            Request::Xor { message, key } | Request::Unxor { message, key } => {
+                field(message.len()) + field(key.len())
+            }
+            Request::Repeat { message, .. } => field(message.len()) + 2 * u32_field,
+            // The inner request has its own type byte
+            Request::Timed(inner) => inner.serialized_size_with(lengths),
+            Request::Noop | Request::Ping | Request::Goodbye | Request::MaxSize => 0,
+        };
+        // The type byte
+        1 + fields
+    }
+
+    /// Describe what `serialize` writes for this request, one group of bytes at a time, e.g.
+    /// `type=2(Jumble) | len=5 | "Hello" | len=2 | amount=42`
+    ///
+    /// Unlike `Debug`, this follows the byte layout: every field's length prefix, and the
+    /// counts before Concat's & KeyValues' fields, in the order they're sent (in the default
+    /// `LengthEncoding::Fixed`, with no framing, timestamps, etc.), to read alongside a hex
+    /// dump like `trace::TraceStream`'s
+    pub fn describe_wire(&self) -> String {
+        let mut groups = vec![];
+        self.describe_wire_into(&mut groups);
+        groups.join(" | ")
+    }
+
+    /// Add this request's groups of bytes to `groups` (see `describe_wire`)
+    fn describe_wire_into(&self, groups: &mut Vec<String>) {
+        let string_field = |groups: &mut Vec<String>, value: &str| {
+            groups.push(format!("len={}", value.len()));
+            groups.push(format!("{:?}", value));
+        };
+        let u32_field = |groups: &mut Vec<String>, name: &str, value: u32| {
+            groups.push(String::from("len=4"));
+            groups.push(format!("{}={}", name, value));
+        };
+        groups.push(format!("type={}({})", u8::from(self), self.variant_name()));
+        match self {
+            Request::Echo(message)
+            | Request::Stats(message)
+            | Request::Checksum(message)
+            | Request::Histogram(message)
+            | Request::Log(message) => string_field(groups, message),
+            Request::Jumble { message, amount } => {
+                string_field(groups, message);
+                groups.push(String::from("len=2"));
+                groups.push(format!("amount={}", amount));
+            }
+            Request::Delay { message, ms } => {
+                string_field(groups, message);
+                u32_field(groups, "ms", *ms);
+            }
+            Request::Concat(parts) => {
+                groups.push(format!("count={}", parts.len()));
+                for part in parts {
+                    string_field(groups, part);
+                }
+            }
+            Request::KeyValues(pairs) => {
+                groups.push(format!("count={}", pairs.len()));
+                for (key, value) in pairs {
+                    string_field(groups, key);
+                    string_field(groups, value);
+                }
+            }
+            Request::Split { message, delimiter } => {
+                string_field(groups, message);
+                string_field(groups, delimiter);
+            }
+            Request::Xor { message, key } | Request::Unxor { message, key } => {
+                string_field(groups, message);
+                string_field(groups, key);
+            }
+            Request::Repeat {
+                message,
+                count,
+                interval_ms,
+            } => {
+                string_field(groups, message);
+                u32_field(groups, "count", *count);
+                u32_field(groups, "interval_ms", *interval_ms);
+            }
+            Request::Timed(inner) => inner.describe_wire_into(groups),
+            // Not necessarily UTF-8, so as hex
+            Request::Reflect(payload) => {
+                groups.push(format!("len={}", payload.len()));
+                groups.push(format!("[{}]", bytes_to_hex(payload)));
+            }
+            Request::Noop | Request::Ping | Request::Goodbye | Request::MaxSize => {}
+        }
+    }
+
+    /// Name of this request's variant, e.g. `"KeyValues"` (`type_name` is the command line's)
+    fn variant_name(&self) -> &'static str {
+        match self {
+            Request::Echo(_) => "Echo",
+            Request::Jumble { .. } => "Jumble",
+            Request::Stats(_) => "Stats",
+            Request::Delay { .. } => "Delay",
+            Request::Reflect(_) => "Reflect",
+            Request::Log(_) => "Log",
+            Request::Concat(_) => "Concat",
+            Request::Noop => "Noop",
+            Request::Ping => "Ping",
+            Request::Checksum(_) => "Checksum",
+            Request::KeyValues(_) => "KeyValues",
+            Request::Split { .. } => "Split",
+            Request::Repeat { .. } => "Repeat",
+            Request::Timed(_) => "Timed",
+            Request::Goodbye => "Goodbye",
+            Request::Xor { .. } => "Xor",
+            Request::Unxor { .. } => "Unxor",
+            Request::MaxSize => "MaxSize",
+            Request::Histogram(_) => "Histogram",
+        }
+    }
+}
+
+/// Name (as used on the command line) and type byte of each Request type
+pub const REQUEST_TYPES: &[(&str, u8)] = &[
+    ("echo", 1),
+    ("jumble", 2),
+    ("stats", 3),
+    ("delay", 4),
+    ("reflect", 5),
+    ("log", 6),
+    ("concat", 7),
+    ("noop", 8),
+    ("ping", 9),
+    ("checksum", 10),
+    ("kv", 11),
+    ("split", 12),
+    ("repeat", 13),
+    ("timed", 14),
+    ("goodbye", 15),
+    ("xor", 16),
+    ("unxor", 17),
+    ("max-size", 18),
+    ("histogram", 19),
+];
+
+/// Look up the type byte for a Request type name (case-insensitive)
+pub fn request_type_code(name: &str) -> Option<u8> {
+    REQUEST_TYPES
+        .iter()
+        .find(|(type_name, _)| type_name.eq_ignore_ascii_case(name))
+        .map(|(_, code)| *code)
+}
+
+impl Request {
+    /// Name of this request's type, e.g. `"echo"`
+    pub fn type_name(&self) -> &'static str {
+        let code = u8::from(self);
+        REQUEST_TYPES
+            .iter()
+            .find(|(_, type_code)| *type_code == code)
+            .map(|(name, _)| *name)
+            .expect("All Request types are named")
+    }
+}
+
+/// Parse a line of interactive input into a Request, using a leading `/` directive to pick
+/// the type, e.g. `/jumble 5 Hello, world!` for a `Jumble` of "Hello, world!" by 5
+///
+/// Directives: `/jumble <amount> <message>`, `/delay <ms> <message>`, `/stats <message>`,
+/// `/checksum <message>`, `/histogram <message>`, `/reflect <message>`, `/log <message>`, `/concat <strings...>`
+/// (split on whitespace), `/kv <key=value...>`, `/split <delimiter> <message>`,
+/// `/repeat <count> <interval_ms> <message>`, `/timed <line>` (e.g. `/timed /stats Hello`),
+/// `/xor <key> <message>`, `/unxor <key> <base64>`, `/max-size`, `/noop` and `/ping`. Anything else (including unknown directives) is echoed as-is.
+/// A missing or malformed number is an error describing the expected usage
+pub fn parse_command(line: &str) -> Result<Request, String> {
+    let directive = match line.strip_prefix('/') {
+        Some(rest) => rest,
+        None => return Ok(Request::Echo(line.to_string())),
+    };
+    let (name, rest) = directive.split_once(' ').unwrap_or((directive, ""));
+    let request = match name {
+        "jumble" => {
+            let (amount, message) = parse_number_arg(rest, "/jumble <amount> <message>")?;
+            Request::Jumble { message, amount }
+        }
+        "delay" => {
+            let (ms, message) = parse_number_arg(rest, "/delay <ms> <message>")?;
+            Request::Delay { message, ms }
+        }
+        "stats" => Request::Stats(rest.to_string()),
+        "checksum" => Request::Checksum(rest.to_string()),
+        "histogram" => Request::Histogram(rest.to_string()),
+        "reflect" => Request::Reflect(rest.as_bytes().to_vec()),
+        "log" => Request::Log(rest.to_string()),
+        "concat" => Request::Concat(rest.split_whitespace().map(String::from).collect()),
+        "kv" => Request::KeyValues(
+            rest.split_whitespace()
+                .map(|pair| match pair.split_once('=') {
+                    Some((key, value)) => Ok((key.to_string(), value.to_string())),
+                    None => Err(format!(
+                        "Invalid pair '{}', usage: /kv <key=value...>",
+                        pair
+                    )),
+                })
+                .collect::<Result<_, _>>()?,
+        ),
+        "split" => {
+            let (delimiter, message) = rest.split_once(' ').unwrap_or((rest, ""));
+            Request::Split {
+                message: message.to_string(),
+                delimiter: delimiter.to_string(),
+            }
+        }
+        "repeat" => {
+            let usage = "/repeat <count> <interval_ms> <message>";
+            let (count, rest) = parse_number_arg(rest, usage)?;
+            let (interval_ms, message) = parse_number_arg(&rest, usage)?;
+            Request::Repeat {
+                message,
+                count,
+                interval_ms,
+            }
+        }
+        "xor" | "unxor" => {
+            let (key, message) = rest.split_once(' ').unwrap_or((rest, ""));
+            let (message, key) = (message.to_string(), key.to_string());
+            match name {
+                "xor" => Request::Xor { message, key },
+                _ => Request::Unxor { message, key },
+            }
+        }
+        "timed" => Request::Timed(Box::new(parse_command(rest)?)),
+        "max-size" => Request::MaxSize,
+        "noop" => Request::Noop,
+        "ping" => Request::Ping,
+        _ => Request::Echo(line.to_string()),
+    };
+    Ok(request)
+}
+
+/// Split a directive's `<number> <message>` arguments (the message may be empty)
+fn parse_number_arg<T: std::str::FromStr>(args: &str, usage: &str) -> Result<(T, String), String> {
+    let (number, message) = args.split_once(' ').unwrap_or((args, ""));
+    let number = number.parse().map_err(|_| match number {
+        "" => format!("Missing number, usage: {}", usage),
+        number => format!("Invalid number '{}', usage: {}", number, usage),
+    })?;
+    Ok((number, message.to_string()))
+}
+
+/// Set of Request types a server is willing to handle
+#[derive(Debug, Clone, Default)]
+pub struct Allowlist {
+    /// Allowed type bytes, or `None` to allow every type
+    types: Option<Vec<u8>>,
+}
+
+impl Allowlist {
+    /// Allow every Request type
+    pub fn allow_all() -> Self {
+        Self::default()
+    }
+
+    /// Only allow the named Request types (see `REQUEST_TYPES`)
+    ///
+    /// An empty list allows every type, and unknown names are an error
+    pub fn from_names(names: &[impl AsRef<str>]) -> Result<Self, String> {
+        if names.is_empty() {
+            return Ok(Self::allow_all());
+        }
+        let types = names
+            .iter()
+            .map(|name| {
+                request_type_code(name.as_ref())
+                    .ok_or_else(|| format!("Unknown request type '{}'", name.as_ref()))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self { types: Some(types) })
+    }
+
+    /// Is the server allowed to handle this request?
+    pub fn allows(&self, request: &Request) -> bool {
+        match &self.types {
+            Some(types) => types.contains(&request.into()),
+            None => true,
         }
     }
 }
@@ -71,30 +812,80 @@ impl Request {
 impl Serialize for Request {
     /// Serialize Request to bytes (to send to server)
     fn serialize(&self, buf: &mut impl Write) -> io::Result<usize> {
-        buf.write_u8(self.into())?; // Message Type byte
+        self.serialize_with_order::<NetworkEndian>(buf)
+    }
+
+    fn serialize_with_order<E: WireOrder>(&self, buf: &mut impl Write) -> io::Result<usize> {
+        buf.write_u8(self.into())
+            .map_err(|e| write_context(e, "type byte"))?;
         let mut bytes_written: usize = 1;
         match self {
-            Request::Echo(message) => {
+            Request::Echo(message)
+            | Request::Stats(message)
+            | Request::Checksum(message)
+            | Request::Histogram(message)
+            | Request::Log(message) => {
                 // Write the variable length message string, preceded by it's length
-                let message = message.as_bytes();
-                buf.write_u16::<NetworkEndian>(message.len() as u16)?;
-                buf.write_all(&message)?;
-                bytes_written += 2 + message.len();
+                bytes_written += write_bytes_field::<E>(buf, message.as_bytes(), "message")?;
             }
             Request::Jumble { message, amount } => {
-                // Write the variable length message string, preceded by it's length
-                let message_bytes = message.as_bytes();
-                buf.write_u16::<NetworkEndian>(message_bytes.len() as u16)?;
-                buf.write_all(&message_bytes)?;
-                bytes_written += 2 + message.len();
+                bytes_written += write_bytes_field::<E>(buf, message.as_bytes(), "message")?;
 
                 // We know that `amount` is always 2 bytes long, but are adding
                 // the length here to stay consistent
-                buf.write_u16::<NetworkEndian>(2)?;
-                buf.write_u16::<NetworkEndian>(*amount)?;
-                bytes_written += 4;
+                bytes_written += E::LENGTHS
+                    .write_length::<E::Order>(buf, 2)
+                    .map_err(|e| write_context(e, "amount length"))?;
+                buf.write_u16::<E::Order>(*amount)
+                    .map_err(|e| write_context(e, "amount"))?;
+                bytes_written += 2;
+            }
+            Request::Delay { message, ms } => {
+                bytes_written += write_bytes_field::<E>(buf, message.as_bytes(), "message")?;
+                // Like Jumble's `amount`, the fixed size `ms` still gets a length
+                bytes_written += write_u32_field::<E>(buf, *ms, "ms")?;
+            }
+            Request::Concat(parts) => {
+                write_count::<E>(buf, parts.len(), "part")?;
+                bytes_written += 2;
+                for part in parts {
+                    bytes_written += write_bytes_field::<E>(buf, part.as_bytes(), "part")?;
+                }
+            }
+            Request::KeyValues(pairs) => {
+                write_count::<E>(buf, pairs.len(), "pair")?;
+                bytes_written += 2;
+                for (key, value) in pairs {
+                    bytes_written += write_bytes_field::<E>(buf, key.as_bytes(), "key")?;
+                    bytes_written += write_bytes_field::<E>(buf, value.as_bytes(), "value")?;
+                }
+            }
+            Request::Split { message, delimiter } => {
+                bytes_written += write_bytes_field::<E>(buf, message.as_bytes(), "message")?;
+                bytes_written += write_bytes_field::<E>(buf, delimiter.as_bytes(), "delimiter")?;
+            }
+            Request::Xor { message, key } | Request::Unxor { message, key } => {
+                bytes_written += write_bytes_field::<E>(buf, message.as_bytes(), "message")?;
+                bytes_written += write_bytes_field::<E>(buf, key.as_bytes(), "key")?;
+            }
+            Request::Repeat {
+                message,
+                count,
+                interval_ms,
+            } => {
+                bytes_written += write_bytes_field::<E>(buf, message.as_bytes(), "message")?;
+                bytes_written += write_u32_field::<E>(buf, *count, "count")?;
+                bytes_written += write_u32_field::<E>(buf, *interval_ms, "interval_ms")?;
+            }
+            // The inner request's errors already say which of its fields failed
+            Request::Timed(inner) => bytes_written += inner.serialize_with_order::<E>(buf)?,
+            Request::Reflect(payload) => {
+                bytes_written += write_bytes_field::<E>(buf, payload, "payload")?;
             }
+            // Nothing but the type byte
+            Request::Noop | Request::Ping | Request::Goodbye | Request::MaxSize => {}
         }
+        debug_assert_eq!(bytes_written, self.serialized_size_with(E::LENGTHS));
         Ok(bytes_written)
     }
 }
@@ -103,167 +894,5323 @@ impl Deserialize for Request {
     type Output = Request;
 
     /// Deserialize Request from bytes (to receive from TcpStream)
-    fn deserialize(mut buf: &mut impl Read) -> io::Result<Self::Output> {
-        match buf.read_u8()? {
+    fn deserialize(buf: &mut impl Read) -> io::Result<Self::Output> {
+        let mut request = Request::Noop;
+        Self::deserialize_into(buf, &mut request)?;
+        Ok(request)
+    }
+
+    /// Deserialize Request from bytes, reusing the message `String` already held by `dest`
+    fn deserialize_into(buf: &mut impl Read, dest: &mut Self::Output) -> io::Result<()> {
+        Self::deserialize_into_with(buf, dest, OnInvalidUtf8::Error)
+    }
+
+    fn deserialize_with(
+        buf: &mut impl Read,
+        on_invalid: OnInvalidUtf8,
+    ) -> io::Result<Self::Output> {
+        Self::deserialize_with_order::<NetworkEndian>(buf, on_invalid)
+    }
+
+    fn deserialize_into_with(
+        buf: &mut impl Read,
+        dest: &mut Self::Output,
+        on_invalid: OnInvalidUtf8,
+    ) -> io::Result<()> {
+        Self::deserialize_into_with_order::<NetworkEndian>(buf, dest, on_invalid)
+    }
+
+    fn deserialize_with_order<E: WireOrder>(
+        buf: &mut impl Read,
+        on_invalid: OnInvalidUtf8,
+    ) -> io::Result<Self::Output> {
+        let mut request = Request::Noop;
+        Self::deserialize_into_with_order::<E>(buf, &mut request, on_invalid)?;
+        Ok(request)
+    }
+
+    fn deserialize_into_with_order<E: WireOrder>(
+        buf: &mut impl Read,
+        dest: &mut Self::Output,
+        on_invalid: OnInvalidUtf8,
+    ) -> io::Result<()> {
+        #[cfg(debug_assertions)]
+        let mut buf = &mut CountingReader::new(buf);
+        #[cfg(not(debug_assertions))]
+        let mut buf = buf;
+        // Take the message allocation regardless of which variant `dest` currently is
+        let message = dest.take_message();
+        *dest = Request::deserialize_nested::<E>(&mut buf, message, on_invalid, 0)?;
+        #[cfg(debug_assertions)]
+        debug_assert_wire_len::<E>(dest, buf.count, on_invalid);
+        Ok(())
+    }
+}
+
+impl Request {
+    /// Deserialize a Request that's nested `depth` deep in others (see `Request::Timed`),
+    /// using `message` for its message `String`
+    ///
+    /// This takes a `dyn Read` since it recurses, which would otherwise instantiate it for
+    /// ever deeper `&mut &mut ... R` types
+    fn deserialize_nested<E: WireOrder>(
+        mut buf: &mut dyn Read,
+        mut message: String,
+        on_invalid: OnInvalidUtf8,
+        depth: usize,
+    ) -> io::Result<Request> {
+        let request = match buf.read_u8()? {
             // Echo
-            1 => Ok(Request::Echo(extract_string(&mut buf)?)),
+            1 => {
+                extract_string_into::<E>(&mut buf, &mut message, usize::MAX, on_invalid)?;
+                Request::Echo(message)
+            }
             // Jumble
             2 => {
-                let message = extract_string(&mut buf)?;
-                let _amount_len = buf.read_u16::<NetworkEndian>()?;
-                let amount = buf.read_u16::<NetworkEndian>()?;
-                Ok(Request::Jumble { message, amount })
+                extract_string_into::<E>(&mut buf, &mut message, usize::MAX, on_invalid)?;
+                // `amount` is always 2 bytes, anything else means the stream is out of sync
+                let amount_len = E::LENGTHS.read_length::<E::Order>(&mut buf)?;
+                if amount_len != 2 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Invalid Jumble amount length {} (expected 2)", amount_len),
+                    ));
+                }
+                let amount = buf.read_u16::<E::Order>()?;
+                Request::Jumble { message, amount }
             }
-            _ => Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "Invalid Request Type",
-            )),
-        }
+            // Stats
+            3 => {
+                extract_string_into::<E>(&mut buf, &mut message, usize::MAX, on_invalid)?;
+                Request::Stats(message)
+            }
+            // Delay
+            4 => {
+                extract_string_into::<E>(&mut buf, &mut message, usize::MAX, on_invalid)?;
+                let ms = extract_u32_field::<E>(&mut buf, "Delay ms")?;
+                Request::Delay { message, ms }
+            }
+            // Reflect
+            5 => {
+                let mut payload = message.into_bytes();
+                extract_bytes_into::<E>(&mut buf, &mut payload, usize::MAX)?;
+                Request::Reflect(payload)
+            }
+            // Log
+            6 => {
+                extract_string_into::<E>(&mut buf, &mut message, usize::MAX, on_invalid)?;
+                Request::Log(message)
+            }
+            // Concat
+            7 => {
+                let count = buf.read_u16::<E::Order>()?;
+                // Grow as the strings arrive, rather than trusting the count up front
+                let mut parts = vec![];
+                for _ in 0..count {
+                    // The first string reuses the message allocation
+                    let mut part = std::mem::take(&mut message);
+                    extract_string_into::<E>(&mut buf, &mut part, usize::MAX, on_invalid)?;
+                    parts.push(part);
+                }
+                Request::Concat(parts)
+            }
+            // Noop
+            8 => Request::Noop,
+            // Ping
+            9 => Request::Ping,
+            // Goodbye
+            15 => Request::Goodbye,
+            // MaxSize
+            18 => Request::MaxSize,
+            // Checksum
+            10 => {
+                extract_string_into::<E>(&mut buf, &mut message, usize::MAX, on_invalid)?;
+                Request::Checksum(message)
+            }
+            // Histogram
+            19 => {
+                extract_string_into::<E>(&mut buf, &mut message, usize::MAX, on_invalid)?;
+                Request::Histogram(message)
+            }
+            // KeyValues
+            11 => {
+                let count = buf.read_u16::<E::Order>()?;
+                // Grow as the pairs arrive, rather than trusting the count up front
+                let mut pairs = vec![];
+                for _ in 0..count {
+                    // The first key reuses the message allocation
+                    let mut key = std::mem::take(&mut message);
+                    extract_string_into::<E>(&mut buf, &mut key, usize::MAX, on_invalid)?;
+                    let mut value = String::new();
+                    extract_string_into::<E>(&mut buf, &mut value, usize::MAX, on_invalid)?;
+                    pairs.push((key, value));
+                }
+                Request::KeyValues(pairs)
+            }
+            // Split
+            12 => {
+                extract_string_into::<E>(&mut buf, &mut message, usize::MAX, on_invalid)?;
+                let mut delimiter = String::new();
+                extract_string_into::<E>(&mut buf, &mut delimiter, usize::MAX, on_invalid)?;
+                Request::Split { message, delimiter }
+            }
+            // Xor & Unxor
+            code @ 16..=17 => {
+                extract_string_into::<E>(&mut buf, &mut message, usize::MAX, on_invalid)?;
+                let mut key = String::new();
+                extract_string_into::<E>(&mut buf, &mut key, usize::MAX, on_invalid)?;
+                match code {
+                    16 => Request::Xor { message, key },
+                    _ => Request::Unxor { message, key },
+                }
+            }
+            // Repeat
+            13 => {
+                extract_string_into::<E>(&mut buf, &mut message, usize::MAX, on_invalid)?;
+                let count = extract_u32_field::<E>(&mut buf, "Repeat count")?;
+                let interval_ms = extract_u32_field::<E>(&mut buf, "Repeat interval_ms")?;
+                Request::Repeat {
+                    message,
+                    count,
+                    interval_ms,
+                }
+            }
+            // Timed
+            14 => {
+                check_nesting_depth(depth)?;
+                let inner = Request::deserialize_nested::<E>(buf, message, on_invalid, depth + 1)?;
+                Request::Timed(Box::new(inner))
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Invalid Request Type",
+                ))
+            }
+        };
+        Ok(request)
     }
 }
 
-/// Response object from server
+/// A Request borrowing its strings from a caller-owned buffer (see `RequestRef::deserialize_ref`)
 ///
-/// In the real-world, this would likely be an enum as well to signal Success vs. Error
-/// But since we're showing that capability with the `Request` struct, we'll keep this one simple
-#[derive(Debug)]
-pub struct Response(pub String);
+/// For handlers that only look at a message (e.g. routing by prefix), this avoids allocating
+/// a `String` for every request. The whole message must already be in the buffer, so it goes
+/// best with framing (see `Protocol::read_frame_into`).
+#[derive(Debug, PartialEq)]
+pub enum RequestRef<'a> {
+    Echo(&'a str),
+    Jumble {
+        message: &'a str,
+        amount: u16,
+    },
+    Stats(&'a str),
+    Delay {
+        message: &'a str,
+        ms: u32,
+    },
+    Reflect(&'a [u8]),
+    Log(&'a str),
+    Concat(ConcatRef<'a>),
+    Noop,
+    Ping,
+    Checksum(&'a str),
+    KeyValues(KeyValuesRef<'a>),
+    Split {
+        message: &'a str,
+        delimiter: &'a str,
+    },
+    Repeat {
+        message: &'a str,
+        count: u32,
+        interval_ms: u32,
+    },
+    Timed(Box<RequestRef<'a>>),
+    Goodbye,
+    Xor {
+        message: &'a str,
+        key: &'a str,
+    },
+    Unxor {
+        message: &'a str,
+        key: &'a str,
+    },
+    MaxSize,
+    Histogram(&'a str),
+}
 
-/// Message format for Response is:
-/// ```ignore
-/// |     u16     |     [u8]      |
-/// |    length   |  value bytes  |
-/// ```
-///
-impl Response {
-    /// Create a new response with a given message
-    pub fn new(message: String) -> Self {
-        Self(message)
+impl<'a> RequestRef<'a> {
+    /// Parse exactly one (network byte order) Request from `buf`, borrowing its strings
+    ///
+    /// Strings are validated with `str::from_utf8` (there's no `OnInvalidUtf8` replacement,
+    /// since that would need a copy of the string)
+    pub fn deserialize_ref(buf: &'a [u8]) -> io::Result<Self> {
+        let mut buf = buf;
+        let request = Self::parse_nested(&mut buf, 0)?;
+        if !buf.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "trailing bytes after message",
+            ));
+        }
+        Ok(request)
     }
 
-    /// Get the response message value
-    pub fn message(&self) -> &str {
-        &self.0
+    /// Parse the Request at the start of `input` (advancing it past the Request), which is
+    /// nested `depth` deep in others (see `Request::Timed`)
+    fn parse_nested(input: &mut &'a [u8], depth: usize) -> io::Result<Self> {
+        let mut buf = *input;
+        let request = match buf.read_u8()? {
+            1 => RequestRef::Echo(extract_str_ref(&mut buf)?),
+            2 => {
+                let message = extract_str_ref(&mut buf)?;
+                let amount_len = buf.read_u16::<NetworkEndian>()?;
+                if amount_len != 2 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Invalid Jumble amount length {} (expected 2)", amount_len),
+                    ));
+                }
+                let amount = buf.read_u16::<NetworkEndian>()?;
+                RequestRef::Jumble { message, amount }
+            }
+            3 => RequestRef::Stats(extract_str_ref(&mut buf)?),
+            4 => {
+                let message = extract_str_ref(&mut buf)?;
+                let ms = extract_u32_field::<NetworkEndian>(&mut buf, "Delay ms")?;
+                RequestRef::Delay { message, ms }
+            }
+            5 => RequestRef::Reflect(extract_bytes_ref(&mut buf)?),
+            6 => RequestRef::Log(extract_str_ref(&mut buf)?),
+            7 => {
+                let count = buf.read_u16::<NetworkEndian>()?;
+                let start = buf;
+                // Validate every string now, so iterating over them can't fail
+                for _ in 0..count {
+                    extract_str_ref(&mut buf)?;
+                }
+                RequestRef::Concat(ConcatRef {
+                    count,
+                    parts: &start[..start.len() - buf.len()],
+                })
+            }
+            8 => RequestRef::Noop,
+            9 => RequestRef::Ping,
+            15 => RequestRef::Goodbye,
+            18 => RequestRef::MaxSize,
+            10 => RequestRef::Checksum(extract_str_ref(&mut buf)?),
+            19 => RequestRef::Histogram(extract_str_ref(&mut buf)?),
+            11 => {
+                let count = buf.read_u16::<NetworkEndian>()?;
+                let start = buf;
+                // Validate every string now, so iterating over them can't fail
+                for _ in 0..count as u32 * 2 {
+                    extract_str_ref(&mut buf)?;
+                }
+                RequestRef::KeyValues(KeyValuesRef {
+                    count,
+                    pairs: &start[..start.len() - buf.len()],
+                })
+            }
+            12 => RequestRef::Split {
+                message: extract_str_ref(&mut buf)?,
+                delimiter: extract_str_ref(&mut buf)?,
+            },
+            13 => RequestRef::Repeat {
+                message: extract_str_ref(&mut buf)?,
+                count: extract_u32_field::<NetworkEndian>(&mut buf, "Repeat count")?,
+                interval_ms: extract_u32_field::<NetworkEndian>(&mut buf, "Repeat interval_ms")?,
+            },
+            14 => {
+                check_nesting_depth(depth)?;
+                RequestRef::Timed(Box::new(Self::parse_nested(&mut buf, depth + 1)?))
+            }
+            16 => RequestRef::Xor {
+                message: extract_str_ref(&mut buf)?,
+                key: extract_str_ref(&mut buf)?,
+            },
+            17 => RequestRef::Unxor {
+                message: extract_str_ref(&mut buf)?,
+                key: extract_str_ref(&mut buf)?,
+            },
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Invalid Request Type",
+                ))
+            }
+        };
+        *input = buf;
+        Ok(request)
     }
-}
 
-impl Serialize for Response {
-    /// Serialize Response to bytes (to send to client)
-    ///
-    /// Returns the number of bytes written
-    fn serialize(&self, buf: &mut impl Write) -> io::Result<usize> {
-        let resp_bytes = self.0.as_bytes();
-        buf.write_u16::<NetworkEndian>(resp_bytes.len() as u16)?;
-        buf.write_all(&resp_bytes)?;
-        Ok(3 + resp_bytes.len()) // Type + len + bytes
+    /// View the message portion of this request (see `Request::message`)
+    pub fn message(&self) -> &'a str {
+        match self {
+            RequestRef::Echo(message)
+            | RequestRef::Jumble { message, .. }
+            | RequestRef::Stats(message)
+            | RequestRef::Checksum(message)
+            | RequestRef::Histogram(message)
+            | RequestRef::Delay { message, .. }
+            | RequestRef::Log(message)
+            | RequestRef::Split { message, .. }
+            | RequestRef::Repeat { message, .. }
+            | RequestRef::Xor { message, .. }
+            | RequestRef::Unxor { message, .. } => message,
+            RequestRef::Timed(inner) => inner.message(),
+            RequestRef::Reflect(_)
+            | RequestRef::Concat(_)
+            | RequestRef::KeyValues(_)
+            | RequestRef::Noop
+            | RequestRef::Ping
+            | RequestRef::Goodbye
+            | RequestRef::MaxSize => "",
+        }
     }
 }
 
-impl Deserialize for Response {
-    type Output = Response;
-    /// Deserialize Response to bytes (to receive from server)
-    fn deserialize(mut buf: &mut impl Read) -> io::Result<Self::Output> {
-        let value = extract_string(&mut buf)?;
-        Ok(Response(value))
-    }
+/// The strings of a `RequestRef::Concat`, still in their wire format (already validated)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ConcatRef<'a> {
+    count: u16,
+    parts: &'a [u8],
 }
 
-/// From a given readable buffer, read the next length (u16) and extract the string bytes
-fn extract_string(buf: &mut impl Read) -> io::Result<String> {
-    // byteorder ReadBytesExt
-    let length = buf.read_u16::<NetworkEndian>()?;
-    // Given the length of our string, only read in that quantity of bytes
-    let mut bytes = vec![0u8; length as usize];
-    buf.read_exact(&mut bytes)?;
-    // And attempt to decode it as UTF8
-    String::from_utf8(bytes).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid utf8"))
+impl<'a> ConcatRef<'a> {
+    pub fn len(&self) -> usize {
+        self.count as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Iterate over the strings (without allocating)
+    pub fn iter(&self) -> impl Iterator<Item = &'a str> {
+        let mut parts = self.parts;
+        (0..self.count)
+            .map(move |_| extract_str_ref(&mut parts).expect("Concat parts were validated"))
+    }
 }
 
-/// Abstracted Protocol that wraps a TcpStream and manages
-/// sending & receiving of messages
-pub struct Protocol {
-    reader: io::BufReader<TcpStream>,
-    stream: TcpStream,
+/// The pairs of a `RequestRef::KeyValues`, still in their wire format (already validated)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KeyValuesRef<'a> {
+    count: u16,
+    pairs: &'a [u8],
 }
 
-impl Protocol {
-    /// Wrap a TcpStream with Protocol
-    pub fn with_stream(stream: TcpStream) -> io::Result<Self> {
-        Ok(Self {
-            reader: io::BufReader::new(stream.try_clone()?),
-            stream,
-        })
+impl<'a> KeyValuesRef<'a> {
+    pub fn len(&self) -> usize {
+        self.count as usize
     }
 
-    /// Establish a connection, wrap stream in BufReader/Writer
-    pub fn connect(dest: SocketAddr) -> io::Result<Self> {
-        let stream = TcpStream::connect(dest)?;
-        eprintln!("Connecting to {}", dest);
-        Self::with_stream(stream)
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
     }
 
-    /// Serialize a message to the server and write it to the TcpStream
-    pub fn send_message(&mut self, message: &impl Serialize) -> io::Result<()> {
-        message.serialize(&mut self.stream)?;
-        self.stream.flush()
+    /// Iterate over the (key, value) pairs (without allocating)
+    pub fn iter(&self) -> impl Iterator<Item = (&'a str, &'a str)> {
+        let mut pairs = self.pairs;
+        (0..self.count).map(move |_| {
+            let key = extract_str_ref(&mut pairs).expect("KeyValues pairs were validated");
+            let value = extract_str_ref(&mut pairs).expect("KeyValues pairs were validated");
+            (key, value)
+        })
     }
+}
 
-    /// Read a message from the inner TcpStream
-    ///
-    /// NOTE: Will block until there's data to read (or deserialize fails with io::ErrorKind::Interrupted)
-    ///       so only use when a message is expected to arrive
-    pub fn read_message<T: Deserialize>(&mut self) -> io::Result<T::Output> {
-        T::deserialize(&mut self.reader)
+/// Read the next length (u16) from a byte slice and borrow that many bytes from it
+fn extract_bytes_ref<'a>(buf: &mut &'a [u8]) -> io::Result<&'a [u8]> {
+    let length = buf.read_u16::<NetworkEndian>()? as usize;
+    if buf.len() < length {
+        return Err(io::ErrorKind::UnexpectedEof.into());
     }
+    let (value, rest) = buf.split_at(length);
+    *buf = rest;
+    Ok(value)
 }
 
-#[cfg(test)]
-mod test {
-    use super::*;
-    use std::io::Cursor;
+/// Same as `extract_bytes_ref`, but the bytes must be valid UTF-8
+fn extract_str_ref<'a>(buf: &mut &'a [u8]) -> io::Result<&'a str> {
+    std::str::from_utf8(extract_bytes_ref(buf)?)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid utf8"))
+}
+
+/// A message tagged with an ID, so its logs can be matched up on the client & server
+///
+/// Message format for Traced is the ID followed by the wrapped message:
+/// ```ignore
+/// |    u64    |     [u8]      |
+/// |    id     |    message    |
+/// ```
+#[derive(Debug, PartialEq)]
+pub struct Traced<T> {
+    pub id: u64,
+    pub message: T,
+}
+
+/// Nanoseconds since the Unix epoch, for message timestamps (see `Protocol::set_timestamps`)
+///
+/// This is the wall clock rather than a monotonic one (like `Instant`), since a monotonic
+/// clock only means something within one process and can't be compared with the other end's.
+/// So a one-way delay is only as accurate as the two clocks are in sync (e.g. with NTP), and a
+/// round trip (from an echoed timestamp) can be thrown off by the clock being adjusted.
+pub fn timestamp_nanos() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_nanos() as u64)
+        .unwrap_or_default()
+}
+
+/// Generate a (random enough) ID for a `Traced` message
+pub fn new_request_id() -> u64 {
+    let nanos = timestamp_nanos();
+    // Mix in the process ID so clients started at the same moment still differ
+    SplitMix64(nanos ^ ((std::process::id() as u64) << 32)).next_u64()
+}
+
+impl<T: Serialize> Serialize for Traced<T> {
+    fn serialize(&self, buf: &mut impl Write) -> io::Result<usize> {
+        self.serialize_with_order::<NetworkEndian>(buf)
+    }
+
+    fn serialize_with_order<E: WireOrder>(&self, buf: &mut impl Write) -> io::Result<usize> {
+        buf.write_u64::<E::Order>(self.id)?;
+        Ok(8 + self.message.serialize_with_order::<E>(buf)?)
+    }
+}
+
+impl<T: Deserialize> Deserialize for Traced<T> {
+    type Output = Traced<T::Output>;
+
+    fn deserialize(buf: &mut impl Read) -> io::Result<Self::Output> {
+        Self::deserialize_with(buf, OnInvalidUtf8::Error)
+    }
+
+    fn deserialize_into(buf: &mut impl Read, dest: &mut Self::Output) -> io::Result<()> {
+        Self::deserialize_into_with(buf, dest, OnInvalidUtf8::Error)
+    }
+
+    fn deserialize_with(
+        buf: &mut impl Read,
+        on_invalid: OnInvalidUtf8,
+    ) -> io::Result<Self::Output> {
+        Self::deserialize_with_order::<NetworkEndian>(buf, on_invalid)
+    }
+
+    fn deserialize_into_with(
+        buf: &mut impl Read,
+        dest: &mut Self::Output,
+        on_invalid: OnInvalidUtf8,
+    ) -> io::Result<()> {
+        Self::deserialize_into_with_order::<NetworkEndian>(buf, dest, on_invalid)
+    }
+
+    fn deserialize_with_order<E: WireOrder>(
+        buf: &mut impl Read,
+        on_invalid: OnInvalidUtf8,
+    ) -> io::Result<Self::Output> {
+        let id = buf.read_u64::<E::Order>()?;
+        let message = T::deserialize_with_order::<E>(buf, on_invalid)?;
+        Ok(Traced { id, message })
+    }
+
+    fn deserialize_into_with_order<E: WireOrder>(
+        buf: &mut impl Read,
+        dest: &mut Self::Output,
+        on_invalid: OnInvalidUtf8,
+    ) -> io::Result<()> {
+        dest.id = buf.read_u64::<E::Order>()?;
+        T::deserialize_into_with_order::<E>(buf, &mut dest.message, on_invalid)
+    }
+}
+
+/// Response object from server
+///
+/// Like `Request`, this is an enum so the server can signal Success vs. Error
+#[derive(Debug, Clone, PartialEq)]
+pub enum Response {
+    /// The request was handled, with the resulting message
+    Ok(String),
+    /// The request could not be handled, with a description of why
+    Err(String),
+    /// Reply to a `Request::Ping` (with no message)
+    Pong,
+    /// The request was handled, with more than one resulting string (e.g. `Request::Split`)
+    Tokens(Vec<String>),
+    /// The request was handled, with a reply in named parts (e.g. a status, headers and a body),
+    /// in the order they were added (see `ResponseBuilder`). Names are unique
+    Segments(Vec<(String, String)>),
+}
+
+/// Encode the Response status as a single byte
+impl From<&Response> for u8 {
+    fn from(resp: &Response) -> Self {
+        match resp {
+            Response::Ok(_) => 1,
+            Response::Err(_) => 2,
+            Response::Pong => 3,
+            Response::Tokens(_) => 4,
+            Response::Segments(_) => 5,
+        }
+    }
+}
+
+/// Message format for Response is:
+/// ```ignore
+/// |    u8    |     u16     |     [u8]      |
+/// |  status  |    length   |  value bytes  |
+/// ```
+///
+/// Except for Tokens, where (like `Request::Concat`) there's a count of (length/bytes) tuples:
+/// ```ignore
+/// |    u8    |    u16    |     u16     |     [u8]      | ... (count times)
+/// |  status  |   count   |    length   |  value bytes  | ...
+/// ```
+///
+/// And Segments, where (like `Request::KeyValues`) there's a count of (name, value) pairs, each
+/// of them two (length/bytes) tuples
+impl Response {
+    /// Create a new (successful) response with a given message
+    pub fn new(message: String) -> Self {
+        Response::Ok(message)
+    }
+
+    /// Get the response message value
+    pub fn message(&self) -> &str {
+        match self {
+            Response::Ok(message) | Response::Err(message) => message,
+            // More than one message
+            Response::Pong | Response::Tokens(_) | Response::Segments(_) => "",
+        }
+    }
+
+    /// Was the request handled successfully?
+    pub fn is_ok(&self) -> bool {
+        matches!(
+            self,
+            Response::Ok(_) | Response::Pong | Response::Tokens(_) | Response::Segments(_)
+        )
+    }
+
+    /// Number of bytes `serialize` writes for this response, without writing them (like
+    /// `Request::serialized_size`)
+    pub fn serialized_size(&self) -> usize {
+        self.serialized_size_with(LengthEncoding::Fixed)
+    }
+
+    /// Same as `serialized_size`, but with length prefixes in `lengths`
+    pub fn serialized_size_with(&self, lengths: LengthEncoding) -> usize {
+        let field = |bytes: usize| lengths.prefix_len(bytes) + bytes;
+        let fields = match self {
+            // The count, then the tuples
+            Response::Tokens(tokens) => {
+                2 + tokens.iter().map(|token| field(token.len())).sum::<usize>()
+            }
+            Response::Segments(segments) => {
+                2 + segments
+                    .iter()
+                    .map(|(name, value)| field(name.len()) + field(value.len()))
+                    .sum::<usize>()
+            }
+            // Pong's (empty) message still has a length
+            resp => field(resp.message().len()),
+        };
+        // The status byte
+        1 + fields
+    }
+
+    /// Get the value of a `Response::Segments`' segment by name (`None` for other Responses)
+    pub fn segment(&self, name: &str) -> Option<&str> {
+        match self {
+            Response::Segments(segments) => segments
+                .iter()
+                .find(|(segment_name, _)| segment_name == name)
+                .map(|(_, value)| value.as_str()),
+            _ => None,
+        }
+    }
+
+    /// Build a Response from its status byte and message
+    fn from_status(status: u8, message: String) -> io::Result<Self> {
+        match status {
+            1 => Ok(Response::Ok(message)),
+            2 => Ok(Response::Err(message)),
+            3 if message.is_empty() => Ok(Response::Pong),
+            3 => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Pong Response with a message",
+            )),
+            // Tokens have a count rather than a single message (see `extract_tokens`)
+            4 => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Tokens Response can't be read as a single message",
+            )),
+            // As do Segments (see `extract_segments`)
+            5 => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Segments Response can't be read as a single message",
+            )),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Invalid Response Status",
+            )),
+        }
+    }
+
+    /// Serialize Response as a series of chunks, for messages too large for a single `u16` length
+    ///
+    /// Message format for a chunked Response is:
+    /// ```ignore
+    /// |    u8    |     u16     |     [u8]      | ... |     u16     |
+    /// |  status  |    length   |  chunk bytes  | ... |      0      |
+    /// ```
+    ///
+    /// Each chunk holds up to `u16::MAX` bytes and a zero-length chunk marks the end of the message
+    /// (similar to HTTP's chunked transfer encoding). Returns the number of bytes written
+    ///
+    /// Only single message Responses can be chunked (like `deserialize_chunked` reads), so
+    /// `Tokens` & `Segments` are an `io::ErrorKind::InvalidInput` error, before anything is written
+    pub fn serialize_chunked(&self, buf: &mut impl Write) -> io::Result<usize> {
+        let multi_value = match self {
+            Response::Tokens(_) => Some("Tokens"),
+            Response::Segments(_) => Some("Segments"),
+            Response::Ok(_) | Response::Err(_) | Response::Pong => None,
+        };
+        if let Some(name) = multi_value {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} Response can't be chunked", name),
+            ));
+        }
+        buf.write_u8(self.into())?;
+        let mut bytes_written = 1;
+        for chunk in self.message().as_bytes().chunks(u16::MAX as usize) {
+            buf.write_u16::<NetworkEndian>(chunk.len() as u16)?;
+            buf.write_all(chunk)?;
+            bytes_written += 2 + chunk.len();
+        }
+        // Terminating chunk
+        buf.write_u16::<NetworkEndian>(0)?;
+        Ok(bytes_written + 2)
+    }
+
+    /// Deserialize a chunked Response (see `serialize_chunked`), reassembling the chunks
+    ///
+    /// Chunks may split a multi-byte character, so UTF-8 is only validated once all chunks are read.
+    /// Messages longer than `max_size` bytes (in total) are rejected, see `ResponseChunks::new`
+    pub fn deserialize_chunked(buf: &mut impl Read, max_size: usize) -> io::Result<Self> {
+        let mut chunks = ResponseChunks::new(buf, max_size)?;
+        let mut bytes: Vec<u8> = vec![];
+        for chunk in &mut chunks {
+            bytes.extend_from_slice(&chunk?);
+        }
+        let message = String::from_utf8(bytes)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid utf8"))?;
+        Self::from_status(chunks.status, message)
+    }
+
+    /// Deserialize Response from bytes, rejecting responses with a message longer than `max_size` bytes
+    ///
+    /// This protects a client from allocating whatever a malicious (or buggy) server says it will send.
+    /// For `Tokens` & `Segments`, the limit is on all of their strings together
+    pub fn deserialize_with_limit(buf: &mut impl Read, max_size: usize) -> io::Result<Self> {
+        Self::deserialize_with_limit_order::<NetworkEndian>(buf, max_size)
+    }
+
+    /// Same as `deserialize_with_limit`, but with `E`'s byte order and length encoding
+    fn deserialize_with_limit_order<E: WireOrder>(
+        buf: &mut impl Read,
+        max_size: usize,
+    ) -> io::Result<Self> {
+        let status = buf.read_u8()?;
+        if status == 4 {
+            // The limit applies to all of the tokens together
+            let tokens = extract_tokens::<E>(buf, String::new(), max_size, OnInvalidUtf8::Error)?;
+            return Ok(Response::Tokens(tokens));
+        }
+        if status == 5 {
+            // And to all of the names & values
+            let segments = extract_segments::<E>(buf, max_size, OnInvalidUtf8::Error)?;
+            return Ok(Response::Segments(segments));
+        }
+        let message = extract_string_with_limit::<E>(buf, max_size)?;
+        Self::from_status(status, message)
+    }
+
+    /// Deserialize Response from bytes, reading the message at most `chunk_size` bytes at a time
+    ///
+    /// `progress` is called after each chunk with `(bytes_read, total_bytes)`, and can return
+    /// `false` to abort the read (e.g. if the user cancelled). See `extract_string_chunked`
+    pub fn deserialize_with_progress(
+        buf: &mut impl Read,
+        chunk_size: usize,
+        progress: impl FnMut(usize, usize) -> bool,
+    ) -> io::Result<Self> {
+        let status = buf.read_u8()?;
+        let message = extract_string_chunked(buf, usize::MAX, chunk_size, progress)?;
+        Self::from_status(status, message)
+    }
+}
+
+/// Builds a `Response::Segments` from named parts, e.g.:
+/// ```
+/// # use tcp_demo_protocol::ResponseBuilder;
+/// let resp = ResponseBuilder::new()
+///     .segment("status", "200")
+///     .segment("content-type", "text/plain")
+///     .segment("body", "Hello")
+///     .build();
+/// assert_eq!(resp.segment("body"), Some("Hello"));
+/// ```
+#[derive(Debug, Default)]
+pub struct ResponseBuilder {
+    segments: Vec<(String, String)>,
+}
+
+impl ResponseBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a segment, or replace the value of the segment already named `name` (keeping its place)
+    pub fn segment(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        let name = name.into();
+        let value = value.into();
+        match self
+            .segments
+            .iter_mut()
+            .find(|(existing, _)| *existing == name)
+        {
+            Some((_, existing)) => *existing = value,
+            None => self.segments.push((name, value)),
+        }
+        self
+    }
+
+    pub fn build(self) -> Response {
+        Response::Segments(self.segments)
+    }
+}
+
+/// Iterator over the chunks of a chunked Response (see `Response::serialize_chunked`),
+/// yielding each chunk's bytes as soon as it has been read
+///
+/// This allows displaying a large response as it arrives (rather than waiting for all of it).
+/// Chunks may split a multi-byte character, so they're bytes rather than `String`s
+pub struct ResponseChunks<R> {
+    buf: R,
+    status: u8,
+    done: bool,
+    /// Most message bytes to read, across all of the chunks
+    max_size: usize,
+    /// Message bytes read so far
+    received: usize,
+}
+
+impl<R: Read> ResponseChunks<R> {
+    /// Read the status byte and prepare to read the chunks following it
+    ///
+    /// There's no limit on the number of chunks, so a chunk that would take the message past
+    /// `max_size` bytes is an `io::ErrorKind::InvalidData` error (before it's read)
+    pub fn new(mut buf: R, max_size: usize) -> io::Result<Self> {
+        let status = buf.read_u8()?;
+        // Check the status is valid before reading any further
+        Response::from_status(status, String::new())?;
+        Ok(Self {
+            buf,
+            status,
+            done: false,
+            max_size,
+            received: 0,
+        })
+    }
+
+    /// Was the request handled successfully? (i.e. are these chunks a `Response::Ok`)
+    pub fn is_ok(&self) -> bool {
+        self.status == 1
+    }
+
+    fn read_chunk(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let length = self.buf.read_u16::<NetworkEndian>()? as usize;
+        if length == 0 {
+            return Ok(None);
+        }
+        if self.received + length > self.max_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Chunked message length exceeds limit of {} bytes",
+                    self.max_size
+                ),
+            ));
+        }
+        let mut chunk = vec![0u8; length];
+        self.buf.read_exact(&mut chunk)?;
+        self.received += length;
+        Ok(Some(chunk))
+    }
+}
+
+impl<R: Read> Iterator for ResponseChunks<R> {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let chunk = self.read_chunk().transpose();
+        // Stop at the terminating chunk, or after an error (the stream is no longer framed)
+        self.done = !matches!(chunk, Some(Ok(_)));
+        chunk
+    }
+}
+
+impl Serialize for Response {
+    /// Serialize Response to bytes (to send to client)
+    ///
+    /// Returns the number of bytes written
+    fn serialize(&self, buf: &mut impl Write) -> io::Result<usize> {
+        self.serialize_with_order::<NetworkEndian>(buf)
+    }
+
+    fn serialize_with_order<E: WireOrder>(&self, buf: &mut impl Write) -> io::Result<usize> {
+        buf.write_u8(self.into())
+            .map_err(|e| write_context(e, "status byte"))?;
+        if let Response::Tokens(tokens) = self {
+            write_count::<E>(buf, tokens.len(), "token")?;
+            let mut bytes_written = 3; // Status + count
+            for token in tokens {
+                bytes_written += write_bytes_field::<E>(buf, token.as_bytes(), "token")?;
+            }
+            debug_assert_eq!(bytes_written, self.serialized_size_with(E::LENGTHS));
+            return Ok(bytes_written);
+        }
+        if let Response::Segments(segments) = self {
+            write_count::<E>(buf, segments.len(), "segment")?;
+            let mut bytes_written = 3; // Status + count
+            for (name, value) in segments {
+                bytes_written += write_bytes_field::<E>(buf, name.as_bytes(), "segment name")?;
+                bytes_written += write_bytes_field::<E>(buf, value.as_bytes(), "segment value")?;
+            }
+            debug_assert_eq!(bytes_written, self.serialized_size_with(E::LENGTHS));
+            return Ok(bytes_written);
+        }
+        // Status + len + bytes
+        let bytes_written = 1 + write_bytes_field::<E>(buf, self.message().as_bytes(), "message")?;
+        debug_assert_eq!(bytes_written, self.serialized_size_with(E::LENGTHS));
+        Ok(bytes_written)
+    }
+}
+
+impl Deserialize for Response {
+    type Output = Response;
+    /// Deserialize Response to bytes (to receive from server)
+    fn deserialize(buf: &mut impl Read) -> io::Result<Self::Output> {
+        Self::deserialize_with(buf, OnInvalidUtf8::Error)
+    }
+
+    /// Deserialize Response from bytes, reusing the `String` already held by `dest`
+    fn deserialize_into(buf: &mut impl Read, dest: &mut Self::Output) -> io::Result<()> {
+        Self::deserialize_into_with(buf, dest, OnInvalidUtf8::Error)
+    }
+
+    fn deserialize_with(
+        buf: &mut impl Read,
+        on_invalid: OnInvalidUtf8,
+    ) -> io::Result<Self::Output> {
+        Self::deserialize_with_order::<NetworkEndian>(buf, on_invalid)
+    }
+
+    fn deserialize_into_with(
+        buf: &mut impl Read,
+        dest: &mut Self::Output,
+        on_invalid: OnInvalidUtf8,
+    ) -> io::Result<()> {
+        Self::deserialize_into_with_order::<NetworkEndian>(buf, dest, on_invalid)
+    }
+
+    fn deserialize_with_order<E: WireOrder>(
+        buf: &mut impl Read,
+        on_invalid: OnInvalidUtf8,
+    ) -> io::Result<Self::Output> {
+        let mut resp = Response::Ok(String::new());
+        Self::deserialize_into_with_order::<E>(buf, &mut resp, on_invalid)?;
+        Ok(resp)
+    }
+
+    fn deserialize_into_with_order<E: WireOrder>(
+        buf: &mut impl Read,
+        dest: &mut Self::Output,
+        on_invalid: OnInvalidUtf8,
+    ) -> io::Result<()> {
+        #[cfg(debug_assertions)]
+        let mut buf = &mut CountingReader::new(buf);
+        #[cfg(not(debug_assertions))]
+        let mut buf = buf;
+        let mut message = match dest {
+            Response::Ok(message) | Response::Err(message) => std::mem::take(message),
+            Response::Tokens(tokens) => tokens.first_mut().map(std::mem::take).unwrap_or_default(),
+            Response::Pong | Response::Segments(_) => String::new(),
+        };
+        let status = buf.read_u8()?;
+        if status == 4 {
+            let tokens = extract_tokens::<E>(&mut buf, message, usize::MAX, on_invalid)?;
+            *dest = Response::Tokens(tokens);
+        } else if status == 5 {
+            let segments = extract_segments::<E>(&mut buf, usize::MAX, on_invalid)?;
+            *dest = Response::Segments(segments);
+        } else {
+            extract_string_into::<E>(&mut buf, &mut message, usize::MAX, on_invalid)?;
+            *dest = Self::from_status(status, message)?;
+        }
+        #[cfg(debug_assertions)]
+        debug_assert_wire_len::<E>(dest, buf.count, on_invalid);
+        Ok(())
+    }
+}
+
+/// Reader adapter counting the bytes read through it (for `debug_assert_wire_len`)
+#[cfg(debug_assertions)]
+struct CountingReader<R> {
+    inner: R,
+    count: usize,
+}
+
+#[cfg(debug_assertions)]
+impl<R> CountingReader<R> {
+    fn new(inner: R) -> Self {
+        Self { inner, count: 0 }
+    }
+}
+
+#[cfg(debug_assertions)]
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.count += len;
+        Ok(len)
+    }
+}
+
+/// Check (in debug builds) that a message just deserialized from `consumed` bytes serializes back
+/// to the same number of bytes, i.e. that the serialize & deserialize paths agree on its layout
+///
+/// Strings that weren't valid UTF-8 may have been replaced with something longer or shorter,
+/// so this only checks messages read with `OnInvalidUtf8::Error`
+#[cfg(debug_assertions)]
+fn debug_assert_wire_len<E: WireOrder>(
+    message: &impl Serialize,
+    consumed: usize,
+    on_invalid: OnInvalidUtf8,
+) {
+    if on_invalid == OnInvalidUtf8::Error {
+        let written = message
+            .serialize_with_order::<E>(&mut io::sink())
+            .expect("Writing to a sink can't fail");
+        debug_assert_eq!(
+            written, consumed,
+            "message was deserialized from a different number of bytes than it serializes to"
+        );
+    }
+}
+
+/// Add which field was being written to a serialize error, e.g. "Broken pipe (os error 32)
+/// (while writing message length)", keeping its `io::ErrorKind` for callers to match on
+fn write_context(err: io::Error, field: &str) -> io::Error {
+    io::Error::new(err.kind(), format!("{} (while writing {})", err, field))
+}
+
+/// Write a variable length field, preceded by its length (see `LengthEncoding`), returning the
+/// bytes written
+///
+/// `name` is for the error if a write fails, e.g. "message"
+fn write_bytes_field<E: WireOrder>(
+    buf: &mut impl Write,
+    bytes: &[u8],
+    name: &str,
+) -> io::Result<usize> {
+    let prefix_len = E::LENGTHS
+        .write_length::<E::Order>(buf, bytes.len())
+        .map_err(|e| write_context(e, &format!("{} length", name)))?;
+    buf.write_all(bytes).map_err(|e| write_context(e, name))?;
+    Ok(prefix_len + bytes.len())
+}
+
+/// Write the (u16) count of a list of fields, e.g. a `Request::Concat`'s strings
+///
+/// Fails with `io::ErrorKind::InvalidInput` if `count` doesn't fit (a wrapped count would desync
+/// the stream), like `LengthEncoding::write_length` does for lengths. `name` is for the error,
+/// e.g. "token"
+fn write_count<E: WireOrder>(buf: &mut impl Write, count: usize, name: &str) -> io::Result<()> {
+    let count = u16::try_from(count).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Too many {}s: {} (at most {})", name, count, u16::MAX),
+        )
+    })?;
+    buf.write_u16::<E::Order>(count)
+        .map_err(|e| write_context(e, &format!("{} count", name)))
+}
+
+/// Write a fixed size u32 field, preceded by its length (see `extract_u32_field`)
+fn write_u32_field<E: WireOrder>(
+    buf: &mut impl Write,
+    value: u32,
+    name: &str,
+) -> io::Result<usize> {
+    let prefix_len = E::LENGTHS
+        .write_length::<E::Order>(buf, 4)
+        .map_err(|e| write_context(e, &format!("{} length", name)))?;
+    buf.write_u32::<E::Order>(value)
+        .map_err(|e| write_context(e, name))?;
+    Ok(prefix_len + 4)
+}
+
+/// Read a fixed size u32 field, which is preceded by its length (always 4) like every other field
+///
+/// `name` is for the error if the length is wrong, e.g. "Delay ms"
+fn extract_u32_field<E: WireOrder>(buf: &mut impl Read, name: &str) -> io::Result<u32> {
+    let len = E::LENGTHS.read_length::<E::Order>(buf)?;
+    if len != 4 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Invalid {} length {} (expected 4)", name, len),
+        ));
+    }
+    buf.read_u32::<E::Order>()
+}
+
+/// Read the count and strings of a `Response::Tokens` (after its status byte), rejecting
+/// them once they're longer than `max_len` bytes in total (see `extract_string_into`)
+///
+/// `first` is used for the first token, to reuse an allocation
+fn extract_tokens<E: WireOrder>(
+    buf: &mut impl Read,
+    first: String,
+    max_len: usize,
+    on_invalid: OnInvalidUtf8,
+) -> io::Result<Vec<String>> {
+    let count = buf.read_u16::<E::Order>()?;
+    // Grow as the tokens arrive, rather than trusting the count up front
+    let mut tokens = vec![];
+    let mut token = first;
+    // Each token can only have what the ones before it left of the limit
+    let mut remaining = max_len;
+    for _ in 0..count {
+        extract_string_into::<E>(buf, &mut token, remaining, on_invalid)?;
+        remaining = remaining.saturating_sub(token.len());
+        tokens.push(std::mem::take(&mut token));
+    }
+    Ok(tokens)
+}
+
+/// Read the count and (name, value) pairs of a `Response::Segments` (after its status byte),
+/// rejecting them once the names & values are longer than `max_len` bytes in total, and any
+/// repeated name
+fn extract_segments<E: WireOrder>(
+    buf: &mut impl Read,
+    max_len: usize,
+    on_invalid: OnInvalidUtf8,
+) -> io::Result<Vec<(String, String)>> {
+    let count = buf.read_u16::<E::Order>()?;
+    let mut names = HashSet::new();
+    // Grow as the segments arrive, rather than trusting the count up front
+    let mut segments = vec![];
+    let mut remaining = max_len;
+    for _ in 0..count {
+        let mut name = String::new();
+        extract_string_into::<E>(buf, &mut name, remaining, on_invalid)?;
+        remaining = remaining.saturating_sub(name.len());
+        let mut value = String::new();
+        extract_string_into::<E>(buf, &mut value, remaining, on_invalid)?;
+        remaining = remaining.saturating_sub(value.len());
+        if !names.insert(name.clone()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Duplicate segment name '{}'", name),
+            ));
+        }
+        segments.push((name, value));
+    }
+    Ok(segments)
+}
+
+/// From a given readable buffer, read the next length (u16) and extract the string bytes,
+/// rejecting strings longer than `max_len` bytes *before* allocating room for them
+fn extract_string_with_limit<E: WireOrder>(
+    buf: &mut impl Read,
+    max_len: usize,
+) -> io::Result<String> {
+    let mut value = String::new();
+    extract_string_into::<E>(buf, &mut value, max_len, OnInvalidUtf8::Error)?;
+    Ok(value)
+}
+
+/// Same as `extract_string_with_limit`, but reuses the allocation of an existing `String`
+/// and handles invalid UTF-8 according to `on_invalid`
+///
+/// If reading fails, `dest` is left empty
+fn extract_string_into<E: WireOrder>(
+    buf: &mut impl Read,
+    dest: &mut String,
+    max_len: usize,
+    on_invalid: OnInvalidUtf8,
+) -> io::Result<()> {
+    // Take the `String`'s bytes (and capacity) to use as our read buffer
+    let mut bytes = std::mem::take(dest).into_bytes();
+    extract_bytes_into::<E>(buf, &mut bytes, max_len)?;
+    // And attempt to decode it as UTF8
+    *dest = on_invalid.decode(bytes)?;
+    Ok(())
+}
+
+/// From a given readable buffer, read the next length (see `LengthEncoding`) and extract that
+/// many bytes into `dest` (replacing its contents, but reusing its allocation)
+///
+/// Strings longer than `max_len` bytes are rejected *before* allocating room for them
+fn extract_bytes_into<E: WireOrder>(
+    buf: &mut impl Read,
+    dest: &mut Vec<u8>,
+    max_len: usize,
+) -> io::Result<()> {
+    let length = E::LENGTHS.read_length::<E::Order>(buf)?;
+    // Don't trust the peer's length prefix until we've checked it
+    if length > max_len {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Message length {} exceeds limit of {} bytes",
+                length, max_len
+            ),
+        ));
+    }
+    dest.clear();
+    // Given the length of our bytes, only read in that quantity of bytes
+    // (this only allocates if the existing capacity is too small)
+    dest.resize(length, 0);
+    buf.read_exact(dest)
+}
+
+/// How the length prefix of each variable length field is written on the wire (see
+/// `ProtocolBuilder::length_encoding`)
+///
+/// A fixed-width prefix wastes bytes when most fields are short: "Hello" needs 2 bytes of length
+/// for 5 bytes of text. `Varint` spends 1 byte on lengths up to 127, 2 up to 16,383, and 3 up to
+/// the largest field (see `write_varint`), at the cost of reading the prefix a byte at a time.
+/// Either way a field is at most `u16::MAX` bytes, and both ends must agree, like with `Endian`.
+/// Counts (e.g. of a `Request::Concat`'s strings) are always a u16
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LengthEncoding {
+    /// A u16
+    #[default]
+    Fixed,
+    /// An unsigned LEB128 varint (see `write_varint`)
+    Varint,
+}
+
+impl LengthEncoding {
+    /// Number of bytes the prefix for a field of `len` bytes takes
+    pub fn prefix_len(self, len: usize) -> usize {
+        match self {
+            LengthEncoding::Fixed => 2,
+            LengthEncoding::Varint => varint_len(len as u64),
+        }
+    }
+
+    /// Write a length prefix (a `Fixed` one in byte order `B`), returning the bytes written
+    ///
+    /// Fails with `io::ErrorKind::InvalidInput` if `len` is more than a field can hold
+    pub fn write_length<B: ByteOrder>(self, buf: &mut impl Write, len: usize) -> io::Result<usize> {
+        let len = u16::try_from(len).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Length {} is more than a field can hold ({})",
+                    len,
+                    u16::MAX
+                ),
+            )
+        })?;
+        match self {
+            LengthEncoding::Fixed => {
+                buf.write_u16::<B>(len)?;
+                Ok(2)
+            }
+            LengthEncoding::Varint => write_varint(buf, len as u64),
+        }
+    }
+
+    /// Read a length prefix (a `Fixed` one in byte order `B`)
+    ///
+    /// Fails with `io::ErrorKind::InvalidData` if a `Varint` is more than a field can hold,
+    /// so a peer can't claim a huge length with a few bytes
+    pub fn read_length<B: ByteOrder>(self, buf: &mut impl Read) -> io::Result<usize> {
+        match self {
+            LengthEncoding::Fixed => Ok(buf.read_u16::<B>()? as usize),
+            LengthEncoding::Varint => match read_varint(buf)? {
+                len if len <= u16::MAX as u64 => Ok(len as usize),
+                len => Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "Length {} is more than a field can hold ({})",
+                        len,
+                        u16::MAX
+                    ),
+                )),
+            },
+        }
+    }
+}
+
+/// The byte order and `LengthEncoding` of a message's fields, for picking them at compile time
+/// with `Serialize::serialize_with_order` & `Deserialize::deserialize_with_order`
+///
+/// byteorder's `NetworkEndian` and `LittleEndian` use `LengthEncoding::Fixed`, and
+/// `VarintLengths<E>` uses `LengthEncoding::Varint` with byte order `E`, e.g.
+/// `request.serialize_with_order::<VarintLengths<NetworkEndian>>(&mut buf)`
+pub trait WireOrder {
+    /// Byte order of the integer fields (and `Fixed` length prefixes)
+    type Order: ByteOrder;
+    /// How each variable length field's length is written
+    const LENGTHS: LengthEncoding;
+}
+
+impl WireOrder for NetworkEndian {
+    type Order = NetworkEndian;
+    const LENGTHS: LengthEncoding = LengthEncoding::Fixed;
+}
+
+impl WireOrder for LittleEndian {
+    type Order = LittleEndian;
+    const LENGTHS: LengthEncoding = LengthEncoding::Fixed;
+}
+
+/// Fail with `io::ErrorKind::Unsupported` unless `E` is the default `NetworkEndian` wire order,
+/// for the `Serialize` & `Deserialize` defaults that can't honour any other
+fn check_default_wire_order<E: WireOrder>() -> io::Result<()> {
+    let mut one = [0u8; 2];
+    E::Order::write_u16(&mut one, 1);
+    if E::LENGTHS != LengthEncoding::Fixed || one != [0, 1] {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "only network byte order & u16 lengths are supported by this message type",
+        ));
+    }
+    Ok(())
+}
+
+/// `WireOrder` with `LengthEncoding::Varint` lengths, and the rest of the integers in byte
+/// order `E`
+#[derive(Debug)]
+pub struct VarintLengths<E>(PhantomData<E>);
+
+impl<E: ByteOrder> WireOrder for VarintLengths<E> {
+    type Order = E;
+    const LENGTHS: LengthEncoding = LengthEncoding::Varint;
+}
+
+/// Write `value` as an unsigned LEB128 varint, returning the bytes written (1 to 10)
+///
+/// Each byte holds 7 bits of the value, least significant first, with the high bit set on
+/// every byte but the last, e.g. 127 is `[0x7f]` and 128 is `[0x80, 0x01]`
+pub fn write_varint(buf: &mut impl Write, mut value: u64) -> io::Result<usize> {
+    let mut bytes = [0u8; 10];
+    let mut len = 0;
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            bytes[len] = byte;
+            len += 1;
+            break;
+        }
+        bytes[len] = byte | 0x80;
+        len += 1;
+    }
+    buf.write_all(&bytes[..len])?;
+    Ok(len)
+}
+
+/// Read an unsigned LEB128 varint (see `write_varint`)
+///
+/// Fails with `io::ErrorKind::InvalidData` if it doesn't fit in a u64, rather than reading
+/// continuation bytes forever, or if it's overlong (e.g. `[0x85, 0x00]` for 5): there's only
+/// one encoding of each value, so a message re-serializes to the bytes it was read from
+pub fn read_varint(buf: &mut impl Read) -> io::Result<u64> {
+    let mut value: u64 = 0;
+    for shift in (0..64).step_by(7) {
+        let byte = buf.read_u8()?;
+        let bits = (byte & 0x7f) as u64;
+        // The 10th byte only has room for the top bit of a u64
+        if shift == 63 && bits > 1 {
+            break;
+        }
+        value |= bits << shift;
+        if byte & 0x80 == 0 {
+            // A trailing zero byte only adds a byte of length
+            if byte == 0 && shift > 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Overlong varint",
+                ));
+            }
+            return Ok(value);
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "Varint doesn't fit in a u64",
+    ))
+}
+
+/// Number of bytes `write_varint` uses for `value`
+pub fn varint_len(value: u64) -> usize {
+    let bits = 64 - value.leading_zeros() as usize;
+    bits.max(1).div_ceil(7)
+}
+
+/// Same as `extract_string_with_limit`, but reads the string bytes in chunks of up to `chunk_size`
+///
+/// The buffer grows one chunk at a time, so a peer claiming a huge length (but not sending it)
+/// can only make us allocate what it actually sent. `progress` is called after every chunk with
+/// `(bytes_read, total_bytes)` and returning `false` aborts the read.
+///
+/// NOTE: UTF-8 can only be validated once the whole message has been read (a chunk may end in
+///       the middle of a character), so the complete message is still held in memory at the end
+fn extract_string_chunked(
+    buf: &mut impl Read,
+    max_len: usize,
+    chunk_size: usize,
+    mut progress: impl FnMut(usize, usize) -> bool,
+) -> io::Result<String> {
+    if chunk_size == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "chunk_size must be greater than 0",
+        ));
+    }
+    let length = buf.read_u16::<NetworkEndian>()? as usize;
+    if length > max_len {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Message length {} exceeds limit of {} bytes",
+                length, max_len
+            ),
+        ));
+    }
+    let mut bytes: Vec<u8> = vec![];
+    while bytes.len() < length {
+        let start = bytes.len();
+        let end = length.min(start + chunk_size);
+        bytes.resize(end, 0);
+        buf.read_exact(&mut bytes[start..])?;
+        if !progress(end, length) {
+            return Err(io::Error::other("Read aborted"));
+        }
+    }
+    String::from_utf8(bytes).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid utf8"))
+}
+
+/// Shake the characters around using a [Fisher-Yates shuffle](https://en.wikipedia.org/wiki/Fisher%E2%80%93Yates_shuffle)
+///
+/// `amount` is used as the seed for the shuffle, so the same message and amount
+/// will always produce the same permutation
+///
+/// The shuffle makes one swap per character whatever the `amount`, so a large amount
+/// costs no more than a small one. The work is in the message's length, which the servers
+/// cap at `MAX_JUMBLE_CHARS` (see `check_jumble`)
+pub fn jumble_message(message: &str, amount: u16) -> String {
+    let mut chars: Vec<char> = message.chars().collect();
+    let mut rng = SplitMix64(amount as u64);
+    // Walk backwards through the chars, swapping each with a random position at or before it
+    for i in (1..chars.len()).rev() {
+        let j = rng.next_below(i + 1);
+        chars.swap(i, j);
+    }
+    chars.into_iter().collect()
+}
+
+/// Refuse to jumble a message of more than `MAX_JUMBLE_CHARS` characters, with the
+/// `Response::Err` message saying why
+pub fn check_jumble(message: &str) -> Result<(), String> {
+    let chars = message.chars().count();
+    if chars > MAX_JUMBLE_CHARS {
+        return Err(format!(
+            "Jumble messages are limited to {} characters, this one has {}",
+            MAX_JUMBLE_CHARS, chars
+        ));
+    }
+    Ok(())
+}
+
+/// Positions (in chars) where a jumbled message differs from the original, e.g. for
+/// highlighting what `jumble_message` changed
+///
+/// A character swapped with an identical one (like the two `l`s in "Hello") isn't a change,
+/// since nothing looks different there
+pub fn jumble_diff(original: &str, jumbled: &str) -> Vec<usize> {
+    original
+        .chars()
+        .zip(jumbled.chars())
+        .enumerate()
+        .filter(|(_, (before, after))| before != after)
+        .map(|(position, _)| position)
+        .collect()
+}
+
+/// CRC-32 (the IEEE 802.3 one used by zip, PNG & Ethernet) of some bytes
+///
+/// A bitwise implementation: slower than the usual lookup table, but short enough to follow
+pub fn crc32(bytes: &[u8]) -> u32 {
+    // The polynomial, bit-reversed since the bits of each byte are processed lowest first
+    const POLYNOMIAL: u32 = 0xedb8_8320;
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            // Subtract (XOR) the polynomial whenever the lowest bit is set
+            crc = (crc >> 1) ^ (POLYNOMIAL & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+/// How many times each character occurs in a message, sorted by character (so the result is
+/// the same every time, unlike iterating over the `HashMap` it's counted in)
+pub fn char_histogram(message: &str) -> Vec<(char, u32)> {
+    let mut counts: HashMap<char, u32> = HashMap::new();
+    for c in message.chars() {
+        *counts.entry(c).or_insert(0) += 1;
+    }
+    let mut histogram: Vec<(char, u32)> = counts.into_iter().collect();
+    histogram.sort_unstable_by_key(|(c, _)| *c);
+    histogram
+}
+
+/// Count the characters, words (separated by whitespace) and lines in a message, e.g.:
+/// ```text
+/// chars=11 words=2 lines=1
+/// ```
+pub fn text_stats(message: &str) -> String {
+    format!(
+        "chars={} words={} lines={}",
+        message.chars().count(),
+        message.split_whitespace().count(),
+        message.lines().count()
+    )
+}
+
+/// Format bytes as space separated (lowercase) hex, e.g. `48 69 ff`
+pub fn bytes_to_hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// XOR each byte with the next byte of the key, starting over at the end of the key, so
+/// XORing the result with the same key gives back the original bytes
+///
+/// **This is NOT encryption**, it's only for illustrating byte manipulation (see
+/// `Request::Xor`). An empty key leaves the bytes as they are
+pub fn xor_bytes(bytes: &[u8], key: &[u8]) -> Vec<u8> {
+    if key.is_empty() {
+        return bytes.to_vec();
+    }
+    bytes
+        .iter()
+        .zip(key.iter().cycle())
+        .map(|(byte, key_byte)| byte ^ key_byte)
+        .collect()
+}
+
+/// The standard base64 alphabet, each character encoding 6 bits
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Standard (padded) base64, for sending bytes that aren't UTF-8 as text
+pub fn base64_encode(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = (chunk[0] as u32) << 16
+            | (*chunk.get(1).unwrap_or(&0) as u32) << 8
+            | *chunk.get(2).unwrap_or(&0) as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(BASE64_ALPHABET[(n >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+/// Decode standard (padded) base64, or `None` if it isn't valid base64
+pub fn base64_decode(encoded: &str) -> Option<Vec<u8>> {
+    let encoded = encoded.as_bytes();
+    if !encoded.len().is_multiple_of(4) {
+        return None;
+    }
+    let mut bytes = Vec::with_capacity(encoded.len() / 4 * 3);
+    let chunks = encoded.chunks(4).count();
+    for (idx, chunk) in encoded.chunks(4).enumerate() {
+        // Only the last chunk can be padded, by up to 2 characters
+        let padding = chunk.iter().rev().take_while(|&&c| c == b'=').count();
+        if padding > 2 || (padding > 0 && idx + 1 < chunks) {
+            return None;
+        }
+        let mut n = 0u32;
+        for &c in &chunk[..4 - padding] {
+            let value = BASE64_ALPHABET.iter().position(|&a| a == c)? as u32;
+            n = n << 6 | value;
+        }
+        n <<= 6 * padding as u32;
+        bytes.extend_from_slice(&n.to_be_bytes()[1..4 - padding]);
+    }
+    Some(bytes)
+}
+
+/// Append connection metadata to a response message, for client-side diagnostics
+///
+/// The suffix is a space-separated list of `key=value` pairs in square brackets:
+/// ```text
+/// 'Hello' from the other side! [peer=1.2.3.4:5678 time_us=42]
+/// ```
+/// - `peer`: The client's source address as observed by the server (after any NAT)
+/// - `time_us`: How long the server spent handling the request, in microseconds
+pub fn append_metadata(message: &mut String, peer_addr: &str, elapsed: Duration) {
+    message.push_str(&format!(
+        " [peer={} time_us={}]",
+        peer_addr,
+        elapsed.as_micros()
+    ));
+}
+
+/// Tiny deterministic PRNG ([SplitMix64](https://prng.di.unimi.it/splitmix64.c)), good enough
+/// for jumbling but definitely not for anything security related
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Random value in `0..bound` (the modulo bias is negligible for the small bounds we use)
+    fn next_below(&mut self, bound: usize) -> usize {
+        (self.next_u64() % bound as u64) as usize
+    }
+}
+
+/// A bi-directional stream that `Protocol` can send & receive messages over
+///
+/// `Protocol` needs separate handles for buffered reading and for writing, so the stream
+/// should be able to clone a handle to the same underlying connection (like `TcpStream::try_clone`).
+/// If it can't, the Protocol shares the one stream instead (see `Protocol::is_degraded`)
+pub trait Stream: Read + Write + Sized {
+    /// Create a new handle to the same underlying stream
+    fn try_clone(&self) -> io::Result<Self>;
+
+    /// Shut down the writing half, so the other end reads EOF once it's read everything sent
+    /// before (a half-close), while this end can still read
+    ///
+    /// Fails with `io::ErrorKind::Unsupported` (the default) for streams that can't
+    fn shutdown_write(&self) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "this stream can't shut down only its writing half",
+        ))
+    }
+}
+
+impl Stream for TcpStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        TcpStream::try_clone(self)
+    }
+
+    fn shutdown_write(&self) -> io::Result<()> {
+        self.shutdown(Shutdown::Write)
+    }
+}
+
+#[cfg(unix)]
+impl Stream for std::os::unix::net::UnixStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        std::os::unix::net::UnixStream::try_clone(self)
+    }
+
+    fn shutdown_write(&self) -> io::Result<()> {
+        self.shutdown(Shutdown::Write)
+    }
+}
+
+/// The two ends of a TCP connection, named by role rather than local/peer
+///
+/// A common surprise is that the server sees the client connecting from some port other
+/// than the server's (e.g. `127.0.0.1:53412` rather than `:4000`). That's expected: the client's
+/// OS picks an ephemeral source port for every new connection, and only the server's side uses
+/// the well-known listening port.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ConnectionInfo {
+    /// The address the server is listening on (and accepted this connection on)
+    pub server_listen_addr: SocketAddr,
+    /// The client's address, with the ephemeral source port picked by the client's OS
+    pub client_source_addr: SocketAddr,
+}
+
+/// Options for a `Protocol`, usually set with a `ProtocolBuilder`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ProtocolConfig {
+    /// Fail reads that block for longer than this (default: block forever)
+    pub read_timeout: Option<Duration>,
+    /// Fail writes that block for longer than this (default: block forever)
+    pub write_timeout: Option<Duration>,
+    /// Disable Nagle's algorithm so small messages are sent immediately
+    pub nodelay: bool,
+    /// Reject incoming messages larger than this many bytes on the wire (default: no limit)
+    pub max_message_size: Option<usize>,
+    /// Kernel TCP keepalive settings (default: disabled)
+    pub keepalive: Option<sockopt::KeepaliveCfg>,
+    /// How to handle received strings that aren't valid UTF-8 (default: error)
+    pub on_invalid_utf8: OnInvalidUtf8,
+    /// Wrap every message in a length-prefixed frame (both ends must agree, see `MalformedFrame`)
+    pub framed: bool,
+    /// When sent messages are flushed to the stream (default: after every message)
+    pub flush_strategy: FlushStrategy,
+    /// Byte order of the integers in each message, like length prefixes (default: network order)
+    pub endian: Endian,
+    /// How the length of each variable length field is written (both ends must agree,
+    /// default: a u16)
+    pub length_encoding: LengthEncoding,
+    /// Prefix every message with a timestamp of when it was sent (both ends must agree, see
+    /// `Protocol::last_timestamp`)
+    pub timestamps: bool,
+    /// Prefix every message with an incrementing sequence number, and reject messages that
+    /// skip or repeat one (both ends must agree, see `Protocol::set_sequence_numbers`)
+    pub sequence_numbers: bool,
+}
+
+/// Byte order of the integers (length prefixes, Jumble's `amount`, etc.) in messages on the wire
+///
+/// Network byte order (big-endian) is the convention for network protocols and what this
+/// protocol uses, but a peer that (unfortunately) chose little-endian framing can still be
+/// talked to. Both ends must agree: a mismatched length prefix reads as a very different length.
+///
+/// To pick the byte order at compile time instead, use `Serialize::serialize_with_order`
+/// and `Deserialize::deserialize_with_order` with a `WireOrder` type (e.g. `LittleEndian`).
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Endian {
+    /// Big-endian, i.e. `NetworkEndian`
+    #[default]
+    Network,
+    /// Little-endian, i.e. `LittleEndian`
+    Little,
+}
+
+/// When `Protocol::send_message` flushes messages to the stream
+///
+/// Flushing after every message gets each one on the wire as soon as possible (best latency),
+/// but costs a `write` syscall (and likely a TCP segment) per message. Batching several messages
+/// into one flush is better for throughput when sending many messages at once.
+///
+/// NOTE: The write buffer is also flushed whenever it fills up, and before any read that has to
+///       wait for data: a message that hasn't been flushed hasn't been sent, so waiting for a
+///       response to it would block forever. So e.g. with `Manual`, requests can be pipelined
+///       with no explicit flush at all (the first read sends them all in one `write`), and a
+///       server's responses to pipelined requests go out together once it's read them all
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum FlushStrategy {
+    /// Flush after every message
+    #[default]
+    Immediate,
+    /// Only flush when `Protocol::flush` is called
+    Manual,
+    /// Flush after every `n` messages (call `Protocol::flush` to send a partial batch)
+    EveryN(usize),
+}
+
+/// Configure a `Protocol` with chainable setters, e.g.:
+/// ```no_run
+/// # use std::time::Duration;
+/// # use tcp_demo_protocol::ProtocolBuilder;
+/// let protocol = ProtocolBuilder::new()
+///     .read_timeout(Duration::from_secs(5))
+///     .nodelay(true)
+///     .max_message_size(1024)
+///     .connect("127.0.0.1:4000".parse().unwrap())?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+///
+/// The socket options (timeouts, nodelay & keepalive) are only available for TCP streams.
+/// `Protocol::connect` & `Protocol::with_stream` are the same as using the default config.
+#[derive(Debug, Clone, Default)]
+pub struct ProtocolBuilder {
+    config: ProtocolConfig,
+    #[cfg(feature = "hmac")]
+    psk: Option<Vec<u8>>,
+}
+
+impl ProtocolBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.config.read_timeout = Some(timeout);
+        self
+    }
+
+    pub fn write_timeout(mut self, timeout: Duration) -> Self {
+        self.config.write_timeout = Some(timeout);
+        self
+    }
+
+    pub fn nodelay(mut self, nodelay: bool) -> Self {
+        self.config.nodelay = nodelay;
+        self
+    }
+
+    pub fn max_message_size(mut self, max_size: usize) -> Self {
+        self.config.max_message_size = Some(max_size);
+        self
+    }
+
+    pub fn keepalive(mut self, cfg: sockopt::KeepaliveCfg) -> Self {
+        self.config.keepalive = Some(cfg);
+        self
+    }
+
+    pub fn on_invalid_utf8(mut self, on_invalid: OnInvalidUtf8) -> Self {
+        self.config.on_invalid_utf8 = on_invalid;
+        self
+    }
+
+    pub fn framed(mut self, framed: bool) -> Self {
+        self.config.framed = framed;
+        self
+    }
+
+    pub fn flush_strategy(mut self, flush_strategy: FlushStrategy) -> Self {
+        self.config.flush_strategy = flush_strategy;
+        self
+    }
+
+    pub fn endian(mut self, endian: Endian) -> Self {
+        self.config.endian = endian;
+        self
+    }
+
+    pub fn length_encoding(mut self, length_encoding: LengthEncoding) -> Self {
+        self.config.length_encoding = length_encoding;
+        self
+    }
+
+    pub fn timestamps(mut self, timestamps: bool) -> Self {
+        self.config.timestamps = timestamps;
+        self
+    }
+
+    pub fn sequence_numbers(mut self, sequence_numbers: bool) -> Self {
+        self.config.sequence_numbers = sequence_numbers;
+        self
+    }
+
+    /// Authenticate every message with a pre-shared key (see `Protocol::set_psk`)
+    #[cfg(feature = "hmac")]
+    pub fn psk(mut self, key: &[u8]) -> Self {
+        self.psk = Some(key.to_vec());
+        self
+    }
+
+    /// Establish a connection and apply the config to it
+    pub fn connect(self, dest: SocketAddr) -> io::Result<Protocol> {
+        let mut protocol = self.wrap(TcpStream::connect(dest)?)?;
+        eprintln!("Connecting to {}", dest);
+        protocol.dialed = true;
+        Ok(protocol)
+    }
+
+    /// Apply the config to an existing stream (e.g. from `TcpListener::accept`)
+    pub fn wrap(self, stream: TcpStream) -> io::Result<Protocol> {
+        stream.set_read_timeout(self.config.read_timeout)?;
+        stream.set_write_timeout(self.config.write_timeout)?;
+        stream.set_nodelay(self.config.nodelay)?;
+        if let Some(cfg) = self.config.keepalive {
+            sockopt::set_tcp_keepalive(&stream, cfg)?;
+        }
+        let mut protocol = Protocol::with_stream(stream)?;
+        protocol.config = self.config;
+        #[cfg(feature = "hmac")]
+        if let Some(key) = self.psk {
+            protocol.set_psk(&key);
+        }
+        Ok(protocol)
+    }
+}
+
+/// Read buffer size for `Protocol::with_stream` (the same as `BufReader::new`)
+pub const DEFAULT_READ_CAPACITY: usize = 8 * 1024;
+
+/// Abstracted Protocol that wraps a stream (by default a TcpStream) and manages
+/// sending & receiving of messages
+pub struct Protocol<S: Stream = TcpStream> {
+    reader: io::BufReader<StreamHandle<S>>,
+    writer: io::BufWriter<StreamHandle<S>>,
+    /// Messages written since the last flush (for `FlushStrategy::EveryN`)
+    unflushed: usize,
+    /// Total bytes of the messages sent & received (see `bytes_sent` & `bytes_received`)
+    bytes_sent: u64,
+    bytes_received: u64,
+    /// Timestamp of the last message read (see `last_timestamp`)
+    last_timestamp: Option<u64>,
+    /// Sequence number for the next message sent (see `set_sequence_numbers`)
+    next_sequence: u32,
+    /// Sequence number of the last message read (see `last_sequence`)
+    last_sequence: Option<u32>,
+    /// Set once a read finds the peer closed its end (see `is_peer_done_sending`)
+    peer_done_sending: bool,
+    /// Pre-shared key for authenticating every message (see `auth`)
+    #[cfg(feature = "hmac")]
+    psk: Option<Vec<u8>>,
+    /// Whether we connected to the other end (client), rather than it connecting to us (server)
+    dialed: bool,
+    config: ProtocolConfig,
+}
+
+impl Protocol {
+    /// Establish a connection, wrap stream in BufReader/Writer
+    pub fn connect(dest: SocketAddr) -> io::Result<Self> {
+        let stream = TcpStream::connect(dest)?;
+        eprintln!("Connecting to {}", dest);
+        let mut protocol = Self::with_stream(stream)?;
+        protocol.dialed = true;
+        Ok(protocol)
+    }
+
+    /// Which end of the connection is the server's listening address & which is the client's
+    ///
+    /// Protocols created with `connect` are the client end, and protocols created
+    /// with `with_stream` are assumed to wrap an accepted (server end) stream
+    pub fn connection_info(&self) -> io::Result<ConnectionInfo> {
+        let (local_addr, peer_addr) = self
+            .writer
+            .get_ref()
+            .with(|stream| Ok::<_, io::Error>((stream.local_addr()?, stream.peer_addr()?)))?;
+        Ok(if self.dialed {
+            ConnectionInfo {
+                server_listen_addr: peer_addr,
+                client_source_addr: local_addr,
+            }
+        } else {
+            ConnectionInfo {
+                server_listen_addr: local_addr,
+                client_source_addr: peer_addr,
+            }
+        })
+    }
+
+    /// Enable kernel TCP keepalive on the connection (see `sockopt::KeepaliveCfg`)
+    pub fn set_keepalive(&self, cfg: sockopt::KeepaliveCfg) -> io::Result<()> {
+        self.writer
+            .get_ref()
+            .with(|stream| sockopt::set_tcp_keepalive(stream, cfg))
+    }
+
+    /// Read the kernel's TCP state (RTT, congestion window, retransmits, ...) for this connection
+    #[cfg(all(target_os = "linux", feature = "tcp-info"))]
+    pub fn tcp_info(&self) -> io::Result<sockopt::TcpInfo> {
+        self.writer.get_ref().with(sockopt::tcp_info)
+    }
+
+    /// Get a handle for interrupting a blocked read from another thread (see `ShutdownHandle`)
+    ///
+    /// Get the handle *before* reading, since the read borrows the Protocol until it returns
+    pub fn shutdown_handle(&self) -> io::Result<ShutdownHandle> {
+        Ok(ShutdownHandle {
+            stream: Arc::new(self.writer.get_ref().with(TcpStream::try_clone)?),
+        })
+    }
+
+    /// Discard any bytes waiting to be read (buffered or still in the socket), returning how
+    /// many there were, e.g. to get a desynced stream back to a known-empty state
+    ///
+    /// Reads without blocking until there's nothing left, the peer closes the connection, or
+    /// `timeout` is up (in case the peer keeps sending), so bytes still in flight are missed.
+    /// It only makes sense when the peer has stopped sending, e.g. after its response to a
+    /// request that failed part way through
+    pub fn drain(&mut self, timeout: Duration) -> io::Result<usize> {
+        let buffered = self.reader.buffer().len();
+        self.reader.consume(buffered);
+
+        self.writer
+            .get_ref()
+            .with(|stream| stream.set_nonblocking(true))?;
+        let drained = self.drain_nonblocking(timeout);
+        // Even if draining failed, later reads expect to block
+        let restored = self
+            .writer
+            .get_ref()
+            .with(|stream| stream.set_nonblocking(false));
+        let drained = drained?;
+        restored?;
+        Ok(buffered + drained)
+    }
+
+    /// Read & discard from the (non-blocking) stream until it would block (see `drain`)
+    fn drain_nonblocking(&mut self, timeout: Duration) -> io::Result<usize> {
+        let start = Instant::now();
+        let mut scratch = [0; 4096];
+        let mut drained = 0;
+        while start.elapsed() < timeout {
+            // Straight from the stream, the buffer was emptied first
+            match self.reader.get_mut().read(&mut scratch) {
+                Ok(0) => break,
+                Ok(n) => drained += n,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(drained)
+    }
+}
+
+/// Connect to `addr`, send `req`, and return its Response, closing the connection after it
+///
+/// The whole one-shot client flow in one call:
+/// ```no_run
+/// # use tcp_demo_protocol::{exchange, Request, DEFAULT_SERVER_ADDR};
+/// let req = Request::Echo(String::from("Hello"));
+/// let resp = exchange(DEFAULT_SERVER_ADDR.parse().unwrap(), &req).unwrap();
+/// ```
+///
+/// Once the request is sent, the writing half is shut down, so the server sees there are no
+/// more requests and closes the connection after responding. Anything it sends after the one
+/// Response is then an `io::ErrorKind::InvalidData` error (so a `Request::Repeat` must have a
+/// `count` of 1). Requests without a Response (like `Request::Log`) are rejected, there's
+/// nothing to wait for; send those with `Protocol::send_only`
+pub fn exchange(addr: SocketAddr, req: &Request) -> io::Result<Response> {
+    if !req.expects_response() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "Request type '{}' has no response, use Protocol::send_only",
+                req.type_name()
+            ),
+        ));
+    }
+    let mut client = Protocol::connect(addr)?;
+    client.send_message(req)?;
+    client.shutdown_write()?;
+    let resp = client.read_message_required::<Response>()?;
+    if !client.at_eof()? {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Unexpected bytes after the response",
+        ));
+    }
+    Ok(resp)
+}
+
+/// Shuts down a `Protocol`'s connection from another thread, e.g. to cancel a client that's
+/// blocked in `read_message` when the user presses Ctrl-C:
+/// ```no_run
+/// # use tcp_demo_protocol::{Protocol, Response, DEFAULT_SERVER_ADDR};
+/// let mut client = Protocol::connect(DEFAULT_SERVER_ADDR.parse().unwrap()).unwrap();
+/// let handle = client.shutdown_handle().unwrap();
+/// std::thread::spawn(move || {
+///     // ... wait for Ctrl-C
+///     handle.shutdown_read().unwrap();
+/// });
+/// // Returns `UnexpectedEof` as soon as the read is shut down
+/// let resp = client.read_message_required::<Response>();
+/// ```
+///
+/// With std sockets, calling `shutdown` on another handle to the same socket is the way to
+/// unblock a read. The handle holds its own clone of the stream, so it can be cloned & sent to
+/// other threads, and it stays valid (but does nothing useful) after the Protocol is dropped
+#[derive(Debug, Clone)]
+pub struct ShutdownHandle {
+    stream: Arc<TcpStream>,
+}
+
+impl ShutdownHandle {
+    /// Shut down the reading half of the connection
+    ///
+    /// A blocked read then returns as if the other end closed the connection (`read_message`
+    /// returns `Ok(None)`, and `read_message_required` an `UnexpectedEof` error), and so does
+    /// every read after it. Writes still work, so the other end can still be told why
+    pub fn shutdown_read(&self) -> io::Result<()> {
+        self.stream.shutdown(Shutdown::Read)
+    }
+
+    /// Shut down both halves of the connection, also failing any later writes
+    pub fn shutdown(&self) -> io::Result<()> {
+        self.stream.shutdown(Shutdown::Both)
+    }
+}
+
+#[cfg(unix)]
+impl Protocol<std::os::unix::net::UnixStream> {
+    /// Establish a connection to a Unix domain socket
+    pub fn connect_unix(path: impl AsRef<std::path::Path>) -> io::Result<Self> {
+        let stream = std::os::unix::net::UnixStream::connect(&path)?;
+        eprintln!("Connecting to unix:{}", path.as_ref().display());
+        let mut protocol = Self::with_stream(stream)?;
+        protocol.dialed = true;
+        Ok(protocol)
+    }
+}
+
+impl<S: Stream> Protocol<S> {
+    /// Wrap a stream with Protocol
+    ///
+    /// If the stream can't be cloned (e.g. the process is out of file descriptors), the
+    /// Protocol falls back to a degraded mode rather than failing (see `is_degraded`)
+    pub fn with_stream(stream: S) -> io::Result<Self> {
+        Self::with_stream_capacity(stream, DEFAULT_READ_CAPACITY)
+    }
+
+    /// Wrap a stream with Protocol, buffering up to `capacity` bytes of reads
+    ///
+    /// Each time the buffer runs dry, the `BufReader` makes one `read` syscall (via `fill_buf`)
+    /// for up to `capacity` bytes. So a larger capacity means fewer syscalls when messages are
+    /// large or arrive back-to-back, and a smaller one means less memory per connection.
+    /// Messages larger than the capacity still work, they just take more than one refill.
+    pub fn with_stream_capacity(stream: S, capacity: usize) -> io::Result<Self> {
+        let clone = stream.try_clone();
+        let (writer, reader) = StreamHandle::pair(stream, clone);
+        Ok(Self {
+            reader: io::BufReader::with_capacity(capacity, reader),
+            writer: io::BufWriter::new(writer),
+            unflushed: 0,
+            bytes_sent: 0,
+            bytes_received: 0,
+            last_timestamp: None,
+            next_sequence: 0,
+            last_sequence: None,
+            peer_done_sending: false,
+            #[cfg(feature = "hmac")]
+            psk: None,
+            dialed: false,
+            config: ProtocolConfig::default(),
+        })
+    }
+
+    /// Whether the stream couldn't be cloned, so reads & writes share the one stream
+    ///
+    /// A degraded Protocol still works for sequential request/response (which is all the
+    /// `&mut self` methods allow anyway), but it's no longer full-duplex: the stream is
+    /// locked for the duration of each read or write, so anything else that needs the stream
+    /// (e.g. `connection_info`) waits for a blocked read to finish. `shutdown_handle` needs
+    /// its own clone of the stream, so it will likely fail too
+    pub fn is_degraded(&self) -> bool {
+        self.writer.get_ref().is_shared()
+    }
+
+    /// The options this Protocol was created with (see `ProtocolBuilder`)
+    pub fn config(&self) -> &ProtocolConfig {
+        &self.config
+    }
+
+    /// Total bytes of all the messages sent, as they went on the wire
+    ///
+    /// This includes the overhead of the frame lengths & authentication tags (if enabled),
+    /// on top of the serialized messages. Bytes still in the write buffer count as sent
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes_sent
+    }
+
+    /// Total bytes of all the messages received, as they came off the wire (see `bytes_sent`)
+    ///
+    /// NOTE: Chunked responses (see `read_response_chunks`) aren't counted
+    pub fn bytes_received(&self) -> u64 {
+        self.bytes_received
+    }
+
+    /// Number of bytes read from the stream (in one `read` call, up to the `BufReader`'s
+    /// capacity) but not consumed by a message yet, e.g. the start of the next message when a
+    /// peer sends several in one write
+    ///
+    /// These are why a `Protocol`'s stream can't be handed off mid-conversation (or read
+    /// directly) without losing data: the bytes are in the buffer, not the socket
+    pub fn buffered_len(&self) -> usize {
+        self.reader.buffer().len()
+    }
+
+    /// Change how received strings that aren't valid UTF-8 are handled (see `OnInvalidUtf8`)
+    pub fn set_on_invalid_utf8(&mut self, on_invalid: OnInvalidUtf8) {
+        self.config.on_invalid_utf8 = on_invalid;
+    }
+
+    /// Reject incoming messages larger than this many bytes on the wire (`None` for no limit)
+    pub fn set_max_message_size(&mut self, max_size: Option<usize>) {
+        self.config.max_message_size = max_size;
+    }
+
+    /// Wrap every message in a length-prefixed frame (see `MalformedFrame`)
+    pub fn set_framed(&mut self, framed: bool) {
+        self.config.framed = framed;
+    }
+
+    /// Change when sent messages are flushed to the stream (see `FlushStrategy`)
+    pub fn set_flush_strategy(&mut self, flush_strategy: FlushStrategy) {
+        self.config.flush_strategy = flush_strategy;
+    }
+
+    /// Change the byte order of the integers in messages (see `Endian`)
+    pub fn set_endian(&mut self, endian: Endian) {
+        self.config.endian = endian;
+    }
+
+    /// Change how the lengths of fields in messages are written (see `LengthEncoding`)
+    pub fn set_length_encoding(&mut self, length_encoding: LengthEncoding) {
+        self.config.length_encoding = length_encoding;
+    }
+
+    /// Authenticate every message sent & received with a pre-shared key
+    ///
+    /// Both ends of the connection must use the same key
+    #[cfg(feature = "hmac")]
+    pub fn set_psk(&mut self, key: &[u8]) {
+        self.psk = Some(key.to_vec());
+    }
+
+    /// Prefix every message with a timestamp of when it was sent (see `last_timestamp`)
+    ///
+    /// Both ends of the connection must agree, the timestamp is an extra field of every message:
+    /// ```ignore
+    /// |    u32    |     u64     |   [u8]    |    [u8]    |
+    /// |  length   |  timestamp  |  message  |  PSK tag   |
+    /// ```
+    /// (where the frame length and tag are only there with framing and a PSK)
+    pub fn set_timestamps(&mut self, timestamps: bool) {
+        self.config.timestamps = timestamps;
+    }
+
+    /// The timestamp of the last message read (`None` without timestamps enabled)
+    ///
+    /// The receiver can compare this to `timestamp_nanos()` for the one-way delay (if the
+    /// clocks are in sync), or a server can echo it back in its response with
+    /// `send_message_at`, so the client can work out the round trip from its own clock
+    pub fn last_timestamp(&self) -> Option<u64> {
+        self.last_timestamp
+    }
+
+    /// Prefix every message with a sequence number, starting at 0 and incrementing (wrapping
+    /// at `u32::MAX`) with each message sent, and check that each message read has the one after
+    /// the last (see `SequenceError`)
+    ///
+    /// Both ends of the connection must agree, the sequence number is an extra field of every
+    /// message (before the timestamp, if there is one):
+    /// ```ignore
+    /// |    u32    |    u32     |     u64     |   [u8]    |    [u8]    |
+    /// |  length   |  sequence  |  timestamp  |  message  |  PSK tag   |
+    /// ```
+    /// This *detects* dropped, duplicated or reordered messages (e.g. a bug in a proxy, or a
+    /// reader that lost its place in the stream), it doesn't recover from them: the message
+    /// with the unexpected number is discarded, and the connection should be closed since every
+    /// message after it is suspect too.
+    pub fn set_sequence_numbers(&mut self, sequence_numbers: bool) {
+        self.config.sequence_numbers = sequence_numbers;
+    }
+
+    /// The sequence number of the last message read (`None` before the first message, or
+    /// without sequence numbers enabled)
+    pub fn last_sequence(&self) -> Option<u32> {
+        self.last_sequence
+    }
+
+    /// Check a received sequence number follows the last one, and remember it either way (so
+    /// one gap is only reported once)
+    fn check_sequence(&mut self, received: u32) -> io::Result<()> {
+        let expected = self.last_sequence.map_or(0, |last| last.wrapping_add(1));
+        self.last_sequence = Some(received);
+        if received != expected {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                SequenceError { expected, received },
+            ));
+        }
+        Ok(())
+    }
+
+    /// Whether a read has found that the peer closed its end of the connection (e.g.
+    /// `read_message` returned `None`), so there's nothing more to read
+    ///
+    /// A peer that's done sending may still be reading: closing just its write half (a TCP
+    /// half-close, like `exchange` does) means "no more requests", not "stop responding". So a
+    /// server can check this once the reads end, and still send the responses it owes before
+    /// closing the connection. Writes only fail (with `io::ErrorKind::BrokenPipe` or
+    /// `ConnectionReset`) if the peer closed both halves, which can't be told apart from a
+    /// half-close until a write fails.
+    ///
+    /// Also set after `ShutdownHandle::shutdown_read`, since reads find EOF after that too
+    pub fn is_peer_done_sending(&self) -> bool {
+        self.peer_done_sending
+    }
+
+    /// Serialize a message to the server and write it to the TcpStream
+    pub fn send_message(&mut self, message: &impl Serialize) -> io::Result<()> {
+        self.send_message_at(message, timestamp_nanos())
+    }
+
+    /// Same as `send_message`, but with the given timestamp rather than the current time
+    /// (e.g. to echo a request's timestamp in its response, see `last_timestamp`)
+    ///
+    /// The timestamp is only sent with timestamps enabled (see `set_timestamps`)
+    pub fn send_message_at(&mut self, message: &impl Serialize, timestamp: u64) -> io::Result<()> {
+        // Serialize to a buffer first, so the message goes out in a single `write` (each field
+        // written straight to a TcpStream would be its own small segment, which Nagle's algorithm
+        // holds back waiting for an ACK). The frame length and tag also depend on the bytes
+        let endian = self.config.endian;
+        let lengths = self.config.length_encoding;
+        let timestamps = self.config.timestamps;
+        let sequence = match self.config.sequence_numbers {
+            true => Some(self.next_sequence),
+            false => None,
+        };
+        let serialize = |bytes: &mut Vec<u8>| -> io::Result<usize> {
+            let sequence_len = if let Some(sequence) = sequence {
+                match endian {
+                    Endian::Network => bytes.write_u32::<NetworkEndian>(sequence)?,
+                    Endian::Little => bytes.write_u32::<LittleEndian>(sequence)?,
+                }
+                4
+            } else {
+                0
+            };
+            let timestamp_len = if timestamps {
+                match endian {
+                    Endian::Network => bytes.write_u64::<NetworkEndian>(timestamp)?,
+                    Endian::Little => bytes.write_u64::<LittleEndian>(timestamp)?,
+                }
+                8
+            } else {
+                0
+            };
+            let start = bytes.len();
+            let length = match (endian, lengths) {
+                (Endian::Network, LengthEncoding::Fixed) => {
+                    message.serialize_with_order::<NetworkEndian>(bytes)
+                }
+                (Endian::Little, LengthEncoding::Fixed) => {
+                    message.serialize_with_order::<LittleEndian>(bytes)
+                }
+                (Endian::Network, LengthEncoding::Varint) => {
+                    message.serialize_with_order::<VarintLengths<NetworkEndian>>(bytes)
+                }
+                (Endian::Little, LengthEncoding::Varint) => {
+                    message.serialize_with_order::<VarintLengths<LittleEndian>>(bytes)
+                }
+            }?;
+            // The frame length is the reported length, so a miscount would desync the stream
+            debug_assert_eq!(
+                length,
+                bytes.len() - start,
+                "serialize reported a different number of bytes than it wrote"
+            );
+            Ok(sequence_len + timestamp_len + length)
+        };
+        let mut bytes: Vec<u8> = vec![];
+        if self.config.framed {
+            // Placeholder for the frame length, filled in once we know it
+            bytes.extend_from_slice(&[0; 4]);
+            let length = serialize(&mut bytes)? as u32;
+            match endian {
+                Endian::Network => NetworkEndian::write_u32(&mut bytes[..4], length),
+                Endian::Little => LittleEndian::write_u32(&mut bytes[..4], length),
+            }
+        } else {
+            serialize(&mut bytes)?;
+        }
+        #[cfg(feature = "hmac")]
+        if let Some(key) = &self.psk {
+            let tag = auth::sign(key, &bytes);
+            bytes.extend_from_slice(&tag);
+        }
+        self.writer.write_all(&bytes)?;
+        self.bytes_sent += bytes.len() as u64;
+        if sequence.is_some() {
+            self.next_sequence = self.next_sequence.wrapping_add(1);
+        }
+        self.unflushed += 1;
+        match self.config.flush_strategy {
+            FlushStrategy::Immediate => self.flush(),
+            FlushStrategy::Manual => Ok(()),
+            FlushStrategy::EveryN(n) if self.unflushed >= n => self.flush(),
+            FlushStrategy::EveryN(_) => Ok(()),
+        }
+    }
+
+    /// Send a request that has no response (like `Request::Log`) and flush it right away
+    ///
+    /// Requests that expect a response are rejected: their unread response would be read
+    /// as the reply to the *next* request, and every exchange after that would be out of sync.
+    /// For the same reason, don't read a response after sending with this.
+    pub fn send_only(&mut self, request: &Request) -> io::Result<()> {
+        if request.expects_response() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Request type '{}' expects a response, use send_message",
+                    request.type_name()
+                ),
+            ));
+        }
+        self.send_message(request)?;
+        self.flush()
+    }
+
+    /// Tell the peer there are no more messages coming: flush what's been sent, then shut down
+    /// the writing half of the connection (see `Stream::shutdown_write`)
+    ///
+    /// The peer's reads then end cleanly (`read_message` returns `None`) once it's read every
+    /// message, and it can still send its responses, e.g. so a client can read until the server
+    /// closes the connection. Sending anything after this fails
+    pub fn shutdown_write(&mut self) -> io::Result<()> {
+        self.flush()?;
+        self.writer.get_ref().with(S::shutdown_write)
+    }
+
+    /// Send an already serialized message (e.g. one read with `read_frame_into`, to relay it to
+    /// another connection) and flush it right away, without parsing it first
+    ///
+    /// `bytes` is just the message, as written by `Serialize::serialize`: the frame length,
+    /// sequence number, timestamp and PSK tag this connection is configured for are still added.
+    /// The bytes themselves are sent as is, so they're trusted to be exactly one well-formed
+    /// message in this connection's byte order. Anything else desyncs the peer, which will read
+    /// the next message from the wrong place (unless the connection is framed, see
+    /// `MalformedFrame`).
+    pub fn send_raw(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.send_message(&RawMessage(bytes))?;
+        self.flush()
+    }
+
+    /// Close the connection with a `Request::Goodbye` handshake: wait for the server to
+    /// acknowledge it, and then to close its end, before closing ours
+    ///
+    /// This is an application-level graceful close on top of TCP's own: once the acknowledgement
+    /// arrives, the server has read every request sent before it. Closing a socket with unread
+    /// data in it sends a reset rather than a FIN, which can throw away responses the other end
+    /// hasn't read yet, so waiting for the acknowledgement makes sure that can't happen.
+    ///
+    /// Read the Responses to any earlier requests first, since they arrive before the
+    /// acknowledgement
+    pub fn close(mut self) -> io::Result<()> {
+        self.send_message(&Request::Goodbye)?;
+        self.flush()?;
+        match self.read_message_required::<Response>()? {
+            Response::Ok(message) if message == GOODBYE_ACK => {}
+            resp => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Unexpected response to Goodbye: {:?}", resp),
+                ))
+            }
+        }
+        // The server closes its end right after acknowledging
+        if !self.at_eof()? {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Unexpected bytes after the Goodbye acknowledgement",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Ask the server for the largest message it accepts, in bytes on the wire (`None` if
+    /// there's no limit), with a `Request::MaxSize`
+    pub fn server_max_message_size(&mut self) -> io::Result<Option<usize>> {
+        self.send_message(&Request::MaxSize)?;
+        self.flush()?;
+        match self.read_message_required::<Response>()? {
+            Response::Ok(message) if message == MAX_SIZE_UNLIMITED => Ok(None),
+            Response::Ok(message) => message.parse().map(Some).map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid max message size '{}'", message),
+                )
+            }),
+            Response::Err(message) => Err(io::Error::other(message)),
+            resp => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unexpected response to MaxSize: {:?}", resp),
+            )),
+        }
+    }
+
+    /// Send any buffered messages
+    ///
+    /// Only needed with a `FlushStrategy` other than `Immediate`
+    pub fn flush(&mut self) -> io::Result<()> {
+        retry_on_interrupt(|| self.writer.flush())?;
+        self.unflushed = 0;
+        Ok(())
+    }
+
+    /// Read a message from the inner TcpStream, or `None` if the peer closed the connection
+    ///
+    /// `None` only means the connection was closed cleanly *between* messages, so keep-alive
+    /// loops can be written as `while let Some(req) = protocol.read_message::<Request>()? {}`.
+    /// Closing part way through a message is still an `io::ErrorKind::UnexpectedEof` error.
+    ///
+    /// NOTE: Will block until there's data to read, so only use when a message is expected to arrive
+    pub fn read_message<T: Deserialize>(&mut self) -> io::Result<Option<T::Output>> {
+        if self.at_eof()? {
+            return Ok(None);
+        }
+        self.read_message_required::<T>().map(Some)
+    }
+
+    /// Read a message from the inner TcpStream, treating a closed connection as an
+    /// `io::ErrorKind::UnexpectedEof` error (e.g. when waiting for the response to a request)
+    ///
+    /// NOTE: Will block until there's data to read (or deserialize fails with io::ErrorKind::Interrupted)
+    ///       so only use when a message is expected to arrive
+    pub fn read_message_required<T: Deserialize>(&mut self) -> io::Result<T::Output> {
+        let on_invalid = self.config.on_invalid_utf8;
+        self.read_with(|mut buf, endian, lengths| match (endian, lengths) {
+            (Endian::Network, LengthEncoding::Fixed) => {
+                T::deserialize_with_order::<NetworkEndian>(&mut buf, on_invalid)
+            }
+            (Endian::Little, LengthEncoding::Fixed) => {
+                T::deserialize_with_order::<LittleEndian>(&mut buf, on_invalid)
+            }
+            (Endian::Network, LengthEncoding::Varint) => {
+                T::deserialize_with_order::<VarintLengths<NetworkEndian>>(&mut buf, on_invalid)
+            }
+            (Endian::Little, LengthEncoding::Varint) => {
+                T::deserialize_with_order::<VarintLengths<LittleEndian>>(&mut buf, on_invalid)
+            }
+        })
+    }
+
+    /// Read a Response from the inner TcpStream, rejecting it if the message is over `max_size` bytes
+    pub fn read_response_with_limit(&mut self, max_size: usize) -> io::Result<Response> {
+        self.read_with(|mut buf, endian, lengths| match (endian, lengths) {
+            (Endian::Network, LengthEncoding::Fixed) => {
+                Response::deserialize_with_limit_order::<NetworkEndian>(&mut buf, max_size)
+            }
+            (Endian::Little, LengthEncoding::Fixed) => {
+                Response::deserialize_with_limit_order::<LittleEndian>(&mut buf, max_size)
+            }
+            (Endian::Network, LengthEncoding::Varint) => Response::deserialize_with_limit_order::<
+                VarintLengths<NetworkEndian>,
+            >(&mut buf, max_size),
+            (Endian::Little, LengthEncoding::Varint) => Response::deserialize_with_limit_order::<
+                VarintLengths<LittleEndian>,
+            >(&mut buf, max_size),
+        })
+    }
+
+    /// Read a message from the inner TcpStream into an existing value, reusing its allocations
+    ///
+    /// Useful when reading many messages in a loop (one `dest` can be reused for each message)
+    pub fn read_message_into<T: Deserialize>(&mut self, dest: &mut T::Output) -> io::Result<()> {
+        let on_invalid = self.config.on_invalid_utf8;
+        self.read_with(|mut buf, endian, lengths| match (endian, lengths) {
+            (Endian::Network, LengthEncoding::Fixed) => {
+                T::deserialize_into_with_order::<NetworkEndian>(&mut buf, dest, on_invalid)
+            }
+            (Endian::Little, LengthEncoding::Fixed) => {
+                T::deserialize_into_with_order::<LittleEndian>(&mut buf, dest, on_invalid)
+            }
+            (Endian::Network, LengthEncoding::Varint) => T::deserialize_into_with_order::<
+                VarintLengths<NetworkEndian>,
+            >(&mut buf, dest, on_invalid),
+            (Endian::Little, LengthEncoding::Varint) => T::deserialize_into_with_order::<
+                VarintLengths<LittleEndian>,
+            >(&mut buf, dest, on_invalid),
+        })
+    }
+
+    /// Read the next frame's message bytes into `dest` without parsing them, reusing its allocation
+    ///
+    /// Only for framed connections (see `ProtocolConfig::framed`), e.g. to parse a
+    /// `RequestRef` that borrows from `dest`
+    pub fn read_frame_into(&mut self, dest: &mut Vec<u8>) -> io::Result<()> {
+        if !self.config.framed {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "only framed messages can be read without parsing them",
+            ));
+        }
+        let endian = self.config.endian;
+        self.read_raw(|buf| read_frame_into(buf, endian, dest))?;
+        if self.config.sequence_numbers {
+            let sequence = parse_frame(dest.get(..4).unwrap_or_default(), |buf| {
+                read_sequence(buf, endian)
+            })?;
+            dest.drain(..4);
+            self.check_sequence(sequence)?;
+        }
+        if self.config.timestamps {
+            let timestamp = parse_frame(dest.get(..8).unwrap_or_default(), |buf| {
+                read_timestamp(buf, endian)
+            })?;
+            dest.drain(..8);
+            self.last_timestamp = Some(timestamp);
+        }
+        Ok(())
+    }
+
+    /// Read a chunked Response, yielding each chunk as it arrives (see `ResponseChunks`)
+    ///
+    /// The message is limited to `max_message_size` bytes in total, like any other
+    /// NOTE: Chunks are yielded before the whole message has been read, so this can't
+    ///       be used with a pre-shared key (the tag can only be checked at the end)
+    pub fn read_response_chunks(&mut self) -> io::Result<ResponseChunks<impl Read + '_>> {
+        #[cfg(feature = "hmac")]
+        if self.psk.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "chunked responses can't be authenticated",
+            ));
+        }
+        if self.config.framed {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "chunked responses can't be framed",
+            ));
+        }
+        if self.config.timestamps {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "chunked responses can't have timestamps",
+            ));
+        }
+        if self.config.sequence_numbers {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "chunked responses can't have sequence numbers",
+            ));
+        }
+        if self.config.endian != Endian::Network {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "chunked responses are always in network byte order",
+            ));
+        }
+        if self.config.length_encoding != LengthEncoding::Fixed {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "chunked responses always have u16 lengths",
+            ));
+        }
+        self.flush_before_read()?;
+        let max_size = self.config.max_message_size.unwrap_or(usize::MAX);
+        ResponseChunks::new(&mut self.reader, max_size)
+    }
+
+    /// Send any messages still buffered before a read that would block, since the message being
+    /// waited for may be the response to one of them (see `FlushStrategy`)
+    ///
+    /// If the next message has (at least partly) arrived already, the read won't wait on the
+    /// other end, so the buffered messages can wait to be sent along with the next ones
+    fn flush_before_read(&mut self) -> io::Result<()> {
+        if self.unflushed > 0 && self.reader.buffer().is_empty() {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Has the peer closed the connection? (blocks until there's data to read, or EOF)
+    fn at_eof(&mut self) -> io::Result<bool> {
+        self.flush_before_read()?;
+        let at_eof = retry_on_interrupt(|| self.reader.fill_buf().map(|buf| buf.is_empty()))?;
+        self.peer_done_sending |= at_eof;
+        Ok(at_eof)
+    }
+
+    /// Run a deserializer (given the configured `Endian` & `LengthEncoding`) over the next message
+    /// (unwrapping its frame if `framed` is set)
+    fn read_with<T>(
+        &mut self,
+        read: impl FnOnce(&mut dyn Read, Endian, LengthEncoding) -> io::Result<T>,
+    ) -> io::Result<T> {
+        let endian = self.config.endian;
+        let lengths = self.config.length_encoding;
+        let timestamps = self.config.timestamps;
+        let sequence_numbers = self.config.sequence_numbers;
+        let mut timestamp = None;
+        let mut sequence = None;
+        let read = |buf: &mut dyn Read, endian| {
+            if sequence_numbers {
+                sequence = Some(read_sequence(buf, endian)?);
+            }
+            if timestamps {
+                timestamp = Some(read_timestamp(buf, endian)?);
+            }
+            read(buf, endian, lengths)
+        };
+        let value = if self.config.framed {
+            // Read the whole frame before parsing it, so a malformed message can't desync the stream
+            let frame = self.read_raw(|buf| read_frame(buf, endian))?;
+            parse_frame(&frame, |buf| read(buf, endian))
+        } else {
+            self.read_raw(|buf| read(buf, endian))
+        };
+        // An unexpected sequence number is the likelier explanation for a message that
+        // doesn't parse, so it's reported first (and not as a recoverable `MalformedFrame`)
+        if let Some(sequence) = sequence {
+            self.check_sequence(sequence)?;
+        }
+        let value = value?;
+        self.last_timestamp = timestamp;
+        Ok(value)
+    }
+
+    /// Run a reader over the stream, verifying the message if a PSK is set
+    /// and enforcing the `max_message_size`
+    fn read_raw<T>(&mut self, read: impl FnOnce(&mut dyn Read) -> io::Result<T>) -> io::Result<T> {
+        self.flush_before_read()?;
+        let limit = self.config.max_message_size.unwrap_or(usize::MAX);
+        let mut reader = LimitedReader {
+            inner: &mut self.reader,
+            remaining: limit,
+        };
+        #[cfg(feature = "hmac")]
+        let result = match &self.psk {
+            Some(key) => auth::read_verified(&mut reader, key, read),
+            None => read(&mut reader),
+        };
+        #[cfg(not(feature = "hmac"))]
+        let result = read(&mut reader);
+        // Count what was read even if it failed, those bytes still came off the wire
+        self.bytes_received += (limit - reader.remaining) as u64;
+        result
+    }
+}
+
+/// A framed message that was received in full, but couldn't be parsed
+///
+/// Framed messages (see `ProtocolConfig::framed`) are wrapped in an outer length prefix:
+/// ```ignore
+/// |     u32      |     [u8]      |
+/// | frame length |  message      |
+/// ```
+///
+/// Without the frame, a reader that fails part way through a message has no idea where the next
+/// message starts (the lengths it would need are inside the part it couldn't parse), so the
+/// connection has to be dropped. With the frame, the whole message is read before it's parsed,
+/// so a malformed message can be skipped and the connection keeps working.
+#[derive(Debug)]
+pub struct MalformedFrame(pub io::Error);
+
+impl std::fmt::Display for MalformedFrame {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Malformed message: {}", self.0)
+    }
+}
+
+impl std::error::Error for MalformedFrame {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.0)
+    }
+}
+
+/// Error for a message whose sequence number doesn't follow the last one read (see
+/// `Protocol::set_sequence_numbers`), wrapped in an `io::ErrorKind::InvalidData` error
+///
+/// This isn't recoverable like a `MalformedFrame`: the messages are still framed correctly, but
+/// some were lost, repeated or reordered on the way, so the conversation is out of sync.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SequenceError {
+    pub expected: u32,
+    pub received: u32,
+}
+
+impl std::fmt::Display for SequenceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        // Compare as the distance forwards, so this still works once the numbers wrap
+        let ahead = self.received.wrapping_sub(self.expected);
+        if ahead < u32::MAX / 2 {
+            write!(
+                f,
+                "Expected message {}, got {} ({} missing)",
+                self.expected, self.received, ahead
+            )
+        } else {
+            write!(
+                f,
+                "Expected message {}, got {} (duplicate or reordered)",
+                self.expected, self.received
+            )
+        }
+    }
+}
+
+impl std::error::Error for SequenceError {}
+
+/// Can the next message still be read after this error? (i.e. is it a `MalformedFrame`)
+pub fn is_recoverable(err: &io::Error) -> bool {
+    err.get_ref()
+        .is_some_and(|inner| inner.is::<MalformedFrame>())
+}
+
+/// How long to wait between the attempts of an operation that's being retried
+///
+/// Each call is for the next retry, and `None` means to give up (returning the last error)
+pub trait Backoff {
+    fn next_delay(&mut self) -> Option<Duration>;
+}
+
+/// The same delay before every retry
+#[derive(Debug, Clone)]
+pub struct ConstantBackoff {
+    delay: Duration,
+    retries_left: usize,
+}
+
+impl ConstantBackoff {
+    pub fn new(delay: Duration, max_retries: usize) -> Self {
+        Self {
+            delay,
+            retries_left: max_retries,
+        }
+    }
+}
+
+impl Backoff for ConstantBackoff {
+    fn next_delay(&mut self) -> Option<Duration> {
+        self.retries_left = self.retries_left.checked_sub(1)?;
+        Some(self.delay)
+    }
+}
+
+/// Double the delay after every retry (up to `max_delay`), so a struggling server
+/// isn't hammered with retries
+#[derive(Debug, Clone)]
+pub struct ExponentialBackoff {
+    next: Duration,
+    max_delay: Duration,
+    retries_left: usize,
+}
+
+impl ExponentialBackoff {
+    pub fn new(initial: Duration, max_delay: Duration, max_retries: usize) -> Self {
+        Self {
+            next: initial.min(max_delay),
+            max_delay,
+            retries_left: max_retries,
+        }
+    }
+}
+
+impl Backoff for ExponentialBackoff {
+    fn next_delay(&mut self) -> Option<Duration> {
+        self.retries_left = self.retries_left.checked_sub(1)?;
+        let delay = self.next;
+        self.next = self.next.saturating_mul(2).min(self.max_delay);
+        Some(delay)
+    }
+}
+
+/// Randomize another backoff's delays to somewhere between half and all of each delay
+///
+/// Without jitter, clients that failed at the same moment (e.g. when a server restarts)
+/// all retry at the same moments too
+pub struct JitteredBackoff<B> {
+    inner: B,
+    rng: SplitMix64,
+}
+
+impl<B: Backoff> JitteredBackoff<B> {
+    pub fn new(inner: B) -> Self {
+        Self {
+            inner,
+            rng: SplitMix64(new_request_id()),
+        }
+    }
+}
+
+impl<B: Backoff> Backoff for JitteredBackoff<B> {
+    fn next_delay(&mut self) -> Option<Duration> {
+        let delay = self.inner.next_delay()?;
+        let half = delay / 2;
+        let jitter_nanos = self.rng.next_u64() % (delay - half).as_nanos().max(1) as u64;
+        Some(half + Duration::from_nanos(jitter_nanos))
+    }
+}
+
+/// Run an operation until it succeeds, sleeping between attempts as long as `backoff` says to
+///
+/// Once `backoff` gives up, the last error is returned
+pub fn retry_with_backoff<T>(
+    backoff: &mut impl Backoff,
+    mut op: impl FnMut() -> io::Result<T>,
+) -> io::Result<T> {
+    loop {
+        match op() {
+            Ok(value) => return Ok(value),
+            Err(e) => match backoff.next_delay() {
+                Some(delay) => std::thread::sleep(delay),
+                None => return Err(e),
+            },
+        }
+    }
+}
+
+/// Read a (u32) length-prefixed frame
+fn read_frame(buf: &mut dyn Read, endian: Endian) -> io::Result<Vec<u8>> {
+    let mut frame: Vec<u8> = vec![];
+    read_frame_into(buf, endian, &mut frame)?;
+    Ok(frame)
+}
+
+/// Read the timestamp that precedes each message (see `Protocol::set_timestamps`)
+fn read_timestamp(buf: &mut dyn Read, endian: Endian) -> io::Result<u64> {
+    match endian {
+        Endian::Network => buf.read_u64::<NetworkEndian>(),
+        Endian::Little => buf.read_u64::<LittleEndian>(),
+    }
+}
+
+/// A message that's already serialized (see `Protocol::send_raw`)
+struct RawMessage<'a>(&'a [u8]);
+
+impl Serialize for RawMessage<'_> {
+    fn serialize(&self, buf: &mut impl Write) -> io::Result<usize> {
+        buf.write_all(self.0)?;
+        Ok(self.0.len())
+    }
+
+    /// The bytes are already in the connection's byte order (see `Protocol::send_raw`)
+    fn serialize_with_order<E: WireOrder>(&self, buf: &mut impl Write) -> io::Result<usize> {
+        self.serialize(buf)
+    }
+}
+
+/// Read the sequence number that precedes each message (see `Protocol::set_sequence_numbers`)
+fn read_sequence(buf: &mut dyn Read, endian: Endian) -> io::Result<u32> {
+    match endian {
+        Endian::Network => buf.read_u32::<NetworkEndian>(),
+        Endian::Little => buf.read_u32::<LittleEndian>(),
+    }
+}
+
+/// Same as `read_frame`, but replacing the contents of `dest` (reusing its allocation)
+fn read_frame_into(buf: &mut dyn Read, endian: Endian, dest: &mut Vec<u8>) -> io::Result<()> {
+    let length = match endian {
+        Endian::Network => buf.read_u32::<NetworkEndian>()?,
+        Endian::Little => buf.read_u32::<LittleEndian>()?,
+    } as u64;
+    // Only allocate as the bytes arrive, rather than trusting the length up front
+    dest.clear();
+    buf.take(length).read_to_end(dest)?;
+    if dest.len() as u64 != length {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(())
+}
+
+/// Run a deserializer over a frame, which must contain exactly one message
+fn parse_frame<T>(
+    frame: &[u8],
+    read: impl FnOnce(&mut dyn Read) -> io::Result<T>,
+) -> io::Result<T> {
+    let malformed = |e| io::Error::new(io::ErrorKind::InvalidData, MalformedFrame(e));
+    let mut remaining = frame;
+    let value = read(&mut remaining).map_err(malformed)?;
+    if !remaining.is_empty() {
+        return Err(malformed(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "trailing bytes after message",
+        )));
+    }
+    Ok(value)
+}
+
+/// Reader adapter that fails once more than a limited number of bytes have been read
+///
+/// Unlike `Read::take` this is an error (rather than EOF), so an oversized message isn't
+/// mistaken for the peer closing the connection
+struct LimitedReader<R> {
+    inner: R,
+    remaining: usize,
+}
+
+impl<R: Read> Read for LimitedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !buf.is_empty() && self.remaining == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Message exceeds the maximum message size",
+            ));
+        }
+        let max = buf.len().min(self.remaining);
+        let len = self.inner.read(&mut buf[..max])?;
+        self.remaining -= len;
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_request_echo_roundtrip() {
+        let req = Request::Echo(String::from("Hello"));
+
+        let mut bytes: Vec<u8> = vec![];
+        req.serialize(&mut bytes).unwrap();
+
+        let mut reader = Cursor::new(bytes);
+        let roundtrip_req = Request::deserialize(&mut reader).unwrap();
+
+        assert!(matches!(roundtrip_req, Request::Echo(_)));
+        assert_eq!(roundtrip_req.message(), "Hello");
+    }
+
+    #[test]
+    fn test_request_jumble_roundtrip() {
+        let req = Request::Jumble {
+            message: String::from("Hello"),
+            amount: 42,
+        };
+
+        let mut bytes: Vec<u8> = vec![];
+        req.serialize(&mut bytes).unwrap();
+
+        let mut reader = Cursor::new(bytes);
+        let roundtrip_req = Request::deserialize(&mut reader).unwrap();
+
+        assert!(matches!(roundtrip_req, Request::Jumble { .. }));
+        assert_eq!(roundtrip_req.message(), "Hello");
+    }
+
+    #[test]
+    fn test_request_describe_wire() {
+        let req = Request::Jumble {
+            message: String::from("Hello"),
+            amount: 42,
+        };
+        assert_eq!(
+            req.describe_wire(),
+            r#"type=2(Jumble) | len=5 | "Hello" | len=2 | amount=42"#
+        );
+        // The inner request follows with its own type byte
+        let req = Request::Timed(Box::new(Request::Concat(vec![String::from("Hi")])));
+        assert_eq!(
+            req.describe_wire(),
+            r#"type=14(Timed) | type=7(Concat) | count=1 | len=2 | "Hi""#
+        );
+        assert_eq!(Request::Ping.describe_wire(), "type=9(Ping)");
+    }
+
+    #[test]
+    fn test_response_roundtrip() {
+        let resp = Response::Ok(String::from("Hello"));
+
+        let mut bytes: Vec<u8> = vec![];
+        resp.serialize(&mut bytes).unwrap();
+
+        let mut reader = Cursor::new(bytes);
+        let roundtrip_resp = Response::deserialize(&mut reader).unwrap();
+
+        assert!(matches!(roundtrip_resp, Response::Ok(_)));
+        assert_eq!(roundtrip_resp.message(), "Hello");
+    }
+
+    #[test]
+    fn test_response_err_roundtrip() {
+        let resp = Response::Err(String::from("Nope"));
+
+        let mut bytes: Vec<u8> = vec![];
+        resp.serialize(&mut bytes).unwrap();
+
+        let mut reader = Cursor::new(bytes);
+        let roundtrip_resp = Response::deserialize(&mut reader).unwrap();
+
+        assert_eq!(roundtrip_resp, resp);
+        assert!(!roundtrip_resp.is_ok());
+    }
+
+    #[test]
+    fn test_response_pong_roundtrip() {
+        let mut bytes: Vec<u8> = vec![];
+        let written = Response::Pong.serialize(&mut bytes).unwrap();
+        assert_eq!(bytes, [3, 0, 0]);
+        assert_eq!(written, bytes.len());
+
+        let roundtrip_resp = Response::deserialize(&mut Cursor::new(bytes)).unwrap();
+        assert_eq!(roundtrip_resp, Response::Pong);
+        assert!(roundtrip_resp.is_ok());
+
+        // A Pong never has a message
+        let err = Response::deserialize(&mut Cursor::new([3, 0, 1, b'!'])).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_request_jumble_invalid_amount_length() {
+        let mut bytes: Vec<u8> = vec![];
+        Request::Jumble {
+            message: String::from("Hello"),
+            amount: 42,
+        }
+        .serialize(&mut bytes)
+        .unwrap();
+        // Overwrite the amount length (after type + len + "Hello")
+        bytes[8..10].copy_from_slice(&10u16.to_be_bytes());
+
+        let err = Request::deserialize(&mut Cursor::new(bytes)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_request_delay_roundtrip() {
+        let req = Request::Delay {
+            message: String::from("Hello"),
+            ms: 100_000,
+        };
+
+        let mut bytes: Vec<u8> = vec![];
+        let written = req.serialize(&mut bytes).unwrap();
+        assert_eq!(written, bytes.len());
+        assert_eq!(written, 14); // type + len + "Hello" + len + ms
+
+        let roundtrip_req = Request::deserialize(&mut Cursor::new(bytes)).unwrap();
+        assert!(matches!(roundtrip_req, Request::Delay { ms: 100_000, .. }));
+        assert_eq!(roundtrip_req.message(), "Hello");
+    }
+
+    #[test]
+    fn test_request_repeat_roundtrip() {
+        let req = Request::Repeat {
+            message: String::from("Hello"),
+            count: 3,
+            interval_ms: 250,
+        };
+
+        let mut bytes: Vec<u8> = vec![];
+        let written = req.serialize(&mut bytes).unwrap();
+        assert_eq!(written, bytes.len());
+        assert_eq!(written, 20); // type + len + "Hello" + len + count + len + interval_ms
+
+        let roundtrip_req = Request::deserialize(&mut Cursor::new(&bytes)).unwrap();
+        assert!(matches!(
+            roundtrip_req,
+            Request::Repeat {
+                count: 3,
+                interval_ms: 250,
+                ..
+            }
+        ));
+        assert_eq!(roundtrip_req.message(), "Hello");
+        assert!(!roundtrip_req.is_cacheable());
+        assert_eq!(
+            RequestRef::deserialize_ref(&bytes).unwrap(),
+            RequestRef::Repeat {
+                message: "Hello",
+                count: 3,
+                interval_ms: 250
+            }
+        );
+
+        // The interval_ms length is wrong
+        bytes[15] = 2;
+        let err = Request::deserialize(&mut Cursor::new(&bytes)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid Repeat interval_ms length 2 (expected 4)"
+        );
+    }
+
+    #[test]
+    fn test_request_timed_roundtrip() {
+        let req = Request::Timed(Box::new(Request::Timed(Box::new(Request::Stats(
+            String::from("Hello"),
+        )))));
+
+        let mut bytes: Vec<u8> = vec![];
+        let written = req.serialize(&mut bytes).unwrap();
+        assert_eq!(written, bytes.len());
+        assert_eq!(written, 10); // type + type + type + len + "Hello"
+        assert_eq!(&bytes[..3], [14, 14, 3]);
+
+        let roundtrip_req = Request::deserialize(&mut Cursor::new(&bytes)).unwrap();
+        assert!(matches!(
+            &roundtrip_req,
+            Request::Timed(inner) if matches!(&**inner, Request::Timed(inner) if matches!(&**inner, Request::Stats(_)))
+        ));
+        assert_eq!(roundtrip_req.message(), "Hello");
+        assert_eq!(roundtrip_req.type_name(), "timed");
+        assert!(!roundtrip_req.is_cacheable());
+        assert_eq!(
+            RequestRef::deserialize_ref(&bytes).unwrap(),
+            RequestRef::Timed(Box::new(RequestRef::Timed(Box::new(RequestRef::Stats(
+                "Hello"
+            )))))
+        );
+
+        // A Timed with nothing to time
+        assert!(Request::deserialize(&mut Cursor::new(&[14])).is_err());
+        assert!(RequestRef::deserialize_ref(&[14]).is_err());
+    }
+
+    #[test]
+    fn test_request_nesting_depth() {
+        let nested = |depth: usize| {
+            let mut bytes = vec![14; depth];
+            bytes.push(8); // Noop
+            bytes
+        };
+
+        let bytes = nested(MAX_NESTING_DEPTH);
+        assert!(Request::deserialize(&mut Cursor::new(&bytes)).is_ok());
+        assert!(RequestRef::deserialize_ref(&bytes).is_ok());
+
+        let bytes = nested(MAX_NESTING_DEPTH + 1);
+        let err = Request::deserialize(&mut Cursor::new(&bytes)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "Requests are nested more than 8 deep");
+        assert!(RequestRef::deserialize_ref(&bytes).is_err());
+
+        // Far too deep to recurse all the way down
+        let bytes = nested(100_000);
+        assert!(Request::deserialize(&mut Cursor::new(&bytes)).is_err());
+        assert!(RequestRef::deserialize_ref(&bytes).is_err());
+    }
+
+    #[test]
+    fn test_traced_roundtrip() {
+        let traced = Traced {
+            id: 0x0123_4567_89ab_cdef,
+            message: Request::Echo(String::from("Hello")),
+        };
+
+        let mut bytes: Vec<u8> = vec![];
+        let written = traced.serialize(&mut bytes).unwrap();
+        assert_eq!(written, bytes.len());
+        assert_eq!(written, 8 + 8); // id + Echo("Hello")
+
+        let roundtrip = Traced::<Request>::deserialize(&mut Cursor::new(&bytes)).unwrap();
+        assert_eq!(roundtrip.id, traced.id);
+        assert_eq!(roundtrip.message.message(), "Hello");
+
+        let mut dest = Traced {
+            id: 0,
+            message: Request::Noop,
+        };
+        Traced::<Request>::deserialize_into(&mut Cursor::new(&bytes), &mut dest).unwrap();
+        assert_eq!(dest.id, traced.id);
+
+        assert_ne!(new_request_id(), new_request_id());
+    }
+
+    #[test]
+    fn test_request_reflect_roundtrip() {
+        // Not valid UTF-8, so this couldn't be sent with Echo
+        let payload = vec![b'H', b'i', 0xFF, 0x00];
+        let req = Request::Reflect(payload.clone());
+
+        let mut bytes: Vec<u8> = vec![];
+        req.serialize(&mut bytes).unwrap();
+        assert_eq!(bytes, [5, 0, 4, b'H', b'i', 0xFF, 0x00]);
+
+        let roundtrip_req = Request::deserialize(&mut Cursor::new(bytes)).unwrap();
+        match roundtrip_req {
+            Request::Reflect(received) => {
+                assert_eq!(received, payload);
+                assert_eq!(bytes_to_hex(&received), "48 69 ff 00");
+            }
+            req => panic!("Unexpected request: {:?}", req),
+        }
+        assert_eq!(bytes_to_hex(&[]), "");
+    }
+
+    #[test]
+    fn test_request_concat_roundtrip() {
+        let parts = vec![String::from("Hello"), String::new(), String::from("wörld")];
+        let req = Request::Concat(parts.clone());
+
+        let mut bytes: Vec<u8> = vec![];
+        let written = req.serialize(&mut bytes).unwrap();
+        // Type, count, then each (length, bytes)
+        assert_eq!(written, 1 + 2 + (2 + 5) + 2 + (2 + 6));
+        assert_eq!(bytes[..3], [7, 0, 3]);
+
+        let roundtrip_req = Request::deserialize(&mut Cursor::new(bytes)).unwrap();
+        match roundtrip_req {
+            Request::Concat(received) => assert_eq!(received, parts),
+            req => panic!("Unexpected request: {:?}", req),
+        }
+
+        let mut bytes: Vec<u8> = vec![];
+        Request::Concat(vec![]).serialize(&mut bytes).unwrap();
+        assert!(matches!(
+            Request::deserialize(&mut Cursor::new(bytes)).unwrap(),
+            Request::Concat(parts) if parts.is_empty()
+        ));
+    }
+
+    /// A quick, deterministic stand-in for the `deserialize_request` fuzz target (see `fuzz/`),
+    /// so CI catches parser panics without a nightly toolchain
+    #[test]
+    fn test_deserialize_arbitrary_bytes() {
+        let mut valid: Vec<Vec<u8>> = vec![];
+        for req in [
+            Request::Jumble {
+                message: String::from("Hello"),
+                amount: 42,
+            },
+            Request::Delay {
+                message: String::from("Hello"),
+                ms: 100,
+            },
+            Request::Concat(vec![String::from("a"), String::from("b")]),
+            Request::KeyValues(vec![(String::from("k"), String::from("v"))]),
+            Request::Split {
+                message: String::from("a,b"),
+                delimiter: String::from(","),
+            },
+            Request::Repeat {
+                message: String::from("Hello"),
+                count: 3,
+                interval_ms: 100,
+            },
+            Request::Timed(Box::new(Request::Stats(String::from("Hello")))),
+            Request::Ping,
+            Request::Goodbye,
+            Request::Xor {
+                message: String::from("Hello"),
+                key: String::from("k"),
+            },
+            Request::MaxSize,
+            Request::Histogram(String::from("Hello")),
+        ] {
+            let mut bytes = vec![];
+            req.serialize(&mut bytes).unwrap();
+            valid.push(bytes);
+        }
+
+        let mut rng = SplitMix64(0);
+        for i in 0..20_000 {
+            // Mostly corrupted valid messages (to get past the type byte), and some pure noise
+            let mut data = if i % 4 == 0 {
+                (0..rng.next_below(32))
+                    .map(|_| rng.next_u64() as u8)
+                    .collect()
+            } else {
+                valid[rng.next_below(valid.len())].clone()
+            };
+            for _ in 0..rng.next_below(3) {
+                if !data.is_empty() {
+                    let at = rng.next_below(data.len());
+                    data[at] = rng.next_u64() as u8;
+                }
+            }
+            data.truncate(rng.next_below(data.len() + 1));
+
+            let _ = Request::deserialize(&mut Cursor::new(&data));
+            let _ = RequestRef::deserialize_ref(&data);
+            let _ = Response::deserialize(&mut Cursor::new(&data));
+        }
+    }
+
+    #[test]
+    fn test_request_key_values_roundtrip() {
+        let pairs = vec![
+            (String::from("host"), String::from("example.com")),
+            (String::from("port"), String::from("4000")),
+            (String::from("debug"), String::new()),
+            (String::new(), String::from("no key")),
+        ];
+        let req = Request::KeyValues(pairs.clone());
+
+        let mut bytes: Vec<u8> = vec![];
+        let written = req.serialize(&mut bytes).unwrap();
+        // Type, count, then each key's & value's (length, bytes)
+        assert_eq!(written, 1 + 2 + (4 * 2 * 2) + 4 + 11 + 4 + 4 + 5 + 6);
+        assert_eq!(written, bytes.len());
+        assert_eq!(bytes[..3], [11, 0, 4]);
+
+        let roundtrip_req = Request::deserialize(&mut Cursor::new(&bytes)).unwrap();
+        match roundtrip_req {
+            Request::KeyValues(received) => assert_eq!(received, pairs),
+            req => panic!("Unexpected request: {:?}", req),
+        }
+
+        match RequestRef::deserialize_ref(&bytes).unwrap() {
+            RequestRef::KeyValues(pairs_ref) => {
+                assert_eq!(pairs_ref.len(), 4);
+                assert!(pairs_ref
+                    .iter()
+                    .eq(pairs.iter().map(|(k, v)| (k.as_str(), v.as_str()))));
+            }
+            req_ref => panic!("Unexpected request: {:?}", req_ref),
+        }
+
+        // A value is missing
+        let truncated = [11, 0, 1, 0, 1, b'k'];
+        assert!(Request::deserialize(&mut Cursor::new(&truncated)).is_err());
+        assert!(RequestRef::deserialize_ref(&truncated).is_err());
+    }
+
+    #[test]
+    fn test_request_split_roundtrip() {
+        let req = Request::Split {
+            message: String::from("a, b, c"),
+            delimiter: String::from(", "),
+        };
+
+        let mut bytes: Vec<u8> = vec![];
+        let written = req.serialize(&mut bytes).unwrap();
+        assert_eq!(bytes, b"\x0c\x00\x07a, b, c\x00\x02, ");
+        assert_eq!(written, bytes.len());
+
+        let roundtrip_req = Request::deserialize(&mut Cursor::new(&bytes)).unwrap();
+        assert_eq!(roundtrip_req.message(), "a, b, c");
+        assert!(matches!(
+            roundtrip_req,
+            Request::Split { delimiter, .. } if delimiter == ", "
+        ));
+        assert!(matches!(
+            RequestRef::deserialize_ref(&bytes).unwrap(),
+            RequestRef::Split {
+                message: "a, b, c",
+                delimiter: ", "
+            }
+        ));
+
+        // The delimiter is missing
+        let truncated = [12, 0, 1, b'a'];
+        assert!(Request::deserialize(&mut Cursor::new(&truncated)).is_err());
+        assert!(RequestRef::deserialize_ref(&truncated).is_err());
+    }
+
+    #[test]
+    fn test_request_xor_roundtrip() {
+        let req = Request::Xor {
+            message: String::from("Hi"),
+            key: String::from("k"),
+        };
+        let mut bytes: Vec<u8> = vec![];
+        let written = req.serialize(&mut bytes).unwrap();
+        assert_eq!(bytes, b"\x10\x00\x02Hi\x00\x01k");
+        assert_eq!(written, bytes.len());
+        assert!(matches!(
+            Request::deserialize(&mut Cursor::new(&bytes)).unwrap(),
+            Request::Xor { message, key } if message == "Hi" && key == "k"
+        ));
+        assert!(matches!(
+            RequestRef::deserialize_ref(&bytes).unwrap(),
+            RequestRef::Xor {
+                message: "Hi",
+                key: "k"
+            }
+        ));
+
+        // Encrypt, then decrypt back to the original (what the server does for each)
+        let message = "Hello, wörld!";
+        let encrypted = base64_encode(&xor_bytes(message.as_bytes(), b"secret"));
+        assert_ne!(encrypted.as_bytes(), message.as_bytes());
+        let req = Request::Unxor {
+            message: encrypted,
+            key: String::from("secret"),
+        };
+        let mut bytes: Vec<u8> = vec![];
+        req.serialize(&mut bytes).unwrap();
+        assert_eq!(bytes[0], 17);
+        let (encrypted, key) = match Request::deserialize(&mut Cursor::new(&bytes)).unwrap() {
+            Request::Unxor { message, key } => (message, key),
+            req => panic!("Unexpected request: {:?}", req),
+        };
+        let decrypted = xor_bytes(&base64_decode(&encrypted).unwrap(), key.as_bytes());
+        assert_eq!(decrypted, message.as_bytes());
+    }
+
+    #[test]
+    fn test_varint() {
+        for (value, encoded) in [
+            (0, &[0x00][..]),
+            (5, &[0x05]),
+            (127, &[0x7f]),
+            (128, &[0x80, 0x01]),
+            (300, &[0xac, 0x02]),
+            (16_383, &[0xff, 0x7f]),
+            (16_384, &[0x80, 0x80, 0x01]),
+            (
+                u64::MAX,
+                &[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01],
+            ),
+        ] {
+            let mut bytes = vec![];
+            assert_eq!(write_varint(&mut bytes, value).unwrap(), encoded.len());
+            assert_eq!(bytes, encoded, "{}", value);
+            assert_eq!(varint_len(value), encoded.len());
+            assert_eq!(read_varint(&mut Cursor::new(&bytes)).unwrap(), value);
+        }
+
+        // Too long for a u64, or cut off before the last byte
+        let err = read_varint(&mut Cursor::new([0xff; 11])).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let err = read_varint(&mut Cursor::new([
+            0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x02,
+        ]))
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let err = read_varint(&mut Cursor::new([0x80])).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_varint_length_encoding() {
+        type Varint = VarintLengths<NetworkEndian>;
+        let echo = Request::Echo(String::from("Hello"));
+        let mut fixed = vec![];
+        echo.serialize(&mut fixed).unwrap();
+        assert_eq!(fixed, b"\x01\x00\x05Hello");
+        let mut varint = vec![];
+        echo.serialize_with_order::<Varint>(&mut varint).unwrap();
+        assert_eq!(varint, b"\x01\x05Hello");
+        assert_eq!(
+            echo.serialized_size_with(LengthEncoding::Varint),
+            varint.len()
+        );
+
+        // The prefix grows with the string at the 127/128 & 16,383/16,384 byte boundaries
+        for (len, prefix_len) in [(127, 1), (128, 2), (16_383, 2), (16_384, 3)] {
+            let resp = Response::Ok("a".repeat(len));
+            let mut bytes = vec![];
+            let written = resp.serialize_with_order::<Varint>(&mut bytes).unwrap();
+            assert_eq!(written, 1 + prefix_len + len);
+            assert_eq!(LengthEncoding::Varint.prefix_len(len), prefix_len);
+            let read = Response::deserialize_with_order::<Varint>(
+                &mut Cursor::new(&bytes),
+                OnInvalidUtf8::Error,
+            );
+            assert_eq!(read.unwrap(), resp);
+        }
+
+        // The fixed size fields are prefixed with their (varint) length too
+        let jumble = Request::Jumble {
+            message: String::from("Hi"),
+            amount: 300,
+        };
+        let mut bytes = vec![];
+        jumble.serialize_with_order::<Varint>(&mut bytes).unwrap();
+        assert_eq!(bytes, [2, 2, b'H', b'i', 2, 0x01, 0x2c]);
+        let read = Request::deserialize_with_order::<Varint>(
+            &mut Cursor::new(&bytes),
+            OnInvalidUtf8::Error,
+        );
+        match read.unwrap() {
+            Request::Jumble { message, amount } => {
+                assert_eq!(message, "Hi");
+                assert_eq!(amount, 300);
+            }
+            req => panic!("Unexpected request: {:?}", req),
+        }
+
+        // Either way a field can't go past a u16, so a peer can't claim a huge length
+        let err = LengthEncoding::Varint
+            .write_length::<NetworkEndian>(&mut vec![], 70_000)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let mut bytes = vec![1];
+        write_varint(&mut bytes, 70_000).unwrap();
+        let err = Request::deserialize_with_order::<Varint>(
+            &mut Cursor::new(&bytes),
+            OnInvalidUtf8::Error,
+        )
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // "Hello" with an overlong (2 byte) length is rejected, rather than read as a message
+        // that re-serializes shorter
+        let overlong = [1, 0x85, 0x00, b'H', b'e', b'l', b'l', b'o'];
+        let err = Request::deserialize_with_order::<Varint>(
+            &mut Cursor::new(&overlong),
+            OnInvalidUtf8::Error,
+        )
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let err = read_varint(&mut Cursor::new([0x80, 0x00])).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_protocol_length_encoding() {
+        let (client_stream, server_stream) = memory::MemoryStream::pair();
+        let mut client = Protocol::with_stream(client_stream).unwrap();
+        let mut server = Protocol::with_stream(server_stream.clone()).unwrap();
+        for protocol in [&mut client, &mut server] {
+            protocol.set_length_encoding(LengthEncoding::Varint);
+        }
+
+        client
+            .send_message(&Request::Echo(String::from("Hi")))
+            .unwrap();
+        // Type byte, 1 byte of length, then the message
+        assert_eq!(server_stream.pending(), 1 + 1 + 2);
+        assert_eq!(
+            server.read_message_required::<Request>().unwrap().message(),
+            "Hi"
+        );
+
+        server
+            .send_message(&Response::Ok(String::from("Hello")))
+            .unwrap();
+        assert_eq!(
+            client.read_response_with_limit(16).unwrap(),
+            Response::Ok(String::from("Hello"))
+        );
+    }
+
+    #[test]
+    fn test_base64() {
+        for (bytes, encoded) in [
+            (&b""[..], ""),
+            (b"f", "Zg=="),
+            (b"fo", "Zm8="),
+            (b"foo", "Zm9v"),
+            (b"foobar", "Zm9vYmFy"),
+            (&[0xff, 0x00, 0xfe], "/wD+"),
+        ] {
+            assert_eq!(base64_encode(bytes), encoded);
+            assert_eq!(base64_decode(encoded).unwrap(), bytes);
+        }
+        // Wrong length, not in the alphabet, too much or misplaced padding
+        for invalid in ["Zg=", "Zm9v!A==", "Z===", "Zg==Zm9v"] {
+            assert_eq!(base64_decode(invalid), None);
+        }
+    }
+
+    #[test]
+    fn test_response_tokens_roundtrip() {
+        let resp = Response::Tokens(vec![
+            String::from("a"),
+            String::new(),
+            String::from("wörld"),
+        ]);
+        let mut bytes: Vec<u8> = vec![];
+        let written = resp.serialize(&mut bytes).unwrap();
+        // Status, count, then each token's (length, bytes)
+        assert_eq!(bytes[..3], [4, 0, 3]);
+        assert_eq!(written, 3 + (3 * 2) + 1 + 6);
+        assert_eq!(written, bytes.len());
+
+        let roundtrip_resp = Response::deserialize(&mut Cursor::new(&bytes)).unwrap();
+        assert_eq!(roundtrip_resp, resp);
+        assert!(roundtrip_resp.is_ok());
+        assert_eq!(roundtrip_resp.message(), "");
+
+        // The limit applies to all of the tokens together (7 bytes), not to each one
+        assert!(Response::deserialize_with_limit(&mut Cursor::new(&bytes), 7).is_ok());
+        let err = Response::deserialize_with_limit(&mut Cursor::new(&bytes), 6).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let mut bytes: Vec<u8> = vec![];
+        Response::Tokens(vec![]).serialize(&mut bytes).unwrap();
+        assert_eq!(bytes, [4, 0, 0]);
+        assert_eq!(
+            Response::deserialize(&mut Cursor::new(&bytes)).unwrap(),
+            Response::Tokens(vec![])
+        );
+
+        // A token is missing
+        assert!(Response::deserialize(&mut Cursor::new([4, 0, 2, 0, 1, b'a'])).is_err());
+
+        // One token too many for the u16 count, rather than a count that wraps to 0
+        let err = Response::Tokens(vec![String::new(); u16::MAX as usize + 1])
+            .serialize(&mut vec![])
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let err = Request::Concat(vec![String::new(); u16::MAX as usize + 1])
+            .serialize(&mut vec![])
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_response_segments_roundtrip() {
+        let resp = ResponseBuilder::new()
+            .segment("status", "200")
+            .segment("headers", "content-type: text/plain")
+            .segment("body", "old")
+            // Replaces the value, keeping its place
+            .segment("body", "Hello")
+            .build();
+        let mut bytes: Vec<u8> = vec![];
+        let written = resp.serialize(&mut bytes).unwrap();
+        // Status, count, then each segment's name and value (length, bytes)
+        assert_eq!(bytes[..8], [5, 0, 3, 0, 6, b's', b't', b'a']);
+        assert_eq!(written, 3 + (6 * 2) + 6 + 3 + 7 + 24 + 4 + 5);
+        assert_eq!(written, bytes.len());
+
+        let roundtrip_resp = Response::deserialize(&mut Cursor::new(&bytes)).unwrap();
+        assert_eq!(roundtrip_resp, resp);
+        assert!(roundtrip_resp.is_ok());
+        assert_eq!(roundtrip_resp.message(), "");
+        assert_eq!(roundtrip_resp.segment("status"), Some("200"));
+        assert_eq!(roundtrip_resp.segment("body"), Some("Hello"));
+        assert_eq!(roundtrip_resp.segment("trailers"), None);
+        match roundtrip_resp {
+            Response::Segments(segments) => {
+                let names: Vec<&str> = segments.iter().map(|(name, _)| name.as_str()).collect();
+                assert_eq!(names, ["status", "headers", "body"]);
+            }
+            resp => panic!("Unexpected response: {:?}", resp),
+        }
+
+        // The limit applies to all of the names & values together (49 bytes)
+        assert!(Response::deserialize_with_limit(&mut Cursor::new(&bytes), 49).is_ok());
+        let err = Response::deserialize_with_limit(&mut Cursor::new(&bytes), 48).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // A name is repeated
+        let repeated = [5, 0, 2, 0, 1, b'a', 0, 0, 0, 1, b'a', 0, 0];
+        let err = Response::deserialize(&mut Cursor::new(repeated)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        // A value is missing
+        assert!(Response::deserialize(&mut Cursor::new([5, 0, 1, 0, 1, b'a'])).is_err());
+    }
+
+    #[test]
+    fn test_request_ref() {
+        let requests = [
+            Request::Jumble {
+                message: String::from("Hello"),
+                amount: 80,
+            },
+            Request::Concat(vec![
+                String::from("a"),
+                String::new(),
+                String::from("wörld"),
+            ]),
+            Request::Noop,
+            Request::Ping,
+            Request::Goodbye,
+            Request::MaxSize,
+        ];
+        for req in &requests {
+            let mut bytes: Vec<u8> = vec![];
+            req.serialize(&mut bytes).unwrap();
+            let req_ref = RequestRef::deserialize_ref(&bytes).unwrap();
+            assert_eq!(req_ref.message(), req.message());
+            match (req, req_ref) {
+                (Request::Jumble { amount, .. }, RequestRef::Jumble { amount: a, .. }) => {
+                    assert_eq!(*amount, a)
+                }
+                (Request::Concat(parts), RequestRef::Concat(parts_ref)) => {
+                    assert_eq!(parts_ref.len(), 3);
+                    assert!(parts_ref.iter().eq(parts.iter().map(String::as_str)));
+                }
+                (Request::Noop, RequestRef::Noop)
+                | (Request::Ping, RequestRef::Ping)
+                | (Request::Goodbye, RequestRef::Goodbye)
+                | (Request::MaxSize, RequestRef::MaxSize) => {}
+                (req, req_ref) => panic!("Mismatched {:?} and {:?}", req, req_ref),
+            }
+        }
+
+        // Truncated, invalid UTF-8 & trailing bytes
+        assert!(RequestRef::deserialize_ref(&[1, 0, 5, b'H']).is_err());
+        assert!(RequestRef::deserialize_ref(&[1, 0, 1, 0xFF]).is_err());
+        assert!(RequestRef::deserialize_ref(&[8, 0]).is_err());
+    }
+
+    #[test]
+    fn test_protocol_read_frame_into() {
+        let (client_stream, server_stream) = memory::MemoryStream::pair();
+        let mut client = Protocol::with_stream(client_stream).unwrap();
+        let mut server = Protocol::with_stream(server_stream).unwrap();
+
+        let mut frame: Vec<u8> = vec![];
+        let err = server.read_frame_into(&mut frame).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+
+        client.set_framed(true);
+        server.set_framed(true);
+        for message in ["Hello", "Hi"] {
+            client
+                .send_message(&Request::Echo(message.to_string()))
+                .unwrap();
+            server.read_frame_into(&mut frame).unwrap();
+            assert_eq!(
+                RequestRef::deserialize_ref(&frame).unwrap(),
+                RequestRef::Echo(message)
+            );
+        }
+    }
+
+    #[test]
+    fn test_request_stats_roundtrip() {
+        let req = Request::Stats(String::from("Hello"));
+
+        let mut bytes: Vec<u8> = vec![];
+        req.serialize(&mut bytes).unwrap();
+        assert_eq!(bytes[0], 3);
+
+        let roundtrip_req = Request::deserialize(&mut Cursor::new(bytes)).unwrap();
+        assert!(matches!(roundtrip_req, Request::Stats(_)));
+        assert_eq!(roundtrip_req.message(), "Hello");
+    }
+
+    #[test]
+    fn test_request_checksum_roundtrip() {
+        let req = Request::Checksum(String::from("hello"));
+
+        let mut bytes: Vec<u8> = vec![];
+        req.serialize(&mut bytes).unwrap();
+        assert_eq!(bytes, [10, 0, 5, b'h', b'e', b'l', b'l', b'o']);
+
+        let roundtrip_req = Request::deserialize(&mut Cursor::new(&bytes)).unwrap();
+        assert!(matches!(roundtrip_req, Request::Checksum(_)));
+        assert_eq!(roundtrip_req.message(), "hello");
+        assert_eq!(
+            RequestRef::deserialize_ref(&bytes).unwrap(),
+            RequestRef::Checksum("hello")
+        );
+    }
+
+    #[test]
+    fn test_request_histogram_roundtrip() {
+        let req = Request::Histogram(String::from("hello"));
+        let mut bytes: Vec<u8> = vec![];
+        req.serialize(&mut bytes).unwrap();
+        assert_eq!(bytes, [19, 0, 5, b'h', b'e', b'l', b'l', b'o']);
+        let roundtrip_req = Request::deserialize(&mut Cursor::new(&bytes)).unwrap();
+        assert!(matches!(roundtrip_req, Request::Histogram(_)));
+        assert_eq!(roundtrip_req.message(), "hello");
+        assert_eq!(
+            RequestRef::deserialize_ref(&bytes).unwrap(),
+            RequestRef::Histogram("hello")
+        );
+
+        assert_eq!(
+            char_histogram("hello"),
+            [('e', 1), ('h', 1), ('l', 2), ('o', 1)]
+        );
+        assert_eq!(
+            char_histogram("wörld wö"),
+            [(' ', 1), ('d', 1), ('l', 1), ('r', 1), ('w', 2), ('ö', 2)]
+        );
+        assert!(char_histogram("").is_empty());
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b"hello"), 0x3610_a686);
+        // The standard check value
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn test_text_stats() {
+        assert_eq!(text_stats("Hello wörld"), "chars=11 words=2 lines=1");
+        assert_eq!(text_stats("one\ntwo  three\n"), "chars=15 words=3 lines=2");
+        assert_eq!(text_stats(""), "chars=0 words=0 lines=0");
+    }
+
+    #[test]
+    fn test_request_noop_roundtrip() {
+        let mut bytes: Vec<u8> = vec![];
+        let written = Request::Noop.serialize(&mut bytes).unwrap();
+        assert_eq!(written, 1);
+        assert_eq!(bytes, [8]);
+
+        let roundtrip_req = Request::deserialize(&mut Cursor::new(bytes)).unwrap();
+        assert!(matches!(roundtrip_req, Request::Noop));
+        assert_eq!(roundtrip_req.message(), "");
+    }
+
+    #[test]
+    fn test_request_max_size_roundtrip() {
+        let mut bytes: Vec<u8> = vec![];
+        let written = Request::MaxSize.serialize(&mut bytes).unwrap();
+        assert_eq!(written, 1);
+        assert_eq!(bytes, [18]);
+
+        let roundtrip_req = Request::deserialize(&mut Cursor::new(&bytes)).unwrap();
+        assert!(matches!(roundtrip_req, Request::MaxSize));
+        assert_eq!(roundtrip_req.type_name(), "max-size");
+        assert!(matches!(parse_command("/max-size"), Ok(Request::MaxSize)));
+    }
+
+    #[test]
+    fn test_serialized_size() {
+        let requests = [
+            Request::Echo(String::from("Hello, wörld")),
+            Request::Jumble {
+                message: String::from("Hello"),
+                amount: 80,
+            },
+            Request::Delay {
+                message: String::new(),
+                ms: 10,
+            },
+            Request::Reflect(vec![0xff; 3]),
+            Request::Concat(vec![String::from("a"), String::new()]),
+            Request::KeyValues(vec![(String::from("k"), String::from("v"))]),
+            Request::Split {
+                message: String::from("a,b"),
+                delimiter: String::from(","),
+            },
+            Request::Repeat {
+                message: String::from("Hi"),
+                count: 2,
+                interval_ms: 0,
+            },
+            Request::Timed(Box::new(Request::Timed(Box::new(Request::Noop)))),
+            Request::Xor {
+                message: String::from("Hi"),
+                key: String::from("k"),
+            },
+            Request::Goodbye,
+        ];
+        for req in &requests {
+            let mut bytes: Vec<u8> = vec![];
+            req.serialize(&mut bytes).unwrap();
+            assert_eq!(req.serialized_size(), bytes.len(), "{:?}", req);
+        }
+        assert_eq!(Request::Echo(String::from("Hello")).serialized_size(), 8);
+        assert_eq!(
+            Request::Jumble {
+                message: String::from("Hello"),
+                amount: 80
+            }
+            .serialized_size(),
+            12
+        );
+
+        let responses = [
+            Response::Ok(String::from("'Hello' from the other side!")),
+            Response::Err(String::new()),
+            Response::Pong,
+            Response::Tokens(vec![String::from("a"), String::from("wörld")]),
+            ResponseBuilder::new().segment("status", "200").build(),
+        ];
+        for resp in &responses {
+            let mut bytes: Vec<u8> = vec![];
+            resp.serialize(&mut bytes).unwrap();
+            assert_eq!(resp.serialized_size(), bytes.len(), "{:?}", resp);
+        }
+        assert_eq!(Response::Pong.serialized_size(), 3);
+    }
+
+    #[test]
+    fn test_on_invalid_utf8() {
+        let bytes = vec![b'H', b'i', 0xFF, b'!'];
+        let err = OnInvalidUtf8::Error.decode(bytes.clone()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            OnInvalidUtf8::Lossy.decode(bytes.clone()).unwrap(),
+            "Hi\u{FFFD}!"
+        );
+        assert_eq!(OnInvalidUtf8::Replace('?').decode(bytes).unwrap(), "Hi?!");
+        // Valid UTF-8 is untouched by every policy
+        assert_eq!(
+            OnInvalidUtf8::Replace('?').decode("wörld".into()).unwrap(),
+            "wörld"
+        );
+    }
+
+    #[test]
+    fn test_protocol_on_invalid_utf8() {
+        let (mut client_stream, server_stream) = memory::MemoryStream::pair();
+        let mut server = Protocol::with_stream(server_stream).unwrap();
+        // Echo request with the 3 byte message "Hi\xFF"
+        let request = [1, 0, 3, b'H', b'i', 0xFF];
+
+        client_stream.write_all(&request).unwrap();
+        let err = server.read_message_required::<Request>().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        server.set_on_invalid_utf8(OnInvalidUtf8::Lossy);
+        client_stream.write_all(&request).unwrap();
+        let req = server.read_message_required::<Request>().unwrap();
+        assert_eq!(req.message(), "Hi\u{FFFD}");
+
+        server.set_on_invalid_utf8(OnInvalidUtf8::Replace('?'));
+        let mut req = Request::Noop;
+        client_stream.write_all(&request).unwrap();
+        server.read_message_into::<Request>(&mut req).unwrap();
+        assert_eq!(req.message(), "Hi?");
+    }
+
+    #[test]
+    fn test_protocol_buffered_len() {
+        let (mut client_stream, server_stream) = memory::MemoryStream::pair();
+        let mut server = Protocol::with_stream(server_stream).unwrap();
+        assert_eq!(server.buffered_len(), 0);
+
+        // Two Echo requests ("Hi" and "Hey") in one write
+        client_stream
+            .write_all(&[1, 0, 2, b'H', b'i', 1, 0, 3, b'H', b'e', b'y'])
+            .unwrap();
+        // Reading ahead buffers everything available, then consuming part leaves the rest
+        assert_eq!(server.reader.fill_buf().unwrap().len(), 11);
+        assert_eq!(server.buffered_len(), 11);
+        server.reader.consume(1);
+        assert_eq!(server.buffered_len(), 10);
+
+        // Reading a message consumes only its own bytes
+        let (mut client_stream, server_stream) = memory::MemoryStream::pair();
+        let mut server = Protocol::with_stream(server_stream).unwrap();
+        client_stream
+            .write_all(&[1, 0, 2, b'H', b'i', 1, 0, 3, b'H', b'e', b'y'])
+            .unwrap();
+        let req = server.read_message_required::<Request>().unwrap();
+        assert_eq!(req.message(), "Hi");
+        assert_eq!(server.buffered_len(), 6);
+        server.read_message_required::<Request>().unwrap();
+        assert_eq!(server.buffered_len(), 0);
+    }
+
+    #[test]
+    fn test_protocol_framed_recovers_from_malformed_message() {
+        let (client_stream, server_stream) = memory::MemoryStream::pair();
+        let mut client = Protocol::with_stream(client_stream.clone()).unwrap();
+        let mut server = Protocol::with_stream(server_stream).unwrap();
+        client.set_framed(true);
+        server.set_framed(true);
+
+        client
+            .send_message(&Request::Echo(String::from("Hello")))
+            .unwrap();
+        // A frame holding an unknown request type (99) with a message after it
+        let mut raw = client_stream;
+        raw.write_all(&[0, 0, 0, 6, 99, 0, 3, b'b', b'a', b'd'])
+            .unwrap();
+        client
+            .send_message(&Request::Echo(String::from("World")))
+            .unwrap();
+
+        assert_eq!(
+            server.read_message_required::<Request>().unwrap().message(),
+            "Hello"
+        );
+        let err = server.read_message_required::<Request>().unwrap_err();
+        assert!(is_recoverable(&err));
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "Malformed message: Invalid Request Type");
+        // The bad frame was skipped, so we're still in sync for the next message
+        assert_eq!(
+            server.read_message_required::<Request>().unwrap().message(),
+            "World"
+        );
+
+        // Running out of bytes mid-frame isn't recoverable
+        raw.write_all(&[0, 0, 0, 6, 1]).unwrap();
+        let err = server.read_message_required::<Request>().unwrap_err();
+        assert!(!is_recoverable(&err));
+    }
+
+    #[test]
+    fn test_backoff_delays() {
+        let ms = Duration::from_millis;
+        let delays = |backoff: &mut dyn Backoff| {
+            std::iter::from_fn(|| backoff.next_delay()).collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            delays(&mut ConstantBackoff::new(ms(50), 3)),
+            [ms(50), ms(50), ms(50)]
+        );
+        assert_eq!(
+            delays(&mut ExponentialBackoff::new(ms(10), ms(50), 5)),
+            [ms(10), ms(20), ms(40), ms(50), ms(50)]
+        );
+        assert!(delays(&mut ConstantBackoff::new(ms(50), 0)).is_empty());
+
+        let jittered = delays(&mut JitteredBackoff::new(ExponentialBackoff::new(
+            ms(10),
+            ms(1000),
+            8,
+        )));
+        assert_eq!(jittered.len(), 8);
+        for (delay, max) in jittered.iter().zip([10, 20, 40, 80, 160, 320, 640, 1000]) {
+            assert!(*delay >= ms(max) / 2 && *delay <= ms(max), "{:?}", delay);
+        }
+
+        // Gives up with the last error
+        let mut attempts = 0;
+        let err = retry_with_backoff(&mut ConstantBackoff::new(Duration::ZERO, 2), || {
+            attempts += 1;
+            Err::<(), _>(io::Error::other(format!("attempt {}", attempts)))
+        })
+        .unwrap_err();
+        assert_eq!(err.to_string(), "attempt 3");
+    }
+
+    #[test]
+    fn test_deserialize_retries_interrupted_reads() {
+        /// Reader that fails with `Interrupted` on every other read (like syscalls hit by signals)
+        struct Interrupting<R> {
+            inner: R,
+            interrupt: bool,
+        }
+
+        impl<R: Read> Read for Interrupting<R> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                self.interrupt = !self.interrupt;
+                if self.interrupt {
+                    return Err(io::ErrorKind::Interrupted.into());
+                }
+                self.inner.read(buf)
+            }
+        }
+
+        let mut bytes: Vec<u8> = vec![];
+        Request::Echo(String::from("Hello"))
+            .serialize(&mut bytes)
+            .unwrap();
+        let mut reader = Interrupting {
+            inner: Cursor::new(bytes),
+            interrupt: false,
+        };
+        let req = Request::deserialize(&mut reader).unwrap();
+        assert_eq!(req.message(), "Hello");
+    }
+
+    #[test]
+    fn test_request_deserialize_exact() {
+        let mut bytes: Vec<u8> = vec![];
+        Request::Echo(String::from("Hello"))
+            .serialize(&mut bytes)
+            .unwrap();
+
+        let req = Request::deserialize_exact(&mut Cursor::new(&bytes)).unwrap();
+        assert_eq!(req.message(), "Hello");
+
+        bytes.extend_from_slice(b"garbage");
+        let err = Request::deserialize_exact(&mut Cursor::new(&bytes)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "trailing bytes after message");
+    }
+
+    #[test]
+    fn test_request_serialize_into_slice() {
+        let req = Request::Echo(String::from("Hello"));
+
+        let mut too_small = [0u8; 4];
+        let err = req.serialize_into_slice(&mut too_small).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WriteZero);
+
+        let mut buf = [0u8; 32];
+        let written = req.serialize_into_slice(&mut buf).unwrap();
+        assert_eq!(written, 8); // type + len + "Hello"
+
+        let roundtrip_req = Request::deserialize(&mut &buf[..written]).unwrap();
+        assert_eq!(roundtrip_req.message(), "Hello");
+    }
+
+    /// Writer that accepts `remaining` bytes and then fails, like a socket closed mid-write
+    struct FailAfter {
+        remaining: usize,
+    }
+
+    impl Write for FailAfter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.remaining == 0 {
+                return Err(io::Error::new(io::ErrorKind::BrokenPipe, "Broken pipe"));
+            }
+            let len = buf.len().min(self.remaining);
+            self.remaining -= len;
+            Ok(len)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_serialize_error_context() {
+        let serialize_err = |message: &dyn Fn(&mut FailAfter) -> io::Result<usize>, after| {
+            let err = message(&mut FailAfter { remaining: after }).unwrap_err();
+            // Still the original kind, so e.g. `is_client_disconnect` works on it
+            assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+            err.to_string()
+        };
+        let req = Request::Jumble {
+            message: String::from("Hello"),
+            amount: 42,
+        };
+        let req = |buf: &mut FailAfter| req.serialize(buf);
+        assert_eq!(
+            serialize_err(&req, 0),
+            "Broken pipe (while writing type byte)"
+        );
+        // Part way through the length
+        assert_eq!(
+            serialize_err(&req, 2),
+            "Broken pipe (while writing message length)"
+        );
+        assert_eq!(
+            serialize_err(&req, 5),
+            "Broken pipe (while writing message)"
+        );
+        assert_eq!(
+            serialize_err(&req, 8),
+            "Broken pipe (while writing amount length)"
+        );
+        assert_eq!(
+            serialize_err(&req, 11),
+            "Broken pipe (while writing amount)"
+        );
+        assert!(req(&mut FailAfter { remaining: 12 }).is_ok());
+
+        let resp = Response::Tokens(vec![String::from("a"), String::from("b")]);
+        let resp = |buf: &mut FailAfter| resp.serialize(buf);
+        assert_eq!(
+            serialize_err(&resp, 0),
+            "Broken pipe (while writing status byte)"
+        );
+        assert_eq!(
+            serialize_err(&resp, 1),
+            "Broken pipe (while writing token count)"
+        );
+        assert_eq!(serialize_err(&resp, 8), "Broken pipe (while writing token)");
+    }
+
+    #[test]
+    fn test_request_deserialize_into_reuses_allocation() {
+        let mut bytes: Vec<u8> = vec![];
+        Request::Echo(String::from("Hi"))
+            .serialize(&mut bytes)
+            .unwrap();
+        Request::Jumble {
+            message: String::from("Hello"),
+            amount: 42,
+        }
+        .serialize(&mut bytes)
+        .unwrap();
+
+        let mut reader = Cursor::new(bytes);
+        let mut req = Request::Echo(String::with_capacity(64));
+        let ptr = req.message().as_ptr();
+
+        Request::deserialize_into(&mut reader, &mut req).unwrap();
+        assert!(matches!(req, Request::Echo(_)));
+        assert_eq!(req.message(), "Hi");
+        assert_eq!(req.message().as_ptr(), ptr);
+
+        Request::deserialize_into(&mut reader, &mut req).unwrap();
+        assert!(matches!(req, Request::Jumble { amount: 42, .. }));
+        assert_eq!(req.message(), "Hello");
+        assert_eq!(req.message().as_ptr(), ptr);
+    }
+
+    #[test]
+    fn test_response_deserialize_with_limit() {
+        let mut bytes: Vec<u8> = vec![];
+        Response::Ok(String::from("Hello"))
+            .serialize(&mut bytes)
+            .unwrap();
+
+        let resp = Response::deserialize_with_limit(&mut Cursor::new(&bytes), 5).unwrap();
+        assert_eq!(resp.message(), "Hello");
+
+        let err = Response::deserialize_with_limit(&mut Cursor::new(&bytes), 4).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
 
     #[test]
-    fn test_request_echo_roundtrip() {
-        let req = Request::Echo(String::from("Hello"));
+    fn test_response_deserialize_with_progress() {
+        let resp = Response::Ok(String::from("Hello, wörld"));
+        let mut bytes: Vec<u8> = vec![];
+        resp.serialize(&mut bytes).unwrap();
+
+        let mut reports = vec![];
+        let roundtrip_resp =
+            Response::deserialize_with_progress(&mut Cursor::new(&bytes), 5, |read, total| {
+                reports.push((read, total));
+                true
+            })
+            .unwrap();
+        assert_eq!(roundtrip_resp, resp);
+        // The 13 byte message is split mid-'ö', which is only decoded at the end
+        assert_eq!(reports, [(5, 13), (10, 13), (13, 13)]);
+
+        // Returning `false` from the progress callback aborts the read
+        let err = Response::deserialize_with_progress(&mut Cursor::new(&bytes), 5, |_, _| false)
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Read aborted");
+    }
+
+    #[test]
+    fn test_response_chunked_roundtrip() {
+        let message: String = "abcdé".repeat(40_000); // 240KB, split across 4 chunks
+        let resp = Response::Ok(message.clone());
 
         let mut bytes: Vec<u8> = vec![];
-        req.serialize(&mut bytes).unwrap();
+        let written = resp.serialize_chunked(&mut bytes).unwrap();
+        assert_eq!(written, bytes.len());
+        assert_eq!(written, 1 + message.len() + 4 * 2 + 2); // status + 4 chunk lengths + terminator
 
         let mut reader = Cursor::new(bytes);
-        let roundtrip_req = Request::deserialize(&mut reader).unwrap();
+        let roundtrip_resp = Response::deserialize_chunked(&mut reader, message.len()).unwrap();
+        assert_eq!(roundtrip_resp.message(), message);
 
-        assert!(matches!(roundtrip_req, Request::Echo(_)));
-        assert_eq!(roundtrip_req.message(), "Hello");
+        // One byte short of the whole message, rejected at the last chunk
+        let mut reader = Cursor::new(reader.into_inner());
+        let err = Response::deserialize_chunked(&mut reader, message.len() - 1).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            reader.position() as usize,
+            1 + 3 * (2 + u16::MAX as usize) + 2
+        );
     }
 
     #[test]
-    fn test_request_jumble_roundtrip() {
+    fn test_response_chunks_iter() {
+        let message: String = "abcdé".repeat(20_000); // 120KB, split across 2 chunks
+        let mut bytes: Vec<u8> = vec![];
+        Response::Err(message.clone())
+            .serialize_chunked(&mut bytes)
+            .unwrap();
+        bytes.push(42); // Start of the next message
+
+        let mut reader = Cursor::new(bytes);
+        let mut chunks = ResponseChunks::new(&mut reader, usize::MAX).unwrap();
+        assert!(!chunks.is_ok());
+        let chunks: Vec<Vec<u8>> = chunks.by_ref().map(Result::unwrap).collect();
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].len(), u16::MAX as usize);
+        assert_eq!(chunks.concat(), message.as_bytes());
+        // Only this message was consumed
+        assert_eq!(reader.read_u8().unwrap(), 42);
+
+        // A truncated chunk is an error, and ends the iterator
+        let mut chunks =
+            ResponseChunks::new(Cursor::new([1, 0, 5, b'H', b'i']), usize::MAX).unwrap();
+        assert!(chunks.next().unwrap().is_err());
+        assert!(chunks.next().is_none());
+    }
+
+    #[test]
+    fn test_response_chunked_tokens() {
+        let mut bytes: Vec<u8> = vec![];
+        let err = Response::Tokens(vec![String::from("a"), String::from("b")])
+            .serialize_chunked(&mut bytes)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(bytes.is_empty());
+
+        // A Pong has no message either, but round trips as an empty one
+        Response::Pong.serialize_chunked(&mut bytes).unwrap();
+        assert_eq!(bytes, [3, 0, 0]);
+        let roundtrip_resp = Response::deserialize_chunked(&mut Cursor::new(bytes), 0).unwrap();
+        assert_eq!(roundtrip_resp, Response::Pong);
+    }
+
+    #[test]
+    fn test_response_chunked_segments() {
+        let resp = ResponseBuilder::new()
+            .segment("status", "200")
+            .segment("content-type", "text/plain")
+            .segment("body", "Hello")
+            .build();
+        let mut bytes: Vec<u8> = vec![];
+        let err = resp.serialize_chunked(&mut bytes).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(err.to_string(), "Segments Response can't be chunked");
+        assert!(bytes.is_empty());
+
+        // The regular serialization still round trips all three segments
+        resp.serialize(&mut bytes).unwrap();
+        let roundtrip_resp = Response::deserialize(&mut Cursor::new(bytes)).unwrap();
+        assert_eq!(roundtrip_resp, resp);
+    }
+
+    #[test]
+    fn test_response_chunked_empty() {
+        let mut bytes: Vec<u8> = vec![];
+        Response::Ok(String::new())
+            .serialize_chunked(&mut bytes)
+            .unwrap();
+        assert_eq!(bytes, [1, 0, 0]);
+
+        let roundtrip_resp = Response::deserialize_chunked(&mut Cursor::new(bytes), 0).unwrap();
+        assert_eq!(roundtrip_resp.message(), "");
+    }
+
+    #[test]
+    fn test_protocol_with_stream_capacity() {
+        let (client_stream, server_stream) = memory::MemoryStream::pair();
+        let mut client = Protocol::with_stream(client_stream).unwrap();
+        let mut server = Protocol::with_stream_capacity(server_stream, 4).unwrap();
+        assert_eq!(server.reader.capacity(), 4);
+
+        // Much larger than the buffer, so it's read over many refills
+        let message = "Hello, wörld! ".repeat(10);
+        client
+            .send_message(&Request::Echo(message.clone()))
+            .unwrap();
+        assert_eq!(
+            server.read_message_required::<Request>().unwrap().message(),
+            message
+        );
+    }
+
+    #[test]
+    fn test_serialize_with_order() {
         let req = Request::Jumble {
+            message: String::from("Hi"),
+            amount: 300,
+        };
+        let mut network: Vec<u8> = vec![];
+        req.serialize_with_order::<NetworkEndian>(&mut network)
+            .unwrap();
+        assert_eq!(network, [2, 0, 2, b'H', b'i', 0, 2, 1, 44]);
+        let mut little: Vec<u8> = vec![];
+        req.serialize_with_order::<LittleEndian>(&mut little)
+            .unwrap();
+        assert_eq!(little, [2, 2, 0, b'H', b'i', 2, 0, 44, 1]);
+
+        let roundtrip = Request::deserialize_with_order::<LittleEndian>(
+            &mut Cursor::new(little),
+            OnInvalidUtf8::Error,
+        )
+        .unwrap();
+        match roundtrip {
+            Request::Jumble { message, amount } => {
+                assert_eq!(message, "Hi");
+                assert_eq!(amount, 300);
+            }
+            req => panic!("Unexpected request: {:?}", req),
+        }
+
+        let resp = Response::Ok(String::from("Hi"));
+        let mut little: Vec<u8> = vec![];
+        resp.serialize_with_order::<LittleEndian>(&mut little)
+            .unwrap();
+        assert_eq!(little, [1, 2, 0, b'H', b'i']);
+        // Read with the wrong byte order, the length is 512 rather than 2
+        assert!(Response::deserialize(&mut Cursor::new(little)).is_err());
+    }
+
+    #[test]
+    fn test_protocol_endian() {
+        let (client_stream, server_stream) = memory::MemoryStream::pair();
+        let mut client = Protocol::with_stream(client_stream).unwrap();
+        let mut server = Protocol::with_stream(server_stream.clone()).unwrap();
+        for protocol in [&mut client, &mut server] {
+            protocol.set_endian(Endian::Little);
+            protocol.set_framed(true);
+        }
+
+        client
+            .send_message(&Request::Echo(String::from("Hi")))
+            .unwrap();
+        // Frame length, then the message
+        assert_eq!(server_stream.pending(), 4 + 5);
+        assert_eq!(
+            server.read_message_required::<Request>().unwrap().message(),
+            "Hi"
+        );
+
+        server
+            .send_message(&Response::Ok(String::from("Hello")))
+            .unwrap();
+        assert_eq!(
+            client.read_response_with_limit(16).unwrap(),
+            Response::Ok(String::from("Hello"))
+        );
+    }
+
+    /// A message type that only implements `serialize`, without `serialize_with_order`
+    struct NetworkOnly(u16);
+
+    impl Serialize for NetworkOnly {
+        fn serialize(&self, buf: &mut impl Write) -> io::Result<usize> {
+            buf.write_u16::<NetworkEndian>(self.0)?;
+            Ok(2)
+        }
+    }
+
+    #[test]
+    fn test_serialize_with_order_default() {
+        let mut bytes = vec![];
+        NetworkOnly(1)
+            .serialize_with_order::<NetworkEndian>(&mut bytes)
+            .unwrap();
+        assert_eq!(bytes, [0, 1]);
+        // Rather than silently sending it in network byte order anyway
+        for err in [
+            NetworkOnly(1).serialize_with_order::<LittleEndian>(&mut vec![]),
+            NetworkOnly(1).serialize_with_order::<VarintLengths<NetworkEndian>>(&mut vec![]),
+        ] {
+            assert_eq!(err.unwrap_err().kind(), io::ErrorKind::Unsupported);
+        }
+
+        let (client_stream, _server_stream) = memory::MemoryStream::pair();
+        let mut client = Protocol::with_stream(client_stream).unwrap();
+        client.set_endian(Endian::Little);
+        let err = client.send_message(&NetworkOnly(1)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn test_protocol_read_message_closed() {
+        let (client_stream, server_stream) = memory::MemoryStream::pair();
+        let mut client = Protocol::with_stream(client_stream).unwrap();
+        let mut server = Protocol::with_stream(server_stream).unwrap();
+
+        client.send_message(&Request::Noop).unwrap();
+        client
+            .send_message(&Request::Echo(String::from("Hello")))
+            .unwrap();
+        let mut requests = vec![];
+        // Nothing left to read is the same as the client closing the connection
+        while let Some(req) = server.read_message::<Request>().unwrap() {
+            requests.push(req);
+        }
+        assert!(matches!(requests[..], [Request::Noop, Request::Echo(_)]));
+        let err = server.read_message_required::<Request>().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+        // Closed part way through a message is still an error
+        client.writer.write_all(&[1, 0, 5, b'H']).unwrap();
+        client.flush().unwrap();
+        let err = server.read_message::<Request>().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_protocol_byte_counters() {
+        let (client_stream, server_stream) = memory::MemoryStream::pair();
+        let mut client = Protocol::with_stream(client_stream).unwrap();
+        let mut server = Protocol::with_stream(server_stream).unwrap();
+
+        let requests = [
+            Request::Echo(String::from("Hello")),
+            Request::Noop,
+            Request::Stats(String::from("one two")),
+        ];
+        let mut total = 0;
+        for req in &requests {
+            client.send_message(req).unwrap();
+            total += req.serialize(&mut io::sink()).unwrap() as u64;
+        }
+        assert_eq!(client.bytes_sent(), total);
+        for _ in &requests {
+            server.read_message_required::<Request>().unwrap();
+        }
+        assert_eq!(server.bytes_received(), total);
+        assert_eq!(server.bytes_sent(), 0);
+
+        // Framing adds a 4 byte length to every message
+        client.set_framed(true);
+        server.set_framed(true);
+        client.send_message(&Request::Noop).unwrap();
+        server.read_message_required::<Request>().unwrap();
+        assert_eq!(client.bytes_sent(), total + 4 + 1);
+        assert_eq!(server.bytes_received(), total + 4 + 1);
+    }
+
+    /// A Response with the length prefix missing from its count
+    #[cfg(debug_assertions)]
+    struct Miscounted(Response);
+
+    #[cfg(debug_assertions)]
+    impl Serialize for Miscounted {
+        fn serialize(&self, buf: &mut impl Write) -> io::Result<usize> {
+            Ok(self.0.serialize(buf)? - 2)
+        }
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "serialize reported a different number of bytes than it wrote")]
+    fn test_debug_assert_serialize_len() {
+        let (client_stream, _server_stream) = memory::MemoryStream::pair();
+        let mut client = Protocol::with_stream(client_stream).unwrap();
+        let _ = client.send_message(&Miscounted(Response::Ok(String::from("Hello"))));
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "message was deserialized from a different number of bytes")]
+    fn test_debug_assert_deserialize_len() {
+        let resp = Response::Ok(String::from("Hello"));
+        let mut bytes: Vec<u8> = vec![];
+        resp.serialize(&mut bytes).unwrap();
+        // What `Response::deserialize` checks, had its `serialize` count regressed
+        debug_assert_wire_len::<NetworkEndian>(
+            &Miscounted(resp),
+            bytes.len(),
+            OnInvalidUtf8::Error,
+        );
+    }
+
+    #[test]
+    fn test_protocol_send_only() {
+        let (client_stream, server_stream) = memory::MemoryStream::pair();
+        let mut client = Protocol::with_stream(client_stream).unwrap();
+        let mut server = Protocol::with_stream(server_stream).unwrap();
+
+        // Flushed even though the strategy says not to
+        client.set_flush_strategy(FlushStrategy::Manual);
+        client
+            .send_only(&Request::Log(String::from("Hello")))
+            .unwrap();
+        match server.read_message_required::<Request>().unwrap() {
+            Request::Log(message) => assert_eq!(message, "Hello"),
+            req => panic!("Unexpected request: {:?}", req),
+        }
+
+        let err = client
+            .send_only(&Request::Echo(String::from("Hello")))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        client.flush().unwrap();
+        assert!(server.read_message::<Request>().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_protocol_flush() {
+        let (client_stream, mut server_stream) = memory::MemoryStream::pair();
+        let mut client = Protocol::with_stream(client_stream).unwrap();
+        let req = Request::Echo(String::from("Hello"));
+
+        client.set_flush_strategy(FlushStrategy::Manual);
+        client.send_message(&req).unwrap();
+        assert_eq!(server_stream.pending(), 0);
+
+        // After a flush, the stream has exactly the bytes that were buffered
+        client.flush().unwrap();
+        let mut expected = Cursor::new(vec![]);
+        req.serialize(&mut expected).unwrap();
+        let mut received = vec![];
+        server_stream.read_to_end(&mut received).unwrap();
+        assert_eq!(received, expected.into_inner());
+
+        // Nothing left to flush
+        client.flush().unwrap();
+        assert_eq!(server_stream.pending(), 0);
+    }
+
+    #[test]
+    fn test_protocol_flush_strategy() {
+        let (client_stream, server_stream) = memory::MemoryStream::pair();
+        let mut client = Protocol::with_stream(client_stream).unwrap();
+
+        // Noop is a single byte on the wire
+        client.set_flush_strategy(FlushStrategy::Manual);
+        client.send_message(&Request::Noop).unwrap();
+        client.send_message(&Request::Noop).unwrap();
+        assert_eq!(server_stream.pending(), 0);
+        client.flush().unwrap();
+        assert_eq!(server_stream.pending(), 2);
+
+        client.set_flush_strategy(FlushStrategy::EveryN(2));
+        client.send_message(&Request::Noop).unwrap();
+        assert_eq!(server_stream.pending(), 2);
+        client.send_message(&Request::Noop).unwrap();
+        assert_eq!(server_stream.pending(), 4);
+
+        client.set_flush_strategy(FlushStrategy::Immediate);
+        client.send_message(&Request::Noop).unwrap();
+        assert_eq!(server_stream.pending(), 5);
+
+        let mut server = Protocol::with_stream(server_stream).unwrap();
+        for _ in 0..5 {
+            assert!(matches!(
+                server.read_message_required::<Request>().unwrap(),
+                Request::Noop
+            ));
+        }
+    }
+
+    #[test]
+    fn test_protocol_flush_before_read() {
+        let (client_stream, server_stream) = memory::MemoryStream::pair();
+        let mut client = Protocol::with_stream(client_stream).unwrap();
+        let mut server = Protocol::with_stream(server_stream).unwrap();
+        client.set_flush_strategy(FlushStrategy::Manual);
+        server.set_flush_strategy(FlushStrategy::Manual);
+
+        // Pipelined requests aren't sent yet...
+        for message in ["one", "two", "three"] {
+            client
+                .send_message(&Request::Echo(message.to_string()))
+                .unwrap();
+        }
+        assert!(server.read_message::<Request>().unwrap().is_none());
+        // ...until the client reads (there's no response yet, which the in-memory stream reads as
+        // closed rather than blocking)
+        assert!(client.read_message::<Response>().unwrap().is_none());
+
+        // The server's responses wait while the next request has already arrived...
+        for _ in 0..3 {
+            let req = server.read_message_required::<Request>().unwrap();
+            server
+                .send_message(&Response::Ok(req.message().to_uppercase()))
+                .unwrap();
+        }
+        assert!(client.read_message::<Response>().unwrap().is_none());
+        // ...and are all sent together once a read would wait
+        assert!(server.read_message::<Request>().unwrap().is_none());
+        for message in ["ONE", "TWO", "THREE"] {
+            let resp = client.read_message_required::<Response>().unwrap();
+            assert_eq!(resp, Response::Ok(message.to_string()));
+        }
+    }
+
+    #[test]
+    fn test_protocol_in_memory() {
+        let (client_stream, server_stream) = memory::MemoryStream::pair();
+        let mut client = Protocol::with_stream(client_stream).unwrap();
+        let mut server = Protocol::with_stream(server_stream).unwrap();
+
+        client
+            .send_message(&Request::Jumble {
+                message: String::from("Hello"),
+                amount: 42,
+            })
+            .unwrap();
+
+        // Handle the request just like the server binary does
+        let resp = match server.read_message_required::<Request>().unwrap() {
+            Request::Jumble { message, amount } => Response::Ok(jumble_message(&message, amount)),
+            req => panic!("Unexpected request: {:?}", req),
+        };
+        server.send_message(&resp).unwrap();
+
+        let resp = client.read_message_required::<Response>().unwrap();
+        assert_eq!(resp.message(), jumble_message("Hello", 42));
+    }
+
+    #[test]
+    fn test_protocol_timestamps() {
+        for framed in [false, true] {
+            let (client_stream, server_stream) = memory::MemoryStream::pair();
+            let mut client = Protocol::with_stream(client_stream).unwrap();
+            let mut server = Protocol::with_stream(server_stream).unwrap();
+            for protocol in [&mut client, &mut server] {
+                protocol.set_framed(framed);
+                protocol.set_timestamps(true);
+            }
+
+            let before = timestamp_nanos();
+            client
+                .send_message(&Request::Echo(String::from("Hello")))
+                .unwrap();
+            let req = server.read_message_required::<Request>().unwrap();
+            assert_eq!(req.message(), "Hello");
+            let timestamp = server.last_timestamp().unwrap();
+            assert!((before..=timestamp_nanos()).contains(&timestamp));
+
+            // The server echoes it back unchanged
+            server
+                .send_message_at(&Response::Ok(String::from("Hi")), timestamp)
+                .unwrap();
+            assert_eq!(client.last_timestamp(), None);
+            let resp = client.read_message_required::<Response>().unwrap();
+            assert_eq!(resp, Response::Ok(String::from("Hi")));
+            assert_eq!(client.last_timestamp(), Some(timestamp));
+        }
+
+        // Raw frames are read without their timestamp, e.g. for `RequestRef`
+        let (client_stream, server_stream) = memory::MemoryStream::pair();
+        let mut client = Protocol::with_stream(client_stream).unwrap();
+        let mut server = Protocol::with_stream(server_stream).unwrap();
+        for protocol in [&mut client, &mut server] {
+            protocol.set_framed(true);
+            protocol.set_timestamps(true);
+        }
+        client.send_message_at(&Request::Noop, 42).unwrap();
+        let mut frame = vec![];
+        server.read_frame_into(&mut frame).unwrap();
+        assert_eq!(frame, [8]);
+        assert_eq!(server.last_timestamp(), Some(42));
+    }
+
+    #[test]
+    fn test_protocol_send_raw() {
+        let mut bytes = vec![];
+        Request::Jumble {
+            message: String::from("Hello"),
+            amount: 80,
+        }
+        .serialize(&mut bytes)
+        .unwrap();
+        for framed in [false, true] {
+            let (client_stream, server_stream) = memory::MemoryStream::pair();
+            let mut client = Protocol::with_stream(client_stream).unwrap();
+            let mut server = Protocol::with_stream(server_stream).unwrap();
+            for protocol in [&mut client, &mut server] {
+                protocol.set_framed(framed);
+                protocol.set_flush_strategy(FlushStrategy::Manual);
+            }
+            // Flushed even though the strategy is Manual
+            client.send_raw(&bytes).unwrap();
+            let received = server.read_message_required::<Request>().unwrap();
+            assert!(matches!(received, Request::Jumble { amount: 80, .. }));
+            assert_eq!(received.message(), "Hello");
+
+            // Relayed as is, e.g. by a proxy
+            if framed {
+                server.send_message(&Request::Ping).unwrap();
+                server.flush().unwrap();
+                let mut frame = vec![];
+                client.read_frame_into(&mut frame).unwrap();
+                client.send_raw(&frame).unwrap();
+                assert!(matches!(
+                    server.read_message_required::<Request>().unwrap(),
+                    Request::Ping
+                ));
+            }
+        }
+    }
+
+    #[test]
+    fn test_protocol_sequence_numbers() {
+        for framed in [false, true] {
+            let (client_stream, server_stream) = memory::MemoryStream::pair();
+            let mut client = Protocol::with_stream(client_stream).unwrap();
+            let mut server = Protocol::with_stream(server_stream).unwrap();
+            for protocol in [&mut client, &mut server] {
+                protocol.set_framed(framed);
+                protocol.set_sequence_numbers(true);
+                protocol.set_timestamps(true);
+            }
+            for expected in 0..3 {
+                client.send_message(&Request::Ping).unwrap();
+                assert!(matches!(
+                    server.read_message_required::<Request>().unwrap(),
+                    Request::Ping
+                ));
+                assert_eq!(server.last_sequence(), Some(expected));
+            }
+            server.send_message(&Response::Pong).unwrap();
+            client.read_message_required::<Response>().unwrap();
+            assert_eq!(client.last_sequence(), Some(0));
+        }
+
+        // Messages written by hand, out of order
+        let (mut client_stream, server_stream) = memory::MemoryStream::pair();
+        let mut server = Protocol::with_stream(server_stream).unwrap();
+        server.set_sequence_numbers(true);
+        let sequence_error = |err: io::Error| {
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            assert!(!is_recoverable(&err));
+            *err.get_ref()
+                .unwrap()
+                .downcast_ref::<SequenceError>()
+                .unwrap()
+        };
+        // A gap, then a duplicate
+        client_stream.write_all(&[0, 0, 0, 2, 8]).unwrap();
+        let err = server.read_message_required::<Request>().unwrap_err();
+        assert_eq!(err.to_string(), "Expected message 0, got 2 (2 missing)");
+        assert_eq!(
+            sequence_error(err),
+            SequenceError {
+                expected: 0,
+                received: 2
+            }
+        );
+        client_stream.write_all(&[0, 0, 0, 2, 8]).unwrap();
+        let err = server.read_message_required::<Request>().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Expected message 3, got 2 (duplicate or reordered)"
+        );
+        // Numbering carries on from the last message read, so each problem is only reported once
+        client_stream.write_all(&[0, 0, 0, 3, 8]).unwrap();
+        assert!(matches!(
+            server.read_message_required::<Request>().unwrap(),
+            Request::Noop
+        ));
+        assert_eq!(server.last_sequence(), Some(3));
+
+        // Raw frames are checked too
+        let (mut client_stream, server_stream) = memory::MemoryStream::pair();
+        let mut server = Protocol::with_stream(server_stream).unwrap();
+        server.set_framed(true);
+        server.set_sequence_numbers(true);
+        client_stream
+            .write_all(&[0, 0, 0, 5, 0, 0, 0, 1, 8])
+            .unwrap();
+        let err = server.read_frame_into(&mut vec![]).unwrap_err();
+        assert_eq!(
+            sequence_error(err),
+            SequenceError {
+                expected: 0,
+                received: 1
+            }
+        );
+    }
+
+    /// Stream that can't be cloned, like a socket when the process is out of file descriptors
+    struct Unclonable(memory::MemoryStream);
+
+    impl Read for Unclonable {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    impl Write for Unclonable {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.0.flush()
+        }
+    }
+
+    impl Stream for Unclonable {
+        fn try_clone(&self) -> io::Result<Self> {
+            Err(io::Error::other("Too many open files"))
+        }
+    }
+
+    #[test]
+    fn test_protocol_degraded_without_clone() {
+        let (client_stream, server_stream) = memory::MemoryStream::pair();
+        let mut client = Protocol::with_stream(Unclonable(client_stream)).unwrap();
+        let mut server = Protocol::with_stream(server_stream).unwrap();
+        assert!(client.is_degraded());
+        assert!(!server.is_degraded());
+
+        // Sequential request/response still works over the shared stream
+        for message in ["Hello", "big", "world"] {
+            client
+                .send_message(&Request::Echo(message.to_string()))
+                .unwrap();
+            let req = server.read_message_required::<Request>().unwrap();
+            server
+                .send_message(&Response::Ok(req.message().to_uppercase()))
+                .unwrap();
+            let resp = client.read_message_required::<Response>().unwrap();
+            assert_eq!(resp, Response::Ok(message.to_uppercase()));
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_protocol_unix_stream() {
+        use std::os::unix::net::UnixStream;
+
+        let (client_stream, server_stream) = UnixStream::pair().unwrap();
+        let mut client: Protocol<UnixStream> = Protocol::with_stream(client_stream).unwrap();
+        let mut server = Protocol::with_stream(server_stream).unwrap();
+
+        client
+            .send_message(&Request::Echo(String::from("Hello")))
+            .unwrap();
+        let req = server.read_message_required::<Request>().unwrap();
+        server
+            .send_message(&Response::Ok(req.message().to_string()))
+            .unwrap();
+
+        let resp = client.read_message_required::<Response>().unwrap();
+        assert_eq!(resp.message(), "Hello");
+    }
+
+    #[test]
+    fn test_protocol_connection_info() {
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let listen_addr = listener.local_addr().unwrap();
+        let client = Protocol::connect(listen_addr).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let server = Protocol::with_stream(stream).unwrap();
+
+        let info = client.connection_info().unwrap();
+        assert_eq!(info.server_listen_addr, listen_addr);
+        // The client's OS picks an ephemeral port, which isn't the server's listening port
+        assert_ne!(info.client_source_addr.port(), listen_addr.port());
+        // Both ends agree on which address is which
+        assert_eq!(server.connection_info().unwrap(), info);
+    }
+
+    #[test]
+    fn test_protocol_drain() {
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = Protocol::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut stream, _) = listener.accept().unwrap();
+
+        // Nothing to drain
+        assert_eq!(client.drain(Duration::from_secs(1)).unwrap(), 0);
+
+        // A response followed by garbage in the same write, so it's all there after the read
+        let mut bytes = vec![];
+        Response::Ok(String::from("Hello"))
+            .serialize(&mut bytes)
+            .unwrap();
+        bytes.extend_from_slice(&[0xff; 100]);
+        stream.write_all(&bytes).unwrap();
+        let resp = client.read_message_required::<Response>().unwrap();
+        assert_eq!(resp.message(), "Hello");
+        assert_eq!(client.drain(Duration::from_secs(1)).unwrap(), 100);
+
+        // Reads block again (until the next message arrives), and it's read from its start
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            Response::Ok(String::from("World"))
+                .serialize(&mut stream)
+                .unwrap();
+        });
+        let resp = client.read_message_required::<Response>().unwrap();
+        assert_eq!(resp.message(), "World");
+    }
+
+    #[test]
+    fn test_protocol_peer_done_sending() {
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let mut client = Protocol::with_stream(stream.try_clone().unwrap()).unwrap();
+        let mut server = Protocol::with_stream(listener.accept().unwrap().0).unwrap();
+
+        for message in ["Hello", "World"] {
+            client
+                .send_message(&Request::Echo(String::from(message)))
+                .unwrap();
+        }
+        // The client half-closes: it's done sending, but still reading
+        stream.shutdown(Shutdown::Write).unwrap();
+
+        let mut queued = vec![];
+        while let Some(req) = server.read_message::<Request>().unwrap() {
+            assert!(!server.is_peer_done_sending());
+            queued.push(Response::Ok(req.message().to_string()));
+        }
+        assert!(server.is_peer_done_sending());
+        assert!(!client.is_peer_done_sending());
+
+        // The server can still send what it owes, then close
+        for resp in &queued {
+            server.send_message(resp).unwrap();
+        }
+        drop(server);
+        for message in ["Hello", "World"] {
+            let resp = client.read_message::<Response>().unwrap().unwrap();
+            assert_eq!(resp.message(), message);
+        }
+        assert!(client.read_message::<Response>().unwrap().is_none());
+        assert!(client.is_peer_done_sending());
+    }
+
+    #[test]
+    fn test_protocol_builder() {
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let keepalive = sockopt::KeepaliveCfg::new(Duration::from_secs(30));
+        let mut client = ProtocolBuilder::new()
+            .read_timeout(Duration::from_secs(5))
+            .write_timeout(Duration::from_secs(6))
+            .nodelay(true)
+            .keepalive(keepalive)
+            .max_message_size(8)
+            .connect(listener.local_addr().unwrap())
+            .unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let mut server = Protocol::with_stream(stream).unwrap();
+
+        assert_eq!(client.config().max_message_size, Some(8));
+        assert_eq!(client.config().keepalive, Some(keepalive));
+        assert_eq!(
+            client
+                .writer
+                .get_ref()
+                .with(TcpStream::read_timeout)
+                .unwrap(),
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(
+            client
+                .writer
+                .get_ref()
+                .with(TcpStream::write_timeout)
+                .unwrap(),
+            Some(Duration::from_secs(6))
+        );
+        assert!(client.writer.get_ref().with(TcpStream::nodelay).unwrap());
+        assert_eq!(server.config(), &ProtocolConfig::default());
+
+        // 3 + 5 bytes fits within the limit, 3 + 6 doesn't
+        server
+            .send_message(&Response::Ok(String::from("Hello")))
+            .unwrap();
+        assert_eq!(
+            client
+                .read_message_required::<Response>()
+                .unwrap()
+                .message(),
+            "Hello"
+        );
+        server
+            .send_message(&Response::Ok(String::from("Hello!")))
+            .unwrap();
+        let err = client.read_message_required::<Response>().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_server_addr_parse() {
+        let addr: ServerAddr = "127.0.0.1:4000".parse().unwrap();
+        assert_eq!(addr, ServerAddr::Tcp("127.0.0.1:4000".parse().unwrap()));
+        assert_eq!(addr.to_string(), "127.0.0.1:4000");
+        assert!("localhost".parse::<ServerAddr>().is_err());
+
+        let addr = "unix:/tmp/demo.sock".parse::<ServerAddr>();
+        #[cfg(unix)]
+        assert_eq!(addr.unwrap().to_string(), "unix:/tmp/demo.sock");
+        #[cfg(not(unix))]
+        assert!(addr.is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_protocol_connect_unix() {
+        use std::os::unix::net::UnixListener;
+
+        let path = std::env::temp_dir().join(format!("tcp_demo_{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut server = Protocol::with_stream(stream).unwrap();
+            let req = server.read_message_required::<Request>().unwrap();
+            server
+                .send_message(&Response::Ok(req.message().to_string()))
+                .unwrap();
+        });
+
+        let mut client = Protocol::connect_unix(&path).unwrap();
+        client
+            .send_message(&Request::Echo(String::from("Hello")))
+            .unwrap();
+        let resp = client.read_message_required::<Response>().unwrap();
+        assert_eq!(resp.message(), "Hello");
+
+        server.join().unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_allowlist() {
+        let echo = Request::Echo(String::from("Hello"));
+        let jumble = Request::Jumble {
             message: String::from("Hello"),
             amount: 42,
         };
+        assert_eq!(request_type_code("Jumble"), Some(2));
+        assert_eq!(jumble.type_name(), "jumble");
 
-        let mut bytes: Vec<u8> = vec![];
-        req.serialize(&mut bytes).unwrap();
+        let allowlist = Allowlist::from_names(&["echo"]).unwrap();
+        assert!(allowlist.allows(&echo));
+        assert!(!allowlist.allows(&jumble));
 
-        let mut reader = Cursor::new(bytes);
-        let roundtrip_req = Request::deserialize(&mut reader).unwrap();
+        let no_names: [&str; 0] = [];
+        let allowlist = Allowlist::from_names(&no_names).unwrap();
+        assert!(allowlist.allows(&echo));
+        assert!(allowlist.allows(&jumble));
 
-        assert!(matches!(roundtrip_req, Request::Jumble { .. }));
+        assert!(Allowlist::from_names(&["echo", "shout"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_command() {
+        assert!(matches!(
+            parse_command("Hello, world!").unwrap(),
+            Request::Echo(message) if message == "Hello, world!"
+        ));
+        assert!(matches!(
+            parse_command("/jumble 5 Hello, world!").unwrap(),
+            Request::Jumble { message, amount: 5 } if message == "Hello, world!"
+        ));
+        assert!(matches!(
+            parse_command("/delay 200 Hello").unwrap(),
+            Request::Delay { message, ms: 200 } if message == "Hello"
+        ));
+        assert!(matches!(
+            parse_command("/stats one two").unwrap(),
+            Request::Stats(message) if message == "one two"
+        ));
+        assert!(matches!(
+            parse_command("/checksum hello").unwrap(),
+            Request::Checksum(message) if message == "hello"
+        ));
+        assert!(matches!(
+            parse_command("/reflect hi").unwrap(),
+            Request::Reflect(payload) if payload == b"hi"
+        ));
+        assert!(matches!(
+            parse_command("/log Deployed").unwrap(),
+            Request::Log(message) if message == "Deployed"
+        ));
+        assert!(matches!(
+            parse_command("/concat big  world").unwrap(),
+            Request::Concat(parts) if parts == ["big", "world"]
+        ));
+        assert!(matches!(
+            parse_command("/kv host=example.com debug=").unwrap(),
+            Request::KeyValues(pairs) if pairs == [
+                (String::from("host"), String::from("example.com")),
+                (String::from("debug"), String::new()),
+            ]
+        ));
+        assert!(matches!(
+            parse_command("/split , a,b,c").unwrap(),
+            Request::Split { message, delimiter } if message == "a,b,c" && delimiter == ","
+        ));
+        assert!(matches!(
+            parse_command("/repeat 3 500 Hello").unwrap(),
+            Request::Repeat { message, count: 3, interval_ms: 500 } if message == "Hello"
+        ));
+        assert!(matches!(
+            parse_command("/timed /jumble 5 Hello").unwrap(),
+            Request::Timed(inner) if matches!(*inner, Request::Jumble { amount: 5, .. })
+        ));
+        assert!(matches!(
+            parse_command("/timed Hello").unwrap(),
+            Request::Timed(inner) if matches!(*inner, Request::Echo(ref message) if message == "Hello")
+        ));
+        assert!(matches!(parse_command("/noop").unwrap(), Request::Noop));
+        assert!(matches!(parse_command("/ping").unwrap(), Request::Ping));
+    }
+
+    #[test]
+    fn test_parse_command_unknown_directive() {
+        // Echoed as-is, including the directive
+        assert!(matches!(
+            parse_command("/shout Hello").unwrap(),
+            Request::Echo(message) if message == "/shout Hello"
+        ));
+        assert!(matches!(
+            parse_command("/").unwrap(),
+            Request::Echo(message) if message == "/"
+        ));
+    }
+
+    #[test]
+    fn test_parse_command_malformed_amount() {
+        assert_eq!(
+            parse_command("/jumble lots Hello").unwrap_err(),
+            "Invalid number 'lots', usage: /jumble <amount> <message>"
+        );
+        // Too large for the u16 amount
+        assert!(parse_command("/jumble 70000 Hello").is_err());
+        assert_eq!(
+            parse_command("/jumble").unwrap_err(),
+            "Missing number, usage: /jumble <amount> <message>"
+        );
+        assert!(parse_command("/delay -1 Hello").is_err());
+        assert_eq!(
+            parse_command("/repeat 3").unwrap_err(),
+            "Missing number, usage: /repeat <count> <interval_ms> <message>"
+        );
+        assert_eq!(
+            parse_command("/kv host").unwrap_err(),
+            "Invalid pair 'host', usage: /kv <key=value...>"
+        );
+    }
+
+    #[cfg(feature = "hmac")]
+    #[test]
+    fn test_protocol_psk() {
+        let (client_stream, server_stream) = memory::MemoryStream::pair();
+        let mut client = Protocol::with_stream(client_stream).unwrap();
+        let mut server = Protocol::with_stream(server_stream).unwrap();
+        client.set_psk(b"secret");
+        server.set_psk(b"secret");
+
+        let req = Request::Echo(String::from("Hello"));
+        client.send_message(&req).unwrap();
+        let roundtrip_req = server.read_message_required::<Request>().unwrap();
         assert_eq!(roundtrip_req.message(), "Hello");
+
+        // Same message, but signed with a different key
+        server.set_psk(b"not the secret");
+        client.send_message(&req).unwrap();
+        let err = server.read_message_required::<Request>().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "authentication failed");
     }
 
     #[test]
-    fn test_response_roundtrip() {
-        let resp = Response(String::from("Hello"));
+    fn test_jumble_message_is_deterministic() {
+        let message = "The quick brown fox jumps over the lazy dog";
+        assert_eq!(jumble_message(message, 42), jumble_message(message, 42));
+        assert_ne!(jumble_message(message, 42), jumble_message(message, 43));
+    }
 
-        let mut bytes: Vec<u8> = vec![];
-        resp.serialize(&mut bytes).unwrap();
+    #[test]
+    fn test_jumble_message_preserves_chars() {
+        let message = "Hello, wörld!";
+        let mut original: Vec<char> = message.chars().collect();
+        let mut jumbled: Vec<char> = jumble_message(message, 100).chars().collect();
+        original.sort_unstable();
+        jumbled.sort_unstable();
+        assert_eq!(original, jumbled);
 
-        let mut reader = Cursor::new(bytes);
-        let roundtrip_resp = Response::deserialize(&mut reader).unwrap();
+        assert_eq!(jumble_message("", 5), "");
+    }
+
+    #[test]
+    fn test_jumble_diff() {
+        // Only the positions that changed: 'H' & 'o' swapped places (the 'l's are the same)
+        assert_eq!(jumble_diff("Hello", "oellH"), [0, 4]);
+        assert_eq!(jumble_diff("Hello", "Hello"), [] as [usize; 0]);
+        // In chars, not bytes
+        assert_eq!(jumble_diff("wörld", "wrölr"), [1, 2, 4]);
+
+        // A known jumble, where only the 'T' stayed put
+        let message = "The quick brown fox";
+        let jumbled = jumble_message(message, 42);
+        assert_eq!(jumbled, "Tx eucofrwokn bqih ");
+        assert_eq!(jumble_diff(message, &jumbled), (1..19).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_check_jumble() {
+        assert!(check_jumble("").is_ok());
+        assert!(check_jumble(&"é".repeat(MAX_JUMBLE_CHARS)).is_ok());
+        assert_eq!(
+            check_jumble(&"x".repeat(MAX_JUMBLE_CHARS + 1)),
+            Err(format!(
+                "Jumble messages are limited to {} characters, this one has {}",
+                MAX_JUMBLE_CHARS,
+                MAX_JUMBLE_CHARS + 1
+            ))
+        );
+    }
 
-        assert!(matches!(roundtrip_resp, Response(_)));
-        assert_eq!(roundtrip_resp.0, "Hello");
+    #[test]
+    fn test_jumble_message_max_amount() {
+        // The largest message & amount a Jumble request can carry
+        let message = "x".repeat(u16::MAX as usize - 1) + "y";
+        let jumbled = jumble_message(&message, u16::MAX);
+        assert_eq!(jumbled.len(), message.len());
+        assert_eq!(jumbled.matches('y').count(), 1);
+    }
+
+    #[test]
+    fn test_append_metadata() {
+        let mut message = String::from("'Hello' from the other side!");
+        append_metadata(&mut message, "1.2.3.4:5678", Duration::from_micros(42));
+        assert_eq!(
+            message,
+            "'Hello' from the other side! [peer=1.2.3.4:5678 time_us=42]"
+        );
     }
 }
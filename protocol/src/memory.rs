@@ -0,0 +1,58 @@
+//! In-memory transport for testing `Protocol` without real sockets
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+
+use crate::Stream;
+
+/// One end of an in-memory, bi-directional pipe
+///
+/// Bytes written to one end of the pair can be read from the other. Reading when no
+/// bytes are pending returns `Ok(0)` (like a closed socket) rather than blocking,
+/// so tests should write before they read.
+#[derive(Clone, Default)]
+pub struct MemoryStream {
+    rx: Arc<Mutex<VecDeque<u8>>>,
+    tx: Arc<Mutex<VecDeque<u8>>>,
+}
+
+impl MemoryStream {
+    /// Create two connected ends of a pipe
+    pub fn pair() -> (Self, Self) {
+        let a = Self::default();
+        let b = Self {
+            rx: a.tx.clone(),
+            tx: a.rx.clone(),
+        };
+        (a, b)
+    }
+
+    /// Number of bytes waiting to be read from this end
+    pub fn pending(&self) -> usize {
+        self.rx.lock().unwrap().len()
+    }
+}
+
+impl Read for MemoryStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.rx.lock().unwrap().read(buf)
+    }
+}
+
+impl Write for MemoryStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.tx.lock().unwrap().extend(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Stream for MemoryStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(self.clone())
+    }
+}
@@ -0,0 +1,159 @@
+//! Server counters, shared between connection threads
+
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{Request, REQUEST_TYPES};
+
+/// Counters that connection threads update as they work
+///
+/// Each field is an atomic so a `&Metrics` (e.g. in an `Arc`) can be shared across threads
+/// without a `Mutex`. `Relaxed` ordering is fine since the counters are independent
+/// and only read for reporting.
+pub struct Metrics {
+    connections: AtomicU64,
+    /// One counter per entry in `REQUEST_TYPES`
+    requests: Vec<AtomicU64>,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    errors: AtomicU64,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            connections: AtomicU64::new(0),
+            requests: REQUEST_TYPES.iter().map(|_| AtomicU64::new(0)).collect(),
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count an accepted connection
+    pub fn record_connection(&self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a received request (by type)
+    pub fn record_request(&self, request: &Request) {
+        let code = u8::from(request);
+        if let Some(idx) = REQUEST_TYPES.iter().position(|(_, c)| *c == code) {
+            self.requests[idx].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Count bytes received from clients
+    pub fn record_bytes_in(&self, bytes: u64) {
+        self.bytes_in.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Count bytes sent to clients
+    pub fn record_bytes_out(&self, bytes: u64) {
+        self.bytes_out.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Count a connection that ended with an error
+    pub fn record_error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Summary table, e.g.:
+/// ```text
+/// connections                3
+/// requests (echo)            2
+/// requests (jumble)          1
+/// requests (stats)           0
+/// requests (delay)           0
+/// requests (reflect)         0
+/// requests (log)             0
+/// requests (concat)          0
+/// requests (noop)            0
+/// requests (ping)            0
+/// requests (checksum)        0
+/// requests (kv)              0
+/// requests (split)           0
+/// requests (repeat)          0
+/// requests (timed)           0
+/// requests (goodbye)         0
+/// requests (xor)             0
+/// requests (unxor)           0
+/// requests (max-size)        0
+/// requests (histogram)        0
+/// bytes in                  42
+/// bytes out                120
+/// errors                     0
+/// ```
+impl fmt::Display for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let row = |f: &mut fmt::Formatter, name: &str, counter: &AtomicU64| {
+            writeln!(f, "{:<19}{:>9}", name, counter.load(Ordering::Relaxed))
+        };
+        row(f, "connections", &self.connections)?;
+        for ((name, _), counter) in REQUEST_TYPES.iter().zip(&self.requests) {
+            row(f, &format!("requests ({})", name), counter)?;
+        }
+        row(f, "bytes in", &self.bytes_in)?;
+        row(f, "bytes out", &self.bytes_out)?;
+        row(f, "errors", &self.errors)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_metrics_summary() {
+        let metrics = Arc::new(Metrics::new());
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let metrics = metrics.clone();
+                std::thread::spawn(move || {
+                    metrics.record_connection();
+                    metrics.record_request(&Request::Echo(String::from("Hello")));
+                    metrics.record_bytes_in(8);
+                    metrics.record_bytes_out(10);
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+        metrics.record_error();
+
+        let summary = metrics.to_string();
+        let lines: Vec<&str> = summary.lines().collect();
+        assert_eq!(lines[0], "connections                4");
+        assert_eq!(lines[1], "requests (echo)            4");
+        assert_eq!(lines[2], "requests (jumble)          0");
+        assert_eq!(lines[3], "requests (stats)           0");
+        assert_eq!(lines[4], "requests (delay)           0");
+        assert_eq!(lines[5], "requests (reflect)         0");
+        assert_eq!(lines[6], "requests (log)             0");
+        assert_eq!(lines[7], "requests (concat)          0");
+        assert_eq!(lines[8], "requests (noop)            0");
+        assert_eq!(lines[9], "requests (ping)            0");
+        assert_eq!(lines[10], "requests (checksum)        0");
+        assert_eq!(lines[11], "requests (kv)              0");
+        assert_eq!(lines[12], "requests (split)           0");
+        assert_eq!(lines[13], "requests (repeat)          0");
+        assert_eq!(lines[14], "requests (timed)           0");
+        assert_eq!(lines[15], "requests (goodbye)         0");
+        assert_eq!(lines[16], "requests (xor)             0");
+        assert_eq!(lines[17], "requests (unxor)           0");
+        assert_eq!(lines[18], "requests (max-size)        0");
+        assert_eq!(lines[19], "requests (histogram)        0");
+        assert_eq!(lines[20], "bytes in                  32");
+        assert_eq!(lines[21], "bytes out                 40");
+        assert_eq!(lines[22], "errors                     1");
+    }
+}
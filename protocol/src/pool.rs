@@ -0,0 +1,216 @@
+//! Client-side pool of reusable connections to a single server
+
+use std::io;
+use std::net::SocketAddr;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Condvar, Mutex};
+
+use crate::{Protocol, Request, Response};
+
+/// A set of `Protocol` connections to one server, shared between threads
+///
+/// `get` hands out an idle connection (or opens a new one, up to `max_size`), and the
+/// connection goes back to the pool when the returned guard is dropped. This saves
+/// a TCP handshake for every request when a client makes many requests.
+///
+/// Idle connections are health checked (with a `Request::Noop`) before being handed out,
+/// and dead ones (e.g. closed by the server) are discarded.
+pub struct ProtocolPool {
+    addr: SocketAddr,
+    max_size: usize,
+    state: Mutex<PoolState>,
+    /// Signaled when a connection is returned (or discarded), for callers waiting in `get`
+    available: Condvar,
+}
+
+struct PoolState {
+    idle: Vec<Protocol>,
+    /// Number of connections that are open (idle + checked out)
+    open: usize,
+}
+
+impl ProtocolPool {
+    /// Create an empty pool, connections are opened as they're needed
+    pub fn new(addr: SocketAddr, max_size: usize) -> Self {
+        assert!(max_size > 0, "ProtocolPool max_size must be at least 1");
+        Self {
+            addr,
+            max_size,
+            state: Mutex::new(PoolState {
+                idle: vec![],
+                open: 0,
+            }),
+            available: Condvar::new(),
+        }
+    }
+
+    /// Check out a connection, blocking if `max_size` connections are already checked out
+    pub fn get(&self) -> io::Result<PooledProtocol<'_>> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(mut protocol) = state.idle.pop() {
+                // Don't hold the lock while talking to the server
+                drop(state);
+                if health_check(&mut protocol).is_ok() {
+                    return Ok(self.guard(protocol));
+                }
+                state = self.state.lock().unwrap();
+                state.open -= 1;
+                continue;
+            }
+            if state.open < self.max_size {
+                // Reserve the slot before connecting so other threads can't exceed the max
+                state.open += 1;
+                drop(state);
+                return match Protocol::connect(self.addr) {
+                    Ok(protocol) => Ok(self.guard(protocol)),
+                    Err(e) => {
+                        self.release_slot();
+                        Err(e)
+                    }
+                };
+            }
+            state = self.available.wait(state).unwrap();
+        }
+    }
+
+    /// Number of open connections (idle + checked out)
+    pub fn open_count(&self) -> usize {
+        self.state.lock().unwrap().open
+    }
+
+    /// Number of connections waiting in the pool to be checked out
+    pub fn idle_count(&self) -> usize {
+        self.state.lock().unwrap().idle.len()
+    }
+
+    fn guard(&self, protocol: Protocol) -> PooledProtocol<'_> {
+        PooledProtocol {
+            pool: self,
+            protocol: Some(protocol),
+        }
+    }
+
+    fn release_slot(&self) {
+        self.state.lock().unwrap().open -= 1;
+        self.available.notify_one();
+    }
+}
+
+/// Send a `Noop` and expect the (empty) `Response::Ok`
+fn health_check(protocol: &mut Protocol) -> io::Result<()> {
+    protocol.send_message(&Request::Noop)?;
+    match protocol.read_message_required::<Response>()? {
+        Response::Ok(_) => Ok(()),
+        Response::Err(message) => Err(io::Error::other(message)),
+        Response::Pong | Response::Tokens(_) | Response::Segments(_) => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Unexpected response type to Noop",
+        )),
+    }
+}
+
+/// A connection checked out of a `ProtocolPool`, returned to the pool on drop
+pub struct PooledProtocol<'a> {
+    pool: &'a ProtocolPool,
+    /// Only `None` after `discard`
+    protocol: Option<Protocol>,
+}
+
+impl PooledProtocol<'_> {
+    /// Close the connection instead of returning it to the pool
+    /// (e.g. after an error left the stream in an unknown state)
+    pub fn discard(mut self) {
+        self.protocol = None;
+        self.pool.release_slot();
+    }
+}
+
+impl Deref for PooledProtocol<'_> {
+    type Target = Protocol;
+
+    fn deref(&self) -> &Protocol {
+        self.protocol
+            .as_ref()
+            .expect("connection is present until dropped")
+    }
+}
+
+impl DerefMut for PooledProtocol<'_> {
+    fn deref_mut(&mut self) -> &mut Protocol {
+        self.protocol
+            .as_mut()
+            .expect("connection is present until dropped")
+    }
+}
+
+impl Drop for PooledProtocol<'_> {
+    fn drop(&mut self) {
+        if let Some(protocol) = self.protocol.take() {
+            self.pool.state.lock().unwrap().idle.push(protocol);
+            self.pool.available.notify_one();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::{Shutdown, TcpListener, TcpStream};
+    use std::sync::mpsc;
+
+    /// Reply to every request with an empty `Response::Ok`, sending each
+    /// accepted stream to `streams` so the test can close it
+    fn spawn_server(streams: mpsc::Sender<TcpStream>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                streams.send(stream.try_clone().unwrap()).unwrap();
+                std::thread::spawn(move || {
+                    let mut protocol = Protocol::with_stream(stream).unwrap();
+                    while let Ok(Some(_)) = protocol.read_message::<Request>() {
+                        if protocol.send_message(&Response::Ok(String::new())).is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn test_pool_checkout_and_return() {
+        let (tx, rx) = mpsc::channel();
+        let pool = ProtocolPool::new(spawn_server(tx), 2);
+
+        let mut a = pool.get().unwrap();
+        let b = pool.get().unwrap();
+        assert_eq!(pool.open_count(), 2);
+        a.send_message(&Request::Echo(String::from("Hello")))
+            .unwrap();
+        assert!(a.read_message_required::<Response>().unwrap().is_ok());
+
+        // Returned connections are reused rather than opening new ones
+        drop(a);
+        assert_eq!(pool.idle_count(), 1);
+        let c = pool.get().unwrap();
+        assert_eq!(pool.idle_count(), 0);
+        assert_eq!(pool.open_count(), 2);
+
+        // A discarded connection frees up its slot
+        b.discard();
+        assert_eq!(pool.open_count(), 1);
+        drop(c);
+
+        // Close the (now idle) connection from the server side, so the health check fails
+        // and a new connection is opened in its place
+        for stream in rx.try_iter() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        let _d = pool.get().unwrap();
+        assert_eq!(pool.open_count(), 1);
+        assert_eq!(pool.idle_count(), 0);
+    }
+}
@@ -0,0 +1,223 @@
+//! Priority queue of requests for worker threads, so cheap latency-sensitive requests (e.g.
+//! `Ping`) aren't stuck behind expensive ones (e.g. a large `Jumble`)
+//!
+//! Connection threads `push` requests (along with whatever a worker needs to answer them, e.g.
+//! a channel back to the connection) and workers `pop` the highest priority one waiting. Requests
+//! with the same priority are popped in the order they were pushed. `workers::WorkerPool` runs
+//! its jobs from one of these (see the server's `--workers`).
+
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::sync::{Condvar, Mutex};
+
+use crate::Request;
+
+/// How soon a request should be handled, relative to the others waiting
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// Requests that take a while to handle, or to send the response to
+    Low,
+    Normal,
+    /// Requests that are (nearly) free to handle, where the client is waiting on the latency
+    High,
+}
+
+/// The `Priority` for a request, by its type
+///
+/// A `Timed` request has the priority of the request it wraps, so timing a request doesn't
+/// change when it's handled
+pub fn request_priority(request: &Request) -> Priority {
+    match request {
+        Request::Ping | Request::Noop | Request::Goodbye | Request::MaxSize => Priority::High,
+        Request::Echo(_)
+        | Request::Stats(_)
+        | Request::Reflect(_)
+        | Request::Log(_)
+        | Request::Checksum(_)
+        | Request::Histogram(_)
+        | Request::KeyValues(_)
+        | Request::Split { .. }
+        | Request::Concat(_)
+        | Request::Xor { .. }
+        | Request::Unxor { .. } => Priority::Normal,
+        Request::Jumble { .. } | Request::Delay { .. } | Request::Repeat { .. } => Priority::Low,
+        Request::Timed(inner) => request_priority(inner),
+    }
+}
+
+/// A queue shared between threads, popping the highest `Priority` item first
+pub struct PriorityQueue<T> {
+    state: Mutex<QueueState<T>>,
+    /// Signalled when an item is pushed
+    available: Condvar,
+}
+
+struct QueueState<T> {
+    heap: BinaryHeap<Entry<T>>,
+    /// Incremented for each push, to keep items with the same priority in order
+    next_seq: u64,
+}
+
+struct Entry<T> {
+    priority: Priority,
+    seq: u64,
+    item: T,
+}
+
+impl<T> PartialEq for Entry<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<T> Eq for Entry<T> {}
+
+impl<T> PartialOrd for Entry<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for Entry<T> {
+    /// Higher priority first, then lower sequence numbers (i.e. pushed earlier) first, as
+    /// `BinaryHeap` pops the greatest entry
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority
+            .cmp(&other.priority)
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+impl<T> PriorityQueue<T> {
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::new(QueueState {
+                heap: BinaryHeap::new(),
+                next_seq: 0,
+            }),
+            available: Condvar::new(),
+        }
+    }
+
+    /// Queue `item` with `priority`, waking a worker blocked in `pop`
+    pub fn push(&self, priority: Priority, item: T) {
+        let mut state = self.state.lock().unwrap();
+        let seq = state.next_seq;
+        state.next_seq += 1;
+        state.heap.push(Entry {
+            priority,
+            seq,
+            item,
+        });
+        self.available.notify_one();
+    }
+
+    /// Remove the highest priority item, blocking until there is one
+    pub fn pop(&self) -> T {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(entry) = state.heap.pop() {
+                return entry.item;
+            }
+            state = self.available.wait(state).unwrap();
+        }
+    }
+
+    /// Remove the highest priority item, if there is one
+    pub fn try_pop(&self) -> Option<T> {
+        self.state
+            .lock()
+            .unwrap()
+            .heap
+            .pop()
+            .map(|entry| entry.item)
+    }
+
+    /// Number of items waiting
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().heap.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<T> Default for PriorityQueue<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::{mpsc, Arc};
+    use std::time::Duration;
+
+    fn jumble(amount: u16) -> Request {
+        Request::Jumble {
+            message: String::from("The quick brown fox"),
+            amount,
+        }
+    }
+
+    #[test]
+    fn test_request_priority() {
+        assert_eq!(request_priority(&Request::Ping), Priority::High);
+        assert_eq!(
+            request_priority(&Request::Echo(String::from("Hello"))),
+            Priority::Normal
+        );
+        assert_eq!(request_priority(&jumble(1000)), Priority::Low);
+        assert_eq!(
+            request_priority(&Request::Timed(Box::new(Request::Ping))),
+            Priority::High
+        );
+    }
+
+    #[test]
+    fn test_high_priority_processed_first() {
+        let queue = Arc::new(PriorityQueue::new());
+        for amount in 1..=3 {
+            let request = jumble(amount);
+            queue.push(request_priority(&request), request);
+        }
+        queue.push(request_priority(&Request::Ping), Request::Ping);
+        assert_eq!(queue.len(), 4);
+
+        // A single worker processing the queue in order
+        let (tx, rx) = mpsc::channel();
+        let worker = {
+            let queue = queue.clone();
+            std::thread::spawn(move || {
+                for _ in 0..4 {
+                    tx.send(queue.pop()).unwrap();
+                }
+            })
+        };
+        worker.join().unwrap();
+        let processed: Vec<Request> = rx.iter().collect();
+        assert!(matches!(processed[0], Request::Ping));
+        // The low priority Jumbles are still processed in the order they were queued
+        for (request, amount) in processed[1..].iter().zip(1..) {
+            assert!(matches!(request, Request::Jumble { amount: a, .. } if *a == amount));
+        }
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn test_pop_blocks_until_push() {
+        let queue = Arc::new(PriorityQueue::new());
+        assert!(queue.try_pop().is_none());
+        let pusher = {
+            let queue = queue.clone();
+            std::thread::spawn(move || {
+                std::thread::sleep(Duration::from_millis(50));
+                queue.push(Priority::Normal, 42);
+            })
+        };
+        assert_eq!(queue.pop(), 42);
+        pusher.join().unwrap();
+    }
+}
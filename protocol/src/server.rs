@@ -0,0 +1,374 @@
+//! A minimal server loop for embedding the protocol in other apps
+//!
+//! The `server` binary has many more options (framing, authentication, caching, ...),
+//! this is just the request/response loop with hooks for the application logic.
+
+use std::io;
+use std::net::{TcpListener, TcpStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::{
+    base64_decode, base64_encode, bytes_to_hex, char_histogram, check_jumble, crc32,
+    is_client_disconnect, jumble_message, panic_message, text_stats, xor_bytes, Protocol, Request,
+    Response, GOODBYE_ACK,
+};
+
+/// Put between the strings of a `Request::Concat`
+const CONCAT_SEPARATOR: &str = " ";
+
+/// Serve requests on `listener` forever, answering each one with `handler` (see
+/// `serve_with_validator`)
+pub fn serve<H>(listener: TcpListener, handler: H)
+where
+    H: Fn(&Request) -> Response + Send + Sync + 'static,
+{
+    serve_with_validator(listener, handler, |_| Ok(()))
+}
+
+/// Serve requests on `listener` forever, each connection in its own thread, rejecting the
+/// requests that `validator` returns an error for
+///
+/// Each request goes through, in order:
+/// - Deserialize
+/// - Keepalive `Ping`s are answered with `Pong` (and go no further)
+/// - A `Goodbye` is acknowledged, and the connection closed (see `Protocol::close`)
+/// - Validate: an `Err` is sent back as a `Response::Err` (with the validator's message)
+/// - Handle: `handler`'s Response is sent back (for a `Request::Repeat`, `count` times,
+///   `interval_ms` apart, unless it's an error)
+///
+/// So `handler` only sees valid requests, e.g. to reject messages over a length:
+/// ```no_run
+/// # use std::net::TcpListener;
+/// # use tcp_demo_protocol::{server::serve_with_validator, Response};
+/// let listener = TcpListener::bind("127.0.0.1:4000").unwrap();
+/// serve_with_validator(
+///     listener,
+///     |req| Response::Ok(req.message().to_uppercase()),
+///     |req| match req.message().len() {
+///         0..=100 => Ok(()),
+///         len => Err(format!("Message is {} bytes, the limit is 100", len)),
+///     },
+/// );
+/// ```
+///
+/// A panic in `handler` or `validator` closes that connection, and the server carries on.
+///
+/// Requests that don't expect a response (e.g. `Log`) are validated & handled the same way,
+/// but nothing is sent back, not even a validation error (see `Request::expects_response`).
+/// Neither is anything for a Repeat with a `count` of 0, so the validator should reject those
+/// (along with any too long or too frequent for the app)
+pub fn serve_with_validator<H, V>(listener: TcpListener, handler: H, validator: V)
+where
+    H: Fn(&Request) -> Response + Send + Sync + 'static,
+    V: Fn(&Request) -> Result<(), String> + Send + Sync + 'static,
+{
+    serve_with_hooks(listener, handler, validator, |_| Ok(true))
+}
+
+/// Like `serve_with_validator`, but first calling `on_accept` with each accepted connection,
+/// e.g. to log it, set socket options, or reject peers by address
+///
+/// Unless it returns `Ok(true)`, the connection is closed without reading anything from it
+/// (an `Err` is reported like any other connection error). It runs on the connection's own
+/// thread, so a slow callback doesn't hold up accepting others. E.g. to block an address:
+/// ```no_run
+/// # use std::net::{IpAddr, Ipv4Addr, TcpListener};
+/// # use tcp_demo_protocol::{server::serve_with_hooks, Response};
+/// let blocklist = [IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))];
+/// let listener = TcpListener::bind("0.0.0.0:4000").unwrap();
+/// serve_with_hooks(
+///     listener,
+///     |req| Response::Ok(req.message().to_string()),
+///     |_| Ok(()),
+///     move |stream| Ok(!blocklist.contains(&stream.peer_addr()?.ip())),
+/// );
+/// ```
+pub fn serve_with_hooks<H, V, A>(listener: TcpListener, handler: H, validator: V, on_accept: A)
+where
+    H: Fn(&Request) -> Response + Send + Sync + 'static,
+    V: Fn(&Request) -> Result<(), String> + Send + Sync + 'static,
+    A: Fn(&TcpStream) -> io::Result<bool> + Send + Sync + 'static,
+{
+    let handler = Arc::new(handler);
+    let validator = Arc::new(validator);
+    let on_accept = Arc::new(on_accept);
+    for stream in listener.incoming().flatten() {
+        let handler = handler.clone();
+        let validator = validator.clone();
+        let on_accept = on_accept.clone();
+        std::thread::spawn(move || {
+            let peer_addr = stream
+                .peer_addr()
+                .map_or_else(|_| String::from("unknown"), |addr| addr.to_string());
+            // A panicking handler only takes down its own connection (which is closed as the
+            // panic unwinds), and is reported along with which connection it was
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                if !on_accept(&stream)? {
+                    return Ok(());
+                }
+                Protocol::with_stream(stream)
+                    .and_then(|protocol| handle_connection(protocol, &*handler, &*validator))
+            }));
+            match result {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) if is_client_disconnect(&e) => {}
+                Ok(Err(e)) => eprintln!("Error: {} [{}]", e, peer_addr),
+                Err(panic) => eprintln!(
+                    "Error: handler panicked: {} [{}]",
+                    panic_message(&*panic),
+                    peer_addr
+                ),
+            }
+        });
+    }
+}
+
+/// The Response to a request that needs no server state or options, or `None` for the
+/// request types that do (`Delay`, `Split`, `MaxSize`, `Timed`, ...) and those without a
+/// single Response (`Log`, `Goodbye`, `Repeat`)
+///
+/// This is the application logic shared by the `server` & `ws-server` binaries, and it makes a
+/// handler for `serve` too:
+/// ```no_run
+/// # use std::net::TcpListener;
+/// # use tcp_demo_protocol::{server::{handle_stateless, serve}, Response};
+/// let listener = TcpListener::bind("127.0.0.1:4000").unwrap();
+/// serve(listener, |req| {
+///     handle_stateless(req).unwrap_or_else(|| Response::Err(String::from("Unsupported")))
+/// });
+/// ```
+pub fn handle_stateless(request: &Request) -> Option<Response> {
+    let resp = match request {
+        Request::Echo(message) => Response::Ok(format!("'{}' from the other side!", message)),
+        Request::Jumble { message, amount } => match check_jumble(message) {
+            Err(e) => Response::Err(e),
+            Ok(()) => Response::Ok(jumble_message(message, *amount)),
+        },
+        Request::Stats(message) => Response::Ok(text_stats(message)),
+        Request::Checksum(message) => Response::Ok(format!("{:08x}", crc32(message.as_bytes()))),
+        Request::Histogram(message) => Response::Segments(
+            char_histogram(message)
+                .into_iter()
+                .map(|(c, count)| (c.to_string(), count.to_string()))
+                .collect(),
+        ),
+        Request::Reflect(payload) => Response::Ok(bytes_to_hex(payload)),
+        Request::Concat(parts) => Response::Ok(parts.join(CONCAT_SEPARATOR)),
+        Request::KeyValues(pairs) => Response::Ok(
+            pairs
+                .iter()
+                .map(|(key, value)| format!("{}={}", key, value))
+                .collect::<Vec<_>>()
+                .join("\n"),
+        ),
+        Request::Xor { key, .. } | Request::Unxor { key, .. } if key.is_empty() => {
+            Response::Err(String::from("Xor key can't be empty"))
+        }
+        Request::Xor { message, key } => {
+            let encoded = base64_encode(&xor_bytes(message.as_bytes(), key.as_bytes()));
+            // Base64 is a third longer, so a long message's can outgrow the (u16) length prefix
+            if encoded.len() > u16::MAX as usize {
+                Response::Err(format!("Xor result is too long: {} bytes", encoded.len()))
+            } else {
+                Response::Ok(encoded)
+            }
+        }
+        Request::Unxor { message, key } => match base64_decode(message) {
+            Some(bytes) => match String::from_utf8(xor_bytes(&bytes, key.as_bytes())) {
+                Ok(decrypted) => Response::Ok(decrypted),
+                Err(_) => Response::Err(String::from(
+                    "Unxor result isn't valid UTF-8 (is it the right key?)",
+                )),
+            },
+            None => Response::Err(String::from("Unxor message isn't valid base64")),
+        },
+        Request::Noop => Response::Ok(String::new()),
+        Request::Ping => Response::Pong,
+        Request::Delay { .. }
+        | Request::Split { .. }
+        | Request::MaxSize
+        | Request::Timed(_)
+        | Request::Log(_)
+        | Request::Goodbye
+        | Request::Repeat { .. } => return None,
+    };
+    Some(resp)
+}
+
+/// Validate & handle requests until the client closes the connection
+fn handle_connection(
+    mut protocol: Protocol,
+    handler: &dyn Fn(&Request) -> Response,
+    validator: &dyn Fn(&Request) -> Result<(), String>,
+) -> io::Result<()> {
+    while let Some(request) = protocol.read_message::<Request>()? {
+        if let Request::Ping = request {
+            protocol.send_message(&Response::Pong)?;
+            continue;
+        }
+        if let Request::Goodbye = request {
+            return protocol.send_message(&Response::Ok(String::from(GOODBYE_ACK)));
+        }
+        let resp = match validator(&request) {
+            Ok(()) => handler(&request),
+            Err(message) => Response::Err(message),
+        };
+        if !request.expects_response() {
+            continue;
+        }
+        let (count, interval_ms) = match request {
+            Request::Repeat {
+                count, interval_ms, ..
+            } if resp.is_ok() => (count, interval_ms),
+            _ => (1, 0),
+        };
+        for i in 0..count {
+            if i > 0 {
+                std::thread::sleep(Duration::from_millis(interval_ms as u64));
+            }
+            protocol.send_message(&resp)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+    use socket2::{Domain, Socket, Type};
+
+    use super::*;
+
+    #[test]
+    fn test_validator_rejects_empty_messages() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            serve_with_validator(
+                listener,
+                |req| Response::Ok(format!("'{}' from the other side!", req.message())),
+                |req| match req.message() {
+                    "" => Err(String::from("Message is empty")),
+                    _ => Ok(()),
+                },
+            )
+        });
+
+        let mut client = Protocol::connect(addr).unwrap();
+        let mut exchange = |req: Request| {
+            client.send_message(&req).unwrap();
+            client.read_message_required::<Response>().unwrap()
+        };
+        assert_eq!(
+            exchange(Request::Echo(String::new())),
+            Response::Err(String::from("Message is empty"))
+        );
+        assert_eq!(
+            exchange(Request::Echo(String::from("Hello"))),
+            Response::Ok(String::from("'Hello' from the other side!"))
+        );
+        // Pings are answered before validation, so their empty message isn't rejected
+        assert_eq!(exchange(Request::Ping), Response::Pong);
+    }
+
+    #[test]
+    fn test_repeat_sends_the_response_count_times() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || serve(listener, |req| Response::Ok(req.message().to_string())));
+
+        let mut client = Protocol::connect(addr).unwrap();
+        client
+            .send_message(&Request::Repeat {
+                message: String::from("Hello"),
+                count: 3,
+                interval_ms: 10,
+            })
+            .unwrap();
+        client
+            .send_message(&Request::Echo(String::from("Bye")))
+            .unwrap();
+        let responses: Vec<Response> = (0..4)
+            .map(|_| client.read_message_required::<Response>().unwrap())
+            .collect();
+        assert_eq!(
+            responses[..3],
+            vec![Response::Ok(String::from("Hello")); 3][..]
+        );
+        assert_eq!(responses[3], Response::Ok(String::from("Bye")));
+    }
+
+    #[test]
+    fn test_on_accept_rejects_blocklisted_peers() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let blocked: IpAddr = Ipv4Addr::new(127, 0, 0, 2).into();
+        std::thread::spawn(move || {
+            serve_with_hooks(
+                listener,
+                |req| Response::Ok(req.message().to_string()),
+                |_| Ok(()),
+                move |stream| Ok(stream.peer_addr()?.ip() != blocked),
+            )
+        });
+        // Connect from a specific loopback address (the OS would pick 127.0.0.1)
+        let connect_from = |ip: IpAddr| {
+            let socket = Socket::new(Domain::IPV4, Type::STREAM, None).unwrap();
+            socket.bind(&SocketAddr::new(ip, 0).into()).unwrap();
+            socket.connect(&addr.into()).unwrap();
+            Protocol::with_stream(TcpStream::from(socket)).unwrap()
+        };
+
+        // The connection is closed without a response
+        let mut client = connect_from(blocked);
+        let _ = client.send_message(&Request::Echo(String::from("Hello")));
+        match client.read_message::<Response>() {
+            Ok(None) | Err(_) => {}
+            Ok(Some(resp)) => panic!("Unexpected response: {:?}", resp),
+        }
+
+        let mut client = connect_from(Ipv4Addr::LOCALHOST.into());
+        client
+            .send_message(&Request::Echo(String::from("Hello")))
+            .unwrap();
+        assert_eq!(
+            client.read_message_required::<Response>().unwrap(),
+            Response::Ok(String::from("Hello"))
+        );
+    }
+
+    #[test]
+    fn test_handler_panic_closes_only_its_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            serve(listener, |req| match req.message() {
+                "panic" => panic!("Handler bug"),
+                message => Response::Ok(message.to_string()),
+            })
+        });
+
+        let mut client = Protocol::connect(addr).unwrap();
+        client
+            .send_message(&Request::Echo(String::from("panic")))
+            .unwrap();
+        // The connection is closed without a response
+        match client.read_message::<Response>() {
+            Ok(None) | Err(_) => {}
+            Ok(Some(resp)) => panic!("Unexpected response: {:?}", resp),
+        }
+
+        // And the server still accepts (and handles) new connections
+        let mut client = Protocol::connect(addr).unwrap();
+        client
+            .send_message(&Request::Echo(String::from("Hello")))
+            .unwrap();
+        assert_eq!(
+            client.read_message_required::<Response>().unwrap(),
+            Response::Ok(String::from("Hello"))
+        );
+    }
+}
@@ -0,0 +1,180 @@
+//! Socket options that std's `TcpStream` doesn't expose, set via [socket2](https://docs.rs/socket2)
+
+use std::io;
+use std::net::TcpStream;
+use std::time::Duration;
+
+use socket2::{SockRef, TcpKeepalive};
+
+/// Kernel TCP keepalive (SO_KEEPALIVE) settings
+///
+/// This is the OS sending empty probe segments on an idle connection, which keeps NAT/firewall
+/// state alive and detects dead peers. It's invisible to the application (unlike a ping message).
+///
+/// Platform support varies:
+/// - `idle` is supported everywhere SO_KEEPALIVE is
+/// - `interval` is ignored on platforms without TCP_KEEPINTVL (e.g. OpenBSD)
+/// - `retries` is ignored on platforms without TCP_KEEPCNT (e.g. Windows, OpenBSD)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KeepaliveCfg {
+    /// How long the connection must be idle before the first probe is sent
+    pub idle: Duration,
+    /// Time between unanswered probes
+    pub interval: Option<Duration>,
+    /// Number of unanswered probes before the connection is considered dead
+    pub retries: Option<u32>,
+}
+
+impl KeepaliveCfg {
+    /// Keepalive after `idle` time, using the OS defaults for interval & retries
+    pub fn new(idle: Duration) -> Self {
+        Self {
+            idle,
+            interval: None,
+            retries: None,
+        }
+    }
+}
+
+/// Enable TCP keepalive on a stream with the given settings
+pub fn set_tcp_keepalive(stream: &TcpStream, cfg: KeepaliveCfg) -> io::Result<()> {
+    #[allow(unused_mut)]
+    let mut keepalive = TcpKeepalive::new().with_time(cfg.idle);
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos",
+        target_os = "ios",
+        target_os = "freebsd",
+        target_os = "windows"
+    ))]
+    if let Some(interval) = cfg.interval {
+        keepalive = keepalive.with_interval(interval);
+    }
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos",
+        target_os = "ios",
+        target_os = "freebsd"
+    ))]
+    if let Some(retries) = cfg.retries {
+        keepalive = keepalive.with_retries(retries);
+    }
+    SockRef::from(stream).set_tcp_keepalive(&keepalive)
+}
+
+/// A snapshot of the kernel's TCP state for a connection (from TCP_INFO)
+///
+/// The fields are a subset of Linux's `struct tcp_info` (see `linux/tcp.h` and `man 7 tcp`),
+/// copied as-is from the kernel, so times are in microseconds and windows are in segments.
+#[cfg(all(target_os = "linux", feature = "tcp-info"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TcpInfo {
+    /// Connection state (e.g. 1 = ESTABLISHED), as in `tcp_states.h`
+    pub state: u8,
+    /// Retransmits of the segment at the head of the queue (resets when it's acked)
+    pub retransmits: u8,
+    /// Total retransmits over the connection's lifetime
+    pub total_retrans: u32,
+    /// Smoothed round trip time (µs)
+    pub rtt_us: u32,
+    /// Round trip time variance (µs)
+    pub rtt_var_us: u32,
+    /// Retransmission timeout (µs)
+    pub rto_us: u32,
+    /// Congestion window (segments)
+    pub snd_cwnd: u32,
+    /// Slow start threshold (segments)
+    pub snd_ssthresh: u32,
+    /// Maximum segment size for sending
+    pub snd_mss: u32,
+    /// Maximum segment size for receiving (as estimated by the kernel)
+    pub rcv_mss: u32,
+    /// Segments sent but not yet acknowledged
+    pub unacked: u32,
+    /// Segments considered lost
+    pub lost: u32,
+    /// Path MTU
+    pub pmtu: u32,
+}
+
+/// Read the kernel's TCP state for a stream (Linux's TCP_INFO socket option)
+#[cfg(all(target_os = "linux", feature = "tcp-info"))]
+pub fn tcp_info(stream: &TcpStream) -> io::Result<TcpInfo> {
+    use std::os::unix::io::AsRawFd;
+
+    // SAFETY: `tcp_info` is plain integers, so all-zeroes is a valid value
+    let mut info: libc::tcp_info = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::tcp_info>() as libc::socklen_t;
+    // SAFETY: `info` & `len` are valid for writes and `len` is the size of `info`
+    // (older kernels fill in less of it, leaving the rest zeroed)
+    let ret = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_INFO,
+            &mut info as *mut libc::tcp_info as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(TcpInfo {
+        state: info.tcpi_state,
+        retransmits: info.tcpi_retransmits,
+        total_retrans: info.tcpi_total_retrans,
+        rtt_us: info.tcpi_rtt,
+        rtt_var_us: info.tcpi_rttvar,
+        rto_us: info.tcpi_rto,
+        snd_cwnd: info.tcpi_snd_cwnd,
+        snd_ssthresh: info.tcpi_snd_ssthresh,
+        snd_mss: info.tcpi_snd_mss,
+        rcv_mss: info.tcpi_rcv_mss,
+        unacked: info.tcpi_unacked,
+        lost: info.tcpi_lost,
+        pmtu: info.tcpi_pmtu,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_set_tcp_keepalive() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+
+        let cfg = KeepaliveCfg {
+            idle: Duration::from_secs(30),
+            interval: Some(Duration::from_secs(5)),
+            retries: Some(3),
+        };
+        set_tcp_keepalive(&stream, cfg).unwrap();
+
+        let sock = SockRef::from(&stream);
+        assert!(sock.keepalive().unwrap());
+        #[cfg(target_os = "linux")]
+        {
+            assert_eq!(sock.keepalive_time().unwrap(), Duration::from_secs(30));
+            assert_eq!(sock.keepalive_interval().unwrap(), Duration::from_secs(5));
+            assert_eq!(sock.keepalive_retries().unwrap(), 3);
+        }
+    }
+
+    #[cfg(all(target_os = "linux", feature = "tcp-info"))]
+    #[test]
+    fn test_tcp_info() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+
+        let info = tcp_info(&stream).unwrap();
+        // TCP_ESTABLISHED
+        assert_eq!(info.state, 1);
+        assert!(info.snd_mss > 0);
+        assert!(info.snd_cwnd > 0);
+    }
+}
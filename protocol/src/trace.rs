@@ -0,0 +1,158 @@
+//! Log every byte a stream reads & writes, for seeing exactly what goes over the wire
+
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::{bytes_to_hex, Stream};
+
+/// Bytes per line of the log
+const BYTES_PER_LINE: usize = 16;
+
+/// Stream wrapper that logs a hex dump of every byte read or written, e.g.:
+/// ```text
+/// write 00000000: 01 00 05 48 65 6c 6c 6f
+/// read  00000000: 01 00 1c 27 48 65 6c 6c 6f 27 20 66 72 6f 6d 20
+/// read  00000010: 74 68 65 20 6f 74 68 65 72 20 73 69 64 65 21
+/// ```
+///
+/// Each direction has its own offset (the position in that direction's bytes), and the log
+/// shows the bytes as each `read` or `write` call returned them, so it also shows how TCP
+/// happened to split up the messages.
+///
+/// Clones (see `Stream::try_clone`) share the log & offsets, so a `Protocol` can wrap one:
+/// `Protocol::with_stream(TraceStream::stderr(stream))`
+pub struct TraceStream<S> {
+    inner: S,
+    log: Arc<Mutex<dyn Write + Send>>,
+    read_offset: Arc<AtomicU64>,
+    write_offset: Arc<AtomicU64>,
+}
+
+impl<S> TraceStream<S> {
+    /// Wrap `inner`, logging its traffic to `log`
+    pub fn new(inner: S, log: impl Write + Send + 'static) -> Self {
+        Self {
+            inner,
+            log: Arc::new(Mutex::new(log)),
+            read_offset: Arc::new(AtomicU64::new(0)),
+            write_offset: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Wrap `inner`, logging its traffic to stderr
+    pub fn stderr(inner: S) -> Self {
+        Self::new(inner, io::stderr())
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    /// Log `bytes` as hex, starting at `offset` in this direction
+    fn log(&self, direction: &str, offset: &AtomicU64, bytes: &[u8]) {
+        let mut offset = offset.fetch_add(bytes.len() as u64, Ordering::Relaxed);
+        let mut log = self.log.lock().unwrap();
+        for line in bytes.chunks(BYTES_PER_LINE) {
+            // Tracing is best effort, it shouldn't break the connection
+            let _ = writeln!(
+                log,
+                "{:<5} {:08x}: {}",
+                direction,
+                offset,
+                bytes_to_hex(line)
+            );
+            offset += line.len() as u64;
+        }
+    }
+}
+
+impl<S: Read> Read for TraceStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.log("read", &self.read_offset, &buf[..len]);
+        Ok(len)
+    }
+}
+
+impl<S: Write> Write for TraceStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.log("write", &self.write_offset, &buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<S: Stream> Stream for TraceStream<S> {
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(Self {
+            inner: self.inner.try_clone()?,
+            log: self.log.clone(),
+            read_offset: self.read_offset.clone(),
+            write_offset: self.write_offset.clone(),
+        })
+    }
+
+    fn shutdown_write(&self) -> io::Result<()> {
+        self.inner.shutdown_write()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory::MemoryStream;
+    use crate::{Protocol, Request, Response};
+
+    /// Log that the test can read back after handing it to a `TraceStream`
+    #[derive(Clone, Default)]
+    struct SharedLog(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedLog {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_trace_stream_log() {
+        let (client_stream, server_stream) = MemoryStream::pair();
+        let log = SharedLog::default();
+        let mut client =
+            Protocol::with_stream(TraceStream::new(client_stream, log.clone())).unwrap();
+        let mut server = Protocol::with_stream(server_stream).unwrap();
+
+        client
+            .send_message(&Request::Echo(String::from("Hi")))
+            .unwrap();
+        server.read_message_required::<Request>().unwrap();
+        server
+            .send_message(&Response::Ok(String::from("'Hi' from the other side!")))
+            .unwrap();
+        client.read_message_required::<Response>().unwrap();
+        client
+            .send_message(&Request::Echo(String::from("Yo")))
+            .unwrap();
+
+        let log = String::from_utf8(log.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(
+            log,
+            "write 00000000: 01 00 02 48 69\n\
+             read  00000000: 01 00 19 27 48 69 27 20 66 72 6f 6d 20 74 68 65\n\
+             read  00000010: 20 6f 74 68 65 72 20 73 69 64 65 21\n\
+             write 00000005: 01 00 02 59 6f\n"
+        );
+    }
+}
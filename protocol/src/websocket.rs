@@ -0,0 +1,453 @@
+//! Minimal [WebSocket (RFC 6455)](https://datatracker.ietf.org/doc/html/rfc6455) framing, so
+//! browsers can exchange `Request`s & `Response`s with a server (see the `ws-server` binary)
+//!
+//! After an HTTP upgrade handshake (see `accept`), each message travels in one binary frame,
+//! with the usual serialized bytes as its payload:
+//! ```ignore
+//! |      u8      |       u8      |     u16 / u64     |  [u8; 4]   |  [u8]   |
+//! | FIN + opcode | MASK + length | (extended length) | (mask key) | payload |
+//! ```
+//!
+//! Only what a simple request/response exchange needs is implemented: messages split over
+//! several frames (fragmentation) and extensions are rejected, and frames are limited to
+//! `MAX_FRAME_SIZE`. Clients must mask their frames, so a server should close the connection
+//! (with status 1002, a protocol error) when `Frame::masked` isn't set, like `ws-server` does.
+
+use std::io::{self, Read, Write};
+
+use byteorder::{NetworkEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::Serialize;
+
+/// Largest frame payload `read_frame` accepts, since its length is read before the payload
+/// is allocated (a length of up to `u64::MAX` would otherwise be trusted)
+pub const MAX_FRAME_SIZE: u64 = 1 << 20;
+
+/// Largest handshake request `accept` reads, for the same reason
+const MAX_HANDSHAKE_SIZE: usize = 8 * 1024;
+
+/// Appended to the client's key before hashing it for the `Sec-WebSocket-Accept` header
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Kind of a WebSocket frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Opcode {
+    Text,
+    Binary,
+    Close,
+    Ping,
+    Pong,
+}
+
+impl Opcode {
+    fn from_u8(opcode: u8) -> io::Result<Self> {
+        match opcode {
+            0x1 => Ok(Opcode::Text),
+            0x2 => Ok(Opcode::Binary),
+            0x8 => Ok(Opcode::Close),
+            0x9 => Ok(Opcode::Ping),
+            0xA => Ok(Opcode::Pong),
+            0x0 => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Fragmented WebSocket messages aren't supported",
+            )),
+            opcode => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid WebSocket opcode {:#x}", opcode),
+            )),
+        }
+    }
+
+    fn is_control(self) -> bool {
+        matches!(self, Opcode::Close | Opcode::Ping | Opcode::Pong)
+    }
+}
+
+impl From<Opcode> for u8 {
+    fn from(opcode: Opcode) -> Self {
+        match opcode {
+            Opcode::Text => 0x1,
+            Opcode::Binary => 0x2,
+            Opcode::Close => 0x8,
+            Opcode::Ping => 0x9,
+            Opcode::Pong => 0xA,
+        }
+    }
+}
+
+/// A whole (unfragmented) frame, with its payload unmasked
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub opcode: Opcode,
+    pub payload: Vec<u8>,
+    /// Whether the sender masked the payload (which every frame from a client must be)
+    pub masked: bool,
+}
+
+/// Read the next frame, unmasking its payload if the sender masked it
+pub fn read_frame(buf: &mut impl Read) -> io::Result<Frame> {
+    let mut header = [0u8; 2];
+    buf.read_exact(&mut header)?;
+    let fin = header[0] & 0x80 != 0;
+    if header[0] & 0x70 != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "WebSocket extensions aren't supported",
+        ));
+    }
+    let opcode = Opcode::from_u8(header[0] & 0x0F)?;
+    if !fin {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Fragmented WebSocket messages aren't supported",
+        ));
+    }
+
+    let masked = header[1] & 0x80 != 0;
+    let length = match header[1] & 0x7F {
+        126 => buf.read_u16::<NetworkEndian>()? as u64,
+        127 => buf.read_u64::<NetworkEndian>()?,
+        length => length as u64,
+    };
+    if opcode.is_control() && length > 125 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "WebSocket control frame over 125 bytes",
+        ));
+    }
+    if length > MAX_FRAME_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "WebSocket frame of {} bytes is over the {} byte limit",
+                length, MAX_FRAME_SIZE
+            ),
+        ));
+    }
+    let mut mask = [0u8; 4];
+    if masked {
+        buf.read_exact(&mut mask)?;
+    }
+
+    let mut payload = vec![0u8; length as usize];
+    buf.read_exact(&mut payload)?;
+    if masked {
+        apply_mask(&mut payload, mask);
+    }
+    Ok(Frame {
+        opcode,
+        payload,
+        masked,
+    })
+}
+
+/// Write `payload` as a single frame
+///
+/// Clients must mask every frame they send (with a new random key each time), and servers
+/// must not mask theirs, so servers pass `None`
+pub fn write_frame(
+    buf: &mut impl Write,
+    opcode: Opcode,
+    payload: &[u8],
+    mask: Option<[u8; 4]>,
+) -> io::Result<()> {
+    // Serialize to a buffer first, so the frame goes out in a single `write`
+    let mut frame = Vec::with_capacity(14 + payload.len());
+    frame.push(0x80 | u8::from(opcode)); // FIN: the whole message is in this frame
+    let mask_bit = if mask.is_some() { 0x80 } else { 0 };
+    match payload.len() {
+        length @ 0..=125 => frame.push(mask_bit | length as u8),
+        length @ 126..=0xFFFF => {
+            frame.push(mask_bit | 126);
+            frame.write_u16::<NetworkEndian>(length as u16)?;
+        }
+        length => {
+            frame.push(mask_bit | 127);
+            frame.write_u64::<NetworkEndian>(length as u64)?;
+        }
+    }
+    let start = frame.len();
+    if let Some(mask) = mask {
+        frame.extend_from_slice(&mask);
+    }
+    frame.extend_from_slice(payload);
+    if let Some(mask) = mask {
+        apply_mask(&mut frame[start + 4..], mask);
+    }
+    buf.write_all(&frame)?;
+    buf.flush()
+}
+
+/// Serialize a message (e.g. a `Response`) and send it as one binary frame, from a server
+pub fn write_message(buf: &mut impl Write, message: &impl Serialize) -> io::Result<()> {
+    let mut bytes = vec![];
+    message.serialize(&mut bytes)?;
+    write_frame(buf, Opcode::Binary, &bytes, None)
+}
+
+/// XOR the payload with the mask key (masking & unmasking are the same operation)
+fn apply_mask(payload: &mut [u8], mask: [u8; 4]) {
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+}
+
+/// Complete the server side of the opening handshake: read the client's HTTP upgrade request
+/// and answer it with `101 Switching Protocols`
+///
+/// Requests that aren't a WebSocket upgrade get a `400 Bad Request` (and an `InvalidData`
+/// error). The request is read a byte at a time, so nothing after it is consumed
+pub fn accept(stream: &mut (impl Read + Write)) -> io::Result<()> {
+    let request = read_handshake(stream)?;
+    let mut lines = request.split("\r\n");
+    let is_get = lines.next().is_some_and(|line| line.starts_with("GET "));
+    let mut upgrade = false;
+    let mut key = None;
+    for (name, value) in lines.filter_map(|line| line.split_once(':')) {
+        let value = value.trim();
+        match name.trim().to_ascii_lowercase().as_str() {
+            "upgrade" => upgrade = value.eq_ignore_ascii_case("websocket"),
+            "sec-websocket-key" => key = Some(value),
+            _ => {}
+        }
+    }
+    let key = match key {
+        Some(key) if is_get && upgrade => key,
+        _ => {
+            stream.write_all(b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n")?;
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Not a WebSocket upgrade request",
+            ));
+        }
+    };
+    let response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(key)
+    );
+    stream.write_all(response.as_bytes())?;
+    stream.flush()
+}
+
+/// Read the HTTP request up to (and including) the blank line after its headers
+fn read_handshake(stream: &mut impl Read) -> io::Result<String> {
+    let mut request = vec![];
+    while !request.ends_with(b"\r\n\r\n") {
+        if request.len() >= MAX_HANDSHAKE_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "WebSocket handshake is too long",
+            ));
+        }
+        request.push(stream.read_u8()?);
+    }
+    String::from_utf8(request)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid utf8"))
+}
+
+/// The `Sec-WebSocket-Accept` header value for a client's `Sec-WebSocket-Key`, proving the
+/// server understood the handshake: base64(SHA-1(key + GUID))
+pub fn accept_key(key: &str) -> String {
+    crate::base64_encode(&sha1(format!("{}{}", key, ACCEPT_GUID).as_bytes()))
+}
+
+/// [SHA-1](https://datatracker.ietf.org/doc/html/rfc3174), which the handshake requires
+///
+/// SHA-1 is broken for anything security related, but here it only shows that the server
+/// speaks WebSocket
+fn sha1(message: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [
+        0x6745_2301,
+        0xEFCD_AB89,
+        0x98BA_DCFE,
+        0x1032_5476,
+        0xC3D2_E1F0,
+    ];
+    // Pad to a multiple of 64 bytes: a 1 bit, zeros, then the length in bits
+    let mut padded = message.to_vec();
+    padded.push(0x80);
+    while padded.len() % 64 != 56 {
+        padded.push(0);
+    }
+    padded.extend_from_slice(&(message.len() as u64 * 8).to_be_bytes());
+
+    for block in padded.chunks(64) {
+        let mut w = [0u32; 80];
+        for (i, word) in block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A82_7999),
+                20..=39 => (b ^ c ^ d, 0x6ED9_EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1B_BCDC),
+                _ => (b ^ c ^ d, 0xCA62_C1D6),
+            };
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        for (h, value) in h.iter_mut().zip([a, b, c, d, e]) {
+            *h = h.wrapping_add(value);
+        }
+    }
+
+    let mut digest = [0u8; 20];
+    for (chunk, word) in digest.chunks_mut(4).zip(h) {
+        chunk.copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory::MemoryStream;
+    use crate::{Deserialize, Request, Response};
+
+    #[test]
+    fn test_sha1() {
+        let hex = |digest: [u8; 20]| crate::bytes_to_hex(&digest).replace(' ', "");
+        assert_eq!(
+            hex(sha1(b"abc")),
+            "a9993e364706816aba3e25717850c26c9cd0d89d"
+        );
+        assert_eq!(hex(sha1(b"")), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+        // Over one block
+        assert_eq!(
+            hex(sha1(
+                b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"
+            )),
+            "84983e441c3bd26ebaae4aa1f95129e5e54670f1"
+        );
+    }
+
+    #[test]
+    fn test_accept_key() {
+        // The example from RFC 6455
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn test_read_frame() {
+        // The masked "Hello" example from RFC 6455
+        let bytes = [
+            0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58,
+        ];
+        let frame = read_frame(&mut &bytes[..]).unwrap();
+        assert_eq!(frame.opcode, Opcode::Text);
+        assert_eq!(frame.payload, b"Hello");
+        assert!(frame.masked);
+
+        // And unmasked
+        let bytes = [0x81, 0x05, 0x48, 0x65, 0x6c, 0x6c, 0x6f];
+        assert_eq!(
+            read_frame(&mut &bytes[..]).unwrap(),
+            Frame {
+                masked: false,
+                ..frame
+            }
+        );
+
+        // The first fragment of a message
+        let bytes = [0x01, 0x03, 0x48, 0x65, 0x6c];
+        let err = read_frame(&mut &bytes[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+
+        // A length that's never allocated
+        let mut bytes = vec![0x82, 127];
+        bytes.extend_from_slice(&u64::MAX.to_be_bytes());
+        let err = read_frame(&mut &bytes[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_write_frame_roundtrip() {
+        // Each length encoding: 7 bit, 16 bit & 64 bit
+        for (length, header_len) in [(5, 2), (126, 4), (0xFFFF, 4), (0x10000, 10)] {
+            let payload: Vec<u8> = (0..length).map(|i| i as u8).collect();
+            for mask in [None, Some([1, 2, 3, 4])] {
+                let mut bytes = vec![];
+                write_frame(&mut bytes, Opcode::Binary, &payload, mask).unwrap();
+                let mask_len = if mask.is_some() { 4 } else { 0 };
+                assert_eq!(bytes.len(), header_len + mask_len + length);
+
+                let frame = read_frame(&mut &bytes[..]).unwrap();
+                assert_eq!(frame.opcode, Opcode::Binary);
+                assert_eq!(frame.payload, payload);
+                assert_eq!(frame.masked, mask.is_some());
+            }
+        }
+
+        // A Response is the frame's whole payload
+        let mut bytes = vec![];
+        write_message(&mut bytes, &Response::Ok(String::from("Hi"))).unwrap();
+        assert_eq!(bytes, [0x82, 5, 1, 0, 2, b'H', b'i']);
+        let frame = read_frame(&mut &bytes[..]).unwrap();
+        let resp = Response::deserialize_exact(&mut &frame.payload[..]).unwrap();
+        assert_eq!(resp, Response::Ok(String::from("Hi")));
+    }
+
+    #[test]
+    fn test_accept_handshake() {
+        let (mut client, mut server) = MemoryStream::pair();
+        client
+            .write_all(
+                b"GET /chat HTTP/1.1\r\n\
+                  Host: 127.0.0.1:4080\r\n\
+                  Upgrade: websocket\r\n\
+                  Connection: Upgrade\r\n\
+                  Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                  Sec-WebSocket-Version: 13\r\n\r\n",
+            )
+            .unwrap();
+        // The first frame, sent right after the handshake
+        let mut req = vec![];
+        Request::Echo(String::from("Hi"))
+            .serialize(&mut req)
+            .unwrap();
+        write_frame(&mut client, Opcode::Binary, &req, Some([9, 8, 7, 6])).unwrap();
+
+        accept(&mut server).unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert_eq!(
+            response,
+            "HTTP/1.1 101 Switching Protocols\r\n\
+             Upgrade: websocket\r\n\
+             Connection: Upgrade\r\n\
+             Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\r\n"
+        );
+        // The frame wasn't consumed by the handshake
+        assert_eq!(read_frame(&mut server).unwrap().payload, req);
+
+        // Plain HTTP
+        let (mut client, mut server) = MemoryStream::pair();
+        client
+            .write_all(b"GET / HTTP/1.1\r\nHost: 127.0.0.1:4080\r\n\r\n")
+            .unwrap();
+        let err = accept(&mut server).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request"));
+    }
+}
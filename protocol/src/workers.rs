@@ -0,0 +1,226 @@
+//! Fixed pool of worker threads, running jobs from a `PriorityQueue` (see the server's
+//! `--workers`)
+//!
+//! Unlike a thread per connection, the pool bounds how many requests are handled at once no
+//! matter how many clients are connected. When they're all busy, the next free worker takes the
+//! most urgent job waiting (by `Priority`), not the oldest, so a `Ping` isn't stuck behind a
+//! queue of large `Jumble`s.
+//!
+//! Pipelined clients match Responses to requests by their order, though, which running one
+//! connection's requests concurrently (or by priority) would break. So each job has a key (e.g.
+//! a connection ID), and the jobs with the same key run one at a time, in the order they were
+//! submitted: a job only joins the priority queue once the previous one with its key is done.
+//! Jobs with different keys run in parallel, on any free worker.
+
+use std::collections::hash_map::{Entry, HashMap};
+use std::collections::VecDeque;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+
+use crate::panic_message;
+use crate::priority::{Priority, PriorityQueue};
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// What a worker pops off the queue
+enum Task {
+    Run(u64, Job),
+    /// End the worker's loop (see `WorkerPool`'s `Drop`)
+    Stop,
+}
+
+/// State shared by the pool and its workers
+struct Shared {
+    queue: PriorityQueue<Task>,
+    /// The keys with a job queued or running, each with the jobs submitted after it (which
+    /// are queued in turn as each one finishes)
+    keys: Mutex<HashMap<u64, VecDeque<(Priority, Job)>>>,
+    /// Signalled when the last key is done, i.e. every job submitted has run
+    idle: Condvar,
+}
+
+impl Shared {
+    /// Queue the next job for `key` now its previous one is done, if there is one
+    fn finish(&self, key: u64) {
+        let mut keys = self.keys.lock().unwrap();
+        match keys.get_mut(&key).and_then(VecDeque::pop_front) {
+            Some((priority, job)) => self.queue.push(priority, Task::Run(key, job)),
+            None => {
+                keys.remove(&key);
+                if keys.is_empty() {
+                    self.idle.notify_all();
+                }
+            }
+        }
+    }
+}
+
+/// Worker threads taking the highest priority job waiting, running the jobs with the same key
+/// one at a time (see the module docs)
+///
+/// Dropping the pool waits for the jobs already submitted to finish
+pub struct WorkerPool {
+    shared: Arc<Shared>,
+    handles: Vec<JoinHandle<()>>,
+}
+
+impl WorkerPool {
+    /// Start `workers` worker threads
+    pub fn new(workers: usize) -> Self {
+        assert!(workers > 0, "WorkerPool needs at least 1 worker");
+        let shared = Arc::new(Shared {
+            queue: PriorityQueue::new(),
+            keys: Mutex::new(HashMap::new()),
+            idle: Condvar::new(),
+        });
+        let handles = (0..workers)
+            .map(|_| {
+                let shared = shared.clone();
+                std::thread::spawn(move || {
+                    while let Task::Run(key, job) = shared.queue.pop() {
+                        // A panicking job shouldn't take its worker (or the jobs after it) with it
+                        if let Err(panic) = panic::catch_unwind(AssertUnwindSafe(job)) {
+                            eprintln!("Worker job panicked: {}", panic_message(&*panic));
+                        }
+                        shared.finish(key);
+                    }
+                })
+            })
+            .collect();
+        Self { shared, handles }
+    }
+
+    /// Queue `job`, to run after the jobs already submitted with `key`, once a worker is free
+    /// and there's no higher priority job waiting
+    pub fn execute(&self, key: u64, priority: Priority, job: impl FnOnce() + Send + 'static) {
+        let job: Job = Box::new(job);
+        match self.shared.keys.lock().unwrap().entry(key) {
+            // Queued by `finish` when the job before it is done
+            Entry::Occupied(mut waiting) => waiting.get_mut().push_back((priority, job)),
+            Entry::Vacant(entry) => {
+                entry.insert(VecDeque::new());
+                self.shared.queue.push(priority, Task::Run(key, job));
+            }
+        }
+    }
+}
+
+impl Drop for WorkerPool {
+    fn drop(&mut self) {
+        let current = std::thread::current().id();
+        // A job holding the last reference to the pool can't wait for itself to finish
+        let in_worker = self.handles.iter().any(|h| h.thread().id() == current);
+        if !in_worker {
+            // Jobs waiting on an earlier one with their key aren't in the queue yet, so wait for
+            // them all to run before telling the workers to stop
+            let mut keys = self.shared.keys.lock().unwrap();
+            while !keys.is_empty() {
+                keys = self.shared.idle.wait(keys).unwrap();
+            }
+        }
+        for _ in 0..self.handles.len() {
+            self.shared.queue.push(Priority::Low, Task::Stop);
+        }
+        for handle in self.handles.drain(..) {
+            if handle.thread().id() != current {
+                let _ = handle.join();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::mpsc;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_busy_pool_runs_high_priority_first() {
+        let handled = Arc::new(Mutex::new(Vec::new()));
+        {
+            let pool = WorkerPool::new(1);
+            // Keep the only worker busy while the other jobs are queued
+            let (started_tx, started_rx) = mpsc::channel();
+            pool.execute(0, Priority::Normal, move || {
+                started_tx.send(()).unwrap();
+                std::thread::sleep(Duration::from_millis(100));
+            });
+            started_rx.recv().unwrap();
+            for (key, priority, name) in [
+                (1, Priority::Low, "jumble 1"),
+                (2, Priority::Low, "jumble 2"),
+                (3, Priority::High, "ping"),
+                (4, Priority::Normal, "echo"),
+            ] {
+                let handled = handled.clone();
+                pool.execute(key, priority, move || handled.lock().unwrap().push(name));
+            }
+            // Dropping the pool waits for the jobs
+        }
+        assert_eq!(
+            *handled.lock().unwrap(),
+            ["ping", "echo", "jumble 1", "jumble 2"]
+        );
+    }
+
+    #[test]
+    fn test_jobs_with_the_same_key_run_in_order() {
+        let handled: Arc<Mutex<Vec<(u64, u32)>>> = Arc::default();
+        {
+            let pool = WorkerPool::new(4);
+            for seq in 0..20 {
+                for conn in 0..8 {
+                    let handled = handled.clone();
+                    // Later jobs are more urgent, and take less time, so they'd finish first if
+                    // they didn't wait for the earlier ones
+                    let priority = match seq {
+                        0..=9 => Priority::Low,
+                        _ => Priority::High,
+                    };
+                    pool.execute(conn, priority, move || {
+                        std::thread::sleep(Duration::from_micros(((20 - seq) * 50) as u64));
+                        handled.lock().unwrap().push((conn, seq));
+                    });
+                }
+            }
+        }
+        let handled = handled.lock().unwrap();
+        assert_eq!(handled.len(), 20 * 8);
+        for conn in 0..8 {
+            let order: Vec<u32> = handled
+                .iter()
+                .filter(|(c, _)| *c == conn)
+                .map(|(_, seq)| *seq)
+                .collect();
+            assert_eq!(order, (0..20).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn test_different_keys_run_in_parallel() {
+        let pool = WorkerPool::new(2);
+        let (tx, rx) = mpsc::channel();
+        let start = Instant::now();
+        for conn in 0..2 {
+            let tx = tx.clone();
+            pool.execute(conn, Priority::Normal, move || {
+                std::thread::sleep(Duration::from_millis(200));
+                tx.send(conn).unwrap();
+            });
+        }
+        drop(tx);
+        assert_eq!(rx.iter().count(), 2);
+        assert!(start.elapsed() < Duration::from_millis(400));
+    }
+
+    #[test]
+    fn test_panicking_job_keeps_worker_running() {
+        let pool = WorkerPool::new(1);
+        let (tx, rx) = mpsc::channel();
+        pool.execute(0, Priority::Normal, || panic!("Job failed"));
+        pool.execute(0, Priority::Normal, move || tx.send(42).unwrap());
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), 42);
+    }
+}
@@ -0,0 +1,38 @@
+//! Run the server binary with `--cache-size`, to check the sizes it accepts
+
+mod common;
+
+use std::process::Command;
+
+use tcp_demo_protocol::{Request, Response};
+
+use common::{connect, start_server_with_args};
+
+#[test]
+fn test_cache_size_zero_rejected() {
+    // Rejected when parsing the arguments, rather than panicking when creating the cache
+    let output = Command::new(env!("CARGO_BIN_EXE_server"))
+        .args(["--addr", "127.0.0.1:0", "--cache-size", "0"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("--cache-size"), "{}", stderr);
+    assert!(!stderr.contains("panicked"), "{}", stderr);
+}
+
+#[test]
+fn test_cache_size_one() {
+    let (_server, addr) = start_server_with_args(&["--cache-size", "1"]);
+    let mut client = connect(addr);
+    // The second is answered from the cache, the same as the first
+    for _ in 0..2 {
+        client
+            .send_message(&Request::Echo(String::from("Hello")))
+            .unwrap();
+        assert_eq!(
+            client.read_message_required::<Response>().unwrap(),
+            Response::Ok(String::from("'Hello' from the other side!"))
+        );
+    }
+}
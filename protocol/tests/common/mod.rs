@@ -0,0 +1,47 @@
+//! Helpers shared by the integration tests, for running the server binary
+
+// Each test file is its own crate, and not all of them use every helper
+#![allow(dead_code)]
+
+use std::net::{SocketAddr, TcpListener};
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+use tcp_demo_protocol::{retry_with_backoff, ConstantBackoff, Protocol};
+
+/// Kills the server process when the test ends (even if it panics)
+pub struct Server(pub Child);
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+pub fn start_server() -> (Server, SocketAddr) {
+    start_server_with_args(&[])
+}
+
+/// Start the server with extra command line arguments, e.g. `&["--framed"]`
+pub fn start_server_with_args(args: &[&str]) -> (Server, SocketAddr) {
+    // Find a free port for the server to bind to
+    let addr = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let child = Command::new(env!("CARGO_BIN_EXE_server"))
+        .args(["--addr", &addr.to_string()])
+        .args(args)
+        .stderr(Stdio::null())
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+    (Server(child), addr)
+}
+
+pub fn connect(addr: SocketAddr) -> Protocol {
+    // Give the server a moment (up to ~5s) to start listening
+    let mut backoff = ConstantBackoff::new(Duration::from_millis(20), 250);
+    retry_with_backoff(&mut backoff, || Protocol::connect(addr)).unwrap()
+}
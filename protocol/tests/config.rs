@@ -0,0 +1,71 @@
+//! Run the server binary with a `--config` file, to check the file's options are used unless
+//! the command line gives them too (or turns them off)
+#![cfg(feature = "config")]
+
+mod common;
+
+use std::net::TcpListener;
+
+use tcp_demo_protocol::{Request, Response};
+
+use common::{connect, start_server_with_args};
+
+/// Write a config file, named `name` in the temp directory, returning its path
+fn write_config(name: &str, contents: &str) -> String {
+    let path = std::env::temp_dir().join(format!("tcp_demo_{}_{}", std::process::id(), name));
+    std::fs::write(&path, contents).unwrap();
+    path.to_str().unwrap().to_string()
+}
+
+#[test]
+fn test_config_file_and_cli_precedence() {
+    // The file's address is never listened on, since the command line's takes precedence
+    let unused_addr = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    for (name, contents) in [
+        (
+            "server.toml",
+            format!("addr = \"{}\"\nframed = true # required\n", unused_addr),
+        ),
+        (
+            "server.json",
+            format!(r#"{{"addr": "{}", "framed": true}}"#, unused_addr),
+        ),
+    ] {
+        let path = write_config(name, &contents);
+        let (_server, addr) = start_server_with_args(&["--config", &path]);
+        let mut client = connect(addr);
+        // The server only understands framed requests, from the file
+        client.set_framed(true);
+        client
+            .send_message(&Request::Echo(String::from("Hello")))
+            .unwrap();
+        let resp = client.read_message_required::<Response>();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            resp.unwrap(),
+            Response::Ok(String::from("'Hello' from the other side!")),
+            "{}",
+            name
+        );
+    }
+}
+
+#[test]
+fn test_config_flag_turned_off_on_cli() {
+    let path = write_config("no_framed.toml", "framed = true\n");
+    let (_server, addr) = start_server_with_args(&["--config", &path, "--no-framed"]);
+    let mut client = connect(addr);
+    // The command line turned off the file's `framed`, so unframed requests are understood
+    client
+        .send_message(&Request::Echo(String::from("Hello")))
+        .unwrap();
+    let resp = client.read_message_required::<Response>();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(
+        resp.unwrap(),
+        Response::Ok(String::from("'Hello' from the other side!"))
+    );
+}
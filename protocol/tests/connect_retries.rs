@@ -0,0 +1,65 @@
+//! Start the client binary before the server, with `--connect-retries` to wait for it
+
+mod common;
+
+use std::net::TcpListener;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+use common::Server;
+
+#[test]
+fn test_client_retries_until_the_server_is_up() {
+    // Find a free port for the server to bind to (once the client is already trying it)
+    let addr = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .to_string();
+    let client = Command::new(env!("CARGO_BIN_EXE_client"))
+        .args(["--addr", &addr, "--connect-retries", "10", "Hello"])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    thread::sleep(Duration::from_millis(200));
+    let _server = Server(
+        Command::new(env!("CARGO_BIN_EXE_server"))
+            .args(["--addr", &addr])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap(),
+    );
+
+    let output = client.wait_with_output().unwrap();
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "'Hello' from the other side!\n"
+    );
+}
+
+#[test]
+fn test_client_gives_up_after_its_retries() {
+    // Nothing is listening on this port
+    let addr = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .to_string();
+    let output = Command::new(env!("CARGO_BIN_EXE_client"))
+        .args([
+            "--addr",
+            &addr,
+            "--connect-retries",
+            "2",
+            "--json-errors",
+            "Hello",
+        ])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2), "{:?}", output);
+}
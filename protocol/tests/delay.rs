@@ -0,0 +1,47 @@
+//! Run the server binary and check `Request::Delay` over a real connection
+
+mod common;
+
+use std::io;
+use std::time::{Duration, Instant};
+
+use tcp_demo_protocol::{ProtocolBuilder, Request, Response};
+
+use common::{connect, start_server};
+
+#[test]
+fn test_delay_request_timing() {
+    let (_server, addr) = start_server();
+    let mut client = connect(addr);
+
+    let start = Instant::now();
+    client
+        .send_message(&Request::Delay {
+            message: String::from("Hello"),
+            ms: 200,
+        })
+        .unwrap();
+    let resp = client.read_message_required::<Response>().unwrap();
+    assert_eq!(
+        resp,
+        Response::Ok(String::from("'Hello' from the other side!"))
+    );
+    assert!(start.elapsed() >= Duration::from_millis(200));
+
+    // A client with a read timeout shorter than the delay gives up waiting
+    let mut impatient = ProtocolBuilder::new()
+        .read_timeout(Duration::from_millis(50))
+        .connect(addr)
+        .unwrap();
+    impatient
+        .send_message(&Request::Delay {
+            message: String::from("Hello"),
+            ms: 500,
+        })
+        .unwrap();
+    let err = impatient.read_message_required::<Response>().unwrap_err();
+    assert!(matches!(
+        err.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    ));
+}
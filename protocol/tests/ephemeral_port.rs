@@ -0,0 +1,47 @@
+//! Run the server binary on any free port (`:0`), and find the port it got from its output
+
+mod common;
+
+use std::io::{BufRead, BufReader};
+use std::net::SocketAddr;
+use std::process::{Command, Stdio};
+
+use tcp_demo_protocol::{Protocol, Request, Response};
+
+use common::Server;
+
+#[test]
+fn test_server_prints_bound_port() {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_server"))
+        .args(["--addr", "127.0.0.1:0"])
+        .stderr(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+    let stderr = child.stderr.take().unwrap();
+    let _server = Server(child);
+
+    // e.g. "Starting server on '127.0.0.1:43567'"
+    let mut lines = BufReader::new(stderr).lines().map(Result::unwrap);
+    let line = lines
+        .find(|line| line.starts_with("Starting server on"))
+        .unwrap();
+    let addr: SocketAddr = line
+        .trim_start_matches("Starting server on '")
+        .trim_end_matches('\'')
+        .parse()
+        .unwrap();
+    // Keep reading, since the server fails to log (and panics) if stderr is closed
+    std::thread::spawn(move || lines.for_each(drop));
+    assert_ne!(addr.port(), 0);
+
+    // The server is already listening by the time it prints the address
+    let mut client = Protocol::connect(addr).unwrap();
+    client
+        .send_message(&Request::Echo(String::from("Hello")))
+        .unwrap();
+    assert_eq!(
+        client.read_message_required::<Response>().unwrap(),
+        Response::Ok(String::from("'Hello' from the other side!"))
+    );
+}
@@ -0,0 +1,40 @@
+//! Run the server binary and send it one-shot requests with `exchange`
+
+mod common;
+
+use std::io;
+use std::time::Duration;
+
+use tcp_demo_protocol::{exchange, retry_with_backoff, ConstantBackoff, Request, Response};
+
+use common::start_server;
+
+#[test]
+fn test_exchange() {
+    let (_server, addr) = start_server();
+    let req = Request::Echo(String::from("Hello"));
+    // Give the server a moment (up to ~5s) to start listening
+    let mut backoff = ConstantBackoff::new(Duration::from_millis(20), 250);
+    let resp = retry_with_backoff(&mut backoff, || exchange(addr, &req)).unwrap();
+    assert_eq!(
+        resp,
+        Response::Ok(String::from("'Hello' from the other side!"))
+    );
+
+    // Each exchange is its own connection
+    let req = Request::Stats(String::from("one two"));
+    assert!(exchange(addr, &req).unwrap().is_ok());
+
+    // More than one response
+    let req = Request::Repeat {
+        message: String::from("Hello"),
+        count: 2,
+        interval_ms: 0,
+    };
+    let err = exchange(addr, &req).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+    // No response at all
+    let err = exchange(addr, &Request::Log(String::from("Hello"))).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+}
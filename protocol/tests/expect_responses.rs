@@ -0,0 +1,52 @@
+//! Run the client binary with `--expect-responses`, to check it prints every response the
+//! server sends to one request
+
+mod common;
+
+use std::process::Command;
+
+use common::{connect, start_server};
+
+/// Run the client with a `--repeat 3` request and these extra args, returning its stdout
+fn client_repeat(addr: std::net::SocketAddr, args: &[&str]) -> String {
+    // Wait for the server to be up before running the client
+    drop(connect(addr));
+    let output = Command::new(env!("CARGO_BIN_EXE_client"))
+        .args([
+            "--addr",
+            &addr.to_string(),
+            "--repeat",
+            "3",
+            "--interval-ms",
+            "0",
+        ])
+        .args(args)
+        .arg("Hello")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{:?}", output);
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn test_expect_responses_until_eof() {
+    let (_server, addr) = start_server();
+    assert_eq!(
+        client_repeat(addr, &["--expect-responses", "until-eof"]),
+        "Hello (1/3)\nHello (2/3)\nHello (3/3)\n"
+    );
+}
+
+#[test]
+fn test_expect_responses_count() {
+    let (_server, addr) = start_server();
+    assert_eq!(
+        client_repeat(addr, &["--expect-responses", "3"]),
+        "Hello (1/3)\nHello (2/3)\nHello (3/3)\n"
+    );
+    // Fewer than were sent, the rest are left unread
+    assert_eq!(
+        client_repeat(addr, &["--expect-responses", "2"]),
+        "Hello (1/3)\nHello (2/3)\n"
+    );
+}
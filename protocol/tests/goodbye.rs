@@ -0,0 +1,44 @@
+//! Run the server binary and check the `Goodbye` handshake that closes a connection gracefully
+
+mod common;
+
+use tcp_demo_protocol::{Request, Response, GOODBYE_ACK};
+
+use common::{connect, start_server};
+
+#[test]
+fn test_goodbye_handshake() {
+    let (_server, addr) = start_server();
+    let mut client = connect(addr);
+
+    // Answered before the acknowledgement, which comes before the server closes its end
+    client
+        .send_message(&Request::Echo(String::from("Hello")))
+        .unwrap();
+    client.send_message(&Request::Goodbye).unwrap();
+    assert_eq!(
+        client.read_message_required::<Response>().unwrap(),
+        Response::Ok(String::from("'Hello' from the other side!"))
+    );
+    assert_eq!(
+        client.read_message_required::<Response>().unwrap(),
+        Response::Ok(String::from(GOODBYE_ACK))
+    );
+    assert!(client.read_message::<Response>().unwrap().is_none());
+
+    // And all in one with `close`
+    let mut client = connect(addr);
+    client.send_message(&Request::Noop).unwrap();
+    assert!(client.read_message_required::<Response>().unwrap().is_ok());
+    client.close().unwrap();
+
+    // A Response the client didn't read first is in the way of the acknowledgement
+    let mut client = connect(addr);
+    client
+        .send_message(&Request::Echo(String::from("Hello")))
+        .unwrap();
+    let err = client.close().unwrap_err();
+    assert!(err
+        .to_string()
+        .starts_with("Unexpected response to Goodbye"));
+}
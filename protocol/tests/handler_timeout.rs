@@ -0,0 +1,42 @@
+//! Run the server binary with `--handler-timeout-ms` and check a slow request gets an error
+
+mod common;
+
+use std::time::{Duration, Instant};
+
+use tcp_demo_protocol::{Request, Response};
+
+use common::{connect, start_server_with_args};
+
+#[test]
+fn test_handler_timeout() {
+    let (_server, addr) = start_server_with_args(&["--handler-timeout-ms", "100"]);
+    let mut client = connect(addr);
+
+    let start = Instant::now();
+    client
+        .send_message(&Request::Delay {
+            message: String::from("Hello"),
+            ms: 500,
+        })
+        .unwrap();
+    let resp = client.read_message_required::<Response>().unwrap();
+    assert_eq!(
+        resp,
+        Response::Err(String::from("Handler timed out, closing the connection"))
+    );
+    assert!(start.elapsed() < Duration::from_millis(500));
+    // Closed, since the handler is still running
+    assert!(client.read_message::<Response>().unwrap().is_none());
+
+    // Other connections are fine, and fast requests aren't affected
+    let mut client = connect(addr);
+    client
+        .send_message(&Request::Echo(String::from("Hello")))
+        .unwrap();
+    let resp = client.read_message_required::<Response>().unwrap();
+    assert_eq!(
+        resp,
+        Response::Ok(String::from("'Hello' from the other side!"))
+    );
+}
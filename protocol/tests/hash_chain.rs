@@ -0,0 +1,81 @@
+//! Run the server binary with `--hash-chain` and check its hashes match the client's own chain
+
+mod common;
+
+use tcp_demo_protocol::chain::{response_chain_hash, HashChain};
+use tcp_demo_protocol::{Request, Response};
+
+use common::{connect, start_server_with_args};
+
+#[test]
+fn test_hash_chain() {
+    let (_server, addr) = start_server_with_args(&["--hash-chain"]);
+    let requests = [
+        Request::Echo(String::from("Hello")),
+        Request::Log(String::from("No response, but still chained")),
+        Request::Stats(String::from("Hello World")),
+        Request::Repeat {
+            message: String::from("Hi"),
+            count: 2,
+            interval_ms: 0,
+        },
+    ];
+
+    let mut client = connect(addr);
+    let mut chain = HashChain::new();
+    let mut hashes = vec![];
+    for request in &requests {
+        client.send_message(request).unwrap();
+        let expected = chain.push_message(request).unwrap();
+        let responses = match request {
+            Request::Log(_) => 0,
+            Request::Repeat { count, .. } => *count,
+            _ => 1,
+        };
+        for _ in 0..responses {
+            let resp = client.read_message_required::<Response>().unwrap();
+            assert_eq!(response_chain_hash(&resp), Some(expected), "{:?}", resp);
+        }
+        hashes.push(expected);
+    }
+    // Pings aren't chained, and their Pong has nowhere to put a hash
+    client.send_message(&Request::Ping).unwrap();
+    assert_eq!(
+        client.read_message_required::<Response>().unwrap(),
+        Response::Pong
+    );
+
+    // A new connection starts a new chain, and the same requests give the same hashes
+    let mut client = connect(addr);
+    client.send_message(&requests[0]).unwrap();
+    let resp = client.read_message_required::<Response>().unwrap();
+    assert_eq!(response_chain_hash(&resp), Some(hashes[0]));
+    assert_eq!(
+        resp.message(),
+        format!("'Hello' from the other side! [chain={:08x}]", hashes[0])
+    );
+}
+
+#[test]
+fn test_hash_chain_leaves_room_for_its_token() {
+    let (_server, addr) = start_server_with_args(&["--hash-chain"]);
+    let mut client = connect(addr);
+    // As many tokens as a count can hold, but the chain hash needs one of them
+    let split = |delimiters: usize| Request::Split {
+        message: ",".repeat(delimiters),
+        delimiter: String::from(","),
+    };
+    client.send_message(&split(u16::MAX as usize - 1)).unwrap();
+    let resp = client.read_message_required::<Response>().unwrap();
+    assert!(
+        resp.message().starts_with("Too many tokens: 65535"),
+        "{:?}",
+        resp.message()
+    );
+
+    client.send_message(&split(u16::MAX as usize - 2)).unwrap();
+    match client.read_message_required::<Response>().unwrap() {
+        Response::Tokens(tokens) => assert_eq!(tokens.len(), u16::MAX as usize),
+        resp => panic!("Unexpected response: {:?}", resp),
+    }
+}
@@ -0,0 +1,68 @@
+//! Run the client binary with and without `--jumble` and `TCP_DEMO_JUMBLE`, to check which
+//! amount it uses
+
+mod common;
+
+use std::net::SocketAddr;
+use std::process::{Command, Output, Stdio};
+
+use tcp_demo_protocol::jumble_message;
+
+use common::{connect, start_server};
+
+const MESSAGE: &str = "The quick brown fox";
+
+/// Run the client with `args` and `TCP_DEMO_JUMBLE` set to `env` (or unset)
+fn run_client(addr: SocketAddr, args: &[&str], env: Option<&str>) -> Output {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_client"));
+    command
+        .args(["--addr", &addr.to_string()])
+        .args(args)
+        .arg(MESSAGE)
+        .stderr(Stdio::piped())
+        .env_remove("TCP_DEMO_JUMBLE");
+    if let Some(env) = env {
+        command.env("TCP_DEMO_JUMBLE", env);
+    }
+    command.output().unwrap()
+}
+
+fn stdout(output: Output) -> String {
+    assert!(output.status.success(), "{:?}", output);
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn test_jumble_amount_precedence() {
+    let (_server, addr) = start_server();
+    // Wait for the server to be listening
+    drop(connect(addr));
+    let echoed = format!("'{}' from the other side!\n", MESSAGE);
+    let jumbled = |amount| format!("{}\n", jumble_message(MESSAGE, amount));
+
+    // Neither: not jumbled
+    assert_eq!(stdout(run_client(addr, &[], None)), echoed);
+    // Only the environment variable
+    assert_eq!(stdout(run_client(addr, &[], Some("42"))), jumbled(42));
+    // The flag wins, even when it's 0
+    assert_eq!(
+        stdout(run_client(addr, &["--jumble", "7"], Some("42"))),
+        jumbled(7)
+    );
+    assert_eq!(
+        stdout(run_client(addr, &["--jumble", "0"], Some("42"))),
+        echoed
+    );
+    // Other request types aren't affected by the default
+    assert!(stdout(run_client(addr, &["--stats"], Some("42"))).starts_with("chars="));
+
+    // An invalid value is an error, rather than silently not jumbling
+    let output = run_client(addr, &[], Some("lots"));
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains("Invalid TCP_DEMO_JUMBLE 'lots'"),
+        "{}",
+        stderr
+    );
+}
@@ -0,0 +1,40 @@
+//! Jumbles of more than `MAX_JUMBLE_CHARS` characters are refused with an error Response
+
+mod common;
+
+use tcp_demo_protocol::{Request, Response, MAX_JUMBLE_CHARS};
+
+use common::{connect, start_server};
+
+#[test]
+fn test_jumble_limit() {
+    let (_server, addr) = start_server();
+    let mut client = connect(addr);
+
+    let message = "x".repeat(MAX_JUMBLE_CHARS);
+    client
+        .send_message(&Request::Jumble {
+            message: message.clone(),
+            amount: u16::MAX,
+        })
+        .unwrap();
+    assert_eq!(
+        client.read_message_required::<Response>().unwrap(),
+        Response::Ok(message)
+    );
+
+    client
+        .send_message(&Request::Jumble {
+            message: "x".repeat(MAX_JUMBLE_CHARS + 1),
+            amount: 1,
+        })
+        .unwrap();
+    assert_eq!(
+        client.read_message_required::<Response>().unwrap(),
+        Response::Err(format!(
+            "Jumble messages are limited to {} characters, this one has {}",
+            MAX_JUMBLE_CHARS,
+            MAX_JUMBLE_CHARS + 1
+        ))
+    );
+}
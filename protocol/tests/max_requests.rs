@@ -0,0 +1,53 @@
+//! Run the server binary with `--max-requests-per-conn` and check it closes connections after
+//! that many requests
+
+mod common;
+
+use tcp_demo_protocol::{Request, Response};
+
+use common::{connect, start_server_with_args};
+
+#[test]
+fn test_max_requests_per_conn() {
+    let (_server, addr) = start_server_with_args(&["--max-requests-per-conn", "3"]);
+    let mut client = connect(addr);
+
+    // Keepalive pings don't count towards the limit
+    client.send_message(&Request::Ping).unwrap();
+    assert_eq!(
+        client.read_message_required::<Response>().unwrap(),
+        Response::Pong
+    );
+    for n in 0..3 {
+        client
+            .send_message(&Request::Echo(format!("Hello {}", n)))
+            .unwrap();
+        assert_eq!(
+            client.read_message_required::<Response>().unwrap(),
+            Response::Ok(format!("'Hello {}' from the other side!", n))
+        );
+    }
+
+    // The 4th is answered with an error, and then the connection is closed
+    client
+        .send_message(&Request::Echo(String::from("Hello 3")))
+        .unwrap();
+    assert_eq!(
+        client.read_message_required::<Response>().unwrap(),
+        Response::Err(String::from(
+            "Request limit of 3 per connection reached, closing the connection"
+        ))
+    );
+    assert!(client.read_message::<Response>().unwrap().is_none());
+    assert!(client.is_peer_done_sending());
+
+    // Other connections have their own count
+    let mut other = connect(addr);
+    other
+        .send_message(&Request::Echo(String::from("Hello")))
+        .unwrap();
+    assert!(matches!(
+        other.read_message_required::<Response>().unwrap(),
+        Response::Ok(_)
+    ));
+}
@@ -0,0 +1,26 @@
+//! Run the server binary and ask for its max message size with `Request::MaxSize`
+
+mod common;
+
+use tcp_demo_protocol::{Request, Response};
+
+use common::{connect, start_server, start_server_with_args};
+
+#[test]
+fn test_server_max_message_size() {
+    let (_server, addr) = start_server_with_args(&["--max-message-size", "64"]);
+    let mut client = connect(addr);
+    assert_eq!(client.server_max_message_size().unwrap(), Some(64));
+
+    // A request that fits is handled as usual
+    client.send_message(&Request::Echo("a".repeat(61))).unwrap();
+    assert!(client.read_message_required::<Response>().unwrap().is_ok());
+
+    // And one that doesn't isn't (the server closes the connection)
+    client.send_message(&Request::Echo("a".repeat(62))).unwrap();
+    assert!(client.read_message_required::<Response>().is_err());
+
+    let (_server, addr) = start_server();
+    let mut client = connect(addr);
+    assert_eq!(client.server_max_message_size().unwrap(), None);
+}
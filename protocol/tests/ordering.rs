@@ -0,0 +1,49 @@
+//! Run the server binary and check pipelined requests are answered in order, with and without
+//! `--workers`
+
+mod common;
+
+use std::net::SocketAddr;
+
+use tcp_demo_protocol::{Request, Response};
+
+use common::{connect, start_server, start_server_with_args};
+
+fn check_pipelined_responses_in_order(addr: SocketAddr) {
+    let mut client = connect(addr);
+
+    // Later requests are quicker to handle, so they'd be answered first if handled concurrently
+    let requests = [
+        Request::Delay {
+            message: String::from("first"),
+            ms: 200,
+        },
+        Request::Delay {
+            message: String::from("second"),
+            ms: 50,
+        },
+        Request::Echo(String::from("third")),
+    ];
+    for request in &requests {
+        client.send_message(request).unwrap();
+    }
+    for expected in ["first", "second", "third"] {
+        let resp = client.read_message_required::<Response>().unwrap();
+        assert_eq!(
+            resp,
+            Response::Ok(format!("'{}' from the other side!", expected))
+        );
+    }
+}
+
+#[test]
+fn test_pipelined_responses_in_order() {
+    let (_server, addr) = start_server();
+    check_pipelined_responses_in_order(addr);
+}
+
+#[test]
+fn test_pipelined_responses_in_order_with_workers() {
+    let (_server, addr) = start_server_with_args(&["--workers", "4"]);
+    check_pipelined_responses_in_order(addr);
+}
@@ -0,0 +1,55 @@
+//! Run the server binary and check that keepalive `Ping`s are answered in between requests
+
+mod common;
+
+use tcp_demo_protocol::{Request, Response};
+
+use common::{connect, start_server};
+
+#[test]
+fn test_ping_interleaved_with_echo() {
+    let (_server, addr) = start_server();
+    let mut client = connect(addr);
+
+    for (i, message) in ["Hello", "big", "world"].iter().enumerate() {
+        // Pings before (and sometimes several in a row) don't shift the Echo responses
+        for _ in 0..=i {
+            client.send_message(&Request::Ping).unwrap();
+            let resp = client.read_message_required::<Response>().unwrap();
+            assert_eq!(resp, Response::Pong);
+        }
+        client
+            .send_message(&Request::Echo(message.to_string()))
+            .unwrap();
+        let resp = client.read_message_required::<Response>().unwrap();
+        assert_eq!(
+            resp,
+            Response::Ok(format!("'{}' from the other side!", message))
+        );
+    }
+
+    // Pipelined: every response comes back in the order its request was sent
+    let requests = [
+        Request::Ping,
+        Request::Echo(String::from("one")),
+        Request::Ping,
+        Request::Ping,
+        Request::Echo(String::from("two")),
+    ];
+    for request in &requests {
+        client.send_message(request).unwrap();
+    }
+    let responses: Vec<Response> = (0..requests.len())
+        .map(|_| client.read_message_required::<Response>().unwrap())
+        .collect();
+    assert_eq!(
+        responses,
+        vec![
+            Response::Pong,
+            Response::Ok(String::from("'one' from the other side!")),
+            Response::Pong,
+            Response::Pong,
+            Response::Ok(String::from("'two' from the other side!")),
+        ]
+    );
+}
@@ -0,0 +1,87 @@
+//! Run the proxy binary between a client and the server binary
+
+mod common;
+
+use std::net::{SocketAddr, TcpListener};
+use std::process::{Command, Stdio};
+
+use tcp_demo_protocol::{Protocol, Request, Response};
+
+use common::{connect, start_server_with_args, Server};
+
+/// Start a proxy in front of the server at `upstream`, with extra command line arguments
+fn start_proxy(upstream: SocketAddr, args: &[&str]) -> (Server, SocketAddr) {
+    // The proxy connects upstream as soon as a client connects to it, without retrying, so
+    // wait for the server to be listening first
+    drop(connect(upstream));
+    let addr = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let child = Command::new(env!("CARGO_BIN_EXE_proxy"))
+        .args(["--addr", &addr.to_string()])
+        .args(["--upstream", &upstream.to_string()])
+        .args(args)
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    (Server(child), addr)
+}
+
+fn connect_framed(addr: SocketAddr) -> Protocol {
+    let mut client = connect(addr);
+    client.set_framed(true);
+    client
+}
+
+#[test]
+fn test_proxy_round_trip() {
+    let (_server, server_addr) = start_server_with_args(&["--framed"]);
+    let (_proxy, proxy_addr) = start_proxy(server_addr, &[]);
+
+    let mut client = connect_framed(proxy_addr);
+    for message in ["Hello", "World"] {
+        client
+            .send_message(&Request::Echo(String::from(message)))
+            .unwrap();
+        assert_eq!(
+            client.read_message_required::<Response>().unwrap(),
+            Response::Ok(format!("'{}' from the other side!", message))
+        );
+    }
+    // Requests without a response are relayed too
+    client
+        .send_only(&Request::Log(String::from("Hello")))
+        .unwrap();
+    client.send_message(&Request::Ping).unwrap();
+    assert_eq!(
+        client.read_message_required::<Response>().unwrap(),
+        Response::Pong
+    );
+
+    // A second client gets its own upstream connection
+    let mut other = connect_framed(proxy_addr);
+    other.send_message(&Request::Ping).unwrap();
+    assert_eq!(
+        other.read_message_required::<Response>().unwrap(),
+        Response::Pong
+    );
+
+    // The server's close is passed back to the client
+    client.close().unwrap();
+}
+
+#[test]
+fn test_proxy_modifies_responses() {
+    let (_server, server_addr) = start_server_with_args(&["--framed"]);
+    let (_proxy, proxy_addr) = start_proxy(server_addr, &["--uppercase", "--log"]);
+
+    let mut client = connect_framed(proxy_addr);
+    client
+        .send_message(&Request::Echo(String::from("Hello")))
+        .unwrap();
+    assert_eq!(
+        client.read_message_required::<Response>().unwrap(),
+        Response::Ok(String::from("'HELLO' FROM THE OTHER SIDE!"))
+    );
+}
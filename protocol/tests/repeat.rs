@@ -0,0 +1,83 @@
+//! Run the server binary and check that a `Repeat` streams back its responses over time
+
+mod common;
+
+use std::time::{Duration, Instant};
+
+use tcp_demo_protocol::{Request, Response};
+
+use common::{connect, start_server};
+
+#[test]
+fn test_repeat_streams_responses() {
+    let (_server, addr) = start_server();
+    let mut client = connect(addr);
+
+    let interval = Duration::from_millis(100);
+    let start = Instant::now();
+    client
+        .send_message(&Request::Repeat {
+            message: String::from("Hello"),
+            count: 4,
+            interval_ms: interval.as_millis() as u32,
+        })
+        .unwrap();
+    let mut arrivals = vec![];
+    for i in 1..=4 {
+        let resp = client.read_message_required::<Response>().unwrap();
+        arrivals.push(start.elapsed());
+        assert_eq!(resp, Response::Ok(format!("Hello ({}/4)", i)));
+    }
+
+    // The first is sent right away, and the rest roughly an interval apart (with some
+    // slack for the scheduler, but not so much that they could've been sent all at once)
+    assert!(arrivals[0] < interval / 2, "{:?}", arrivals);
+    for gap in arrivals.windows(2).map(|pair| pair[1] - pair[0]) {
+        assert!(gap >= interval * 3 / 4, "{:?}", arrivals);
+    }
+    assert!(arrivals[3] < interval * 3 * 2, "{:?}", arrivals);
+
+    // Exactly 4: the next response is to the next request
+    client
+        .send_message(&Request::Echo(String::from("Bye")))
+        .unwrap();
+    let resp = client.read_message_required::<Response>().unwrap();
+    assert_eq!(
+        resp,
+        Response::Ok(String::from("'Bye' from the other side!"))
+    );
+}
+
+#[test]
+fn test_repeat_invalid() {
+    let (_server, addr) = start_server();
+    let mut client = connect(addr);
+    let mut repeat = |count: u32, interval_ms: u32| {
+        let req = Request::Repeat {
+            message: String::from("Hello"),
+            count,
+            interval_ms,
+        };
+        client.send_message(&req).unwrap();
+        client.read_message_required::<Response>().unwrap()
+    };
+
+    assert_eq!(
+        repeat(0, 100),
+        Response::Err(String::from("Repeat count must be at least 1"))
+    );
+    assert_eq!(
+        repeat(1_000, 1_000),
+        Response::Err(String::from("Repeat would take longer than 10s"))
+    );
+    // Even without any interval
+    assert_eq!(
+        repeat(u32::MAX, 0),
+        Response::Err(String::from("Repeat count must be at most 1000"))
+    );
+    // A single response has no intervals to wait for
+    assert_eq!(
+        repeat(1, u32::MAX),
+        Response::Ok(String::from("Hello (1/1)"))
+    );
+}
@@ -0,0 +1,40 @@
+//! Run the replay binary on a captured byte stream
+
+use std::process::Command;
+
+use tcp_demo_protocol::{Request, Serialize};
+
+#[test]
+fn test_replay_reports_offsets() {
+    let mut capture = vec![];
+    let first = Request::Echo(String::from("Hello"))
+        .serialize(&mut capture)
+        .unwrap();
+    Request::Ping.serialize(&mut capture).unwrap();
+    let malformed = capture.len();
+    // An unknown request type, followed by what might have been its fields
+    capture.extend_from_slice(&[0xff, 0x00, 0x01]);
+
+    let path = std::env::temp_dir().join(format!("tcp_demo_replay_{}.bin", std::process::id()));
+    std::fs::write(&path, &capture).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_replay"))
+        .arg(&path)
+        .output()
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert!(!output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(
+        stdout,
+        format!(
+            "0: Echo(\"Hello\")\n\
+             {}: Ping\n\
+             {}: Error: Invalid Request Type\n\
+             Stopped with 3 of {} bytes unparsed\n",
+            first,
+            malformed,
+            capture.len()
+        )
+    );
+}
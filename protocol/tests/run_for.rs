@@ -0,0 +1,45 @@
+//! Run the server binary with `--run-for-secs` and check it shuts itself down
+
+use std::net::TcpListener;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Start the server on `addr` for a second, and check it exits cleanly by itself
+fn check_runs_for_a_second(addr: &str) {
+    let mut server = Command::new(env!("CARGO_BIN_EXE_server"))
+        .args(["--addr", addr, "--run-for-secs", "1"])
+        .stderr(Stdio::null())
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+    let start = Instant::now();
+    let status = loop {
+        if let Some(status) = server.try_wait().unwrap() {
+            break status;
+        }
+        if start.elapsed() > Duration::from_secs(10) {
+            let _ = server.kill();
+            panic!("Server on {} didn't shut down after --run-for-secs", addr);
+        }
+        thread::sleep(Duration::from_millis(50));
+    };
+    assert!(status.success(), "{}", status);
+}
+
+#[test]
+fn test_run_for_secs() {
+    check_runs_for_a_second("127.0.0.1:0");
+}
+
+#[test]
+fn test_run_for_secs_on_every_ipv6_address() {
+    // Skip on hosts without IPv6
+    let port = match TcpListener::bind("[::]:0") {
+        Ok(listener) => listener.local_addr().unwrap().port(),
+        Err(_) => return,
+    };
+    // Where the listener is IPv6-only (e.g. `net.ipv6.bindv6only` on Linux), the shutdown can
+    // only wake the accept loop by connecting to `[::1]`, not `127.0.0.1`
+    check_runs_for_a_second(&format!("[::]:{}", port));
+}
@@ -0,0 +1,35 @@
+//! Interrupt a client blocked reading from a server that never responds
+
+use std::io;
+use std::net::TcpListener;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tcp_demo_protocol::{Protocol, Request, Response};
+
+#[test]
+fn test_shutdown_handle_interrupts_blocked_read() {
+    // A quiet server: accepts the connection, but never reads or writes
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut client = Protocol::connect(listener.local_addr().unwrap()).unwrap();
+    let (_server_stream, _) = listener.accept().unwrap();
+
+    client
+        .send_message(&Request::Echo(String::from("Hello?")))
+        .unwrap();
+
+    let handle = client.shutdown_handle().unwrap();
+    let shutdown = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(100));
+        handle.shutdown_read().unwrap();
+    });
+
+    let start = Instant::now();
+    let err = client.read_message_required::<Response>().unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    assert!(start.elapsed() < Duration::from_secs(5));
+    shutdown.join().unwrap();
+
+    // Later reads don't block either
+    assert!(client.read_message::<Response>().unwrap().is_none());
+}
@@ -0,0 +1,63 @@
+//! Run the server binary with `--single-threaded` and check it handles clients one at a time
+
+mod common;
+
+use std::io;
+use std::time::Duration;
+
+use tcp_demo_protocol::{Protocol, ProtocolBuilder, Request, Response};
+
+use common::{connect, start_server_with_args};
+
+fn echo(client: &mut Protocol, message: &str) -> Response {
+    client
+        .send_message(&Request::Echo(String::from(message)))
+        .unwrap();
+    client.read_message_required::<Response>().unwrap()
+}
+
+fn is_timeout(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+#[test]
+fn test_single_threaded() {
+    let (_server, addr) = start_server_with_args(&["--single-threaded"]);
+
+    let mut first = connect(addr);
+    assert_eq!(
+        echo(&mut first, "first"),
+        Response::Ok(String::from("'first' from the other side!"))
+    );
+
+    // A second client isn't answered while the first is still connected...
+    let mut second = ProtocolBuilder::new()
+        .read_timeout(Duration::from_millis(200))
+        .connect(addr)
+        .unwrap();
+    second
+        .send_message(&Request::Echo(String::from("second")))
+        .unwrap();
+    let err = second.read_message_required::<Response>().unwrap_err();
+    assert!(is_timeout(&err), "{}", err);
+
+    // ...but is once it disconnects
+    drop(first);
+    let resp = (0..25)
+        .find_map(|_| match second.read_message_required::<Response>() {
+            Err(e) if is_timeout(&e) => None,
+            resp => Some(resp.unwrap()),
+        })
+        .expect("No response after the first client disconnected");
+    assert_eq!(
+        resp,
+        Response::Ok(String::from("'second' from the other side!"))
+    );
+    assert_eq!(
+        echo(&mut second, "again"),
+        Response::Ok(String::from("'again' from the other side!"))
+    );
+}
@@ -0,0 +1,36 @@
+//! Run the server binary and check how it splits messages into a `Response::Tokens`
+
+mod common;
+
+use tcp_demo_protocol::{Request, Response};
+
+use common::{connect, start_server};
+
+#[test]
+fn test_split() {
+    let (_server, addr) = start_server();
+    let mut client = connect(addr);
+    let mut split = |message: &str, delimiter: &str| {
+        let req = Request::Split {
+            message: message.to_string(),
+            delimiter: delimiter.to_string(),
+        };
+        client.send_message(&req).unwrap();
+        client.read_message_required::<Response>().unwrap()
+    };
+    let tokens = |tokens: &[&str]| Response::Tokens(tokens.iter().map(|t| t.to_string()).collect());
+
+    assert_eq!(split("a, b, c", ", "), tokens(&["a", "b", "c"]));
+    // Adjacent & trailing delimiters give empty tokens
+    assert_eq!(split("a,,b,", ","), tokens(&["a", "", "b", ""]));
+    // Without the delimiter, the whole message is one token
+    assert_eq!(split("Hello", ","), tokens(&["Hello"]));
+    assert_eq!(split("", ","), tokens(&[""]));
+    assert_eq!(
+        split("Hello", ""),
+        Response::Err(String::from("Split delimiter can't be empty"))
+    );
+
+    // The connection is still fine after the error
+    assert_eq!(split("wörld", "ö"), tokens(&["w", "rld"]));
+}
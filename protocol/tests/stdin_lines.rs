@@ -0,0 +1,39 @@
+//! Run the client binary with `--stdin-lines`, piping it a few messages
+
+mod common;
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use common::{connect, start_server};
+
+#[test]
+fn test_client_stdin_lines() {
+    let (_server, addr) = start_server();
+    // Wait for the server to be listening
+    drop(connect(addr));
+
+    let mut client = Command::new(env!("CARGO_BIN_EXE_client"))
+        .args(["--addr", &addr.to_string(), "--stdin-lines"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    // Blank (and whitespace only) lines are skipped, and CRLF line endings are stripped
+    client
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(b"Hello\n\n   \nbig world\r\nlast\n")
+        .unwrap();
+    let output = client.wait_with_output().unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "'Hello' from the other side!\n\
+         'big world' from the other side!\n\
+         'last' from the other side!\n"
+    );
+}
@@ -0,0 +1,80 @@
+//! Run the server binary and check that a `Timed` request's Response includes how long it took
+
+mod common;
+
+use tcp_demo_protocol::{Request, Response};
+
+use common::{connect, start_server};
+
+/// Split the `[<micros>µs] ` prefix from a Timed Response's message
+fn split_duration(message: &str) -> (u128, &str) {
+    let (duration, rest) = message
+        .strip_prefix('[')
+        .and_then(|message| message.split_once("µs] "))
+        .unwrap_or_else(|| panic!("No duration in '{}'", message));
+    (duration.parse().unwrap(), rest)
+}
+
+#[test]
+fn test_timed() {
+    let (_server, addr) = start_server();
+    let mut client = connect(addr);
+    let mut timed = |req: Request| {
+        client.send_message(&Request::Timed(Box::new(req))).unwrap();
+        client.read_message_required::<Response>().unwrap()
+    };
+
+    let resp = timed(Request::Delay {
+        message: String::from("Hello"),
+        ms: 50,
+    });
+    let message = match resp {
+        Response::Ok(message) => message,
+        resp => panic!("Unexpected response: {:?}", resp),
+    };
+    let (micros, rest) = split_duration(&message);
+    assert!(micros >= 50_000, "Delay took {}µs", micros);
+    assert_eq!(rest, "'Hello' from the other side!");
+
+    // Tokens get the duration as an extra first token
+    let resp = timed(Request::Split {
+        message: String::from("a,b"),
+        delimiter: String::from(","),
+    });
+    match resp {
+        Response::Tokens(tokens) => {
+            assert_eq!(tokens.len(), 3);
+            assert!(tokens[0].ends_with("µs"));
+            assert_eq!(tokens[1..], ["a", "b"]);
+        }
+        resp => panic!("Unexpected response: {:?}", resp),
+    }
+
+    // Timing a Timed times the time taken to time it
+    let resp = timed(Request::Timed(Box::new(Request::Noop)));
+    let message = match resp {
+        Response::Ok(message) => message,
+        resp => panic!("Unexpected response: {:?}", resp),
+    };
+    let (_, rest) = split_duration(&message);
+    assert_eq!(split_duration(rest).1, "");
+
+    // Errors are passed through as-is
+    assert_eq!(
+        timed(Request::Split {
+            message: String::from("Hello"),
+            delimiter: String::new(),
+        }),
+        Response::Err(String::from("Split delimiter can't be empty"))
+    );
+
+    // Requests without a single Response have nothing to time
+    assert_eq!(
+        timed(Request::Ping),
+        Response::Err(String::from("Request type 'ping' can't be timed"))
+    );
+    assert_eq!(
+        timed(Request::Log(String::from("Hello"))),
+        Response::Err(String::from("Request type 'log' can't be timed"))
+    );
+}
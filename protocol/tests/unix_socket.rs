@@ -0,0 +1,53 @@
+//! Run the server & client binaries over a Unix domain socket (`--addr unix:/path`)
+#![cfg(unix)]
+
+mod common;
+
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+use tcp_demo_protocol::{retry_with_backoff, ConstantBackoff, Protocol, Request, Response};
+
+use common::Server;
+
+#[test]
+fn test_unix_socket() {
+    let path: PathBuf =
+        std::env::temp_dir().join(format!("tcp_demo_unix_{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let addr = format!("unix:{}", path.display());
+    let server = Server(
+        Command::new(env!("CARGO_BIN_EXE_server"))
+            .args(["--addr", &addr])
+            .stderr(Stdio::null())
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap(),
+    );
+
+    // Give the server a moment (up to ~5s) to start listening
+    let mut backoff = ConstantBackoff::new(Duration::from_millis(20), 250);
+    let mut client = retry_with_backoff(&mut backoff, || Protocol::connect_unix(&path)).unwrap();
+    client
+        .send_message(&Request::Echo(String::from("Hello")))
+        .unwrap();
+    assert_eq!(
+        client.read_message_required::<Response>().unwrap(),
+        Response::Ok(String::from("'Hello' from the other side!"))
+    );
+
+    let output = Command::new(env!("CARGO_BIN_EXE_client"))
+        .args(["--addr", &addr, "Hi"])
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "'Hi' from the other side!\n"
+    );
+
+    // Killed rather than shut down, so the server leaves its socket file behind
+    drop(server);
+    let _ = std::fs::remove_file(&path);
+}
@@ -0,0 +1,104 @@
+//! Talk to the ws-server binary like a browser would, and like a client that breaks the rules
+#![cfg(feature = "websocket")]
+
+mod common;
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+use tcp_demo_protocol::websocket::{read_frame, write_frame, Opcode};
+use tcp_demo_protocol::{
+    retry_with_backoff, ConstantBackoff, Deserialize, Request, Response, Serialize,
+};
+
+use common::Server;
+
+/// Start the ws-server, and upgrade a connection to it to WebSocket
+fn connect() -> (Server, BufReader<TcpStream>) {
+    let addr = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let server = Server(
+        Command::new(env!("CARGO_BIN_EXE_ws-server"))
+            .args(["--addr", &addr.to_string()])
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap(),
+    );
+    // Give the server a moment (up to ~5s) to start listening
+    let mut backoff = ConstantBackoff::new(Duration::from_millis(20), 250);
+    let mut stream = retry_with_backoff(&mut backoff, || TcpStream::connect(addr)).unwrap();
+    write!(
+        stream,
+        "GET / HTTP/1.1\r\n\
+         Host: {}\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+         Sec-WebSocket-Version: 13\r\n\r\n",
+        addr
+    )
+    .unwrap();
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line).unwrap();
+    assert_eq!(line, "HTTP/1.1 101 Switching Protocols\r\n");
+    // Skip the rest of the headers
+    while line != "\r\n" {
+        line.clear();
+        reader.read_line(&mut line).unwrap();
+    }
+    (server, reader)
+}
+
+/// Send a Request in a binary frame, masked with `mask` (or not)
+fn send(reader: &mut BufReader<TcpStream>, req: &Request, mask: Option<[u8; 4]>) {
+    let mut payload = vec![];
+    req.serialize(&mut payload).unwrap();
+    write_frame(reader.get_mut(), Opcode::Binary, &payload, mask).unwrap();
+}
+
+fn receive(reader: &mut BufReader<TcpStream>) -> Response {
+    let frame = read_frame(reader).unwrap();
+    assert_eq!(frame.opcode, Opcode::Binary);
+    Response::deserialize_exact(&mut &frame.payload[..]).unwrap()
+}
+
+#[test]
+fn test_ws_server_answers_stateless_requests() {
+    let (_server, mut reader) = connect();
+    send(
+        &mut reader,
+        &Request::Echo(String::from("Hi")),
+        Some([1, 2, 3, 4]),
+    );
+    assert_eq!(
+        receive(&mut reader),
+        Response::Ok(String::from("'Hi' from the other side!"))
+    );
+    let concat = Request::Concat(vec![String::from("Hello"), String::from("there")]);
+    send(&mut reader, &concat, Some([5, 6, 7, 8]));
+    assert_eq!(
+        receive(&mut reader),
+        Response::Ok(String::from("Hello there"))
+    );
+    send(&mut reader, &Request::MaxSize, Some([5, 6, 7, 8]));
+    assert_eq!(
+        receive(&mut reader),
+        Response::Err(String::from(
+            "Request type 'max-size' isn't supported over WebSocket"
+        ))
+    );
+}
+
+#[test]
+fn test_ws_server_closes_on_unmasked_frame() {
+    let (_server, mut reader) = connect();
+    send(&mut reader, &Request::Echo(String::from("Hi")), None);
+    let frame = read_frame(&mut reader).unwrap();
+    assert_eq!(frame.opcode, Opcode::Close);
+    assert_eq!(frame.payload, 1002u16.to_be_bytes());
+}
@@ -0,0 +1,49 @@
+//! Run the server binary with `--workers` and check a busy pool takes urgent requests first
+
+mod common;
+
+use std::time::{Duration, Instant};
+
+use tcp_demo_protocol::{Request, Response};
+
+use common::{connect, start_server_with_args};
+
+fn delay(message: &str, ms: u32) -> Request {
+    Request::Delay {
+        message: String::from(message),
+        ms,
+    }
+}
+
+#[test]
+fn test_high_priority_request_handled_first() {
+    let (_server, addr) = start_server_with_args(&["--workers", "1"]);
+    let mut busy = connect(addr);
+    let mut slow = connect(addr);
+    let mut quick = connect(addr);
+
+    // Keep the only worker busy, then queue a slow (low priority) Delay before a quick Echo
+    busy.send_message(&delay("busy", 500)).unwrap();
+    std::thread::sleep(Duration::from_millis(100));
+    slow.send_message(&delay("slow", 300)).unwrap();
+    std::thread::sleep(Duration::from_millis(100));
+    quick
+        .send_message(&Request::Echo(String::from("quick")))
+        .unwrap();
+
+    // The Echo is handled as soon as the worker is free, before the Delay queued ahead of it
+    assert_eq!(
+        quick.read_message_required::<Response>().unwrap(),
+        Response::Ok(String::from("'quick' from the other side!"))
+    );
+    let quick_done = Instant::now();
+    assert_eq!(
+        slow.read_message_required::<Response>().unwrap(),
+        Response::Ok(String::from("'slow' from the other side!"))
+    );
+    assert!(quick_done.elapsed() >= Duration::from_millis(200));
+    assert_eq!(
+        busy.read_message_required::<Response>().unwrap(),
+        Response::Ok(String::from("'busy' from the other side!"))
+    );
+}
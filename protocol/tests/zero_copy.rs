@@ -0,0 +1,53 @@
+//! Check that `RequestRef` parses without allocating (using a counting global allocator,
+//! which is why this is its own test binary)
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use tcp_demo_protocol::{Request, RequestRef, Serialize};
+
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::SeqCst);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+#[test]
+fn test_deserialize_ref_doesnt_allocate() {
+    let mut bytes: Vec<u8> = vec![];
+    Request::Concat(vec![String::from("Hello"), String::from("wörld")])
+        .serialize(&mut bytes)
+        .unwrap();
+    let mut echo: Vec<u8> = vec![];
+    Request::Echo(String::from("Hello"))
+        .serialize(&mut echo)
+        .unwrap();
+
+    let before = ALLOCATIONS.load(Ordering::SeqCst);
+    let concat = match RequestRef::deserialize_ref(&bytes).unwrap() {
+        RequestRef::Concat(parts) => parts,
+        req => panic!("Unexpected request: {:?}", req),
+    };
+    let mut total_len = 0;
+    for part in concat.iter() {
+        total_len += part.len();
+    }
+    let message = RequestRef::deserialize_ref(&echo).unwrap().message();
+    assert_eq!(ALLOCATIONS.load(Ordering::SeqCst), before);
+
+    assert_eq!(total_len, "Hello".len() + "wörld".len());
+    // The message is borrowed straight out of the input buffer
+    assert!(echo.as_ptr_range().contains(&message.as_ptr()));
+}
@@ -3,22 +3,38 @@ use std::net::{SocketAddr, TcpStream};
 
 use structopt::StructOpt;
 
-use tcp_demo_raw::{extract_string_unbuffered, write_data, DEFAULT_SERVER_ADDR};
+use tcp_demo_raw::{
+    error_exit_code, extract_string_unbuffered, json_error, parse_addr, write_data,
+    DEFAULT_SERVER_ADDR,
+};
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "client")]
 struct Args {
     message: String,
     /// Server destination address
-    #[structopt(long, default_value = DEFAULT_SERVER_ADDR, global = true)]
+    #[structopt(long, default_value = DEFAULT_SERVER_ADDR, global = true, parse(try_from_str = parse_addr))]
     addr: SocketAddr,
+    /// Print errors to stderr as JSON (`{"error": "...", "kind": "..."}`) and exit with a code
+    /// for the kind of error (e.g. 2 = connection refused, 3 = timeout, see `error_exit_code`)
+    #[structopt(long)]
+    json_errors: bool,
 }
 
 fn main() -> io::Result<()> {
     let args = Args::from_args();
+    match run(&args) {
+        Err(e) if args.json_errors => {
+            eprintln!("{}", json_error(&e));
+            std::process::exit(error_exit_code(&e));
+        }
+        result => result,
+    }
+}
 
+fn run(args: &Args) -> io::Result<()> {
     let mut stream = TcpStream::connect(args.addr)?;
-    write_data(&mut stream, &args.message.as_bytes())?;
+    write_data(&mut stream, args.message.as_bytes())?;
 
     // Now read & print the response
     // (this will block until all data has been received)
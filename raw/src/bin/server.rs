@@ -3,13 +3,15 @@ use std::net::{SocketAddr, TcpListener, TcpStream};
 
 use structopt::StructOpt;
 
-use tcp_demo_raw::{extract_string_buffered, write_data, DEFAULT_SERVER_ADDR};
+use tcp_demo_raw::{
+    extract_string_buffered, parse_addr, serve_connections, write_data, DEFAULT_SERVER_ADDR,
+};
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "server")]
 struct Args {
     /// Service listening address
-    #[structopt(long, default_value = DEFAULT_SERVER_ADDR, global = true)]
+    #[structopt(long, default_value = DEFAULT_SERVER_ADDR, global = true, parse(try_from_str = parse_addr))]
     addr: SocketAddr,
 }
 
@@ -23,20 +25,13 @@ fn handle_connection(stream: TcpStream) -> io::Result<()> {
     let mut writer = BufWriter::new(stream);
 
     let message = extract_string_buffered(&mut reader)?;
-    write_data(&mut writer, &message.as_bytes())
+    write_data(&mut writer, message.as_bytes())
 }
 
 fn main() -> io::Result<()> {
     let args = Args::from_args();
-    eprintln!("Starting server on '{}'", args.addr);
-
     let listener = TcpListener::bind(args.addr)?;
-    for stream in listener.incoming() {
-        if let Ok(stream) = stream {
-            std::thread::spawn(move || {
-                handle_connection(stream).map_err(|e| eprintln!("Error: {}", e))
-            });
-        }
-    }
+    eprintln!("Starting server on '{}'", listener.local_addr()?);
+    serve_connections(listener, handle_connection);
     Ok(())
 }
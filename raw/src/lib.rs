@@ -1,10 +1,82 @@
 //! Shared code between client & server
 
-use std::io::{self, BufRead};
+use std::any::Any;
+use std::convert::TryFrom;
+use std::io::{self, BufRead, Read};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
+use std::panic;
+use std::sync::{Arc, Mutex};
 
 pub const DEFAULT_SERVER_ADDR: &str = "127.0.0.1:4000";
 const MESSAGE_BUFFER_SIZE: usize = 32;
 
+/// Parse a TCP socket address (e.g. `--addr`), explaining what's wrong with it if it's invalid
+///
+/// `SocketAddr`'s own error is just "invalid socket address syntax", so this tells apart
+/// the usual mistakes: a missing port (`127.0.0.1`), an invalid IP address
+/// (`127.0.0.256:4000`), and a hostname (`localhost:4000`), which would need a DNS lookup
+pub fn parse_addr(s: &str) -> Result<SocketAddr, String> {
+    if let Ok(addr) = s.parse() {
+        return Ok(addr);
+    }
+    // A bare (or bracketed) IPv6 address is full of colons, none of which separate a port
+    let (host, port) = if s.ends_with(']') || s.parse::<IpAddr>().is_ok() {
+        (s, "")
+    } else {
+        s.rsplit_once(':').unwrap_or((s, ""))
+    };
+    let host = host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host);
+    if host.is_empty() {
+        return Err(format!(
+            "Missing IP address in '{}', e.g. '127.0.0.1{}'",
+            s, s
+        ));
+    }
+    let ip = match host.parse::<IpAddr>() {
+        Ok(ip) => ip,
+        // Looks like it was meant to be an IP address (rather than a hostname)
+        Err(_) if host.contains(':') || host.chars().all(|c| c.is_ascii_digit() || c == '.') => {
+            return Err(format!("Invalid IP address '{}' in '{}'", host, s))
+        }
+        Err(_) => {
+            return Err(format!(
+                "'{}' is a hostname, which would need resolving: use its IP address instead \
+                 (e.g. 127.0.0.1 for localhost)",
+                host
+            ))
+        }
+    };
+    if port.is_empty() {
+        Err(format!(
+            "Missing port in '{}', e.g. '{}'",
+            s,
+            SocketAddr::new(ip, 4000)
+        ))
+    } else {
+        Err(format!(
+            "Invalid port '{}' in '{}' (must be a number from 0 to 65535)",
+            port, s
+        ))
+    }
+}
+
+/// Run an I/O operation, retrying it if it was interrupted by a signal (`ErrorKind::Interrupted`)
+///
+/// A blocking syscall that's interrupted by a signal (EINTR on Unix) fails without doing
+/// anything, and the idiomatic handling is to just try again. `write_all` and `read_exact`
+/// already do this internally, but single `read`, `fill_buf` and `flush` calls don't.
+pub fn retry_on_interrupt<T>(mut op: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    loop {
+        match op() {
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            result => return result,
+        }
+    }
+}
+
 /// Given a buffer (in this case, TcpStream), write the bytes
 /// to be transmitted via TCP
 pub fn write_data(stream: &mut impl io::Write, data: &[u8]) -> io::Result<()> {
@@ -23,7 +95,7 @@ pub fn write_data(stream: &mut impl io::Write, data: &[u8]) -> io::Result<()> {
     // }
 
     // Signal that we're done writing and the data should be sent (with TCP PSH bit)
-    stream.flush()
+    retry_on_interrupt(|| stream.flush())
 }
 
 /// Given a buffer (in this case, TcpStream), attempt to read
@@ -39,7 +111,7 @@ pub fn extract_string_unbuffered(buf: &mut impl io::Read) -> io::Result<String>
     loop {
         // Read from the current data in the TcpStream
         // !NOTE: Each time this is called it can be a syscall
-        let bytes_read = buf.read(&mut rx_bytes)?;
+        let bytes_read = retry_on_interrupt(|| buf.read(&mut rx_bytes))?;
 
         // However many bytes we read, extend the `received` string bytes
         received.extend_from_slice(&rx_bytes[..bytes_read]);
@@ -61,12 +133,16 @@ pub fn extract_string_unbuffered(buf: &mut impl io::Read) -> io::Result<String>
 
 /// Given a buffer (in this case, TcpStream), use `BufReader` and `BufRead` trait
 /// to read the pending bytes in the stream
+///
+/// NOTE: `fill_buf` returns *everything* received so far, which may include the start of the
+///       next message if the peer sent more than one. That's fine for one message per connection
+///       (like our server), but for a reused stream see `extract_exact_from_buffered`
 pub fn extract_string_buffered(mut buf: &mut impl io::Read) -> io::Result<String> {
     let mut reader = io::BufReader::new(&mut buf);
 
     // `fill_buf` will return a ref to the bytes pending (received by TCP)
     // This is still a lower-level call, so we have to follow it up with a call to consume
-    let received: Vec<u8> = reader.fill_buf()?.to_vec();
+    let received: Vec<u8> = retry_on_interrupt(|| reader.fill_buf().map(<[u8]>::to_vec))?;
 
     // Mark the bytes read as consumed so the buffer will not return them in a subsequent read
     reader.consume(received.len());
@@ -79,6 +155,263 @@ pub fn extract_string_buffered(mut buf: &mut impl io::Read) -> io::Result<String
     })
 }
 
+/// Given a buffered reader and a known message length (e.g. from a length prefix), read
+/// exactly `len` bytes and decode them to a String
+///
+/// Only the bytes of this message are consumed, so any bytes of following messages
+/// stay in the reader's buffer for the next call
+pub fn extract_exact_from_buffered(reader: &mut impl BufRead, len: usize) -> io::Result<String> {
+    let mut received: Vec<u8> = Vec::with_capacity(len);
+    while received.len() < len {
+        // Fill the buffer (retrying if interrupted), then borrow what's pending
+        // (`fill_buf` doesn't read again while there are buffered bytes)
+        retry_on_interrupt(|| reader.fill_buf().map(|_| ()))?;
+        let pending = reader.fill_buf()?;
+        if pending.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Stream closed before the whole message was received",
+            ));
+        }
+        // Take only what's left of this message, even if more bytes are pending
+        let take = pending.len().min(len - received.len());
+        received.extend_from_slice(&pending[..take]);
+        reader.consume(take);
+    }
+
+    String::from_utf8(received).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Couldn't parse received string as utf8",
+        )
+    })
+}
+
+/// Given a buffer (in this case, TcpStream), write the bytes prefixed with their
+/// length (as a big-endian `u32`), so the reader knows exactly where the message ends
+///
+/// Compare with `write_data`, where the reader can only guess (see `extract_string_unbuffered`).
+/// This is the idea the [protocol demo](../protocol) builds on.
+pub fn write_framed_data(stream: &mut impl io::Write, data: &[u8]) -> io::Result<()> {
+    let len = u32::try_from(data.len()).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Message is too long for a u32 length prefix",
+        )
+    })?;
+    stream.write_all(&len.to_be_bytes())?;
+    stream.write_all(data)?;
+    retry_on_interrupt(|| stream.flush())
+}
+
+/// Largest message `read_framed_data` accepts, in bytes
+pub const MAX_FRAME_SIZE: u32 = 1024 * 1024;
+
+/// Given a buffer (in this case, TcpStream), read one message written by `write_framed_data`
+///
+/// No guessing at where the message ends: read the length, then exactly that many bytes.
+/// A message that arrives in several TCP segments is read whole, and any following message
+/// is left in the stream for the next call.
+///
+/// The length comes from the peer, so it isn't trusted: anything over `MAX_FRAME_SIZE` is
+/// rejected, and the buffer only grows as the bytes actually arrive (a prefix alone can't make
+/// us allocate 4 GiB)
+pub fn read_framed_data(stream: &mut impl io::Read) -> io::Result<Vec<u8>> {
+    let mut len_bytes = [0u8; 4];
+    stream.read_exact(&mut len_bytes)?;
+    let len = u32::from_be_bytes(len_bytes);
+    if len > MAX_FRAME_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Message of {} bytes is larger than the maximum of {}",
+                len, MAX_FRAME_SIZE
+            ),
+        ));
+    }
+    let mut received = vec![];
+    stream.take(len as u64).read_to_end(&mut received)?;
+    if received.len() != len as usize {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(received)
+}
+
+/// Did this error happen because the client went away? (e.g. it gave up waiting and closed
+/// the connection before reading the response)
+///
+/// That's normal client behavior rather than a problem with the server
+pub fn is_client_disconnect(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::BrokenPipe
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+    )
+}
+
+/// The message a thread panicked with, from the payload returned by `catch_unwind` or `join`
+///
+/// `panic!` with a literal gives a `&str` payload and with format arguments a `String`,
+/// anything else (from `panic_any`) has no message we can show
+pub fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "Box<dyn Any>"
+    }
+}
+
+/// Exit code for a command line client that failed with `err`, so scripts can tell failures apart
+///
+/// | Code | Error kinds |
+/// |------|-------------|
+/// | 1    | Anything else (including error Responses from the server) |
+/// | 2    | `ConnectionRefused` (no server listening) |
+/// | 3    | `TimedOut`, `WouldBlock` (a read or write timeout) |
+/// | 4    | `ConnectionReset`, `ConnectionAborted`, `BrokenPipe`, `UnexpectedEof` (connection lost) |
+/// | 5    | `InvalidData` (malformed message) |
+/// | 6    | `InvalidInput` (bad arguments) |
+pub fn error_exit_code(err: &io::Error) -> i32 {
+    match err.kind() {
+        io::ErrorKind::ConnectionRefused => 2,
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => 3,
+        io::ErrorKind::ConnectionReset
+        | io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::BrokenPipe
+        | io::ErrorKind::UnexpectedEof => 4,
+        io::ErrorKind::InvalidData => 5,
+        io::ErrorKind::InvalidInput => 6,
+        _ => 1,
+    }
+}
+
+/// An error as a JSON object for scripts, e.g.
+/// `{"error": "Connection refused (os error 111)", "kind": "ConnectionRefused"}`
+pub fn json_error(err: &io::Error) -> String {
+    format!(
+        r#"{{"error": {}, "kind": {}}}"#,
+        json_string(&err.to_string()),
+        json_string(&format!("{:?}", err.kind()))
+    )
+}
+
+/// Quote & escape a string for JSON output
+fn json_string(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// One of two handles to a stream: its own clone, or (when cloning failed) the one stream
+/// shared with the other handle
+///
+/// This is how `LinesCodec` & `Protocol` (in the other crates) keep a separate reader & writer
+/// even when `try_clone` fails (e.g. the process is out of file descriptors): a shared stream
+/// still works for sequential request/response, but it's locked for the duration of each read
+/// or write, so it's no longer full-duplex
+pub enum StreamHandle<S> {
+    Cloned(S),
+    Shared(Arc<Mutex<S>>),
+}
+
+impl<S> StreamHandle<S> {
+    /// Handles to `stream` & its `clone`, in that order, or two handles sharing `stream` if
+    /// the clone failed
+    pub fn pair(stream: S, clone: io::Result<S>) -> (Self, Self) {
+        match clone {
+            Ok(clone) => (StreamHandle::Cloned(stream), StreamHandle::Cloned(clone)),
+            Err(_) => {
+                let stream = Arc::new(Mutex::new(stream));
+                (
+                    StreamHandle::Shared(stream.clone()),
+                    StreamHandle::Shared(stream),
+                )
+            }
+        }
+    }
+
+    /// Whether this handle shares the stream with the other one (because cloning failed)
+    pub fn is_shared(&self) -> bool {
+        matches!(self, StreamHandle::Shared(_))
+    }
+
+    /// Call `f` with the stream, locking it if it's shared
+    pub fn with<T>(&self, f: impl FnOnce(&S) -> T) -> T {
+        match self {
+            StreamHandle::Cloned(stream) => f(stream),
+            StreamHandle::Shared(stream) => f(&stream.lock().unwrap()),
+        }
+    }
+}
+
+impl<S: io::Read> io::Read for StreamHandle<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            StreamHandle::Cloned(stream) => stream.read(buf),
+            StreamHandle::Shared(stream) => stream.lock().unwrap().read(buf),
+        }
+    }
+}
+
+impl<S: io::Write> io::Write for StreamHandle<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            StreamHandle::Cloned(stream) => stream.write(buf),
+            StreamHandle::Shared(stream) => stream.lock().unwrap().write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            StreamHandle::Cloned(stream) => stream.flush(),
+            StreamHandle::Shared(stream) => stream.lock().unwrap().flush(),
+        }
+    }
+}
+
+/// Accept connections forever, handling each on its own thread with `handler`
+///
+/// A panic in `handler` would otherwise only be reported by the default panic hook, which
+/// doesn't know which connection it was handling, so it's caught and reported along with the
+/// peer's address. The stream is dropped (and so closed) as the panic unwinds, and the other
+/// connections carry on
+pub fn serve_connections(listener: TcpListener, handler: fn(TcpStream) -> io::Result<()>) {
+    for stream in listener.incoming().flatten() {
+        std::thread::spawn(move || {
+            let peer_addr = stream
+                .peer_addr()
+                .map_or_else(|_| String::from("unknown"), |addr| addr.to_string());
+            match panic::catch_unwind(|| handler(stream)) {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) if is_client_disconnect(&e) => {
+                    eprintln!("Client disconnected before response");
+                }
+                Ok(Err(e)) => eprintln!("Error: {}", e),
+                Err(panic) => eprintln!(
+                    "Error: handler panicked: {} [{}]",
+                    panic_message(&*panic),
+                    peer_addr
+                ),
+            }
+        });
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -101,4 +434,256 @@ mod test {
 
         assert_eq!(message, result);
     }
+
+    /// Reader that fails with `Interrupted` on its first read (like a syscall hit by a signal)
+    struct InterruptOnce<R> {
+        inner: R,
+        interrupted: bool,
+    }
+
+    impl<R: io::Read> io::Read for InterruptOnce<R> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if !self.interrupted {
+                self.interrupted = true;
+                return Err(io::ErrorKind::Interrupted.into());
+            }
+            self.inner.read(buf)
+        }
+    }
+
+    #[test]
+    fn test_retry_on_interrupt() {
+        let mut buf = [0u8; 4];
+        let mut reader = InterruptOnce {
+            inner: Cursor::new("Hi"),
+            interrupted: false,
+        };
+        assert_eq!(retry_on_interrupt(|| reader.read(&mut buf)).unwrap(), 2);
+    }
+
+    #[test]
+    fn test_extract_string_retries_interrupted_reads() {
+        let mut reader = InterruptOnce {
+            inner: Cursor::new("Hello"),
+            interrupted: false,
+        };
+        assert_eq!(extract_string_unbuffered(&mut reader).unwrap(), "Hello");
+
+        let mut reader = InterruptOnce {
+            inner: Cursor::new("Hello"),
+            interrupted: false,
+        };
+        assert_eq!(extract_string_buffered(&mut reader).unwrap(), "Hello");
+    }
+
+    #[test]
+    fn test_extract_exact_from_buffered() {
+        // Two messages, each prefixed with a single length byte
+        let bytes = b"\x05Hello\x0dfrom a buffer";
+        let mut reader = io::BufReader::new(Cursor::new(&bytes[..]));
+
+        let read_message = |reader: &mut io::BufReader<_>| {
+            let mut len = [0u8; 1];
+            io::Read::read_exact(reader, &mut len)?;
+            extract_exact_from_buffered(reader, len[0] as usize)
+        };
+        assert_eq!(read_message(&mut reader).unwrap(), "Hello");
+        assert_eq!(read_message(&mut reader).unwrap(), "from a buffer");
+        assert_eq!(
+            read_message(&mut reader).unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+
+    #[test]
+    fn test_framed_data_back_to_back() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+
+        // Both messages are likely to arrive together, which would be ambiguous without the prefix
+        write_framed_data(&mut client, b"Hello").unwrap();
+        write_framed_data(&mut client, b"").unwrap();
+        write_framed_data(&mut client, b"from the same stream").unwrap();
+        drop(client);
+
+        assert_eq!(read_framed_data(&mut server).unwrap(), b"Hello");
+        assert_eq!(read_framed_data(&mut server).unwrap(), b"");
+        assert_eq!(
+            read_framed_data(&mut server).unwrap(),
+            b"from the same stream"
+        );
+        assert_eq!(
+            read_framed_data(&mut server).unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+
+    #[test]
+    fn test_read_framed_data_too_large() {
+        // Rejected from the prefix alone, without waiting for (or allocating) the bytes
+        let mut reader = Cursor::new((MAX_FRAME_SIZE + 1).to_be_bytes());
+        assert_eq!(
+            read_framed_data(&mut reader).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        let mut reader = Cursor::new(u32::MAX.to_be_bytes());
+        assert_eq!(
+            read_framed_data(&mut reader).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn test_read_framed_data_truncated() {
+        // The prefix promises 5 bytes, but only 3 arrive
+        let mut reader = Cursor::new(b"\x00\x00\x00\x05Hel");
+        assert_eq!(
+            read_framed_data(&mut reader).unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+
+    #[test]
+    fn test_is_client_disconnect() {
+        for kind in [
+            io::ErrorKind::BrokenPipe,
+            io::ErrorKind::ConnectionReset,
+            io::ErrorKind::ConnectionAborted,
+        ] {
+            assert!(is_client_disconnect(&io::Error::from(kind)));
+        }
+        assert!(!is_client_disconnect(&io::Error::from(
+            io::ErrorKind::InvalidData
+        )));
+        assert!(!is_client_disconnect(&io::Error::from(
+            io::ErrorKind::UnexpectedEof
+        )));
+    }
+
+    #[test]
+    fn test_panic_message() {
+        let payload = std::panic::catch_unwind(|| panic!("Oops")).unwrap_err();
+        assert_eq!(panic_message(&*payload), "Oops");
+        let payload = std::panic::catch_unwind(|| panic!("Oops {}", 42)).unwrap_err();
+        assert_eq!(panic_message(&*payload), "Oops 42");
+        let payload = std::panic::catch_unwind(|| std::panic::panic_any(42)).unwrap_err();
+        assert_eq!(panic_message(&*payload), "Box<dyn Any>");
+    }
+
+    #[test]
+    fn test_handler_panic_closes_only_its_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            serve_connections(listener, |mut stream| {
+                let message = extract_string_buffered(&mut stream)?;
+                if message == "panic" {
+                    panic!("Handler bug");
+                }
+                write_data(&mut stream, message.as_bytes())
+            })
+        });
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        write_data(&mut client, b"panic").unwrap();
+        client.shutdown(std::net::Shutdown::Write).unwrap();
+        // The connection is closed without a response
+        let mut response = vec![];
+        let _ = client.read_to_end(&mut response);
+        assert!(response.is_empty());
+
+        // And the server still accepts (and handles) new connections
+        let mut client = TcpStream::connect(addr).unwrap();
+        write_data(&mut client, b"Hello").unwrap();
+        client.shutdown(std::net::Shutdown::Write).unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert_eq!(response, "Hello");
+    }
+
+    #[test]
+    fn test_error_exit_code() {
+        for (kind, code) in [
+            (io::ErrorKind::ConnectionRefused, 2),
+            (io::ErrorKind::TimedOut, 3),
+            (io::ErrorKind::WouldBlock, 3),
+            (io::ErrorKind::ConnectionReset, 4),
+            (io::ErrorKind::UnexpectedEof, 4),
+            (io::ErrorKind::InvalidData, 5),
+            (io::ErrorKind::InvalidInput, 6),
+            (io::ErrorKind::PermissionDenied, 1),
+        ] {
+            assert_eq!(error_exit_code(&io::Error::from(kind)), code, "{:?}", kind);
+        }
+        assert_eq!(
+            error_exit_code(&io::Error::other("Request type 'echo' is not allowed")),
+            1
+        );
+    }
+
+    #[test]
+    fn test_json_error() {
+        assert_eq!(
+            json_error(&io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Bad \"tag\"\n\u{1}"
+            )),
+            r#"{"error": "Bad \"tag\"\n\u0001", "kind": "InvalidData"}"#
+        );
+    }
+
+    #[test]
+    fn test_parse_addr() {
+        assert_eq!(
+            parse_addr("127.0.0.1:4000"),
+            Ok(SocketAddr::from(([127, 0, 0, 1], 4000)))
+        );
+        assert_eq!(parse_addr("[::1]:4000").unwrap().to_string(), "[::1]:4000");
+
+        assert_eq!(
+            parse_addr("127.0.0.1").unwrap_err(),
+            "Missing port in '127.0.0.1', e.g. '127.0.0.1:4000'"
+        );
+        assert_eq!(
+            parse_addr("127.0.0.1:").unwrap_err(),
+            "Missing port in '127.0.0.1:', e.g. '127.0.0.1:4000'"
+        );
+        assert_eq!(
+            parse_addr("::1").unwrap_err(),
+            "Missing port in '::1', e.g. '[::1]:4000'"
+        );
+        assert_eq!(
+            parse_addr("[::1]").unwrap_err(),
+            "Missing port in '[::1]', e.g. '[::1]:4000'"
+        );
+        assert_eq!(
+            parse_addr("127.0.0.1:http").unwrap_err(),
+            "Invalid port 'http' in '127.0.0.1:http' (must be a number from 0 to 65535)"
+        );
+        assert!(parse_addr("127.0.0.1:70000")
+            .unwrap_err()
+            .starts_with("Invalid port"));
+
+        assert_eq!(
+            parse_addr("127.0.0.256:4000").unwrap_err(),
+            "Invalid IP address '127.0.0.256' in '127.0.0.256:4000'"
+        );
+        assert_eq!(
+            parse_addr("[fe80::zz]:4000").unwrap_err(),
+            "Invalid IP address 'fe80::zz' in '[fe80::zz]:4000'"
+        );
+        assert_eq!(
+            parse_addr(":4000").unwrap_err(),
+            "Missing IP address in ':4000', e.g. '127.0.0.1:4000'"
+        );
+
+        for s in ["localhost:4000", "localhost", "example.com:80"] {
+            assert!(
+                parse_addr(s).unwrap_err().contains("is a hostname"),
+                "{}",
+                s
+            );
+        }
+    }
 }